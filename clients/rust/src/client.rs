@@ -0,0 +1,245 @@
+/// Client of the external generation service
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::{Stream, StreamExt};
+use tokio::time::{sleep, timeout};
+use tonic::codec::{CompressionEncoding, Streaming};
+use tonic::metadata::{AsciiMetadataValue, MetadataMap};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Request, Status};
+use tracing::warn;
+use crate::proto::generation_service_client::GenerationServiceClient;
+use crate::proto::*;
+use crate::{ClientError, Result};
+
+/// Retries of requests which fail because the server is unreachable or overloaded
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Zero disables retries
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each subsequent one. The server's
+    /// suggested wait is used instead when it's longer
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Connection and request settings, unset values use tonic's defaults
+#[derive(Clone, Debug, Default)]
+pub struct ClientConfig {
+    /// Max time to wait for unary requests to complete, and for each response of a stream
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// Required for https endpoints
+    pub tls: Option<ClientTlsConfig>,
+    /// API key or JWT, sent as a bearer token
+    pub token: Option<String>,
+    /// Sent as x-caller-id, used by the server for per-client limits and request logging
+    /// if it doesn't authenticate requests
+    pub caller_id: Option<String>,
+    pub retry: RetryPolicy,
+    /// Max size of messages sent and received, tonic's default limit is 4MiB for received messages
+    pub max_message_size: Option<usize>,
+    /// Gzip-compress requests and accept compressed responses
+    pub compression: bool,
+}
+
+/// Generation service client, cheap to clone and share between tasks
+#[derive(Clone, Debug)]
+pub struct Client {
+    stub: GenerationServiceClient<Channel>,
+    metadata: Arc<MetadataMap>,
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// Connect to the server at the given URI, e.g. `http://localhost:8033`
+    pub async fn connect(uri: &str, config: ClientConfig) -> Result<Self> {
+        let mut endpoint = Endpoint::from_shared(uri.to_string())
+            .map_err(|e| ClientError::Connection(format!("invalid URI {uri}: {e}")))?;
+        if let Some(tls) = config.tls {
+            endpoint = endpoint.tls_config(tls)?;
+        }
+        if let Some(connect_timeout) = config.connect_timeout {
+            endpoint = endpoint.connect_timeout(connect_timeout);
+        }
+        let mut stub = GenerationServiceClient::new(endpoint.connect().await?);
+        if let Some(size) = config.max_message_size {
+            stub = stub.max_decoding_message_size(size).max_encoding_message_size(size);
+        }
+        if config.compression {
+            stub = stub.send_compressed(CompressionEncoding::Gzip).accept_compressed(CompressionEncoding::Gzip);
+        }
+
+        let header = |value: String, name: &str| value.parse::<AsciiMetadataValue>()
+            .map_err(|_| ClientError::Connection(format!("{name} isn't a valid header value")));
+        let mut metadata = MetadataMap::new();
+        if let Some(token) = config.token {
+            metadata.insert("authorization", header(format!("Bearer {token}"), "token")?);
+        }
+        if let Some(caller_id) = config.caller_id {
+            metadata.insert("x-caller-id", header(caller_id, "caller_id")?);
+        }
+        Ok(Self { stub, metadata: Arc::new(metadata), timeout: config.timeout, retry: config.retry })
+    }
+
+    /// Generate text for each of the given inputs
+    pub async fn generate(
+        &self, model_id: &str, texts: Vec<String>, params: impl Into<Parameters>,
+    ) -> Result<Vec<GenerationResponse>> {
+        let request = BatchedGenerationRequest {
+            model_id: model_id.to_string(),
+            requests: texts.into_iter().map(|text| GenerationRequest { text, ..Default::default() }).collect(),
+            params: Some(params.into()),
+            ..Default::default()
+        };
+        self.call(|mut stub, request| async move { stub.generate(request).await }, request).await
+            .map(|response| response.responses)
+    }
+
+    /// Generate text for a single input
+    pub async fn generate_one(
+        &self, model_id: &str, text: impl Into<String>, params: impl Into<Parameters>,
+    ) -> Result<GenerationResponse> {
+        self.generate(model_id, vec![text.into()], params).await?.pop()
+            .ok_or_else(|| ClientError::Generation("empty response".to_string()))
+    }
+
+    /// Generate text for a single input, streaming the responses as tokens are generated.
+    /// Opening the stream is retried, but the stream itself isn't resumed if it fails
+    pub async fn generate_stream(
+        &self, model_id: &str, text: impl Into<String>, params: impl Into<Parameters>,
+    ) -> Result<impl Stream<Item = Result<GenerationResponse>>> {
+        let request = SingleGenerationRequest {
+            model_id: model_id.to_string(),
+            request: Some(GenerationRequest { text: text.into(), ..Default::default() }),
+            params: Some(params.into()),
+            ..Default::default()
+        };
+        let stream = self.call(
+            |mut stub, request| async move { stub.generate_stream(request).await }, request,
+        ).await?;
+        Ok(with_item_timeout(stream, self.timeout))
+    }
+
+    /// Generate text for a single input, streaming only the text as it's generated
+    pub async fn generate_text_stream(
+        &self, model_id: &str, text: impl Into<String>, params: impl Into<Parameters>,
+    ) -> Result<impl Stream<Item = Result<String>>> {
+        let stream = self.generate_stream(model_id, text, params).await?;
+        Ok(stream.filter_map(|response| futures::future::ready(match response {
+            Ok(response) if response.text.is_empty() => None,
+            result => Some(result.map(|response| response.text)),
+        })))
+    }
+
+    /// Tokenize each of the given texts
+    pub async fn tokenize(
+        &self, model_id: &str, texts: Vec<String>, return_tokens: bool,
+    ) -> Result<Vec<TokenizeResponse>> {
+        let request = BatchedTokenizeRequest {
+            model_id: model_id.to_string(),
+            requests: texts.into_iter().map(|text| TokenizeRequest { text }).collect(),
+            return_tokens,
+            return_offsets: false,
+        };
+        self.call(|mut stub, request| async move { stub.tokenize(request).await }, request).await
+            .map(|response| response.responses)
+    }
+
+    /// Get the model's kind and limits
+    pub async fn model_info(&self, model_id: &str) -> Result<ModelInfoResponse> {
+        let request = ModelInfoRequest { model_id: model_id.to_string() };
+        self.call(|mut stub, request| async move { stub.model_info(request).await }, request).await
+    }
+
+    /// List the models which can be requested, with their limits and supported features
+    pub async fn list_models(&self) -> Result<Vec<ModelInfoResponse>> {
+        let request = ListModelsRequest {};
+        self.call(|mut stub, request| async move { stub.list_models(request).await }, request).await
+            .map(|response| response.models)
+    }
+
+    /// Register a long prompt, returning its id and token count. Requests can then set
+    /// `prompt_id` in place of sending it, with `text` appended to it
+    pub async fn register_prompt(&self, text: impl Into<String>) -> Result<RegisterPromptResponse> {
+        let request = RegisterPromptRequest { text: text.into() };
+        self.call(|mut stub, request| async move { stub.register_prompt(request).await }, request).await
+    }
+
+    /// Release a registered prompt before it expires
+    pub async fn release_prompt(&self, prompt_id: &str) -> Result<()> {
+        let request = ReleasePromptRequest { prompt_id: prompt_id.to_string() };
+        self.call(|mut stub, request| async move { stub.release_prompt(request).await }, request).await
+            .map(|_| ())
+    }
+
+    /// Make a request, with the configured metadata and timeout, retrying it if it fails
+    /// with a retryable error
+    async fn call<M: Clone, T, F, Fut>(&self, method: F, message: M) -> Result<T>
+    where
+        F: Fn(GenerationServiceClient<Channel>, Request<M>) -> Fut,
+        Fut: Future<Output = std::result::Result<tonic::Response<T>, Status>>,
+    {
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let mut request = Request::new(message.clone());
+            *request.metadata_mut() = (*self.metadata).clone();
+            let response = method(self.stub.clone(), request);
+            let result = match self.timeout {
+                Some(duration) => timeout(duration, response).await
+                    .map_err(|_| ClientError::Timeout(format!("no response within {duration:?}")))
+                    .and_then(|result| result.map_err(ClientError::from)),
+                None => response.await.map_err(ClientError::from),
+            };
+            match result {
+                Err(err) if err.is_retryable() && attempt <= self.retry.max_retries => {
+                    if let ClientError::Overloaded(_, Some(retry_after)) = &err {
+                        backoff = backoff.max(Duration::from_millis(*retry_after as u64));
+                    }
+                    let delay = backoff.min(self.retry.max_backoff);
+                    warn!("Request attempt {attempt} failed, retrying in {delay:?}: {err}");
+                    sleep(delay).await;
+                    backoff *= 2;
+                    attempt += 1;
+                },
+                result => return result.map(tonic::Response::into_inner),
+            }
+        }
+    }
+}
+
+/// Fail the stream if the next response isn't received within the timeout
+fn with_item_timeout(
+    stream: Streaming<GenerationResponse>, item_timeout: Option<Duration>,
+) -> impl Stream<Item = Result<GenerationResponse>> {
+    futures::stream::unfold(Some(stream), move |state| async move {
+        let mut stream = state?;
+        let next = match item_timeout {
+            Some(duration) => match timeout(duration, stream.next()).await {
+                Ok(next) => next,
+                Err(_) => return Some((
+                    Err(ClientError::Timeout(format!("no streamed response within {duration:?}"))), None,
+                )),
+            },
+            None => stream.next().await,
+        };
+        match next? {
+            Ok(response) => Some((Ok(response), Some(stream))),
+            // Nothing follows an error
+            Err(status) => Some((Err(status.into()), None)),
+        }
+    })
+}
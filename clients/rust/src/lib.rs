@@ -0,0 +1,64 @@
+//! Client of the external text generation gRPC API (`fmaas.GenerationService`)
+
+mod client;
+mod params;
+#[allow(clippy::derive_partial_eq_without_eq, clippy::large_enum_variant)]
+mod pb;
+
+pub use client::{Client, ClientConfig, RetryPolicy};
+pub use params::ParametersBuilder;
+/// Generated messages of the external API
+pub use pb::fmaas as proto;
+pub use tonic::transport::ClientTlsConfig;
+use prost::Message;
+use thiserror::Error;
+use tonic::{transport, Code, Status};
+
+#[derive(Error, Debug, Clone)]
+pub enum ClientError {
+    #[error("Could not connect to text generation server: {0}")]
+    Connection(String),
+    #[error("Text generation server timed out: {0}")]
+    Timeout(String),
+    /// The server is too busy, with its suggested wait before retrying
+    #[error("Text generation server overloaded: {0}")]
+    Overloaded(String, Option<u32>),
+    /// Invalid request, e.g. unsupported parameters or input which is too long
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("{0}")]
+    Generation(String),
+}
+
+impl ClientError {
+    /// Whether the request may succeed if retried
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Connection(_) | Self::Overloaded(..))
+    }
+}
+
+impl From<Status> for ClientError {
+    fn from(err: Status) -> Self {
+        let message = err.message().to_string();
+        match err.code() {
+            Code::DeadlineExceeded => Self::Timeout(message),
+            Code::Unavailable => Self::Connection(message),
+            Code::ResourceExhausted => {
+                let retry_after = proto::OverloadedDetails::decode(err.details()).ok()
+                    .map(|details| details.retry_after_millis)
+                    .filter(|&millis| millis > 0);
+                Self::Overloaded(message, retry_after)
+            },
+            Code::InvalidArgument | Code::FailedPrecondition => Self::InvalidRequest(message),
+            _ => Self::Generation(message),
+        }
+    }
+}
+
+impl From<transport::Error> for ClientError {
+    fn from(err: transport::Error) -> Self {
+        Self::Connection(err.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
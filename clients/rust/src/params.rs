@@ -0,0 +1,147 @@
+/// Typed construction of generation parameters
+use crate::proto::{
+    DecodingMethod, DecodingParameters, Parameters, ResponseOptions, SamplingParameters,
+    StoppingCriteria,
+};
+use crate::proto::sampling_parameters::TemperatureBreakpoint;
+
+/// Builder of request parameters, unset ones use the server's defaults
+#[derive(Clone, Debug, Default)]
+pub struct ParametersBuilder {
+    params: Parameters,
+}
+
+impl ParametersBuilder {
+    /// Greedy decoding
+    pub fn greedy() -> Self {
+        Self::default()
+    }
+
+    /// Sampling at the given temperature
+    pub fn sampling(temperature: f32) -> Self {
+        let mut builder = Self::default();
+        builder.params.method = DecodingMethod::Sample as i32;
+        builder.sampling_params().temperature = temperature;
+        builder
+    }
+
+    pub fn top_k(mut self, top_k: u32) -> Self {
+        self.sampling_params().top_k = top_k;
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.sampling_params().top_p = top_p;
+        self
+    }
+
+    pub fn typical_p(mut self, typical_p: f32) -> Self {
+        self.sampling_params().typical_p = typical_p;
+        self
+    }
+
+    /// Random seed, so that sampled output can be reproduced
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.sampling_params().seed = Some(seed);
+        self
+    }
+
+    /// Vary the sampling temperature as tokens are generated, interpolating between
+    /// `(token_index, temperature)` breakpoints
+    pub fn temperature_schedule(mut self, breakpoints: impl IntoIterator<Item = (u32, f32)>) -> Self {
+        self.sampling_params().temperature_schedule = breakpoints.into_iter()
+            .map(|(token_index, temperature)| TemperatureBreakpoint { token_index, temperature })
+            .collect();
+        self
+    }
+
+    pub fn max_new_tokens(mut self, max_new_tokens: u32) -> Self {
+        self.stopping_params().max_new_tokens = max_new_tokens;
+        self
+    }
+
+    pub fn min_new_tokens(mut self, min_new_tokens: u32) -> Self {
+        self.stopping_params().min_new_tokens = min_new_tokens;
+        self
+    }
+
+    pub fn time_limit_millis(mut self, time_limit_millis: u32) -> Self {
+        self.stopping_params().time_limit_millis = time_limit_millis;
+        self
+    }
+
+    pub fn stop_sequences<S: Into<String>>(mut self, stop_sequences: impl IntoIterator<Item = S>) -> Self {
+        self.stopping_params().stop_sequences = stop_sequences.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn stop_regex<S: Into<String>>(mut self, stop_regex: impl IntoIterator<Item = S>) -> Self {
+        self.stopping_params().stop_regex = stop_regex.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.decoding_params().repetition_penalty = repetition_penalty;
+        self
+    }
+
+    pub fn truncate_input_tokens(mut self, truncate_input_tokens: u32) -> Self {
+        self.params.truncate_input_tokens = truncate_input_tokens;
+        self
+    }
+
+    /// Include the input text in the output
+    pub fn include_input_text(mut self) -> Self {
+        self.response_options().input_text = true;
+        self
+    }
+
+    /// Include the generated tokens, with their logprobs and ranks, and the
+    /// given number of top candidate tokens at each position if non-zero
+    pub fn include_generated_tokens(mut self, top_n_tokens: u32) -> Self {
+        let response = self.response_options();
+        response.generated_tokens = true;
+        response.token_logprobs = true;
+        response.token_ranks = true;
+        response.top_n_tokens = Some(top_n_tokens);
+        self
+    }
+
+    /// Include this many generated tokens in each streamed response
+    pub fn stream_chunk_tokens(mut self, stream_chunk_tokens: u32) -> Self {
+        self.response_options().stream_chunk_tokens = stream_chunk_tokens;
+        self
+    }
+
+    /// Modify parameters which have no dedicated setter
+    pub fn with(mut self, modify: impl FnOnce(&mut Parameters)) -> Self {
+        modify(&mut self.params);
+        self
+    }
+
+    pub fn build(self) -> Parameters {
+        self.params
+    }
+
+    fn sampling_params(&mut self) -> &mut SamplingParameters {
+        self.params.sampling.get_or_insert_with(Default::default)
+    }
+
+    fn stopping_params(&mut self) -> &mut StoppingCriteria {
+        self.params.stopping.get_or_insert_with(Default::default)
+    }
+
+    fn decoding_params(&mut self) -> &mut DecodingParameters {
+        self.params.decoding.get_or_insert_with(Default::default)
+    }
+
+    fn response_options(&mut self) -> &mut ResponseOptions {
+        self.params.response.get_or_insert_with(Default::default)
+    }
+}
+
+impl From<ParametersBuilder> for Parameters {
+    fn from(builder: ParametersBuilder) -> Self {
+        builder.build()
+    }
+}
@@ -1,5 +1,7 @@
 import asyncio
+import copy
 import glob
+import math
 import os
 import random
 import sys
@@ -150,6 +152,13 @@ async def run_unary_test_case(stub, case):
         assert not expected_err
         # Convert response back to dict
         response_dict = json_format.MessageToDict(response)
+        expected_responses = (expected or {}).get("responses", [])
+        for i, r in enumerate(response_dict.get("responses", [])):
+            # Usage includes timings which vary between runs
+            r.pop("usage", None)
+            # Stop details are only compared in cases which include them
+            if i >= len(expected_responses) or "stopDetails" not in expected_responses[i]:
+                r.pop("stopDetails", None)
         if not skip_check:
             if response_dict != approx(expected):
                 print(f'================ Test: {case.get("name")}:')
@@ -262,6 +271,8 @@ async def run_test_cases_async(test_cases, seq2seq_model=False):
             await asyncio.sleep(0.3)
         # Add in the multi-input seed case
         tasks.append(asyncio.create_task(_test_multi_input_seeds(stub)))
+        tasks.append(asyncio.create_task(_test_seed_reproducibility(stub)))
+        tasks.append(asyncio.create_task(_test_score(stub, seq2seq_model)))
         done, pending = await asyncio.wait(
             tasks, return_when=asyncio.FIRST_EXCEPTION, timeout=TESTS_TIMEOUT,
         )
@@ -305,6 +316,59 @@ async def _test_multi_input_seeds(stub):
             assert 0 <= seed <= 4294967295
 
 
+async def _test_seed_reproducibility(stub):
+    # Ensure that resending sampling requests with the seeds returned for
+    # them reproduces the same outputs, for both batch and single requests
+    with open("test_cases_common.yaml") as f:
+        test_case = yaml.load(f, Loader=yaml.Loader)
+        request = test_case["seed_test"]["request"]
+        message = json_format.ParseDict(request, pb2.BatchedGenerationRequest())
+        response = await stub.Generate(message)
+        first_responses = json_format.MessageToDict(response)["responses"]
+
+        for first in first_responses:
+            seeded_request = copy.deepcopy(request)
+            seeded_request["requests"] = seeded_request["requests"][:1]
+            seeded_request["params"]["sampling"]["seed"] = first["seed"]
+            message = json_format.ParseDict(seeded_request, pb2.BatchedGenerationRequest())
+            response = await stub.Generate(message)
+            second = json_format.MessageToDict(response)["responses"][0]
+            assert second["seed"] == first["seed"]
+            assert second["text"] == first["text"]
+
+            # Same again via the streaming API
+            seeded_request["request"] = seeded_request.pop("requests")[0]
+            message = json_format.ParseDict(seeded_request, pb2.SingleGenerationRequest())
+            output, seed = "", None
+            async for response in stub.GenerateStream(message):
+                response_dict = json_format.MessageToDict(response)
+                output += response_dict.get("text", "")
+                seed = response_dict.get("seed", seed)
+            assert seed == first["seed"]
+            assert output == first["text"]
+
+
+async def _test_score(stub, seq2seq_model):
+    # Ensure that scored inputs' nll and perplexity are consistent with their token logprobs
+    message = pb2.BatchedScoreRequest(requests=[
+        pb2.ScoreRequest(text="A very simple sentence"), pb2.ScoreRequest(text="Hello"),
+    ])
+    if seq2seq_model:
+        with pytest.raises(grpc.RpcError) as e:
+            await stub.Score(message)
+        assert e.value.code() == grpc.StatusCode.FAILED_PRECONDITION
+        return
+
+    response = await stub.Score(message)
+    for result in response.responses:
+        assert len(result.tokens) == result.input_token_count
+        assert math.isnan(result.tokens[0].logprob)
+        logprobs = [t.logprob for t in result.tokens[1:]]
+        assert result.nll == pytest.approx(-sum(logprobs), rel=1e-4)
+        if logprobs:
+            assert result.perplexity == pytest.approx(math.exp(result.nll / len(logprobs)), rel=1e-4)
+
+
 @pytest.mark.model("bigscience/bloom-560m")
 @pytest.mark.extensions(".safetensors,.json,.model")
 @pytest.mark.shards(1)
@@ -355,6 +419,7 @@ def test_explicit_path():
             result = asyncio.get_event_loop().run_until_complete(test_model_info())
             assert result.max_sequence_length == 200
             assert result.max_new_tokens == 169
+            assert result.max_top_n_tokens == 5
             assert result.model_kind == pb2.ModelInfoResponse.ModelKind.ENCODER_DECODER
         finally:
             p.terminate()
@@ -37,6 +37,12 @@ struct Args {
     max_sequence_length: usize,
     #[clap(default_value = "1024", long, env)]
     max_new_tokens: usize,
+    #[clap(default_value = "5", long, env)]
+    max_top_n_tokens: u32,
+    #[clap(default_value = "0", long, env)]
+    default_top_n_tokens: u32,
+    #[clap(default_value = "truncate", long, env)]
+    token_limit_policy: String,
     #[clap(default_value = "12", long, env)]
     max_batch_size: usize,
     #[clap(default_value = None, long, env)]
@@ -45,6 +51,12 @@ struct Args {
     max_prefill_weight: Option<usize>,
     #[clap(default_value = "24", long, env)]
     max_waiting_tokens: usize,
+    #[clap(default_value = "adaptive", long, env)]
+    waiting_tokens_policy: String,
+    #[clap(long, env)]
+    pipeline_prefill: bool,
+    #[clap(long, env)]
+    retry_failed_batches: bool,
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
     #[clap(default_value = "8033", long, short, env)]
@@ -64,9 +76,127 @@ struct Args {
     #[clap(long, env)]
     tls_client_ca_cert_path: Option<String>,
     #[clap(long, env)]
+    api_keys_path: Option<String>,
+    #[clap(long, env)]
+    jwt_key_path: Option<String>,
+    #[clap(long, env)]
+    jwt_issuer: Option<String>,
+    #[clap(long, env)]
+    jwt_audience: Option<String>,
+    #[clap(default_value = "tenant", long, env)]
+    jwt_tenant_claim: String,
+    #[clap(long, env)]
     output_special_tokens: bool,
+    #[clap(default_value = "\\n\\n", long, env)]
+    seq2seq_input_separator: String,
     #[clap(default_value = "1.0", long, short, env)]
     cuda_process_memory_fraction: f32,
+    #[clap(default_value = "0.0", long, env)]
+    determinism_audit_fraction: f32,
+    #[clap(long, env)]
+    warmup: bool,
+    #[clap(long, env)]
+    request_log_sink: Option<String>,
+    #[clap(default_value = "truncate:32", long, env)]
+    log_text_policy: String,
+    #[clap(long, env)]
+    request_hooks: Option<String>,
+    #[clap(long, env)]
+    grpc_compression: bool,
+    #[clap(long, env)]
+    shard_grpc_compression: bool,
+    #[clap(long, env)]
+    grpc_web_origins: Option<String>,
+    #[clap(long, env)]
+    fim_sentinel_tokens: Option<String>,
+    #[clap(long, env)]
+    runtime_config_path: Option<String>,
+    #[clap(long, env)]
+    parameter_policy_path: Option<String>,
+    #[clap(long, env)]
+    prompt_templates_path: Option<String>,
+    #[clap(long, env)]
+    coalesce_requests: bool,
+    #[clap(default_value = "32", long, env)]
+    stream_buffer_size: usize,
+    #[clap(default_value = "coalesce", long, env)]
+    slow_stream_policy: String,
+    #[clap(default_value = "10", long, env)]
+    shard_health_check_interval_secs: u64,
+    #[clap(long, env)]
+    max_concurrent_requests_per_client: Option<usize>,
+    #[clap(long, env)]
+    max_queued_prompt_bytes: Option<usize>,
+    #[clap(default_value = "auto", long, env)]
+    batch_type: String,
+    #[clap(default_value = "0", long, env)]
+    response_cache_size: u64,
+    #[clap(default_value = "300", long, env)]
+    response_cache_ttl_secs: u64,
+    #[clap(default_value = "0", long, env)]
+    prompt_registry_capacity_bytes: u64,
+    #[clap(default_value = "3600", long, env)]
+    registered_prompt_idle_timeout_secs: u64,
+    #[clap(long, env)]
+    shard_keepalive_interval_secs: Option<u64>,
+    #[clap(long, env)]
+    shard_keepalive_timeout_secs: Option<u64>,
+    #[clap(long, env)]
+    shard_initial_stream_window_size: Option<u32>,
+    #[clap(long, env)]
+    shard_initial_connection_window_size: Option<u32>,
+    #[clap(long, env)]
+    shard_max_message_size: Option<usize>,
+    #[clap(long, env)]
+    shard_connect_timeout_secs: Option<u64>,
+    #[clap(long, env)]
+    shard_connect_retries: Option<u32>,
+    #[clap(long, env)]
+    shard_tcp_nodelay: Option<bool>,
+    #[clap(long, env)]
+    shard_step_timeout_secs: Option<u64>,
+    #[clap(long, env)]
+    shutdown_grace_period_secs: Option<u64>,
+    #[clap(long, env)]
+    ttft_slo_millis: Option<u64>,
+    #[clap(long, env)]
+    shard_prefill_progress: bool,
+    #[clap(default_value = "none", long, env)]
+    preemption_policy: String,
+    #[clap(default_value = "256", long, env)]
+    preemption_min_generated_tokens: u32,
+    #[clap(default_value = "huggingface", long, env)]
+    decoder_backend: String,
+    #[clap(long, env)]
+    decoder_model_path: Option<String>,
+    #[clap(default_value = "65536", long, env)]
+    token_text_cache_size: u64,
+    #[clap(default_value = "65536", long, env)]
+    max_decoded_top_tokens: usize,
+    #[clap(default_value = "0", long, env)]
+    max_generation_jobs: u64,
+    #[clap(default_value = "600", long, env)]
+    generation_job_ttl_secs: u64,
+    #[clap(long, env)]
+    generation_job_journal_path: Option<String>,
+    #[clap(long, env)]
+    kv_cache_capacity_bytes: Option<u64>,
+    #[clap(default_value = "fifo", long, env)]
+    scheduling_policy: String,
+    #[clap(default_value = "20", long, env)]
+    sjf_aging_rate: f64,
+    #[clap(long, env)]
+    latency_lane_max_input_tokens: Option<usize>,
+    #[clap(long, env)]
+    latency_lane_max_new_tokens: Option<u32>,
+    #[clap(default_value = "0", long, env)]
+    max_embedding_batch_size: usize,
+    #[clap(default_value = "16384", long, env)]
+    max_embedding_batch_tokens: usize,
+    #[clap(long, env)]
+    admin_api: bool,
+    #[clap(default_value = "0", long, env)]
+    replay_buffer_size: usize,
 }
 
 fn main() -> ExitCode {
@@ -173,8 +303,12 @@ fn main() -> ExitCode {
         return ExitCode::SUCCESS;
     }
 
-    let tokenizer_path = resolve_tokenizer_path(args.model_name, args.revision)
+    let tokenizer_path = resolve_tokenizer_path(args.model_name.clone(), args.revision.clone())
         .expect("Could not find tokenizer for model");
+    // The model's config.json is alongside its tokenizer
+    let model_config_path = Path::new(&tokenizer_path).with_file_name("config.json")
+        .to_string_lossy().to_string();
+    let tokenizer_config_path = Path::new(&tokenizer_path).with_file_name("tokenizer_config.json");
 
     // All shard started
     // Start webserver
@@ -187,10 +321,62 @@ fn main() -> ExitCode {
         args.max_sequence_length.to_string(),
         "--max-new-tokens".to_string(),
         args.max_new_tokens.to_string(),
+        "--max-top-n-tokens".to_string(),
+        args.max_top_n_tokens.to_string(),
+        "--default-top-n-tokens".to_string(),
+        args.default_top_n_tokens.to_string(),
+        "--token-limit-policy".to_string(),
+        args.token_limit_policy,
+        "--model-name".to_string(),
+        args.model_name,
         "--max-batch-size".to_string(),
         args.max_batch_size.to_string(),
         "--max-waiting-tokens".to_string(),
         args.max_waiting_tokens.to_string(),
+        "--waiting-tokens-policy".to_string(),
+        args.waiting_tokens_policy,
+        "--stream-buffer-size".to_string(),
+        args.stream_buffer_size.to_string(),
+        "--slow-stream-policy".to_string(),
+        args.slow_stream_policy,
+        "--log-text-policy".to_string(),
+        args.log_text_policy,
+        "--shard-health-check-interval-secs".to_string(),
+        args.shard_health_check_interval_secs.to_string(),
+        "--batch-type".to_string(),
+        args.batch_type,
+        "--response-cache-size".to_string(),
+        args.response_cache_size.to_string(),
+        "--response-cache-ttl-secs".to_string(),
+        args.response_cache_ttl_secs.to_string(),
+        "--prompt-registry-capacity-bytes".to_string(),
+        args.prompt_registry_capacity_bytes.to_string(),
+        "--registered-prompt-idle-timeout-secs".to_string(),
+        args.registered_prompt_idle_timeout_secs.to_string(),
+        "--preemption-policy".to_string(),
+        args.preemption_policy,
+        "--preemption-min-generated-tokens".to_string(),
+        args.preemption_min_generated_tokens.to_string(),
+        "--decoder-backend".to_string(),
+        args.decoder_backend,
+        "--token-text-cache-size".to_string(),
+        args.token_text_cache_size.to_string(),
+        "--max-decoded-top-tokens".to_string(),
+        args.max_decoded_top_tokens.to_string(),
+        "--max-generation-jobs".to_string(),
+        args.max_generation_jobs.to_string(),
+        "--generation-job-ttl-secs".to_string(),
+        args.generation_job_ttl_secs.to_string(),
+        "--scheduling-policy".to_string(),
+        args.scheduling_policy,
+        "--sjf-aging-rate".to_string(),
+        args.sjf_aging_rate.to_string(),
+        "--max-embedding-batch-size".to_string(),
+        args.max_embedding_batch_size.to_string(),
+        "--max-embedding-batch-tokens".to_string(),
+        args.max_embedding_batch_tokens.to_string(),
+        "--replay-buffer-size".to_string(),
+        args.replay_buffer_size.to_string(),
         "--port".to_string(),
         args.port.to_string(),
         "--grpc-port".to_string(),
@@ -201,6 +387,44 @@ fn main() -> ExitCode {
         tokenizer_path,
     ];
 
+    if let Some(slo) = args.ttft_slo_millis {
+        argv.push("--ttft-slo-millis".to_string());
+        argv.push(slo.to_string());
+    }
+
+    if let Some(max_per_client) = args.max_concurrent_requests_per_client {
+        argv.push("--max-concurrent-requests-per-client".to_string());
+        argv.push(max_per_client.to_string());
+    }
+
+    if let Some(max_bytes) = args.max_queued_prompt_bytes {
+        argv.push("--max-queued-prompt-bytes".to_string());
+        argv.push(max_bytes.to_string());
+    }
+
+    if let Some(grace_period) = args.shutdown_grace_period_secs {
+        argv.push("--shutdown-grace-period-secs".to_string());
+        argv.push(grace_period.to_string());
+    }
+
+    // Connection and HTTP/2 settings of the router's channels to the shards
+    for (flag, value) in [
+        ("--shard-keepalive-interval-secs", args.shard_keepalive_interval_secs.map(|v| v.to_string())),
+        ("--shard-keepalive-timeout-secs", args.shard_keepalive_timeout_secs.map(|v| v.to_string())),
+        ("--shard-initial-stream-window-size", args.shard_initial_stream_window_size.map(|v| v.to_string())),
+        ("--shard-initial-connection-window-size", args.shard_initial_connection_window_size.map(|v| v.to_string())),
+        ("--shard-max-message-size", args.shard_max_message_size.map(|v| v.to_string())),
+        ("--shard-connect-timeout-secs", args.shard_connect_timeout_secs.map(|v| v.to_string())),
+        ("--shard-connect-retries", args.shard_connect_retries.map(|v| v.to_string())),
+        ("--shard-tcp-nodelay", args.shard_tcp_nodelay.map(|v| v.to_string())),
+        ("--shard-step-timeout-secs", args.shard_step_timeout_secs.map(|v| v.to_string())),
+    ] {
+        if let Some(value) = value {
+            argv.push(flag.to_string());
+            argv.push(value);
+        }
+    }
+
     if let Some(max_batch_weight) = args.max_batch_weight {
         argv.push("--max-batch-weight".to_string());
         argv.push(max_batch_weight.to_string());
@@ -223,6 +447,25 @@ fn main() -> ExitCode {
         argv.push(path);
     }
 
+    if let Some(path) = args.api_keys_path {
+        argv.push("--api-keys-path".to_string());
+        argv.push(path);
+    }
+    if let Some(path) = args.jwt_key_path {
+        argv.push("--jwt-key-path".to_string());
+        argv.push(path);
+    }
+    if let Some(issuer) = args.jwt_issuer {
+        argv.push("--jwt-issuer".to_string());
+        argv.push(issuer);
+    }
+    if let Some(audience) = args.jwt_audience {
+        argv.push("--jwt-audience".to_string());
+        argv.push(audience);
+    }
+    argv.push("--jwt-tenant-claim".to_string());
+    argv.push(args.jwt_tenant_claim);
+
     if args.json_output {
         argv.push("--json-output".to_string());
     }
@@ -231,6 +474,119 @@ fn main() -> ExitCode {
         argv.push("--output-special-tokens".into());
     }
 
+    argv.push("--seq2seq-input-separator".to_string());
+    argv.push(args.seq2seq_input_separator);
+
+    if let Some(capacity) = args.kv_cache_capacity_bytes {
+        argv.push("--kv-cache-capacity-bytes".to_string());
+        argv.push(capacity.to_string());
+        argv.push("--model-config-path".to_string());
+        argv.push(model_config_path);
+    }
+
+    // Its chat template renders the tools provided with requests
+    if tokenizer_config_path.exists() {
+        argv.push("--tokenizer-config-path".to_string());
+        argv.push(tokenizer_config_path.to_string_lossy().to_string());
+    }
+
+    if let Some(path) = args.decoder_model_path {
+        argv.push("--decoder-model-path".to_string());
+        argv.push(path);
+    }
+
+    if let Some(sink) = args.request_log_sink {
+        argv.push("--request-log-sink".to_string());
+        argv.push(sink);
+    }
+
+    if let Some(hooks) = args.request_hooks {
+        argv.push("--request-hooks".to_string());
+        argv.push(hooks);
+    }
+
+    if args.grpc_compression {
+        argv.push("--grpc-compression".into());
+    }
+
+    if args.shard_grpc_compression {
+        argv.push("--shard-grpc-compression".into());
+    }
+
+    if let Some(tokens) = args.latency_lane_max_input_tokens {
+        argv.push("--latency-lane-max-input-tokens".to_string());
+        argv.push(tokens.to_string());
+    }
+
+    if let Some(tokens) = args.latency_lane_max_new_tokens {
+        argv.push("--latency-lane-max-new-tokens".to_string());
+        argv.push(tokens.to_string());
+    }
+
+    if let Some(origins) = args.grpc_web_origins {
+        argv.push("--grpc-web-origins".to_string());
+        argv.push(origins);
+    }
+
+    if args.shard_prefill_progress {
+        argv.push("--shard-prefill-progress".into());
+    }
+
+    if let Some(tokens) = args.fim_sentinel_tokens {
+        argv.push("--fim-sentinel-tokens".to_string());
+        argv.push(tokens);
+    }
+
+    if let Some(path) = args.runtime_config_path {
+        argv.push("--runtime-config-path".to_string());
+        argv.push(path);
+    }
+
+    if let Some(path) = args.generation_job_journal_path {
+        argv.push("--generation-job-journal-path".to_string());
+        argv.push(path);
+    }
+
+    if let Some(path) = args.parameter_policy_path {
+        argv.push("--parameter-policy-path".to_string());
+        argv.push(path);
+    }
+
+    if let Some(path) = args.prompt_templates_path {
+        argv.push("--prompt-templates-path".to_string());
+        argv.push(path);
+    }
+
+    if let Some(revision) = args.revision {
+        argv.push("--model-version".to_string());
+        argv.push(revision);
+    }
+
+    if args.pipeline_prefill {
+        argv.push("--pipeline-prefill".into());
+    }
+
+    if args.retry_failed_batches {
+        argv.push("--retry-failed-batches".into());
+    }
+
+    if args.coalesce_requests {
+        argv.push("--coalesce-requests".into());
+    }
+
+    if args.admin_api {
+        argv.push("--admin-api".into());
+    }
+
+    if args.warmup {
+        argv.push("--warmup".into());
+    }
+
+    if args.determinism_audit_fraction > 0.0 {
+        argv.push("--determinism-audit-fraction".into());
+        argv.push(args.determinism_audit_fraction.to_string());
+    }
+
     let mut webserver = match Popen::create(
         &argv,
         PopenConfig {
@@ -254,7 +610,7 @@ fn main() -> ExitCode {
                 tracing::error!("{err}");
             }
 
-            shutdown_shards(shutdown, &shutdown_receiver);
+            shutdown_shards(shutdown, shutdown_receiver);
             return ExitCode::FAILURE;
         }
     };
@@ -287,7 +643,7 @@ fn main() -> ExitCode {
         match webserver.poll() {
             Some(_) => {
                 tracing::error!("Webserver Crashed");
-                shutdown_shards(shutdown, &shutdown_receiver);
+                shutdown_shards(shutdown, shutdown_receiver);
                 return ExitCode::FAILURE;
             }
             None => {
@@ -301,7 +657,7 @@ fn main() -> ExitCode {
     info!("Waiting for router to gracefully shutdown");
     webserver.wait_timeout(Duration::from_secs(120)).unwrap();
     info!("Router terminated");
-    shutdown_shards(shutdown, &shutdown_receiver);
+    shutdown_shards(shutdown, shutdown_receiver);
 
     exit_code
 }
@@ -501,7 +857,7 @@ fn shard_manager(
     loop {
         // Process exited
         if p.poll().is_some() {
-            let mut err = String::new();
+            let err = String::new();
             //We don't need to do this now that we're logging
             //p.stderr.take().unwrap().read_to_string(&mut err).unwrap();
             status_sender
@@ -550,7 +906,7 @@ fn resolve_tokenizer_path(model_name: String, revision: Option<String>) -> Resul
     let cache = env::var("TRANSFORMERS_CACHE")
         .or_else(|_| env::var("HUGGINGFACE_HUB_CACHE")).ok();
     let mut model_dir = cache.as_ref().map(
-        |c| Path::new(&c).join(format!("models--{}", model_name.replace("/", "--")))
+        |c| Path::new(&c).join(format!("models--{}", model_name.replace('/', "--")))
     );
     if let Some(ref d) = model_dir {
         if !d.try_exists()? {
@@ -1,13 +1,23 @@
-use std::fs;
+use std::{env, fs};
+use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     fs::create_dir("src/pb").unwrap_or(());
+    // Encoded descriptors served by the gRPC reflection service
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR")?).join("fmaas_descriptor.bin");
     tonic_build::configure()
         .build_client(false)
         .build_server(true)
         .out_dir("src/pb")
+        .file_descriptor_set_path(descriptor_path)
         .include_file("mod.rs")
-        .compile(&["../proto/generation.proto"], &["../proto"])
+        .compile(&["../proto/generation.proto", "../proto/generation_v2.proto"], &["../proto"])
+        .unwrap_or_else(|e| panic!("protobuf compilation failed: {}", e));
+    // Client of the request log collector, kept out of the descriptors served by reflection
+    tonic_build::configure()
+        .build_client(true)
+        .build_server(false)
+        .compile(&["../proto/request_log.proto"], &["../proto"])
         .unwrap_or_else(|e| panic!("protobuf compilation failed: {}", e));
 
     Ok(())
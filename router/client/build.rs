@@ -1,10 +1,11 @@
-use std::fs;
+use std::{env, fs};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     fs::create_dir("src/pb").unwrap_or(());
     tonic_build::configure()
         .build_client(true)
-        .build_server(false)
+        // The shard service is only implemented by the mock shard
+        .build_server(env::var_os("CARGO_FEATURE_MOCK").is_some())
         .out_dir("src/pb")
         .include_file("mod.rs")
         .compile(&["../../proto/generate.proto"], &["../../proto"])
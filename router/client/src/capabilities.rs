@@ -0,0 +1,97 @@
+/// Optional features of the shards, which differ between versions of the shard server
+use crate::pb::generate::v1::CapabilitiesResponse;
+
+/// Features supported by all of a set of shards
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShardCapabilities {
+    /// Distinct versions of the shard server, "unknown" for those which don't report it
+    pub versions: Vec<String>,
+    pub top_n_tokens: bool,
+    pub speculative_decoding: bool,
+    pub adapters: bool,
+    /// Smallest limit of tokens in a batch, None if no shard has one
+    pub max_batch_tokens: Option<u32>,
+    pub prefill_progress: bool,
+    pub embeddings: bool,
+    pub offload: bool,
+    pub repetition_penalty_range: bool,
+    pub no_repeat_ngram_size: bool,
+    pub token_healing: bool,
+    pub watermark: bool,
+    pub bad_words: bool,
+    pub input_ids: bool,
+    pub json_schema: bool,
+    pub deterministic: bool,
+}
+
+impl ShardCapabilities {
+    /// Those assumed of shards which predate the Capabilities RPC
+    pub fn legacy() -> Self {
+        Self { versions: vec!["unknown".to_string()], top_n_tokens: true, ..Default::default() }
+    }
+
+    /// Capabilities of a set of shards, or of a set of replicas, given those of each.
+    /// None if none of them report their capabilities
+    pub fn combine(capabilities: impl IntoIterator<Item = Option<Self>>) -> Option<Self> {
+        let capabilities: Vec<_> = capabilities.into_iter().collect();
+        if capabilities.iter().all(Option::is_none) {
+            return None
+        }
+        capabilities.into_iter().map(|c| c.unwrap_or_else(Self::legacy)).reduce(|a, b| a.intersect(b))
+    }
+
+    /// Features supported by both
+    fn intersect(mut self, other: Self) -> Self {
+        for version in other.versions {
+            if !self.versions.contains(&version) {
+                self.versions.push(version);
+            }
+        }
+        Self {
+            versions: self.versions,
+            top_n_tokens: self.top_n_tokens && other.top_n_tokens,
+            speculative_decoding: self.speculative_decoding && other.speculative_decoding,
+            adapters: self.adapters && other.adapters,
+            max_batch_tokens: match (self.max_batch_tokens, other.max_batch_tokens) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            prefill_progress: self.prefill_progress && other.prefill_progress,
+            embeddings: self.embeddings && other.embeddings,
+            offload: self.offload && other.offload,
+            repetition_penalty_range: self.repetition_penalty_range && other.repetition_penalty_range,
+            no_repeat_ngram_size: self.no_repeat_ngram_size && other.no_repeat_ngram_size,
+            token_healing: self.token_healing && other.token_healing,
+            watermark: self.watermark && other.watermark,
+            bad_words: self.bad_words && other.bad_words,
+            input_ids: self.input_ids && other.input_ids,
+            json_schema: self.json_schema && other.json_schema,
+            deterministic: self.deterministic && other.deterministic,
+        }
+    }
+}
+
+impl From<CapabilitiesResponse> for ShardCapabilities {
+    fn from(response: CapabilitiesResponse) -> Self {
+        Self {
+            versions: vec![
+                if response.version.is_empty() { "unknown".to_string() } else { response.version }
+            ],
+            top_n_tokens: response.top_n_tokens,
+            speculative_decoding: response.speculative_decoding,
+            adapters: response.adapters,
+            max_batch_tokens: (response.max_batch_tokens > 0).then_some(response.max_batch_tokens),
+            prefill_progress: response.prefill_progress,
+            embeddings: response.embeddings,
+            offload: response.offload,
+            repetition_penalty_range: response.repetition_penalty_range,
+            no_repeat_ngram_size: response.no_repeat_ngram_size,
+            token_healing: response.token_healing,
+            watermark: response.watermark,
+            bad_words: response.bad_words,
+            input_ids: response.input_ids,
+            json_schema: response.json_schema,
+            deterministic: response.deterministic,
+        }
+    }
+}
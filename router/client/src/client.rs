@@ -1,44 +1,171 @@
 /// Single shard Client
+use std::future::Future;
 use std::time::Duration;
 use crate::pb::generate::v1::text_generation_service_client::TextGenerationServiceClient;
 use crate::pb::generate::v1::*;
-use crate::{ClientError, Result};
-use tonic::transport::{Channel, Uri};
+use crate::{ClientError, GenerateTokenResponse, Result};
+use crate::encoded::EncodedMessage;
+use tonic::client::Grpc;
+use tonic::codec::{CompressionEncoding, ProstCodec, Streaming};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::{Code, Status};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{sleep, timeout_at, Instant};
+use tonic::transport::{Channel, Endpoint, Uri};
 use tracing::*;
 use crate::pb::generate::v1::model_info_response::ModelType;
 
 const PREFIX_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before the first connection retry, doubled for each subsequent one
+const CONNECT_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+const PREFILL_PATH: &str = "/generate.v1.TextGenerationService/Prefill";
+const PREFILL_STREAM_PATH: &str = "/generate.v1.TextGenerationService/PrefillStream";
+
+/// Connection and HTTP/2 settings of the channels to the shards, unset values use tonic's defaults
+#[derive(Debug, Clone, Default)]
+pub struct ChannelConfig {
+    /// Interval of keepalive pings, which are also sent while the connection is idle
+    pub keepalive_interval: Option<Duration>,
+    /// Time to wait for a keepalive ping to be acknowledged before closing the connection
+    pub keepalive_timeout: Option<Duration>,
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+    /// Max size of messages sent and received, tonic's default limit is 4MiB for received messages
+    pub max_message_size: Option<usize>,
+    /// Time to wait for each connection attempt
+    pub connect_timeout: Option<Duration>,
+    pub tcp_nodelay: Option<bool>,
+    /// Number of times a failed connection attempt is retried, with exponential backoff
+    pub connect_retries: u32,
+}
+
+impl ChannelConfig {
+    fn configure(&self, mut endpoint: Endpoint) -> Endpoint {
+        if let Some(interval) = self.keepalive_interval {
+            endpoint = endpoint.http2_keep_alive_interval(interval).keep_alive_while_idle(true);
+        }
+        if let Some(timeout) = self.keepalive_timeout {
+            endpoint = endpoint.keep_alive_timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            endpoint = endpoint.connect_timeout(timeout);
+        }
+        if let Some(nodelay) = self.tcp_nodelay {
+            endpoint = endpoint.tcp_nodelay(nodelay);
+        }
+        endpoint
+            .initial_stream_window_size(self.initial_stream_window_size)
+            .initial_connection_window_size(self.initial_connection_window_size)
+    }
+
+    fn stub(&self, channel: Channel) -> TextGenerationServiceClient<Channel> {
+        let stub = TextGenerationServiceClient::new(channel);
+        match self.max_message_size {
+            Some(size) => stub.max_decoding_message_size(size).max_encoding_message_size(size),
+            None => stub,
+        }
+    }
+
+    /// Untyped client of the channel, for requests which are already encoded
+    fn grpc(&self, channel: Channel) -> Grpc<Channel> {
+        let grpc = Grpc::new(channel);
+        match self.max_message_size {
+            Some(size) => grpc.max_decoding_message_size(size).max_encoding_message_size(size),
+            None => grpc,
+        }
+    }
+
+    /// Make a connection attempt, retrying failed ones up to `connect_retries` times
+    async fn connect_with_retries<F: Future<Output = Result<Channel>>>(
+        &self, target: &str, connect: impl Fn() -> F,
+    ) -> Result<Channel> {
+        let mut backoff = CONNECT_RETRY_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match connect().await {
+                Err(err) if attempt <= self.connect_retries => {
+                    warn!("Connection attempt {attempt} to {target} failed, retrying in {backoff:?}: {err}");
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Create a request which the shard should complete by the given deadline
+fn request_with_deadline<T>(message: T, deadline: Option<Instant>) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    if let Some(deadline) = deadline {
+        request.set_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+    request
+}
+
+/// Await a shard call, abandoning it if it hasn't completed by the given deadline
+/// so that an unresponsive shard can't block the caller indefinitely
+async fn with_deadline<T>(
+    method: &str,
+    deadline: Option<Instant>,
+    call: impl Future<Output = std::result::Result<T, Status>>,
+) -> Result<T> {
+    match deadline {
+        Some(deadline) => timeout_at(deadline, call).await
+            .map_err(|_| ClientError::Timeout(format!("{method} didn't complete by its deadline")))?
+            .map_err(ClientError::from),
+        None => call.await.map_err(ClientError::from),
+    }
+}
 
 /// Text Generation Inference gRPC client
 #[derive(Debug, Clone)]
 pub struct Client {
     stub: TextGenerationServiceClient<Channel>,
+    /// Sends the pre-encoded prefill requests
+    grpc: Grpc<Channel>,
 }
 
 impl Client {
     /// Returns a client connected to the given url
-    pub async fn connect(uri: Uri) -> Result<Self> {
-        let channel = Channel::builder(uri).connect().await?;
+    pub async fn connect(uri: Uri, config: &ChannelConfig) -> Result<Self> {
+        let endpoint = config.configure(Channel::builder(uri.clone()));
+        let channel = config.connect_with_retries(&uri.to_string(), || async {
+            Ok(endpoint.connect().await?)
+        }).await?;
 
         Ok(Self {
-            stub: TextGenerationServiceClient::new(channel),
+            stub: config.stub(channel.clone()),
+            grpc: config.grpc(channel),
         })
     }
 
     /// Returns a client connected to the given unix socket
-    pub async fn connect_uds(path: String) -> Result<Self> {
-        let channel = Channel::from_shared("http://[::]:50051".to_string())
-            .unwrap()
-            .connect_with_connector(tower::service_fn(move |_: Uri| {
+    pub async fn connect_uds(path: String, config: &ChannelConfig) -> Result<Self> {
+        let endpoint = Channel::from_shared("http://[::]:50051".to_string()).unwrap();
+        let endpoint = config.configure(endpoint);
+        let channel = config.connect_with_retries(&path, || async {
+            let path = path.clone();
+            Ok(endpoint.connect_with_connector(tower::service_fn(move |_: Uri| {
                 tokio::net::UnixStream::connect(path.clone())
-            }))
-            .await?;
+            })).await?)
+        }).await?;
 
         Ok(Self {
-            stub: TextGenerationServiceClient::new(channel),
+            stub: config.stub(channel.clone()),
+            grpc: config.grpc(channel),
         })
     }
 
+    /// Compress requests with the given encoding and advertise support for compressed responses
+    pub fn with_compression(mut self, encoding: CompressionEncoding) -> Self {
+        self.stub = self.stub.send_compressed(encoding).accept_compressed(encoding);
+        self.grpc = self.grpc.send_compressed(encoding).accept_compressed(encoding);
+        self
+    }
+
     /// Returns a list of uris or unix sockets of all shards
     #[instrument(skip(self))]
     pub async fn service_discovery(&mut self) -> Result<Vec<String>> {
@@ -72,6 +199,55 @@ impl Client {
         Ok(())
     }
 
+    /// Move the cache of the given requests to host memory, removing them from the batch
+    ///
+    /// Returns id of the remaining batch, None if no requests remain
+    #[instrument(skip(self))]
+    pub async fn offload_requests(
+        &mut self, batch: CachedBatch, request_ids: Vec<u64>,
+    ) -> Result<Option<u64>> {
+        let request = tonic::Request::new(OffloadRequestsRequest {
+            batch: Some(batch), request_ids,
+        });
+        let response = self.stub
+            .offload_requests(request)
+            .instrument(info_span!("offload_requests"))
+            .await?
+            .into_inner();
+        Ok(response.batch_id)
+    }
+
+    /// Compute embeddings of the given inputs
+    ///
+    /// Returns the embedding of each successful input and the errors of any that failed
+    #[instrument(skip_all, fields(size = inputs.len()))]
+    pub async fn embed(
+        &mut self, inputs: Vec<EmbedInput>,
+    ) -> Result<(Vec<Embedding>, Vec<GenerateError>)> {
+        let request = tonic::Request::new(EmbedRequest { inputs });
+        let response = self.stub
+            .embed(request)
+            .instrument(info_span!("embed"))
+            .await?
+            .into_inner();
+        Ok((response.embeddings, response.errors))
+    }
+
+    /// Remove the completed requests listed in the batch's status from the cached batch,
+    /// or discard it if it has no status
+    ///
+    /// Returns id of the updated batch, None if no requests remain
+    #[instrument(skip(self))]
+    pub async fn update_batch(&mut self, batch: CachedBatch) -> Result<Option<u64>> {
+        let request = tonic::Request::new(UpdateBatchRequest { batch: Some(batch) });
+        let response = self.stub
+            .update_batch(request)
+            .instrument(info_span!("update_batch"))
+            .await?
+            .into_inner();
+        Ok(response.batch_id)
+    }
+
     /// Get shard model info
     #[instrument(skip(self))]
     pub async fn model_info(&mut self) -> Result<(ModelType, u32, bool)> {
@@ -86,6 +262,29 @@ impl Client {
             .ok_or(ClientError::Generation("Unrecognized model type".to_string()))
     }
 
+    /// Get id and version of the shard's model, empty if unknown
+    #[instrument(skip(self))]
+    pub async fn model_identity(&mut self) -> Result<(String, String)> {
+        let request = tonic::Request::new(ModelInfoRequest {});
+        let response = self.stub
+            .model_info(request)
+            .instrument(info_span!("model_info"))
+            .await?
+            .into_inner();
+        Ok((response.model_id, response.model_version))
+    }
+
+    /// Get the shard's version and optional features, None if it predates the Capabilities RPC
+    #[instrument(skip(self))]
+    pub async fn capabilities(&mut self) -> Result<Option<CapabilitiesResponse>> {
+        let request = tonic::Request::new(CapabilitiesRequest {});
+        match self.stub.capabilities(request).instrument(info_span!("capabilities")).await {
+            Ok(response) => Ok(Some(response.into_inner())),
+            Err(status) if status.code() == Code::Unimplemented => Ok(None),
+            Err(status) => Err(status.into()),
+        }
+    }
+
     /// Get model health
     #[instrument(skip(self))]
     pub async fn health(&mut self) -> Result<HealthResponse> {
@@ -112,43 +311,99 @@ impl Client {
     /// Generate one token for each request in the given batch
     ///
     /// Returns first generated token for each request in the batch, id of the next cached batch,
-    /// and input token info if requested
-    #[instrument(skip(self))]
+    /// and input token info if requested. Fails with [`ClientError::Timeout`] if the shard
+    /// doesn't respond by the deadline. If a progress sender is provided, the shard's progress
+    /// through the inputs is sent to it while the batch is prefilled.
+    #[instrument(skip(self, progress))]
     pub async fn prefill(
-        &mut self, batch: Batch, to_prune: Vec<CachedBatch>,
-    ) -> Result<(Vec<Token>, Vec<InputTokens>, Vec<GenerateError>, u64)> {
-        let request = tonic::Request::new(PrefillRequest{
-            batch: Some(batch), to_prune,
-        });
-        let response = self
-            .stub
-            .prefill(request)
-            .instrument(info_span!("generate"))
-            .await?
-            .into_inner();
+        &mut self,
+        batch: Batch,
+        deadline: Option<Instant>,
+        progress: Option<UnboundedSender<PrefillProgress>>,
+    ) -> Result<GenerateTokenResponse> {
+        let request = EncodedMessage::new(&PrefillRequest{ batch: Some(batch) });
+        self.prefill_encoded(request, deadline, progress).await
+    }
+
+    /// Prefill the batch of an already encoded [`PrefillRequest`], as [`Client::prefill`]
+    #[instrument(skip_all, fields(bytes = request.len()))]
+    pub(crate) async fn prefill_encoded(
+        &mut self,
+        request: EncodedMessage,
+        deadline: Option<Instant>,
+        progress: Option<UnboundedSender<PrefillProgress>>,
+    ) -> Result<GenerateTokenResponse> {
+        let request = request_with_deadline(request, deadline);
+        let response = match progress {
+            Some(progress) => with_deadline(
+                "prefill",
+                deadline,
+                self.prefill_stream(request, progress).instrument(info_span!("generate")),
+            ).await?,
+            None => with_deadline(
+                "prefill", deadline, self.prefill_unary(request).instrument(info_span!("generate")),
+            ).await?,
+        };
         let result = response
             .result
             .ok_or_else(|| ClientError::Generation("Unexpected empty response".into()))?;
         Ok((result.output_tokens, response.input_tokens, result.errors, result.batch_id))
     }
 
+    /// Prefill via the unary method
+    async fn prefill_unary(
+        &mut self, request: tonic::Request<EncodedMessage>,
+    ) -> std::result::Result<PrefillResponse, Status> {
+        self.ready().await?;
+        let path = PathAndQuery::from_static(PREFILL_PATH);
+        Ok(self.grpc.unary(request, path, ProstCodec::default()).await?.into_inner())
+    }
+
+    /// Prefill via the streaming method, forwarding progress updates until the result arrives
+    async fn prefill_stream(
+        &mut self,
+        request: tonic::Request<EncodedMessage>,
+        progress: UnboundedSender<PrefillProgress>,
+    ) -> std::result::Result<PrefillResponse, Status> {
+        self.ready().await?;
+        let path = PathAndQuery::from_static(PREFILL_STREAM_PATH);
+        let mut stream: Streaming<PrefillStreamResponse> = self.grpc
+            .server_streaming(request, path, ProstCodec::default()).await?.into_inner();
+        while let Some(message) = stream.message().await? {
+            match message.response {
+                Some(prefill_stream_response::Response::Progress(update)) => {
+                    // Receiver may have gone away, the result is still needed
+                    progress.send(update).unwrap_or_default();
+                },
+                Some(prefill_stream_response::Response::Result(response)) => return Ok(response),
+                None => (),
+            }
+        }
+        Err(Status::internal("Prefill stream ended without a result"))
+    }
+
+    /// Wait for the channel to be ready to send a request
+    async fn ready(&mut self) -> std::result::Result<(), Status> {
+        self.grpc.ready().await
+            .map_err(|e| Status::unknown(format!("Service was not ready: {e}")))
+    }
+
     /// Generate one token for each request in the given cached batch(es)
     ///
-    /// Returns next generated token of each request in the batches and id of the next cached batch
+    /// Returns next generated token of each request in the batches and id of the next cached batch.
+    /// Fails with [`ClientError::Timeout`] if the shard doesn't respond by the deadline.
     #[instrument(skip(self))]
     pub async fn next_token(
         &mut self,
         batches: Vec<CachedBatch>,
-    ) -> Result<Option<(Vec<Token>, Vec<InputTokens>, Vec<GenerateError>, u64)>> {
-        let request = tonic::Request::new(
-            NextTokenRequest { batches }
-        );
-        let response = self
-            .stub
-            .next_token(request)
-            .instrument(info_span!("generate_with_cache"))
-            .await?
-            .into_inner();
+        deadline: Option<Instant>,
+    ) -> Result<Option<GenerateTokenResponse>> {
+        let request = request_with_deadline(NextTokenRequest { batches }, deadline);
+        let response = with_deadline(
+            "next_token",
+            deadline,
+            self.stub.next_token(request).instrument(info_span!("generate_with_cache")),
+        ).await?.into_inner();
         Ok(response.result.map(|r| (r.output_tokens, vec![], r.errors, r.batch_id)))
     }
 }
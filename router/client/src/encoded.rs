@@ -0,0 +1,48 @@
+/// Pre-encoded messages
+use std::fmt;
+use prost::bytes::{Buf, BufMut, Bytes};
+use prost::encoding::{skip_field, DecodeContext, WireType};
+use prost::{DecodeError, Message};
+
+/// A protobuf message which has already been encoded, and is sent as is. Cloning it
+/// only clones a reference to the bytes, so a large message can be encoded once and
+/// sent to every shard.
+#[derive(Clone, Default)]
+pub(crate) struct EncodedMessage(Bytes);
+
+impl EncodedMessage {
+    pub(crate) fn new(message: &impl Message) -> Self {
+        Self(message.encode_to_vec().into())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl fmt::Debug for EncodedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncodedMessage").field("len", &self.0.len()).finish()
+    }
+}
+
+impl Message for EncodedMessage {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.0);
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self, tag: u32, wire_type: WireType, buf: &mut B, ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        // Only ever sent, never received
+        skip_field(wire_type, tag, buf, ctx)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
@@ -1,21 +1,29 @@
 //! Text Generation gRPC client library
 
+mod capabilities;
 mod client;
+mod encoded;
+#[cfg(feature = "mock")]
+pub mod mock;
 #[allow(clippy::derive_partial_eq_without_eq)]
 mod pb;
 mod sharded_client;
 
-pub use client::Client;
+pub use capabilities::ShardCapabilities;
+pub use client::{ChannelConfig, Client};
 pub use pb::generate::v1::{
     Batch, Token, InputTokens, NextTokenChooserParameters, RequestedDetails,
     Request, StopSequence, CachedBatch, RequestsStatus, GenerateError,
-    HealthResponse,
+    HealthResponse, EmbedInput, Embedding, PrefillProgress,
+};
+pub use pb::generate::v1::next_token_chooser_parameters::{
+    LengthPenalty, TemperatureBreakpoint, TokenSequence, Watermark,
 };
-pub use pb::generate::v1::next_token_chooser_parameters::LengthPenalty;
 pub use sharded_client::ShardedClient;
+pub use tonic::codec::CompressionEncoding;
 use thiserror::Error;
 use tonic::transport;
-use tonic::Status;
+use tonic::{Code, Status};
 
 #[derive(Error, Debug, Clone)]
 pub enum ClientError {
@@ -23,11 +31,16 @@ pub enum ClientError {
     Connection(String),
     #[error("{0}")]
     Generation(String),
+    #[error("Text Generation server timed out: {0}")]
+    Timeout(String),
 }
 
 impl From<Status> for ClientError {
     fn from(err: Status) -> Self {
-        Self::Generation(err.message().to_string())
+        match err.code() {
+            Code::DeadlineExceeded => Self::Timeout(err.message().to_string()),
+            _ => Self::Generation(err.message().to_string()),
+        }
     }
 }
 
@@ -38,3 +51,6 @@ impl From<transport::Error> for ClientError {
 }
 
 pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Generated tokens, input token details, per-request errors and next batch id
+pub type GenerateTokenResponse = (Vec<Token>, Vec<InputTokens>, Vec<GenerateError>, u64);
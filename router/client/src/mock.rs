@@ -0,0 +1,486 @@
+/// Mock shard server for testing the router without a model or GPUs. It serves the shard
+/// gRPC service on a unix socket, as the Python shards do, generating scripted tokens with
+/// configurable latencies, and failures can be injected into its calls.
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use futures::stream::{self, BoxStream};
+use tokio::net::UnixListener;
+use tokio::sync::oneshot;
+use tonic::{Response, Status};
+use tonic::transport::Server;
+use crate::{ChannelConfig, Result, ShardedClient};
+use crate::pb::generate::v1::{
+    Batch, CachedBatch, CapabilitiesRequest, CapabilitiesResponse, ClearCacheRequest,
+    ClearCacheResponse, EmbedRequest, EmbedResponse, GenerateError, GenerateResult, HealthRequest,
+    HealthResponse, InputTokens, ModelInfoRequest, ModelInfoResponse, NextTokenRequest,
+    NextTokenResponse, OffloadRequestsRequest, OffloadRequestsResponse, PrefillRequest,
+    PrefillResponse, PrefillStreamResponse, PrefixLookupRequest, PrefixLookupResponse,
+    Request, ServiceDiscoveryRequest, ServiceDiscoveryResponse, Token, UpdateBatchRequest, UpdateBatchResponse,
+};
+use crate::pb::generate::v1::model_info_response::ModelType;
+use crate::pb::generate::v1::prefill_stream_response;
+use crate::pb::generate::v1::text_generation_service_server::{
+    TextGenerationService, TextGenerationServiceServer,
+};
+
+/// Distinguishes the sockets of mock shards started by the same process
+static NEXT_SOCKET_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Chooses the token id generated for a request at each index of its output
+pub type TokenScript = Arc<dyn Fn(&Request, u32) -> u32 + Send + Sync>;
+
+/// Behaviour of a mock shard
+#[derive(Clone)]
+pub struct MockShardConfig {
+    pub seq2seq: bool,
+    pub eos_token_id: u32,
+    /// Token ids generated for each request
+    pub script: TokenScript,
+    /// Time taken by each Prefill call
+    pub prefill_latency: Duration,
+    /// Time taken by each NextToken call
+    pub step_latency: Duration,
+}
+
+impl Default for MockShardConfig {
+    fn default() -> Self {
+        Self {
+            seq2seq: false,
+            eos_token_id: 0,
+            // Never EOS, so requests stop once they reach their max new tokens
+            script: Arc::new(|_, index| index + 1),
+            prefill_latency: Duration::ZERO,
+            step_latency: Duration::ZERO,
+        }
+    }
+}
+
+impl MockShardConfig {
+    /// Generate the given token ids in turn for every request, followed by EOS
+    pub fn with_tokens(mut self, token_ids: Vec<u32>) -> Self {
+        let eos_token_id = self.eos_token_id;
+        self.script = Arc::new(
+            move |_, index| token_ids.get(index as usize).copied().unwrap_or(eos_token_id)
+        );
+        self
+    }
+}
+
+/// Failure injected into the calls of a mock shard
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Fail the next Prefill call
+    Prefill,
+    /// Fail the next NextToken call
+    NextToken,
+    /// Delay the next Prefill or NextToken call, in addition to the configured latency
+    Delay(Duration),
+    /// Fail every Prefill and NextToken call of a batch with a request whose inputs
+    /// contain this text, discarding the batch
+    PoisonInputs(String),
+    /// Return an error in place of the tokens of requests whose inputs contain this text
+    RequestError(String),
+}
+
+/// Number of calls a mock shard received of each generation method
+#[derive(Clone, Debug, Default)]
+pub struct MockCalls {
+    pub prefill: u32,
+    pub next_token: u32,
+    pub update_batch: u32,
+    pub offload_requests: u32,
+    pub clear_cache: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Method {
+    Prefill,
+    NextToken,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Prefill => "Prefill",
+            Self::NextToken => "NextToken",
+        })
+    }
+}
+
+/// A request in a cached batch
+struct Sequence {
+    request: Request,
+    generated: u32,
+}
+
+#[derive(Default)]
+struct State {
+    batches: HashMap<u64, Vec<Sequence>>,
+    /// Sequences removed from their batch by OffloadRequests, by request id
+    offloaded: HashMap<u64, Sequence>,
+    faults: Vec<Fault>,
+    calls: MockCalls,
+}
+
+impl State {
+    /// Take any delay to be added to the next generation call
+    fn delay(&mut self) -> Duration {
+        let mut delay = Duration::ZERO;
+        self.faults.retain(|fault| match fault {
+            Fault::Delay(d) => {
+                delay += *d;
+                false
+            },
+            _ => true,
+        });
+        delay
+    }
+
+    /// Error to fail a generation call of the given sequences with, if any.
+    /// One-off faults are removed once injected.
+    fn failure(&mut self, method: Method, sequences: &[Sequence]) -> Option<Status> {
+        let index = self.faults.iter().position(|fault| match fault {
+            Fault::Prefill => method == Method::Prefill,
+            Fault::NextToken => method == Method::NextToken,
+            Fault::PoisonInputs(text) => sequences.iter().any(|s| s.request.inputs.contains(text)),
+            Fault::Delay(_) | Fault::RequestError(_) => false,
+        })?;
+        let message = match &self.faults[index] {
+            Fault::PoisonInputs(text) => format!("mock {method} failure of inputs containing {text:?}"),
+            _ => {
+                self.faults.remove(index);
+                format!("mock {method} failure")
+            },
+        };
+        Some(Status::internal(message))
+    }
+
+    /// Whether a request is to be failed with a per-request error
+    fn request_error(&self, request: &Request) -> Option<GenerateError> {
+        self.faults.iter().find_map(|fault| match fault {
+            Fault::RequestError(text) if request.inputs.contains(text) => Some(GenerateError {
+                request_id: request.id,
+                message: format!("mock error of inputs containing {text:?}"),
+            }),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct MockService {
+    config: MockShardConfig,
+    state: Arc<Mutex<State>>,
+    /// Url returned by service discovery
+    url: String,
+}
+
+impl MockService {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Generate the next token of each sequence
+    fn generate(&self, state: &State, batch_id: u64, sequences: &mut [Sequence]) -> GenerateResult {
+        let mut output_tokens = vec![];
+        let mut errors = vec![];
+        for sequence in sequences {
+            let request = &sequence.request;
+            if let Some(error) = state.request_error(request) {
+                errors.push(error);
+                continue
+            }
+            let details = request.details.clone().unwrap_or_default();
+            output_tokens.push(Token {
+                request_id: request.id,
+                token_id: (self.config.script)(request, sequence.generated),
+                logprob: if details.logprobs { -1.0 } else { 0.0 },
+                rank: u32::from(details.ranks),
+                ..Default::default()
+            });
+            sequence.generated += 1;
+        }
+        GenerateResult { output_tokens, errors, batch_id }
+    }
+
+    async fn do_prefill(&self, batch: Batch) -> std::result::Result<PrefillResponse, Status> {
+        let delay = self.state().delay();
+        tokio::time::sleep(self.config.prefill_latency + delay).await;
+
+        let mut state = self.state();
+        state.calls.prefill += 1;
+        let mut sequences = batch.requests.into_iter().map(|request| {
+            match request.resumed_id.and_then(|id| state.offloaded.remove(&id)) {
+                Some(offloaded) => Sequence { request, generated: offloaded.generated },
+                None => Sequence { request, generated: 0 },
+            }
+        }).collect::<Vec<_>>();
+        if let Some(status) = state.failure(Method::Prefill, &sequences) {
+            return Err(status)
+        }
+        let input_tokens = sequences.iter()
+            .filter(|s| s.request.resumed_id.is_none())
+            .filter(|s| s.request.details.as_ref().map_or(false, |d| d.input_toks))
+            .map(|s| InputTokens {
+                request_id: s.request.id,
+                tokens: (0..s.request.input_length).map(|i| Token {
+                    token_id: i + 1, ..Default::default()
+                }).collect(),
+            })
+            .collect();
+        let result = self.generate(&state, batch.id, &mut sequences);
+        state.batches.insert(batch.id, sequences);
+        Ok(PrefillResponse { result: Some(result), input_tokens })
+    }
+}
+
+/// Remove the completed requests of a cached batch, None if the batch is finished
+fn prune(mut sequences: Vec<Sequence>, batch: &CachedBatch) -> Option<Vec<Sequence>> {
+    let completed_ids = &batch.status.as_ref()?.completed_ids;
+    sequences.retain(|s| !completed_ids.contains(&s.request.id));
+    (!sequences.is_empty()).then_some(sequences)
+}
+
+#[tonic::async_trait]
+impl TextGenerationService for MockService {
+    async fn service_discovery(
+        &self, _request: tonic::Request<ServiceDiscoveryRequest>,
+    ) -> std::result::Result<Response<ServiceDiscoveryResponse>, Status> {
+        Ok(Response::new(ServiceDiscoveryResponse { urls: vec![self.url.clone()] }))
+    }
+
+    async fn clear_cache(
+        &self, _request: tonic::Request<ClearCacheRequest>,
+    ) -> std::result::Result<Response<ClearCacheResponse>, Status> {
+        let mut state = self.state();
+        state.calls.clear_cache += 1;
+        state.batches.clear();
+        state.offloaded.clear();
+        Ok(Response::new(ClearCacheResponse {}))
+    }
+
+    async fn model_info(
+        &self, _request: tonic::Request<ModelInfoRequest>,
+    ) -> std::result::Result<Response<ModelInfoResponse>, Status> {
+        let model_type = if self.config.seq2seq { ModelType::Seq2seqLm } else { ModelType::CausalLm };
+        Ok(Response::new(ModelInfoResponse {
+            model_type: model_type.into(),
+            eos_token: self.config.eos_token_id,
+            batch_padding: false,
+            model_id: "mock".to_string(),
+            model_version: String::new(),
+        }))
+    }
+
+    async fn prefill(
+        &self, request: tonic::Request<PrefillRequest>,
+    ) -> std::result::Result<Response<PrefillResponse>, Status> {
+        let batch = request.into_inner().batch
+            .ok_or_else(|| Status::invalid_argument("batch is required"))?;
+        Ok(Response::new(self.do_prefill(batch).await?))
+    }
+
+    type PrefillStreamStream = BoxStream<'static, std::result::Result<PrefillStreamResponse, Status>>;
+
+    async fn prefill_stream(
+        &self, request: tonic::Request<PrefillRequest>,
+    ) -> std::result::Result<Response<Self::PrefillStreamStream>, Status> {
+        // Inputs are prefilled in a single chunk, so no progress is reported
+        let batch = request.into_inner().batch
+            .ok_or_else(|| Status::invalid_argument("batch is required"))?;
+        let response = PrefillStreamResponse {
+            response: Some(prefill_stream_response::Response::Result(self.do_prefill(batch).await?)),
+        };
+        Ok(Response::new(Box::pin(stream::once(async { Ok(response) }))))
+    }
+
+    async fn next_token(
+        &self, request: tonic::Request<NextTokenRequest>,
+    ) -> std::result::Result<Response<NextTokenResponse>, Status> {
+        let delay = self.state().delay();
+        tokio::time::sleep(self.config.step_latency + delay).await;
+
+        let mut state = self.state();
+        state.calls.next_token += 1;
+        let mut batch_id = None;
+        let mut sequences = vec![];
+        // Batches are concatenated into the first which has requests remaining
+        for batch in request.into_inner().batches {
+            let cached = state.batches.remove(&batch.batch_id).ok_or_else(
+                || Status::not_found(format!("batch {} not found", batch.batch_id))
+            )?;
+            if let Some(remaining) = prune(cached, &batch) {
+                batch_id.get_or_insert(batch.batch_id);
+                sequences.extend(remaining);
+            }
+        }
+        let Some(batch_id) = batch_id else {
+            return Ok(Response::new(NextTokenResponse { result: None }))
+        };
+        if let Some(status) = state.failure(Method::NextToken, &sequences) {
+            return Err(status)
+        }
+        let result = self.generate(&state, batch_id, &mut sequences);
+        state.batches.insert(batch_id, sequences);
+        Ok(Response::new(NextTokenResponse { result: Some(result) }))
+    }
+
+    async fn update_batch(
+        &self, request: tonic::Request<UpdateBatchRequest>,
+    ) -> std::result::Result<Response<UpdateBatchResponse>, Status> {
+        let batch = request.into_inner().batch
+            .ok_or_else(|| Status::invalid_argument("batch is required"))?;
+        let mut state = self.state();
+        state.calls.update_batch += 1;
+        let cached = state.batches.remove(&batch.batch_id)
+            .ok_or_else(|| Status::not_found(format!("batch {} not found", batch.batch_id)))?;
+        let batch_id = prune(cached, &batch).map(|remaining| {
+            state.batches.insert(batch.batch_id, remaining);
+            batch.batch_id
+        });
+        Ok(Response::new(UpdateBatchResponse { batch_id }))
+    }
+
+    async fn prefix_lookup(
+        &self, request: tonic::Request<PrefixLookupRequest>,
+    ) -> std::result::Result<Response<PrefixLookupResponse>, Status> {
+        let prefix_id = request.into_inner().prefix_id;
+        Err(Status::not_found(format!("prefix {prefix_id} not found")))
+    }
+
+    async fn health(
+        &self, _request: tonic::Request<HealthRequest>,
+    ) -> std::result::Result<Response<HealthResponse>, Status> {
+        Ok(Response::new(HealthResponse {}))
+    }
+
+    async fn offload_requests(
+        &self, request: tonic::Request<OffloadRequestsRequest>,
+    ) -> std::result::Result<Response<OffloadRequestsResponse>, Status> {
+        let OffloadRequestsRequest { batch, request_ids } = request.into_inner();
+        let batch = batch.ok_or_else(|| Status::invalid_argument("batch is required"))?;
+        let mut state = self.state();
+        state.calls.offload_requests += 1;
+        let cached = state.batches.remove(&batch.batch_id)
+            .ok_or_else(|| Status::not_found(format!("batch {} not found", batch.batch_id)))?;
+        let (offloaded, remaining): (Vec<_>, Vec<_>) = cached.into_iter()
+            .partition(|s| request_ids.contains(&s.request.id));
+        for sequence in offloaded {
+            state.offloaded.insert(sequence.request.id, sequence);
+        }
+        let batch_id = (!remaining.is_empty()).then(|| {
+            state.batches.insert(batch.batch_id, remaining);
+            batch.batch_id
+        });
+        Ok(Response::new(OffloadRequestsResponse { batch_id }))
+    }
+
+    async fn embed(
+        &self, _request: tonic::Request<EmbedRequest>,
+    ) -> std::result::Result<Response<EmbedResponse>, Status> {
+        Err(Status::unimplemented("embeddings aren't supported by the mock shard"))
+    }
+
+    async fn capabilities(
+        &self, _request: tonic::Request<CapabilitiesRequest>,
+    ) -> std::result::Result<Response<CapabilitiesResponse>, Status> {
+        Ok(Response::new(CapabilitiesResponse {
+            version: "mock".to_string(),
+            offload: true,
+            ..Default::default()
+        }))
+    }
+}
+
+/// Mock shard served in-process, which stops when dropped
+pub struct MockShard {
+    socket_path: PathBuf,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockShard {
+    /// Serve a mock shard on a new unix socket in the temporary directory
+    pub async fn start(config: MockShardConfig) -> std::io::Result<Self> {
+        let socket_path = std::env::temp_dir().join(format!(
+            "tgi-mock-shard-{}-{}", std::process::id(), NEXT_SOCKET_ID.fetch_add(1, Ordering::Relaxed),
+        ));
+        Self::start_at(socket_path, config).await
+    }
+
+    /// Serve a mock shard on the unix socket at the given path
+    pub async fn start_at(socket_path: PathBuf, config: MockShardConfig) -> std::io::Result<Self> {
+        // Remove any socket left by an earlier run
+        std::fs::remove_file(&socket_path).unwrap_or_default();
+        let listener = UnixListener::bind(&socket_path)?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let service = MockService {
+            config,
+            state: state.clone(),
+            url: format!("unix://{}", socket_path.display()),
+        };
+        let incoming = stream::unfold(listener, |listener| async move {
+            let connection = listener.accept().await.map(|(stream, _)| stream);
+            Some((connection, listener))
+        });
+        let (shutdown, shutdown_signal) = oneshot::channel();
+        tokio::spawn(
+            Server::builder()
+                .add_service(TextGenerationServiceServer::new(service))
+                .serve_with_incoming_shutdown(incoming, async { shutdown_signal.await.unwrap_or_default() })
+        );
+        Ok(Self { socket_path, state, shutdown: Some(shutdown) })
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Connect to the mock shard as the router does
+    pub async fn client(&self) -> Result<ShardedClient> {
+        ShardedClient::connect_uds(
+            self.socket_path.display().to_string(), &ChannelConfig::default(),
+        ).await
+    }
+
+    /// Inject a failure into subsequent calls
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push(fault);
+    }
+
+    /// Remove any injected failures which remain
+    pub fn clear_faults(&self) {
+        self.state.lock().unwrap().faults.clear();
+    }
+
+    /// Calls received so far
+    pub fn calls(&self) -> MockCalls {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Number of batches currently held in the mock shard's cache
+    pub fn cached_batches(&self) -> usize {
+        self.state.lock().unwrap().batches.len()
+    }
+
+    /// Ids of the requests currently held in the mock shard's cache
+    pub fn cached_request_ids(&self) -> Vec<u64> {
+        let state = self.state.lock().unwrap();
+        let mut ids: Vec<u64> = state.batches.values().flatten().map(|s| s.request.id).collect();
+        ids.sort_unstable();
+        ids
+    }
+}
+
+impl Drop for MockShard {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).unwrap_or_default();
+        }
+        std::fs::remove_file(&self.socket_path).unwrap_or_default();
+    }
+}
@@ -1,79 +1,132 @@
 /// Multi shard Client
-use crate::{ClientError, GenerateError, Result};
-use crate::{Batch, Client, HealthResponse, Token};
+use crate::{ClientError, GenerateTokenResponse, Result};
+use crate::{Batch, ChannelConfig, Client, HealthResponse, PrefillProgress, ShardCapabilities};
+use crate::encoded::EncodedMessage;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use futures::future::join_all;
 use tokio::runtime::Handle;
 use tokio::sync::{broadcast, mpsc};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout, Instant, MissedTickBehavior};
+use tonic::codec::CompressionEncoding;
 use tonic::transport::Uri;
-use crate::pb::generate::v1::{CachedBatch, InputTokens};
+use crate::pb::generate::v1::{CachedBatch, EmbedInput, Embedding, GenerateError, PrefillRequest};
 use crate::pb::generate::v1::model_info_response::ModelType;
 use crate::sharded_client::Request::{NextToken, Prefill};
 
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone, Debug)]
 enum Request {
-    Prefill(Batch, Vec<CachedBatch>),
-    NextToken(Vec<CachedBatch>),
+    /// Encoded once for all the shards
+    Prefill(EncodedMessage, Option<Instant>, Option<UnboundedSender<PrefillProgress>>),
+    NextToken(Vec<CachedBatch>, Option<Instant>),
 }
 
 /// Text Generation Inference gRPC multi client
 #[derive(Debug)]
 pub struct ShardedClient {
     clients: Vec<Client>,
-    sender: broadcast::Sender<(Request, mpsc::Sender<
-        Result<Option<(Vec<Token>, Vec<InputTokens>, Vec<GenerateError>, u64)>>
-    >)>,
+    /// Features supported by all the shards, None if none of them report their capabilities
+    capabilities: Option<Arc<ShardCapabilities>>,
+    sender: broadcast::Sender<(Request, mpsc::Sender<Result<Option<GenerateTokenResponse>>>)>,
     handle: Handle,
 }
 
 impl Clone for ShardedClient {
     fn clone(&self) -> Self {
-        Self::new(self.clients.clone())
+        Self::new(self.clients.clone(), self.capabilities.clone())
     }
 }
 
 impl ShardedClient {
-    fn new(clients: Vec<Client>) -> Self {
+    fn new(clients: Vec<Client>, capabilities: Option<Arc<ShardCapabilities>>) -> Self {
         let (sender, _) = broadcast::channel::<(Request, mpsc::Sender<_>)>(16);
 
         // Spawn a task for each shard
-        for mut client in clients.clone() {
+        for (index, client) in clients.clone().into_iter().enumerate() {
             let mut receiver: broadcast::Receiver<(Request, _)> = sender.subscribe();
             tokio::spawn(async move {
                 while let Ok((request , response_chan)) = receiver.recv().await {
-                    let result = match request {
-                        Prefill(batch, to_prune) =>
-                            client.prefill(batch, to_prune).await.map(|r| Some(r)),
-                        NextToken(batches) =>
-                            client.next_token(batches).await,
-                    };
-                    response_chan.try_send(result).unwrap_or_default();
+                    // Requests are sent concurrently so that a prefill can overlap with
+                    // the running batch's next token, they're otherwise awaited in turn
+                    let mut client = client.clone();
+                    tokio::spawn(async move {
+                        let start = Instant::now();
+                        let (method, result) = match request {
+                            // All shards prefill the same way, the first reports progress
+                            Prefill(request, deadline, progress) => ("prefill", client.prefill_encoded(
+                                request, deadline, progress.filter(|_| index == 0),
+                            ).await.map(Some)),
+                            NextToken(batches, deadline) =>
+                                ("next_token", client.next_token(batches, deadline).await),
+                        };
+                        // Per shard, to find any which are consistently slower than the others
+                        metrics::histogram!(
+                            "tgi_shard_request_duration", start.elapsed().as_secs_f64(),
+                            "method" => method, "shard" => index.to_string(),
+                        );
+                        response_chan.try_send(result).unwrap_or_default();
+                    });
                 }
             });
         }
 
-        Self { clients, sender, handle: Handle::current() }
+        Self { clients, capabilities, sender, handle: Handle::current() }
+    }
+
+    /// Create a new ShardedClient of the given shards, querying their capabilities
+    async fn with_capabilities(mut clients: Vec<Client>) -> Result<Self> {
+        let futures = clients.iter_mut().map(|client| client.capabilities());
+        let reported: Vec<_> = join_all(futures).await.into_iter().collect::<Result<_>>()?;
+        if reported.windows(2).any(|pair| pair[0] != pair[1]) {
+            tracing::warn!(
+                "Shards report differing capabilities, only features supported by all of them \
+                will be used: {reported:?}"
+            );
+        }
+        let capabilities = ShardCapabilities::combine(
+            reported.into_iter().map(|r| r.map(ShardCapabilities::from))
+        );
+        Ok(Self::new(clients, capabilities.map(Arc::new)))
     }
 
     /// Create a new ShardedClient from a master client. The master client will communicate with
     /// the other shards and returns all uris/unix sockets with the `service_discovery` gRPC method.
-    async fn from_master_client(mut master_client: Client) -> Result<Self> {
+    async fn from_master_client(mut master_client: Client, config: &ChannelConfig) -> Result<Self> {
         // Get all uris/unix sockets from the master client
         let uris = master_client.service_discovery().await.unwrap();
-        let futures = uris.into_iter().map(Client::connect_uds);
+        let futures = uris.into_iter().map(|uri| Client::connect_uds(uri, config));
         let clients: Result<Vec<Client>> = join_all(futures).await.into_iter().collect();
-        Ok(Self::new(clients?))
+        Self::with_capabilities(clients?).await
     }
 
     /// Returns a client connected to the given uri
-    pub async fn connect(uri: Uri) -> Result<Self> {
-        let master_client = Client::connect(uri).await?;
-        Self::from_master_client(master_client).await
+    pub async fn connect(uri: Uri, config: &ChannelConfig) -> Result<Self> {
+        let master_client = Client::connect(uri, config).await?;
+        Self::from_master_client(master_client, config).await
+    }
+
+    /// Returns a client of the single shard at the given uri, without service discovery,
+    /// for shards which serve the model on their own, e.g. those found via DNS
+    pub async fn connect_single(uri: Uri, config: &ChannelConfig) -> Result<Self> {
+        Self::with_capabilities(vec![Client::connect(uri, config).await?]).await
     }
 
     /// Returns a client connected to the given unix socket
-    pub async fn connect_uds(path: String) -> Result<Self> {
-        let master_client = Client::connect_uds(path).await?;
-        Self::from_master_client(master_client).await
+    pub async fn connect_uds(path: String, config: &ChannelConfig) -> Result<Self> {
+        let master_client = Client::connect_uds(path, config).await?;
+        Self::from_master_client(master_client, config).await
+    }
+
+    /// Use the given compression encoding for all shard requests
+    pub fn with_compression(self, encoding: CompressionEncoding) -> Self {
+        Self::new(
+            self.clients.into_iter().map(|c| c.with_compression(encoding)).collect(), self.capabilities,
+        )
     }
 
     /// GRPC health check
@@ -86,33 +139,77 @@ impl ShardedClient {
         join_all(futures).await.pop().unwrap()
     }
 
+    /// Check that each shard is answering gRPC calls, in shard order
+    pub async fn shard_health(&self) -> Vec<Result<()>> {
+        shard_health(&self.clients).await
+    }
+
+    /// Periodically check that every shard is answering gRPC calls, recording
+    /// per-shard health gauges labeled with the given replica index. Any failure
+    /// marks the replica's generation as unhealthy, so that requests are routed
+    /// to other replicas and the next health probe performs a real generation request.
+    pub fn spawn_health_monitor(
+        &self, period: Duration, generation_health: Arc<AtomicBool>, replica: usize,
+    ) -> JoinHandle<()> {
+        let clients = self.clients.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let results = shard_health(&clients).await;
+                for (shard, result) in results.iter().enumerate() {
+                    let healthy = if result.is_ok() { 1.0 } else { 0.0 };
+                    metrics::gauge!(
+                        "tgi_shard_healthy", healthy,
+                        "shard" => shard.to_string(), "replica" => replica.to_string(),
+                    );
+                    if let Err(err) = result {
+                        tracing::error!("Health check of shard {shard} of replica {replica} failed: {err}");
+                    }
+                }
+                if results.iter().any(Result::is_err) {
+                    generation_health.store(false, Ordering::SeqCst);
+                }
+            }
+        })
+    }
+
     /// Generate one token for each request in the given batch
     ///
     /// Returns first generated token for each request in the batch, id of the next cached batch,
-    /// and input token info if requested.
-    ///
-    /// Optionally prunes existing batches first to maximize available memory
+    /// and input token info if requested. Fails with [`ClientError::Timeout`] if any shard
+    /// doesn't respond by the deadline. Progress reported by the first shard is sent to the
+    /// progress sender, if provided, which requires the shards to support streaming prefill.
     pub async fn prefill(
-        &mut self, batch: Batch, to_prune: Vec<CachedBatch>,
-    ) -> Result<Option<(Vec<Token>, Vec<InputTokens>, Vec<GenerateError>, u64)>> {
+        &self,
+        batch: Batch,
+        deadline: Option<Instant>,
+        progress: Option<UnboundedSender<PrefillProgress>>,
+    ) -> Result<Option<GenerateTokenResponse>> {
         if batch.requests.is_empty() {
             return Ok(None);
         }
+        // Encoded once rather than by each shard's client
+        let request = EncodedMessage::new(&PrefillRequest { batch: Some(batch) });
+        metrics::histogram!("tgi_shard_prefill_request_bytes", request.len() as f64);
         let (tx, mut rx) = mpsc::channel(1);
-        self.sender.send((Prefill(batch, to_prune), tx))
+        self.sender.send((Prefill(request, deadline, progress), tx))
             .map_err(|e| ClientError::Generation(e.to_string()))?;
         rx.recv().await.ok_or_else(|| ClientError::Connection("client closed".to_string()))?
     }
 
     /// Generate one token for each request in the given cached batch
     ///
-    /// Returns next generated token of each request in the batches and id of the next cached batch
+    /// Returns next generated token of each request in the batches and id of the next cached batch.
+    /// Fails with [`ClientError::Timeout`] if any shard doesn't respond by the deadline.
     pub async fn next_token(
-        &mut self,
+        &self,
         batches: Vec<CachedBatch>,
-    ) -> Result<Option<(Vec<Token>, Vec<InputTokens>, Vec<GenerateError>, u64)>> {
+        deadline: Option<Instant>,
+    ) -> Result<Option<GenerateTokenResponse>> {
         let (tx, mut rx) = mpsc::channel(1);
-        self.sender.send((NextToken(batches), tx))
+        self.sender.send((NextToken(batches, deadline), tx))
             .map_err(|e| ClientError::Generation(e.to_string()))?;
         rx.recv().await.ok_or_else(|| ClientError::Connection("client closed".to_string()))?
     }
@@ -127,12 +224,46 @@ impl ShardedClient {
         join_all(futures).await.into_iter().collect()
     }
 
+    /// Remove completed requests from the cached batch in all shards, or discard it if finished
+    pub async fn update_batch(&mut self, batch: CachedBatch) -> Result<Option<u64>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.update_batch(batch.clone()))
+            .collect();
+        join_all(futures).await.pop().unwrap()
+    }
+
+    /// Offload the cache of the given requests in all shards, removing them from the batch
+    pub async fn offload_requests(
+        &mut self, batch: CachedBatch, request_ids: Vec<u64>,
+    ) -> Result<Option<u64>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.offload_requests(batch.clone(), request_ids.clone()))
+            .collect();
+        join_all(futures).await.pop().unwrap()
+    }
+
+    /// Compute embeddings of the given inputs, in all shards
+    pub async fn embed(
+        &mut self, inputs: Vec<EmbedInput>,
+    ) -> Result<(Vec<Embedding>, Vec<GenerateError>)> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.embed(inputs.clone()))
+            .collect();
+        join_all(futures).await.pop().unwrap()
+    }
+
     /// Get length of prompt prefix - verifies existence and populates cache
-    pub fn prefix_lookup(&mut self, prefix_id: &String) -> Result<usize> {
+    pub fn prefix_lookup(&mut self, prefix_id: &str) -> Result<usize> {
         let futures: Vec<_> = self
             .clients
             .iter_mut()
-            .map(|client| client.prefix_lookup(prefix_id.clone()))
+            .map(|client| client.prefix_lookup(prefix_id.to_string()))
             .collect();
         let v: Vec<Result<u32>> = self.handle.block_on(join_all(futures));
 
@@ -145,4 +276,23 @@ impl ShardedClient {
         self.clients[0].model_info().await
             .map(|(mt, eos, bpad)| (mt == ModelType::Seq2seqLm, eos, bpad))
     }
+
+    /// Features supported by all the shards, None if they don't report their capabilities
+    pub fn capabilities(&self) -> Option<&ShardCapabilities> {
+        self.capabilities.as_deref()
+    }
+
+    /// Get id and version of the shards' model, empty if unknown
+    pub async fn model_identity(&mut self) -> Result<(String, String)> {
+        self.clients[0].model_identity().await
+    }
+}
+
+async fn shard_health(clients: &[Client]) -> Vec<Result<()>> {
+    let checks = clients.iter().cloned().map(|mut client| async move {
+        timeout(HEALTH_CHECK_TIMEOUT, client.health()).await
+            .map_err(|_| ClientError::Timeout("health check".to_string()))
+            .and_then(|result| result.map(|_| ()))
+    });
+    join_all(checks).await
 }
@@ -0,0 +1,218 @@
+/// Admin endpoints reporting the router's internal state, for debugging stuck batches,
+/// and changing its log filter at runtime
+use std::sync::Arc;
+use axum::extract::Extension;
+use axum::http::{HeaderMap, StatusCode};
+use axum::http::header::AUTHORIZATION;
+use axum::Json;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::info;
+use crate::ErrorResponse;
+use crate::queue::BatchingConfig;
+use crate::server::{LogLevelSetter, ServerState};
+
+/// State of the admin endpoint, served only if an admin token is configured
+#[derive(Clone)]
+pub(crate) struct AdminState {
+    pub(crate) server: ServerState,
+    pub(crate) batching_config: watch::Receiver<BatchingConfig>,
+    /// Digest of the bearer token which requests must present
+    token_digest: Arc<[u8]>,
+    /// Changes the log filter, None if it can't be changed at runtime
+    log_level_setter: Option<Arc<LogLevelSetter>>,
+}
+
+/// Digest of the admin token, which presented tokens are compared against
+pub(crate) fn token_digest(token: &str) -> Arc<[u8]> {
+    Sha256::digest(token.as_bytes()).to_vec().into()
+}
+
+/// Whether the presented token is the one with the given digest. Digests are compared
+/// so that the time taken doesn't reveal how much of the token matched.
+pub(crate) fn matches_token(token: &str, digest: &[u8]) -> bool {
+    *Sha256::digest(token.as_bytes()) == *digest
+}
+
+impl AdminState {
+    pub(crate) fn new(
+        server: ServerState, batching_config: watch::Receiver<BatchingConfig>, token: &str,
+        log_level_setter: Option<Arc<LogLevelSetter>>,
+    ) -> Self {
+        Self { server, batching_config, token_digest: token_digest(token), log_level_setter }
+    }
+
+    /// Whether the request presents the admin token
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        headers.get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map_or(false, |token| matches_token(token, &self.token_digest))
+    }
+
+    pub(crate) fn authorize(&self, headers: &HeaderMap) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+        if self.is_authorized(headers) {
+            return Ok(())
+        }
+        Err((
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse {
+                error: "invalid or missing admin token".to_string(),
+                error_code: "unauthorized",
+                retry: None,
+                errors: vec![],
+            }),
+        ))
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct RouterState {
+    config: ConfigState,
+    replicas: Vec<ReplicaState>,
+}
+
+#[derive(Serialize)]
+struct ConfigState {
+    max_batch_size: usize,
+    max_batch_weight: usize,
+    max_prefill_weight: usize,
+    max_waiting_tokens: usize,
+    max_queue_size: usize,
+    shard_step_timeout_secs: Option<u64>,
+    max_sequence_length: usize,
+    max_new_tokens: usize,
+    max_concurrent_requests: usize,
+    available_request_permits: usize,
+}
+
+#[derive(Serialize)]
+struct ReplicaState {
+    // Whether the replica's shards last succeeded in generating
+    healthy: bool,
+    batch_id: Option<u64>,
+    // Time since the running batch last completed a generation step
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_step_age_ms: Option<u128>,
+    entries: Vec<EntryState>,
+    queue: QueueState,
+    shards: Vec<ShardState>,
+}
+
+#[derive(Serialize)]
+struct EntryState {
+    id: u64,
+    age_ms: u128,
+    // Time since the entry was first added to a batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batched_ms: Option<u128>,
+    input_tokens: usize,
+    generated_tokens: u32,
+    max_new_tokens: u32,
+    preempted: bool,
+}
+
+#[derive(Serialize)]
+struct QueueState {
+    length: usize,
+    input_tokens: usize,
+    preempted: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oldest_age_ms: Option<u128>,
+    admission_rate: f64,
+}
+
+#[derive(Serialize)]
+struct ShardState {
+    healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Report the current batch and queue of each replica, the health of its shards
+/// and the batching config
+pub(crate) async fn admin_state(
+    state: Extension<AdminState>, headers: HeaderMap,
+) -> Result<Json<RouterState>, (StatusCode, Json<ErrorResponse>)> {
+    state.authorize(&headers)?;
+    let server = &state.server;
+    let deployment = server.deployment();
+    let now = Instant::now();
+    let age_ms = |time: Instant| now.saturating_duration_since(time).as_millis();
+
+    let shard_health = join_all(deployment.clients.iter().map(|c| c.shard_health())).await;
+    let replicas = deployment.batcher.replicas().iter().zip(shard_health).map(|(replica, shards)| {
+        let queue = replica.queue_status();
+        let batch = replica.batch_state();
+        ReplicaState {
+            healthy: replica.healthy(),
+            batch_id: batch.batch_id,
+            last_step_age_ms: batch.batch_id.and(batch.updated).map(age_ms),
+            entries: batch.entries.iter().map(|e| EntryState {
+                id: e.id,
+                age_ms: age_ms(e.queue_time),
+                batched_ms: e.batch_time.map(age_ms),
+                input_tokens: e.input_length,
+                generated_tokens: e.generated_tokens,
+                max_new_tokens: e.max_new_tokens,
+                preempted: e.preempted,
+            }).collect(),
+            queue: QueueState {
+                length: queue.queued,
+                input_tokens: queue.queued_tokens,
+                preempted: queue.preempted,
+                oldest_age_ms: queue.oldest.map(age_ms),
+                admission_rate: queue.admission_rate,
+            },
+            shards: shards.into_iter().map(|result| ShardState {
+                healthy: result.is_ok(),
+                error: result.err().map(|e| e.to_string()),
+            }).collect(),
+        }
+    }).collect();
+
+    let batching_config = state.batching_config.borrow().clone();
+    Ok(Json(RouterState {
+        config: ConfigState {
+            max_batch_size: batching_config.size_limit,
+            max_batch_weight: batching_config.weight_limit,
+            max_prefill_weight: batching_config.prefill_weight_limit,
+            max_waiting_tokens: batching_config.max_waiting_tokens,
+            max_queue_size: batching_config.queue_size_limit,
+            shard_step_timeout_secs: batching_config.step_timeout.map(|t| t.as_secs()),
+            max_sequence_length: server.max_sequence_length,
+            max_new_tokens: server.max_new_tokens,
+            max_concurrent_requests: server.max_concurrent_requests,
+            available_request_permits: server.limit_concurrent_requests.available_permits(),
+        },
+        replicas,
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LogFilterRequest {
+    /// Level or comma-separated directives, e.g. "info,text_generation_router::batcher=debug"
+    filter: String,
+}
+
+/// Change the log filter, e.g. to log particular modules at DEBUG level. Requests
+/// flagged for debugging are still logged at DEBUG level whatever the filter
+pub(crate) async fn set_log_filter(
+    state: Extension<AdminState>, headers: HeaderMap, Json(request): Json<LogFilterRequest>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    state.authorize(&headers)?;
+    let error = |status, error_code, error| (
+        status, Json(ErrorResponse { error, error_code, retry: None, errors: vec![] }),
+    );
+    let Some(set_filter) = &state.log_level_setter else {
+        return Err(error(
+            StatusCode::NOT_IMPLEMENTED, "unsupported", "log filter can't be changed at runtime".to_string(),
+        ))
+    };
+    set_filter(&request.filter).map_err(|e| error(StatusCode::BAD_REQUEST, "invalid_filter", e))?;
+    info!("Log filter is now {:?}", request.filter);
+    Ok(StatusCode::NO_CONTENT)
+}
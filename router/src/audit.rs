@@ -0,0 +1,114 @@
+/// Greedy decoding determinism audits
+use rand::Rng;
+use tracing::{info, warn};
+use crate::GenerateRequest;
+use crate::batcher::InferResponse;
+use crate::deployment::Deployment;
+use crate::log_redaction::redact_token_ids;
+use crate::pb::fmaas::StopReason::{Cancelled, Error, Preempted, QueueTimeLimit, ServerShutdown, TimeLimit};
+use crate::server::ServerState;
+
+/// Whether the given request is eligible for a determinism audit
+/// and was chosen based on the configured sampling fraction
+pub(crate) fn should_audit(state: &ServerState, deployment: &Deployment, request: &GenerateRequest) -> bool {
+    let fraction = state.determinism_audit_fraction;
+    fraction > 0.0
+        && deployment.determinism_audits
+        && request.parameters.temperature == 0.0
+        && rand::thread_rng().gen::<f32>() < fraction
+}
+
+/// Re-run a completed greedy request in the background, with the deployment which generated
+/// it, and compare the generated token ids with those of the original response. Audit
+/// requests are skipped rather than queued if there is no spare concurrent request capacity.
+pub(crate) fn spawn_audit(
+    state: &ServerState,
+    deployment: &Deployment,
+    input_length: usize,
+    mut request: GenerateRequest,
+    original: &InferResponse,
+) {
+    if matches!(original.reason, TimeLimit | QueueTimeLimit | Cancelled | Error | Preempted | ServerShutdown) {
+        // Incomplete output, nothing meaningful to compare
+        return
+    }
+    let Ok(permit) = state.limit_concurrent_requests.clone().try_acquire_owned() else {
+        metrics::increment_counter!("tgi_determinism_audit_skipped");
+        return
+    };
+    // Original deadline is likely to have passed already
+    request.parameters.deadline = None;
+    request.parameters.max_time = None;
+    let batcher = deployment.batcher.clone();
+    let model = deployment.model.clone();
+    let expected = original.token_ids.clone();
+    let original_id = original.request_id;
+    tokio::spawn(async move {
+        let _permit = permit;
+        metrics::increment_counter!("tgi_determinism_audit_count");
+        let rerun = match batcher.infer(input_length, request).await {
+            Ok(response) => response,
+            Err(err) => {
+                metrics::increment_counter!("tgi_determinism_audit_failure");
+                warn!("Determinism audit re-run of request {original_id:?} failed: {err}");
+                return
+            }
+        };
+        let actual = &rerun.token_ids;
+        match first_divergence(&expected, actual) {
+            None => {
+                info!("Determinism audit of request {original_id:?} passed, {} token(s) matched, \
+                    model {} version {}", expected.len(), model.id, model.version);
+            },
+            Some(index) => {
+                metrics::increment_counter!("tgi_determinism_audit_divergence");
+                metrics::histogram!("tgi_determinism_audit_divergence_index", index as f64);
+                warn!(
+                    "Determinism audit divergence: request {original_id:?} re-run as {:?} differs \
+                    at token index {index}: original {} token(s) {}, re-run {} token(s) {}, \
+                    model {} version {}",
+                    rerun.request_id, expected.len(), redact_token_ids(&expected[index..]),
+                    actual.len(), redact_token_ids(&actual[index..]), model.id, model.version,
+                );
+            },
+        }
+    });
+}
+
+/// Index of the first token at which two generated sequences differ, or None if they're
+/// the same. If one is a prefix of the other this is the length of the shorter one.
+pub(crate) fn first_divergence(expected: &[u32], actual: &[u32]) -> Option<usize> {
+    match expected.iter().zip(actual).position(|(e, a)| e != a) {
+        None if expected.len() == actual.len() => None,
+        position => Some(position.unwrap_or(expected.len().min(actual.len()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batcher::InferResponse;
+    use crate::decoder::Decoder;
+    use crate::decoder_backends::WordBackend;
+    use super::first_divergence;
+
+    fn decoded_response(decoder: &Decoder, token_ids: Vec<u32>) -> InferResponse {
+        let mut response = InferResponse { token_ids, ..Default::default() };
+        response.decode_output_text(decoder).unwrap();
+        response
+    }
+
+    #[test]
+    fn audit_compares_decoded_responses() {
+        let backend = WordBackend(vec!["A", "the", "cat", "sat", "ran", "</s>"]);
+        let decoder = Decoder::new(Box::new(backend), false, 5, true, String::new(), 0, None);
+        let original = decoded_response(&decoder, vec![1, 2, 3]);
+        let same = decoded_response(&decoder, vec![1, 2, 3]);
+        let different = decoded_response(&decoder, vec![1, 2, 4]);
+        let shorter = decoded_response(&decoder, vec![1, 2]);
+
+        assert_eq!(original.output_text, " the cat sat");
+        assert_eq!(first_divergence(&original.token_ids, &same.token_ids), None);
+        assert_eq!(first_divergence(&original.token_ids, &different.token_ids), Some(2));
+        assert_eq!(first_divergence(&original.token_ids, &shorter.token_ids), Some(2));
+    }
+}
@@ -0,0 +1,238 @@
+/// Authentication of external gRPC requests with static API keys or JWTs
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::{Signer, Verifier};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tonic::metadata::MetadataValue;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Allowed difference between our clock and the token issuer's, when checking expiry
+const CLOCK_SKEW_LEEWAY_SECS: u64 = 60;
+
+/// Who an authenticated request is from
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CallerIdentity {
+    caller: String,
+    /// Tenant for queue fairness, the caller if unset
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKeyEntry {
+    key: String,
+    #[serde(flatten)]
+    identity: CallerIdentity,
+}
+
+/// Settings of JWT validation
+pub(crate) struct JwtConfig {
+    /// PEM public key (RSA or EC) or, otherwise, HMAC secret which tokens are signed with
+    pub(crate) key_path: String,
+    pub(crate) issuer: Option<String>,
+    pub(crate) audience: Option<String>,
+    /// Claim holding the caller's tenant, the subject is used if absent
+    pub(crate) tenant_claim: String,
+}
+
+enum JwtKey {
+    Public(PKey<Public>),
+    Hmac(Vec<u8>),
+}
+
+struct JwtValidator {
+    key: JwtKey,
+    issuer: Option<String>,
+    audience: Option<String>,
+    tenant_claim: String,
+}
+
+/// Interceptor of the external gRPC services which authenticates requests if enabled.
+/// The x-caller-id and x-tenant-id headers of authenticated requests are replaced with
+/// their identity, used for client rate limiting, request logging and tenant fairness.
+#[derive(Clone, Default)]
+pub(crate) struct Authenticator {
+    inner: Option<Arc<AuthInner>>,
+}
+
+struct AuthInner {
+    /// Callers by SHA-256 digest of their API key
+    api_keys: HashMap<Vec<u8>, CallerIdentity>,
+    jwt: Option<JwtValidator>,
+}
+
+impl Authenticator {
+    /// Authenticator accepting the API keys in the given JSON file, a list of
+    /// `{"key", "caller", "tenant"}` objects, and/or JWTs. Disabled if neither are set
+    pub(crate) fn new(api_keys_path: Option<&str>, jwt: Option<JwtConfig>) -> Result<Self, String> {
+        if api_keys_path.is_none() && jwt.is_none() {
+            return Ok(Self::default())
+        }
+        let api_keys = match api_keys_path {
+            Some(path) => load_api_keys(path)?,
+            None => HashMap::new(),
+        };
+        let jwt = jwt.map(JwtValidator::new).transpose()?;
+        Ok(Self { inner: Some(Arc::new(AuthInner { api_keys, jwt })) })
+    }
+
+    fn authenticate<T>(inner: &AuthInner, request: &Request<T>) -> Result<CallerIdentity, &'static str> {
+        let metadata = request.metadata();
+        let credential = metadata.get("authorization")
+            .and_then(|mv| mv.to_str().ok())
+            .and_then(|auth| auth.strip_prefix("Bearer "))
+            .or_else(|| metadata.get("x-api-key").and_then(|mv| mv.to_str().ok()))
+            .ok_or("missing")?
+            .trim();
+        if let Some(identity) = inner.api_keys.get(&Sha256::digest(credential.as_bytes()).to_vec()) {
+            return Ok(identity.clone())
+        }
+        match &inner.jwt {
+            Some(jwt) if credential.split('.').count() == 3 => jwt.validate(credential),
+            _ => Err("invalid"),
+        }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(inner) = &self.inner else {
+            return Ok(request)
+        };
+        let identity = Self::authenticate(inner, &request).map_err(|reason| {
+            metrics::increment_counter!("tgi_auth_failure", "reason" => reason);
+            tracing::debug!("Request authentication failed: {reason}");
+            Status::unauthenticated(format!("authentication failed: {reason}"))
+        })?;
+        let tenant = identity.tenant.as_ref().unwrap_or(&identity.caller);
+        let (Ok(caller), Ok(tenant)) = (
+            MetadataValue::try_from(&identity.caller), MetadataValue::try_from(tenant),
+        ) else {
+            return Err(Status::unauthenticated("caller identity isn't a valid header value"))
+        };
+        let metadata = request.metadata_mut();
+        metadata.insert("x-caller-id", caller);
+        metadata.insert("x-tenant-id", tenant);
+        Ok(request)
+    }
+}
+
+fn load_api_keys(path: &str) -> Result<HashMap<Vec<u8>, CallerIdentity>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("couldn't read API keys file {path}: {e}"))?;
+    let entries: Vec<ApiKeyEntry> = serde_json::from_str(&contents)
+        .map_err(|e| format!("invalid API keys file {path}: {e}"))?;
+    entries.into_iter().map(|entry| match entry.key.trim() {
+        "" => Err(format!("empty API key for caller {} in {path}", entry.identity.caller)),
+        key => Ok((Sha256::digest(key.as_bytes()).to_vec(), entry.identity)),
+    }).collect()
+}
+
+impl JwtValidator {
+    fn new(config: JwtConfig) -> Result<Self, String> {
+        let path = &config.key_path;
+        let contents = std::fs::read(path)
+            .map_err(|e| format!("couldn't read JWT key file {path}: {e}"))?;
+        let key = match PKey::public_key_from_pem(&contents) {
+            Ok(key) if matches!(key.id(), Id::RSA | Id::EC) => JwtKey::Public(key),
+            Ok(_) => return Err(format!("JWT public key {path} must be an RSA or EC key")),
+            Err(_) if contents.starts_with(b"-----BEGIN") =>
+                return Err(format!("invalid JWT public key {path}")),
+            Err(_) => {
+                let end = contents.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(0, |i| i + 1);
+                JwtKey::Hmac(contents[..end].to_vec())
+            },
+        };
+        Ok(Self { key, issuer: config.issuer, audience: config.audience, tenant_claim: config.tenant_claim })
+    }
+
+    fn validate(&self, token: &str) -> Result<CallerIdentity, &'static str> {
+        let (signed, signature) = token.rsplit_once('.').ok_or("invalid")?;
+        let (header, claims) = signed.split_once('.').ok_or("invalid")?;
+        let decode = |part: &str| BASE64_URL.decode(part).map_err(|_| "invalid");
+        let header: Value = serde_json::from_slice(&decode(header)?).map_err(|_| "invalid")?;
+        let algorithm = header.get("alg").and_then(Value::as_str).ok_or("invalid")?;
+        if !self.verify(algorithm, signed.as_bytes(), &decode(signature)?)? {
+            return Err("invalid")
+        }
+
+        let claims: Value = serde_json::from_slice(&decode(claims)?).map_err(|_| "invalid")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let time_claim = |name| claims.get(name).and_then(Value::as_u64);
+        if time_claim("exp").map_or(true, |exp| exp + CLOCK_SKEW_LEEWAY_SECS < now) {
+            return Err("expired")
+        }
+        if time_claim("nbf").map_or(false, |nbf| nbf > now + CLOCK_SKEW_LEEWAY_SECS) {
+            return Err("not_yet_valid")
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err("wrong_issuer")
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err("wrong_audience")
+            }
+        }
+        let caller = claims.get("sub").and_then(Value::as_str).ok_or("invalid")?.to_string();
+        let tenant = claims.get(&self.tenant_claim).and_then(Value::as_str).map(str::to_string);
+        Ok(CallerIdentity { caller, tenant })
+    }
+
+    /// Whether the signature is valid for the signed part of the token
+    fn verify(&self, algorithm: &str, signed: &[u8], signature: &[u8]) -> Result<bool, &'static str> {
+        if algorithm.len() != 5 || !algorithm.is_ascii() {
+            return Err("unsupported")
+        }
+        let (family, bits) = algorithm.split_at(2);
+        let digest = match bits {
+            "256" => MessageDigest::sha256(),
+            "384" => MessageDigest::sha384(),
+            "512" => MessageDigest::sha512(),
+            _ => return Err("unsupported"),
+        };
+        let verified = match (family, &self.key) {
+            ("HS", JwtKey::Hmac(secret)) => {
+                let key = PKey::hmac(secret).map_err(|_| "invalid")?;
+                let expected = Signer::new(digest, &key).and_then(|mut s| s.sign_oneshot_to_vec(signed))
+                    .map_err(|_| "invalid")?;
+                expected.len() == signature.len() && memcmp::eq(&expected, signature)
+            },
+            ("RS", JwtKey::Public(key)) if key.id() == Id::RSA => {
+                Verifier::new(digest, key).and_then(|mut v| v.verify_oneshot(signature, signed))
+                    .unwrap_or(false)
+            },
+            ("ES", JwtKey::Public(key)) if key.id() == Id::EC => {
+                // JWS signatures are the concatenated r and s values rather than DER
+                let (r, s) = signature.split_at(signature.len() / 2);
+                let der = BigNum::from_slice(r).and_then(|r| Ok((r, BigNum::from_slice(s)?)))
+                    .and_then(|(r, s)| EcdsaSig::from_private_components(r, s))
+                    .and_then(|sig| sig.to_der())
+                    .map_err(|_| "invalid")?;
+                Verifier::new(digest, key).and_then(|mut v| v.verify_oneshot(&der, signed))
+                    .unwrap_or(false)
+            },
+            _ => return Err("unsupported"),
+        };
+        Ok(verified)
+    }
+}
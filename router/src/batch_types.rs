@@ -1,68 +1,89 @@
 use std::cmp::max;
 use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::sync::Arc;
 use nohash_hasher::IntMap;
 use num::integer::Roots;
 use crate::queue::Entry;
 
-pub(crate) trait BatchType: Send + Sync + Clone + 'static {
-    type Stats: Default;
+/// Statistics of a batch from which strategies compute its weight
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct BatchStats {
+    pub(crate) total_tokens: usize,
+    pub(crate) max_input_length: usize,
+    pub(crate) max_output_length: usize,
+}
 
+impl BatchStats {
     /// Update batch statistics with an additional request
-    fn update_stats(stats: &Self::Stats, input_length: usize, output_length: usize) -> Self::Stats;
+    pub(crate) fn update(&self, input_length: usize, output_length: usize) -> Self {
+        Self {
+            total_tokens: self.total_tokens + input_length + output_length,
+            max_input_length: max(self.max_input_length, input_length),
+            max_output_length: max(self.max_output_length, output_length),
+        }
+    }
+
+    /// Compute batch statistics given map of entries
+    pub(crate) fn compute(entries: &IntMap<u64, Entry>) -> Self {
+        entries.values().fold(
+            Self::default(),
+            |stats, entry| {
+                let generated_count = entry.generated_tokens;
+                stats.update(
+                    entry.input_length + generated_count as usize,
+                    (entry.request.parameters.max_new_tokens - generated_count) as usize,
+                )
+            }
+        )
+    }
+}
+
+/// Batching strategy, corresponding to the memory model of the shards
+pub(crate) trait BatchType: Send + Sync + Debug {
     /// Calculate batch weight given batch statistics
-    fn batch_weight(stats: &Self::Stats, batch_size: usize) -> usize;
+    fn batch_weight(&self, stats: &BatchStats, batch_size: usize) -> usize;
     /// Calculate prefill batch weight given prefill batch statistics
-    fn prefill_weight(prefill_stats: &Self::Stats, batch_size: usize) -> usize;
+    fn prefill_weight(&self, prefill_stats: &BatchStats, batch_size: usize) -> usize;
     /// Indicate whether a hypothetical batch will exceed the combined weight limit
     fn exceeds_weight(
-        tree: &BTreeSet<(usize, usize, usize)>, max_total_weight: usize, current_output_len: usize
+        &self, tree: &BTreeSet<(usize, usize, usize)>, max_total_weight: usize, current_output_len: usize
     ) -> bool;
     /// Provide a count of tokens for a given batch, including padding tokens if applicable
-    fn count_tokens(input_lengths: impl Iterator<Item=usize>, batch_size: usize) -> usize;
+    fn count_tokens(&self, input_lengths: &mut dyn Iterator<Item=usize>, batch_size: usize) -> usize;
+    /// Number of token positions the KV cache must hold once every sequence
+    /// in the batch has reached its maximum length
+    fn max_cache_tokens(&self, stats: &BatchStats, batch_size: usize) -> usize;
 
     /// max_prefill_weight to use when none is specified
-    fn default_max_prefill_weight() -> usize;
+    fn default_max_prefill_weight(&self) -> usize;
+}
 
-    /// Compute batch statistics given map of entries
-    fn compute_stats(entries: &IntMap<u64, Entry>) -> Self::Stats {
-        entries.iter().fold(
-            Self::Stats::default(),
-            |stats, (_, entry)| {
-                let generated_count = entry.generated_tokens;
-                Self::update_stats(
-                    &stats,
-                    entry.input_length + generated_count as usize,
-                    (entry.request.parameters.max_new_tokens - generated_count) as usize,
-                )
-            }
-        )
+/// Look up a batching strategy by name
+pub(crate) fn batch_type_for_name(name: &str) -> Result<Arc<dyn BatchType>, String> {
+    match name {
+        "flash" => Ok(Arc::new(FlashBatch {})),
+        "padded" => Ok(Arc::new(PaddedBatch {})),
+        _ => Err(format!("invalid batch type '{name}', must be auto, flash or padded")),
     }
 }
 
 /// Non-padded batch used in flash attention
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub(crate) struct FlashBatch {}
 
 impl BatchType for FlashBatch {
-    /// Keep track of total number of tokens in the batch
-    type Stats = usize;
-
-    fn update_stats(
-        total_tokens: &Self::Stats, input_length: usize, output_length: usize
-    ) -> Self::Stats {
-        total_tokens + input_length + output_length
+    /// Weight is the total number of tokens in the batch
+    fn batch_weight(&self, stats: &BatchStats, _batch_size: usize) -> usize {
+        stats.total_tokens
     }
 
-    fn batch_weight(total_tokens: &Self::Stats, _batch_size: usize) -> usize {
-        *total_tokens
-    }
-
-    fn prefill_weight(total_tokens: &Self::Stats, _batch_size: usize) -> usize {
-        *total_tokens
+    fn prefill_weight(&self, stats: &BatchStats, _batch_size: usize) -> usize {
+        stats.total_tokens
     }
 
     fn exceeds_weight(
-        tree: &BTreeSet<(usize, usize, usize)>, max_total_weight: usize, current_output_len: usize
+        &self, tree: &BTreeSet<(usize, usize, usize)>, max_total_weight: usize, current_output_len: usize
     ) -> bool {
         let mut in_sum = 0;
         // Work backwards from longest projected entry
@@ -82,45 +103,38 @@ impl BatchType for FlashBatch {
         false
     }
 
-    fn count_tokens(input_lengths: impl Iterator<Item=usize>, _: usize) -> usize {
+    fn count_tokens(&self, input_lengths: &mut dyn Iterator<Item=usize>, _: usize) -> usize {
         input_lengths.sum()
     }
 
-    fn default_max_prefill_weight() -> usize {
+    fn max_cache_tokens(&self, stats: &BatchStats, _batch_size: usize) -> usize {
+        stats.total_tokens
+    }
+
+    fn default_max_prefill_weight(&self) -> usize {
         8192
     }
 }
 
 /// Regular rectangular padded
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub(crate) struct PaddedBatch {}
 
 impl BatchType for PaddedBatch {
-    /// Keep track of maximum input length, maximum output length
-    type Stats = (usize, usize);
-
-    fn update_stats(
-        max_in_out_lengths: &Self::Stats, input_length: usize, output_length: usize
-    ) -> Self::Stats {
-        let (max_input_length, max_output_length) = max_in_out_lengths;
-        (max(*max_input_length, input_length), max(*max_output_length, output_length))
-    }
-
-    fn batch_weight(max_in_out_lengths: &Self::Stats, batch_size: usize) -> usize {
-        let (max_input_length, max_output_length) = max_in_out_lengths;
-        let max_seq_len = max_input_length + max_output_length;
+    /// Weight is based on the maximum input length and maximum output length
+    fn batch_weight(&self, stats: &BatchStats, batch_size: usize) -> usize {
+        let max_seq_len = stats.max_input_length + stats.max_output_length;
         // Memory requirement roughly proportional to batch_size * seq_len^2
         batch_size * max_seq_len.pow(2)
     }
 
-    fn prefill_weight(max_in_out_lengths: &Self::Stats, batch_size: usize) -> usize {
+    fn prefill_weight(&self, stats: &BatchStats, batch_size: usize) -> usize {
         // Empirically, prefill latency is proportional to batch_size * seq_len^(3/2)
-        let (max_input_length, _) = max_in_out_lengths;
-        batch_size * max_input_length.pow(3).sqrt()
+        batch_size * stats.max_input_length.pow(3).sqrt()
     }
 
     fn exceeds_weight(
-        tree: &BTreeSet<(usize, usize, usize)>, max_total_weight: usize, current_output_len: usize
+        &self, tree: &BTreeSet<(usize, usize, usize)>, max_total_weight: usize, current_output_len: usize
     ) -> bool {
         let mut max_in_len = 0;
         // Work backwards from longest projected entry
@@ -138,11 +152,16 @@ impl BatchType for PaddedBatch {
         false
     }
 
-    fn count_tokens(input_lengths: impl Iterator<Item=usize>, batch_size: usize) -> usize {
+    fn count_tokens(&self, input_lengths: &mut dyn Iterator<Item=usize>, batch_size: usize) -> usize {
         input_lengths.max().unwrap_or(0) * batch_size
     }
 
-    fn default_max_prefill_weight() -> usize {
+    /// Every sequence is padded to the longest one
+    fn max_cache_tokens(&self, stats: &BatchStats, batch_size: usize) -> usize {
+        batch_size * (stats.max_input_length + stats.max_output_length)
+    }
+
+    fn default_max_prefill_weight(&self) -> usize {
         300000
     }
 }
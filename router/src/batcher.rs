@@ -1,97 +1,399 @@
 use std::cmp::max;
 /// Batching and inference logic
-use crate::queue::{BatchingConfig, Entry, Queue};
-use crate::{ErrorResponse, GenerateRequest};
-use axum::http::StatusCode;
+use crate::queue::{
+    BatchingConfig, Entry, LaneConfig, Queue, QueueEstimate, QueueMemory, QueueStatus, SchedulingPolicy,
+};
+use crate::{ErrorResponse, GenerateParameters, GenerateRequest};
+use axum::http::{HeaderValue, StatusCode};
+use axum::http::header::RETRY_AFTER;
 use axum::Json;
+use axum::response::{IntoResponse, Response};
+use std::any::Any;
 use std::future::Future;
 use std::mem::take;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::task::{Context, Poll};
-use futures::{FutureExt, pin_mut, TryFutureExt};
-use futures::future::Map;
+use futures::{FutureExt, pin_mut};
+use futures::future::{BoxFuture, join, Shared};
 use nohash_hasher::IntMap;
-use text_generation_client::{ClientError, Token, ShardedClient, CachedBatch, RequestsStatus, InputTokens, GenerateError, Batch};
+use parking_lot::Mutex;
+use smallvec::{smallvec, SmallVec};
+use std::collections::HashMap;
+use text_generation_client::{
+    ClientError, Token, ShardedClient, CachedBatch, RequestsStatus, InputTokens, GenerateError, Batch,
+    GenerateTokenResponse, PrefillProgress,
+};
+use serde::Serialize;
 use thiserror::Error;
 use tokio::select;
 
-use tokio::sync::oneshot;
-use tokio::sync::mpsc::{channel, Sender, unbounded_channel, UnboundedReceiver};
+use tokio::sync::{oneshot, watch, Semaphore};
+use tokio::sync::mpsc::{self, channel};
 use tokio::sync::mpsc::error::TrySendError;
-use tokio::sync::oneshot::error::RecvError;
-use tokio::sync::oneshot::Receiver;
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 use tokio_stream::Stream;
-use tracing::{debug, info, instrument, warn, enabled, Level, error};
+use tracing::{debug, info, warn, enabled, Level, error};
 use crate::batch_types::BatchType;
-use crate::batcher::InferError::{GenerationError, RequestQueueFull};
+use crate::batcher::InferError::{DetokenizationError, FirstTokenObjective, GenerationError, RequestQueueFull};
 use crate::batcher::TokenInfos::{WithIds, WithStrings};
-use crate::decoder::{Decoder, IncrementalDecoder, IncrementalDecoderWrapper};
-use crate::pb::fmaas::{StopReason, TokenInfo};
+use crate::decoder::{DecodeOptions, Decoder, IncrementalDecoder, IncrementalDecoderWrapper};
+use crate::preemption::{Preemption, PreemptionPolicy};
+use crate::trace::{applied_penalties, GenerationTrace, stop_criterion, strip_trace_details};
+use crate::pb::fmaas::{NormalizedParameters, StopDetails, StopReason, TokenInfo};
 use crate::pb::fmaas::StopReason::{
-    Cancelled, EosToken, Error, MaxTokens, NotFinished, StopSequence, TimeLimit, TokenLimit
+    Cancelled, EosToken, Error, LogprobThreshold, MaxTokens, NotFinished, Preempted, ServerShutdown,
+    QueueTimeLimit, SlowConsumer, StopSequence, TimeLimit, TokenLimit,
 };
 use crate::pb::fmaas::token_info::TopToken;
+use crate::pb::fmaas::generation_trace::StopCriterion;
+use crate::streaming::{stream_channel, SlowStreamPolicy, StreamBufferConfig, StreamSendError};
+use crate::token_healing::HealedPrefix;
+use crate::response_cache::{request_key, ResponseCache};
+use crate::replicas::{combined_queue_status, select_replica, Replica};
+use crate::kv_cache::KvCacheModel;
+use crate::hooks::RequestHooks;
+use crate::waiting_tokens::{WaitingTokensController, WaitingTokensPolicy};
+use crate::request_metrics::{record_cancellation, record_rejection, Cancellation, Rejection};
+
+/// Report requests rejected before being queued to their hooks
+fn report_rejection(entries: &mut [Entry], err: &InferError) {
+    for entry in entries {
+        if let Some(hooks) = &mut entry.hooks {
+            hooks.error(err.to_string());
+        }
+    }
+}
+
+/// In-progress unary inference shared between identical requests
+type SharedInfer = Shared<BoxFuture<'static, Result<InferResponse, InferError>>>;
 
 /// Batcher
 #[derive(Clone)]
 pub(crate) struct Batcher {
-    /// Request queue
-    sender: Sender<Vec<Entry>>,
+    /// Request queue of each data-parallel replica
+    replicas: Arc<Vec<Replica>>,
     /// Tokenizer
     decoder: Arc<Decoder>,
+    /// In-progress deterministic requests keyed by their content,
+    /// present only if duplicate request coalescing is enabled
+    in_flight: Option<Arc<Mutex<HashMap<String, SharedInfer>>>>,
+    /// Buffering of streaming responses
+    stream_config: StreamBufferConfig,
+    /// Cache of responses to deterministic requests, if enabled
+    response_cache: Option<ResponseCache>,
+    /// Capacity of each replica's queue channel
+    queue_size: usize,
+    /// Batching config, may be updated at runtime
+    config: watch::Receiver<BatchingConfig>,
+    /// Memory held by queued prompts, if it's limited
+    queue_memory: Option<QueueMemory>,
+    /// Limits the number of unary responses decoded concurrently
+    decode_permits: Arc<Semaphore>,
+    /// Time to first token objective, requests projected to exceed it are rejected
+    ttft_slo: Option<Duration>,
+    /// Hooks run at each stage of submitted requests, if any are registered
+    hooks: Option<RequestHooks>,
+    /// Set once the server is shutting down, to stop generation of the running requests
+    shutting_down: Arc<AtomicBool>,
+    /// Used to start the queue and batching task of replicas which are added
+    replica_settings: ReplicaSettings,
+    /// Index of the next replica added, unique among those of previous deployments
+    next_replica_index: Arc<AtomicUsize>,
+}
+
+/// Settings of each replica's queue and batching task
+#[derive(Clone)]
+struct ReplicaSettings {
+    batch_type: Arc<dyn BatchType>,
+    kv_cache: Option<KvCacheModel>,
+    scheduling: SchedulingPolicy,
+    lanes: Option<LaneConfig>,
+    preemption: Option<Preemption>,
+    prefill_progress: bool,
+    waiting_tokens_policy: WaitingTokensPolicy,
+    pipeline_prefill: bool,
+    retry_failed_batches: bool,
+    pause_slow_streams: bool,
 }
 
 impl Batcher {
-    pub(crate) fn new<B: BatchType>(
-        client: ShardedClient,
-        config: BatchingConfig,
-        max_waiting_tokens: usize,
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        clients: Vec<ShardedClient>,
+        config: watch::Receiver<BatchingConfig>,
         queue_size: usize,
+        max_queued_prompt_bytes: Option<usize>,
         decoder: Decoder,
-        generation_health: Arc<AtomicBool>,
-        batch_type: B,
+        batch_type: Arc<dyn BatchType>,
+        coalesce_requests: bool,
+        stream_config: StreamBufferConfig,
+        response_cache: Option<ResponseCache>,
+        preemption: Option<Preemption>,
+        kv_cache: Option<KvCacheModel>,
+        scheduling: SchedulingPolicy,
+        lanes: Option<LaneConfig>,
+        detokenization_workers: usize,
+        ttft_slo: Option<Duration>,
+        prefill_progress: bool,
+        waiting_tokens_policy: WaitingTokensPolicy,
+        pipeline_prefill: bool,
+        retry_failed_batches: bool,
+        hooks: Option<RequestHooks>,
+    ) -> Self {
+        let in_flight = coalesce_requests.then(Default::default);
+        let mut batcher = Self {
+            replicas: Default::default(),
+            decoder: Arc::new(decoder),
+            in_flight, stream_config, response_cache, queue_size, config,
+            queue_memory: max_queued_prompt_bytes.map(QueueMemory::new),
+            decode_permits: Arc::new(Semaphore::new(detokenization_workers)),
+            ttft_slo,
+            hooks,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            replica_settings: ReplicaSettings {
+                batch_type, kv_cache, scheduling, lanes, preemption, prefill_progress,
+                waiting_tokens_policy, pipeline_prefill, retry_failed_batches,
+                pause_slow_streams: stream_config.policy == SlowStreamPolicy::Pause,
+            },
+            next_replica_index: Default::default(),
+        };
+        // Each replica has its own queue and batching task
+        let replicas = clients.into_iter().map(|client| batcher.spawn_replica(client)).collect();
+        batcher.replicas = Arc::new(replicas);
+        batcher
+    }
+
+    /// Batcher for a changed set of replicas, sharing the queues and batching tasks of
+    /// those kept, given by their position, with this one. Those added are given their
+    /// own, with preemption and prefill progress as supported by the new set's shards.
+    /// The batching tasks of replicas which aren't kept stop once this batcher is dropped
+    /// and their requests complete, so that only one task ever drives a replica's shards.
+    pub(crate) fn with_replicas(
+        &self,
+        kept: &[usize],
+        added: Vec<ShardedClient>,
+        preemption: Option<Preemption>,
+        prefill_progress: bool,
     ) -> Self {
+        let mut batcher = Self {
+            replica_settings: ReplicaSettings { preemption, prefill_progress, ..self.replica_settings.clone() },
+            ..self.clone()
+        };
+        let replicas = kept.iter().map(|&i| self.replicas[i].clone())
+            .chain(added.into_iter().map(|client| batcher.spawn_replica(client)))
+            .collect();
+        batcher.replicas = Arc::new(replicas);
+        batcher
+    }
+
+    /// Start the queue and batching task of a replica, which run until
+    /// all clones of the returned replica are dropped
+    fn spawn_replica(&self, client: ShardedClient) -> Replica {
+        let settings = &self.replica_settings;
+        let index = self.next_replica_index.fetch_add(1, Ordering::SeqCst);
         // Set up queue
-        let (sender, receiver) = channel(queue_size);
-        let decoder = Arc::new(decoder);
+        let (sender, receiver) = channel(self.queue_size);
+        let (status_sender, queue_status) = watch::channel(QueueStatus::default());
+        let (batch_state_sender, batch_state) = watch::channel(BatchState::default());
+        // Unknown until the replica's shards first generate or are health checked
+        let generation_health = Arc::new(AtomicBool::new(false));
 
         // Spawn batching background task that contains all the inference logic
-        tokio::spawn(std::panic::AssertUnwindSafe(batching_task(
+        tokio::spawn(supervise_batching(
+            index,
             client,
-            max_waiting_tokens,
-            Queue::new(config, batch_type, receiver),
-            decoder.clone(),
-            generation_health,
-        )).catch_unwind().map_err(|panic| {
-            error!("Batching task panicked: {panic:?}");
-            std::process::exit(1);
-        }));
+            Queue::new(
+                self.config.clone(), settings.batch_type.clone(), settings.kv_cache, settings.scheduling,
+                settings.lanes, receiver, status_sender,
+            ),
+            settings.batch_type.clone(),
+            self.decoder.clone(),
+            generation_health.clone(),
+            settings.preemption,
+            batch_state_sender,
+            self.config.clone(),
+            settings.prefill_progress,
+            settings.waiting_tokens_policy,
+            settings.pipeline_prefill,
+            settings.retry_failed_batches,
+            settings.pause_slow_streams,
+            self.shutting_down.clone(),
+        ));
+
+        Replica::new(index, sender, queue_status, batch_state, generation_health)
+    }
+
+    /// Stop generation of all running requests, which complete with the ServerShutdown
+    /// stop reason after their next token. Requests still queued are stopped likewise
+    /// once batched.
+    pub(crate) fn stop_generation(&self) {
+        if !self.shutting_down.swap(true, Ordering::SeqCst) {
+            info!("Stopping generation of in-progress requests for shutdown");
+        }
+    }
+
+    /// Current queue length and estimated wait for a newly submitted request
+    pub(crate) fn queue_estimate(&self) -> QueueEstimate {
+        combined_queue_status(&self.replicas).estimate(0)
+    }
+
+    /// Each replica's queue status and running batch, for the admin state endpoint
+    pub(crate) fn replicas(&self) -> &[Replica] {
+        &self.replicas
+    }
 
-        Self { sender, decoder }
+
+    /// Backoff hints for a request rejected after reaching the given limit
+    pub(crate) fn retry_hint(&self, limit: usize) -> RetryHint {
+        let estimate = self.queue_estimate();
+        RetryHint {
+            queue_length: estimate.position - 1,
+            limit: limit.min(u32::MAX as usize) as u32,
+            // Retrying before the current queue is expected to drain is likely to fail again
+            retry_after_millis: estimate.wait.unwrap_or(MIN_RETRY_AFTER)
+                .clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER).as_millis() as u64,
+        }
     }
 
     // Returns input if queue is full
-    fn enqueue_request(&self, entries: Vec<Entry>) -> Result<(), InferError> {
-        self.sender.try_send(entries).map_err(|se| match se {
-            TrySendError::Full(ents) => {
+    fn enqueue_request(&self, mut entries: Vec<Entry>) -> Result<(), InferError> {
+        if let Some(hooks) = &self.hooks {
+            for entry in entries.iter_mut() {
+                let handle = hooks.handle(&entry.request, entry.input_length, entry.stream_tx.is_some());
+                handle.request();
+                entry.hooks = Some(handle);
+            }
+        }
+        let replica = select_replica(&self.replicas);
+        let status = replica.queue_status();
+        if let Some(slo) = self.ttft_slo {
+            if let Err(err) = self.shed_load(replica, &status, slo, entries.len()) {
+                report_rejection(&mut entries, &err);
+                return Err(err)
+            }
+        }
+        if let Some(memory) = &self.queue_memory {
+            if !memory.reserve(&mut entries) {
+                warn!("Rejecting request of {} input(s) due to queued prompt memory limit", entries.len());
+                record_rejection(Rejection::QueueFull, entries.len());
+                let err = RequestQueueFull(self.retry_hint(memory.limit()));
+                report_rejection(&mut entries, &err);
+                return Err(err)
+            }
+        }
+        let queue_size_limit = self.config.borrow().queue_size_limit;
+        if status.queued + entries.len() > queue_size_limit {
+            warn!("Rejecting request of {} input(s) due to full request queue", entries.len());
+            record_rejection(Rejection::QueueFull, entries.len());
+            let err = RequestQueueFull(self.retry_hint(queue_size_limit));
+            report_rejection(&mut entries, &err);
+            return Err(err)
+        }
+        for (offset, entry) in entries.iter_mut().enumerate() {
+            entry.queue_estimate = Some(status.estimate(offset));
+        }
+        replica.assign(&mut entries);
+        replica.sender.try_send(entries).map_err(|se| match se {
+            TrySendError::Full(mut ents) => {
                 warn!(
                     "Unexpected: Rejecting request of {} input(s) due to full request queue",
                     ents.len()
                 );
-                RequestQueueFull()
+                record_rejection(Rejection::QueueFull, ents.len());
+                let err = RequestQueueFull(self.retry_hint(self.queue_size));
+                report_rejection(&mut ents, &err);
+                err
             },
             TrySendError::Closed(_) => panic!("Queue closed"),
         })
     }
 
+    /// Reject requests which would wait behind a queue for so long that their time to first
+    /// token is projected to exceed the objective, rather than queueing them to time out later.
+    /// The projection is the estimated queue wait plus the replica's recent time from batching
+    /// to first token. Requests aren't shed if there's no queue to wait behind.
+    fn shed_load(
+        &self, replica: &Replica, status: &QueueStatus, slo: Duration, count: usize,
+    ) -> Result<(), InferError> {
+        let Some(wait) = status.estimate(0).wait.filter(|_| status.queued > 0) else {
+            return Ok(())
+        };
+        let projected = wait + replica.batch_state().first_token_latency.unwrap_or_default();
+        metrics::histogram!("tgi_projected_ttft_duration", projected.as_secs_f64());
+        if projected <= slo {
+            return Ok(())
+        }
+        record_rejection(Rejection::FirstTokenObjective, count);
+        Err(FirstTokenObjective(RetryHint {
+            queue_length: status.queued as u32,
+            limit: slo.as_millis() as u32,
+            // The queue needs to drain by the excess before the objective can be met
+            retry_after_millis: (projected - slo)
+                .clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER).as_millis() as u64,
+        }))
+    }
+
     /// Add a new request to the queue and return a future that will generate the text
     pub(crate) async fn infer(
         &self,
         input_length: usize,
         request: GenerateRequest,
+    ) -> Result<InferResponse, InferError> {
+        let cache_key = self.response_cache.as_ref().and_then(|_| ResponseCache::key(&request));
+        if let Some(key) = &cache_key {
+            if let Some(response) = self.response_cache.as_ref().unwrap().get(key).await {
+                return Ok(response)
+            }
+        }
+        let result = self.infer_coalesced(input_length, request).await;
+        if let (Some(key), Ok(response)) = (cache_key, &result) {
+            self.response_cache.as_ref().unwrap().put(key, response);
+        }
+        result
+    }
+
+    async fn infer_coalesced(
+        &self,
+        input_length: usize,
+        request: GenerateRequest,
+    ) -> Result<InferResponse, InferError> {
+        let Some(in_flight) = self.in_flight.as_ref().filter(|_| is_coalescable(&request)) else {
+            return self.infer_single(input_length, request).await
+        };
+        // Identical deterministic requests share the result of the first
+        let key = request_key(&request);
+        let shared = {
+            let mut in_flight = in_flight.lock();
+            match in_flight.get(&key) {
+                Some(existing) => {
+                    metrics::increment_counter!("tgi_request_coalesced");
+                    existing.clone()
+                },
+                None => {
+                    let batcher = self.clone();
+                    let shared = async move {
+                        batcher.infer_single(input_length, request).await
+                    }.boxed().shared();
+                    in_flight.insert(key.clone(), shared.clone());
+                    shared
+                },
+            }
+        };
+        let result = shared.clone().await;
+        let mut in_flight = in_flight.lock();
+        if matches!(in_flight.get(&key), Some(s) if s.ptr_eq(&shared)) {
+            in_flight.remove(&key);
+        }
+        result
+    }
+
+    async fn infer_single(
+        &self,
+        input_length: usize,
+        request: GenerateRequest,
     ) -> Result<InferResponse, InferError> {
         // One shot channel to communicate with the background batching task
         let (response_tx, response_rx) = oneshot::channel();
@@ -104,36 +406,66 @@ impl Batcher {
         // Await on the response from the background task
         // We can safely unwrap as the background task will never drop the sender
         match response_rx.await.unwrap() {
-            Ok(ir) => ir.ensure_decoded(&self.decoder),
+            Ok(ir) => self.decode_response(ir).await,
             Err(err) => Err(GenerationError(err.to_string())),
         }
     }
 
+    /// Decode a unary response on the blocking pool, so that decoding large
+    /// responses doesn't hold up the async runtime's threads
+    async fn decode_response(&self, response: InferResponse) -> Result<InferResponse, InferError> {
+        if response.is_fully_decoded() {
+            return Ok(response)
+        }
+        // Semaphore is never closed
+        let _permit = self.decode_permits.acquire().await.unwrap();
+        let decoder = self.decoder.clone();
+        tokio::task::spawn_blocking(move || response.ensure_decoded(&decoder)).await
+            .unwrap_or_else(|err| Err(DetokenizationError(err.to_string())))
+    }
+
     // Add a batch of new requests to the queue and return an vec of futures that will generate the text
     pub(crate) async fn infer_batch(
         &self,
         requests: Vec<(usize, GenerateRequest)>,
-    ) -> Result<Vec<Map<Receiver<Result<InferResponse, ClientError>>,
-        impl FnOnce(Result<Result<InferResponse, ClientError>, RecvError>) -> Result<InferResponse, InferError> + '_>>, InferError> {
+    ) -> Result<Vec<BoxFuture<'_, Result<InferResponse, InferError>>>, InferError> {
 
         let mut response_chans= vec![];
 
-        let entries: Vec<Entry> = requests.into_iter()
-            .map(|(input_length, request)| {
-                // One shot channel to communicate with the background batching task
-                let (response_tx, response_rx) = oneshot::channel();
-                response_chans.push(response_rx
-                    .map(move |r: Result<Result<InferResponse, ClientError>, RecvError>| match r.unwrap() {
-                        Ok(ir) => ir.ensure_decoded(&self.decoder),
-                        Err(err) => Err(GenerationError(err.to_string())),
-                    })
-                );
-
-                Entry::new(request, input_length, Some(response_tx), None)
-            }).collect();
+        let mut entries: Vec<Entry> = Vec::with_capacity(requests.len());
+        for (input_length, request) in requests {
+            // One shot channel to communicate with the background batching task
+            let (response_tx, response_rx) = oneshot::channel();
+            let mut cache_key = self.response_cache.as_ref()
+                .and_then(|_| ResponseCache::key(&request));
+            let cached = match &cache_key {
+                Some(key) => self.response_cache.as_ref().unwrap().get(key).await,
+                None => None,
+            };
+            if let Some(response) = cached {
+                // Cached responses don't need to be queued
+                response_tx.send(Ok(response)).unwrap_or_default();
+                cache_key = None;
+            } else {
+                entries.push(Entry::new(request, input_length, Some(response_tx), None));
+            }
+            response_chans.push(async move {
+                match response_rx.await.unwrap() {
+                    Ok(ir) => self.decode_response(ir).await.map(|ir| {
+                        if let Some(key) = cache_key {
+                            self.response_cache.as_ref().unwrap().put(key, &ir);
+                        }
+                        ir
+                    }),
+                    Err(err) => Err(GenerationError(err.to_string())),
+                }
+            }.boxed());
+        }
 
         // Try to add the request to the queue
-        self.enqueue_request(entries)?;
+        if !entries.is_empty() {
+            self.enqueue_request(entries)?;
+        }
 
         Ok(response_chans)
     }
@@ -144,24 +476,32 @@ impl Batcher {
         input_length: usize,
         request: GenerateRequest,
         result_map: fn (Result<InferResponse, InferError>) -> T,
-        on_drop: fn (&C, u32, StopReason, Option<u64>, Option<Times>, String, Option<InferError>),
+        on_drop: OnDrop<C>,
         on_drop_context: C,
     ) -> Result<ResponseStream<T, C>, InferError> {
         // Channel to communicate with the background batching task
-        let (response_tx, response_rx) = unbounded_channel();
+        let (mut response_tx, response_rx) = stream_channel(
+            self.stream_config, request.parameters.stream_chunk_tokens,
+        );
 
-        // Send first response with input token count (and text if requested), and random seed used
-        response_tx.send(Ok(InferResponse{
+        // Send first response with input token count (and text if requested), random seed used,
+        // and queue position
+        response_tx.send(InferResponse{
+            queue_estimate: Some(self.queue_estimate()),
             in_token_count: input_length as u32,
             output_text: request.parameters.include_input_text
-                .then(|| request.inputs.clone())
+                .then(|| original_input(&request) + &self.decoder.input_separator)
                 .unwrap_or_default(),
             seed: request.parameters.seed.unwrap_or_default(),
+            warnings: parameter_warnings(&request.parameters),
+            normalized_params: normalized_params(&request.parameters, input_length),
             ..Default::default()
-        })).unwrap_or_default();
+        }).unwrap_or_default();
 
-        let has_stop_seq = !request.parameters.stop_seqs.is_empty();
+        let has_stop_seq = request.parameters.has_stop_text();
         let include_token_info = request.parameters.include_gen_tokens;
+        let decode_options = DecodeOptions::for_params(&request.parameters);
+        let healed_prefix = request.healed_prefix.clone().map(HealedPrefix::new);
 
         // Try to add the request to the queue
         self.enqueue_request(vec![
@@ -176,13 +516,14 @@ impl Batcher {
             on_drop,
             on_drop_context: Arc::new(on_drop_context),
             token_count: 0,
+            healed_prefix,
             output: if has_stop_seq {
                 // If stop sequences are requested, incremental decoding is already done in
                 // the batching loop
                 Accumulator::String(String::new())
             } else {
                 Accumulator::Decoder(IncrementalDecoderWrapper::for_decoder(
-                    &self.decoder, self.decoder.seq2seq,
+                    &self.decoder, self.decoder.seq2seq, decode_options,
                 ))
             },
             times: None,
@@ -213,16 +554,22 @@ impl Default for Accumulator {
     }
 }
 
+/// Callback invoked when a response stream is dropped, with the final token count,
+/// stop reason, request id, timings, output text and error if any
+type OnDrop<C> = fn (&C, u32, StopReason, Option<u64>, Option<Times>, String, Option<InferError>);
+
 /// State associated with the ongoing response stream
 pub struct ResponseStream<T, C> {
-    inner: UnboundedReceiver<Result<InferResponse, ClientError>>,
+    inner: mpsc::Receiver<Result<InferResponse, ClientError>>,
     map_func: fn (Result<InferResponse, InferError>) -> T,
     // This is only an option to avoid Arc clones when used in poll_next
     decoder: Option<Arc<Decoder>>,
     include_token_info: bool,
-    on_drop: fn (&C, u32, StopReason, Option<u64>, Option<Times>, String, Option<InferError>),
+    on_drop: OnDrop<C>,
     on_drop_context: Arc<C>,
     token_count: u32,
+    // Healed prompt text to remove from the start of the decoded output
+    healed_prefix: Option<HealedPrefix>,
     output: Accumulator,
     times: Option<Times>,
     request_id: Option<u64>,
@@ -267,41 +614,54 @@ impl<T, C> Stream for ResponseStream<T, C> {
                                 if let Some(rid) = ir.request_id {
                                     self.request_id = Some(rid);
                                 }
-                                let token = match &ir.tokens {
-                                    WithIds(toks) if !toks.is_empty() => Some(&toks[0]),
-                                    _ => None
+                                let tokens = match &ir.tokens {
+                                    WithIds(toks) => &toks[..],
+                                    _ => &[],
                                 };
                                 // Detatch and reattach the decoder to appease borrow checker
                                 // while avoiding having to clone Arcs
                                 let decoder = take(&mut self.decoder);
                                 match &mut self.output {
                                     Accumulator::String(str) => {
-                                        str.push_str(&*ir.output_text);
+                                        str.push_str(&ir.output_text);
                                     },
                                     Accumulator::Decoder(id) => {
-                                        if let Some(tok) = token {
+                                        let mut output_text = String::new();
+                                        // There may be multiple tokens if responses were merged
+                                        for tok in tokens {
                                             match id.next(
                                                 tok.token_id,
                                                 decoder.as_ref().unwrap(),
                                             ) {
-                                                Ok(text) => ir.output_text = text,
-                                                Err(err) => decode_err = Some(err),
+                                                Ok(text) => output_text += &text,
+                                                Err(err) => {
+                                                    decode_err = Some(err);
+                                                    break
+                                                },
                                             }
                                         }
                                         // Add remainder if this is the last one
                                         if decode_err.is_none() && ir.reason != NotFinished {
                                             match id.flush(decoder.as_ref().unwrap()) {
-                                                Ok(text) => ir.output_text += &text,
+                                                Ok(text) => output_text += &text,
                                                 Err(err) => decode_err = Some(err),
                                             }
                                         }
+                                        if let Some(healed_prefix) = &mut self.healed_prefix {
+                                            healed_prefix.strip(&mut output_text);
+                                        }
+                                        if ir.output_text.is_empty() {
+                                            ir.output_text = output_text;
+                                        } else {
+                                            ir.output_text += &output_text;
+                                        }
                                     }
                                 }
                                 self.decoder = decoder;
                                 if !self.include_token_info {
                                     ir.tokens.clear();
                                 }
-                                ir.decode_token_infos(&self.decoder.as_ref().unwrap());
+                                ir.decode_token_infos(self.decoder.as_ref().unwrap());
                                 if ir.tokens.is_empty() && ir.output_text.is_empty()
                                     && ir.reason == NotFinished && ir.gen_token_count != 0 {
                                     // Don't include response if it's empty, unless it's the first
@@ -329,24 +689,117 @@ impl<T, C> Stream for ResponseStream<T, C> {
     }
 }
 
-/// Batching logic
-/// Will be launched in a background Tokio task
-///
-/// Batches requests and sends them to the inference server
-// #[instrument(skip(client, receiver, shared))]
-async fn batching_task<B: BatchType>(
+/// The input text as originally provided, before any token healing
+fn original_input(request: &GenerateRequest) -> String {
+    match &request.healed_prefix {
+        Some(prefix) => format!("{}{prefix}", request.inputs),
+        None => request.inputs.clone(),
+    }
+}
+
+/// Whether a request's output is fully determined by its content
+/// so that it can share the result of an identical in-progress request.
+/// Requests with time limits are excluded since these may differ.
+fn is_coalescable(request: &GenerateRequest) -> bool {
+    request.parameters.temperature == 0.0 && !request.parameters.has_time_limit()
+}
+
+/// Runs the batching loop of a replica in a background Tokio task, restarting it if it
+/// panics. The requests in progress are failed and the shards' cache is cleared, while
+/// those not yet taken from the queue channel are kept for the restarted loop.
+#[allow(clippy::too_many_arguments)]
+async fn supervise_batching(
+    index: usize,
     mut client: ShardedClient,
-    max_waiting_tokens: usize,
-    mut queue: Queue<B>,
+    mut queue: Queue,
+    batch_type: Arc<dyn BatchType>,
     decoder: Arc<Decoder>,
     generation_health: Arc<AtomicBool>,
+    preemption: Option<Preemption>,
+    batch_state: watch::Sender<BatchState>,
+    config: watch::Receiver<BatchingConfig>,
+    prefill_progress: bool,
+    waiting_tokens_policy: WaitingTokensPolicy,
+    pipeline_prefill: bool,
+    retry_failed_batches: bool,
+    pause_slow_streams: bool,
+    shutting_down: Arc<AtomicBool>,
 ) {
+    // Measurements are kept across restarts of the batching loop
+    let mut waiting_tokens = WaitingTokensController::new(waiting_tokens_policy);
     let mut processor = TokenProcessor {
         entries: IntMap::default(),
         decoder: &decoder,
-        generation_health,
+        generation_health: generation_health.clone(),
+        config,
+        prefill_progress,
+        pipeline_prefill,
+        retry_failed_batches,
+        failed: None,
+        recovered: vec![],
+        // Paused requests are offloaded, otherwise they're cancelled
+        pause_slow_streams: pause_slow_streams && client.capabilities().map_or(false, |c| c.offload),
+        slow_streams: vec![],
+        shutting_down,
     };
 
+    loop {
+        let result = AssertUnwindSafe(batching_task(
+            &mut client, &mut queue, &mut processor, batch_type.as_ref(), preemption, &batch_state,
+            &mut waiting_tokens,
+        )).catch_unwind().await;
+        let panic = match result {
+            Ok(()) => break,
+            Err(panic) => panic,
+        };
+        error!("Batching loop of replica {index} panicked, restarting: {}", panic_message(&*panic));
+        metrics::increment_counter!("tgi_batching_task_restart_count");
+        generation_health.store(false, Ordering::SeqCst);
+
+        let error = ClientError::Generation("request failed due to an internal error".to_string());
+        processor.failed = None;
+        processor.recovered.clear();
+        processor.slow_streams.clear();
+        for (_, mut entry) in processor.entries().drain() {
+            // The panic may have occurred after the final response was sent
+            if entry.response_tx.is_some() || entry.stream_tx.is_some() {
+                entry.send_final(Err(error.clone())).unwrap_or_default();
+            }
+        }
+        queue = queue.restart(&error);
+        metrics::gauge!("tgi_batch_current_size", 0.0);
+        metrics::gauge!("tgi_batch_input_tokens", 0.0);
+        metrics::gauge!("tgi_batch_max_remaining_tokens", 0.0);
+        batch_state.send_modify(BatchState::clear);
+
+        // Discard the failed batch and any others cached by the shards
+        if let Err(err) = client.clear_cache().await {
+            error!("Failed to clear the cache of replica {index} after batching loop panic: {err}");
+        }
+    }
+
+    info!("Batching loop exiting");
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic.downcast_ref::<&str>().copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+/// Batching logic
+///
+/// Batches requests and sends them to the inference server, returning once the queue is closed
+// #[instrument(skip(client, receiver, shared))]
+async fn batching_task(
+    client: &mut ShardedClient,
+    queue: &mut Queue,
+    processor: &mut TokenProcessor<'_>,
+    batch_type: &dyn BatchType,
+    preemption: Option<Preemption>,
+    batch_state: &watch::Sender<BatchState>,
+    controller: &mut WaitingTokensController,
+) {
     // Get the next batch from the queue
     while let Some(batch) = queue.next_batch(processor.entries()).await {
         if enabled!(Level::DEBUG) {
@@ -355,71 +808,128 @@ async fn batching_task<B: BatchType>(
         }
         log_new_batch(batch.id, processor.entries());
 
+        let prefill_start = Instant::now();
         let mut cached_batch = processor.prefill(
-            &mut client, batch, vec![], None, &mut queue,
+            client, batch, None, queue,
         ).await;
+        controller.record_prefill(prefill_start.elapsed());
         let mut waiting_tokens = 1;
         let mut batch_max_remaining_tokens = None;
+        // Batch prefilled during the last generation step, to be added to the running batch
+        let mut pipelined_batch = None;
 
         // We loop until we do not receive any cached batch from the inference server (== until
         // all requests have met their stopping criteria)
         while let Some(batch) = cached_batch {
-            let batch_size = processor.entries().len();
-            let batch_id = batch.batch_id;
             let mut batches = vec![batch];
+            batches.extend(pipelined_batch.take());
+            batches.append(&mut processor.recovered);
+
+            // Pause requests whose stream consumers fell behind in the last step, unless
+            // a pipelined or recovered batch has yet to be added to the running batch
+            if !processor.slow_streams.is_empty() && batches.len() == 1 {
+                processor.pause_slow_streams(client, &mut batches, queue).await;
+                if batches.is_empty() {
+                    // All requests were paused, fetch a new batch
+                    break
+                }
+            }
+            let batch_size = processor.entries().len();
+            let batch_id = batches[0].batch_id;
 
             // Recompute or decrement batch_remaining_tokens as appropriate
             batch_max_remaining_tokens = Some(batch_max_remaining_tokens.map_or_else(
                 || processor.max_remaining_tokens(), |t| t - 1
             ));
 
-            let batch_tokens = <B>::count_tokens(
-                processor.entries().iter().map(
-                    |(_, e)| e.input_length + e.generated_tokens as usize
-                ),
+            let entries = processor.entries();
+            let batch_tokens = batch_type.count_tokens(
+                &mut entries.values().map(|e| e.input_length + e.generated_tokens as usize),
                 batch_size,
             );
 
             metrics::gauge!("tgi_batch_current_size", batch_size as f64);
             metrics::gauge!("tgi_batch_input_tokens", batch_tokens as f64);
             metrics::gauge!("tgi_batch_max_remaining_tokens", batch_max_remaining_tokens.unwrap() as f64);
+            batch_state.send_modify(|state| state.update(batch_id, entries));
 
-            // Don't interfere with current batch if it's about to complete
-            if batch_max_remaining_tokens.unwrap() >= 2 {
+            // Don't interfere with current batch if it's about to complete, or if a
+            // pipelined batch has yet to be added to it
+            if batch_max_remaining_tokens.unwrap() >= 2 && batches.len() == 1 {
                 // Determine min num of requests for add-on batch based on current batch size and
                 // tokens since last prefill
+                let max_waiting_tokens = controller.max_waiting_tokens(
+                    queue.max_waiting_tokens(), queue.len(), batch_size,
+                );
                 let min_size = if batch_size <= 1 || waiting_tokens >= max_waiting_tokens {
                     1
                 } else {
                     max(1, (batch_size * (max_waiting_tokens - waiting_tokens)) / max_waiting_tokens)
                 };
 
-                // Try to get a new batch
-                if let Some(new_batch) = queue.try_next_batch(processor.entries(), min_size) {
+                // Try to get a new batch, if none of the waiting requests fit then
+                // make room for urgent ones by preempting running requests
+                let mut new_batch = queue.try_next_batch(processor.entries(), min_size);
+                if let (None, 1, Some(preemption)) = (&new_batch, min_size, &preemption) {
+                    let chosen = preemption.select(&queue.waiting_priorities(), processor.entries());
+                    if !chosen.is_empty() {
+                        processor.preempt(
+                            client, &mut batches, chosen, preemption.policy, queue,
+                        ).await;
+                        if batches.is_empty() {
+                            // All batches completed or failed, fetch a new one
+                            break
+                        }
+                        new_batch = queue.try_next_batch(processor.entries(), min_size);
+                    }
+                }
+                if let Some(new_batch) = new_batch {
                     info!(
                         "DEBUG: Pulled batch of {} extra request(s) from queue: {:?}",
                         new_batch.requests.len(),
                         new_batch.requests.iter().map(|r| r.id).collect::<Vec<u64>>()
                     );
 
-                    // Determine whether existing batch needs pruning
-                    let to_prune = match &batches[0].status {
-                        Some(rs) if rs.completed_ids.is_empty() => vec![],
-                        _ => batches.clone(),
-                    };
-
-                    // Generate one token for this new batch to have the attention past in cache
                     let first_new_id = new_batch.requests.first()
                         .expect("Batch can't be empty here").id;
+
+                    // Prune completed requests from the existing batch first, to
+                    // maximize the memory available for the new one
+                    if !matches!(&batches[0].status, Some(rs) if rs.completed_ids.is_empty()) {
+                        let batch = batches.remove(0);
+                        batches.extend(processor.update_batch(client, batch, first_new_id).await);
+                    }
+
+                    if processor.pipeline_prefill && !batches.is_empty() {
+                        // Prefill the new batch while the running batch generates its next
+                        // token, it's then added to the running batch for the following step
+                        let (next_cached_batch, new_cached_batch) = processor.prefill_with_next_token(
+                            client, new_batch, first_new_id, batches, queue, controller,
+                        ).await;
+                        waiting_tokens = 1;
+                        batch_max_remaining_tokens = None;
+                        match (next_cached_batch, new_cached_batch) {
+                            (Some(next), new) => {
+                                cached_batch = Some(next);
+                                pipelined_batch = new;
+                            },
+                            (None, new) => cached_batch = new,
+                        }
+                        let added_batch_size = processor.entries().keys()
+                            .filter(|id| **id >= first_new_id).count();
+                        if let (Some(batch), true) = (&cached_batch, added_batch_size > 0) {
+                            info!("Extending batch #{batch_id} with pipelined batch of {added_batch_size}");
+                            log_new_batch(batch.batch_id, processor.entries());
+                        }
+                        continue
+                    }
+
+                    // Generate one token for this new batch to have the attention past in cache
+                    let prefill_start = Instant::now();
                     let new_cached_batch = processor.prefill(
-                        &mut client, new_batch, to_prune, Some(first_new_id), &mut queue
+                        client, new_batch, Some(first_new_id), queue
                     ).await;
-
-                    // Hack for now - update existing batch based on pruning that would have been done
-                    match batches[0].status.as_mut() {
-                        Some(rs) => rs.completed_ids.clear(),
-                        None => batches.clear(),
-                    };
+                    controller.record_prefill(prefill_start.elapsed());
 
                     // Reset waiting counter and batch_remaining_tokens
                     waiting_tokens = 1;
@@ -454,7 +964,9 @@ async fn batching_task<B: BatchType>(
                 }
             }
 
-            cached_batch = processor.next_token(&mut client, batches, &mut queue).await;
+            let step_start = Instant::now();
+            cached_batch = processor.next_token(client, batches, queue).await;
+            controller.record_step(step_start.elapsed());
             waiting_tokens += 1;
             // Reset batch_remaining_tokens if any requests in the batch completed
             if batch_max_remaining_tokens.is_some() && some_completed(&cached_batch) {
@@ -465,9 +977,8 @@ async fn batching_task<B: BatchType>(
         metrics::gauge!("tgi_batch_current_size", 0.0);
         metrics::gauge!("tgi_batch_input_tokens", 0.0);
         metrics::gauge!("tgi_batch_max_remaining_tokens", 0.0);
+        batch_state.send_modify(BatchState::clear);
     }
-
-    info!("Batching loop exiting");
 }
 
 
@@ -484,6 +995,27 @@ fn log_new_batch(id: u64, entries: &IntMap<u64, Entry>) {
     }
 }
 
+/// Label for the range of batch sizes which the given size falls in
+fn batch_size_bucket(batch_size: usize) -> &'static str {
+    match batch_size {
+        0..=1 => "1",
+        2..=4 => "2-4",
+        5..=8 => "5-8",
+        9..=16 => "9-16",
+        17..=32 => "17-32",
+        33..=64 => "33-64",
+        65..=128 => "65-128",
+        _ => "129+",
+    }
+}
+
+fn record_inference(method: &'static str, batch_size: usize) {
+    metrics::increment_counter!("tgi_batch_inference_count", "method" => method);
+    metrics::histogram!(
+        "tgi_batch_inference_batch_size", batch_size as f64, "method" => method,
+    );
+}
+
 fn some_completed(batch: &Option<CachedBatch>) -> bool {
     batch.as_ref().map_or(
         true,|b| b.status.as_ref().map_or(
@@ -492,10 +1024,52 @@ fn some_completed(batch: &Option<CachedBatch>) -> bool {
     )
 }
 
+/// Offload the cache of the given requests in all shards, removing them from the running batch
+async fn offload_requests(
+    client: &mut ShardedClient, batches: &mut Vec<CachedBatch>, ids: &[u64],
+) -> Result<(), ClientError> {
+    match client.offload_requests(batches[0].clone(), ids.to_vec()).await? {
+        Some(batch_id) => batches[0] = CachedBatch {
+            batch_id, status: Some(RequestsStatus { completed_ids: vec![] }),
+        },
+        None => batches.clear(),
+    }
+    Ok(())
+}
+
+/// Prefill progress updates and the ids of the streaming requests to forward them to
+type PrefillProgressUpdates = (mpsc::UnboundedReceiver<PrefillProgress>, Vec<u64>);
+
+/// Time allowed beyond the latest deadline of a batch's requests for a generation step to complete
+const STEP_DEADLINE_GRACE: Duration = Duration::from_secs(5);
+
+/// Bytes of previously generated text, before that of the latest token,
+/// within which stop regex matches are searched for
+const STOP_REGEX_WINDOW: usize = 512;
+
 struct TokenProcessor<'a> {
     entries: IntMap<u64, Entry>,
     decoder: &'a Decoder,
     generation_health: Arc<AtomicBool>,
+    /// Batching config, for the max time to wait for the shards to complete a generation step
+    config: watch::Receiver<BatchingConfig>,
+    /// Whether the shards report prefill progress, which is forwarded to streaming requests
+    prefill_progress: bool,
+    /// Whether batches added to the running batch are prefilled concurrently with its
+    /// next generation step, rather than before it
+    pipeline_prefill: bool,
+    /// Whether the requests of a failed batch are regenerated rather than failed
+    retry_failed_batches: bool,
+    /// Error of the last failed step and the ids of its requests kept to be retried
+    failed: Option<(ClientError, Vec<u64>)>,
+    /// Batches of retried requests, to be added to the running batch on its next step
+    recovered: Vec<CachedBatch>,
+    /// Whether streaming requests whose consumers fall behind are paused, rather than cancelled
+    pause_slow_streams: bool,
+    /// Ids of the requests to pause before the next step
+    slow_streams: Vec<u64>,
+    /// Set once the server is shutting down, after which requests are stopped
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl<'a> TokenProcessor<'a> {
@@ -506,75 +1080,309 @@ impl<'a> TokenProcessor<'a> {
 
     /// Max number of tokens to generate before the current batch will complete
     fn max_remaining_tokens(&self) -> u32 {
-        self.entries.iter().map(
-            |(_, e)| e.request.parameters.max_new_tokens - e.generated_tokens
+        self.entries.values().map(
+            |e| e.request.parameters.max_new_tokens - e.generated_tokens
         ).sum()
     }
 
-    async fn prefill<B: BatchType>(
+    /// Deadline for the shards to complete a generation step of the entries with ids from
+    /// `start_id`. Once all of their deadlines have passed the result is no longer needed,
+    /// a grace period allows those reaching their deadline mid-step to complete normally.
+    fn step_deadline(&self, start_id: Option<u64>) -> Option<Instant> {
+        let start_id = start_id.unwrap_or(0);
+        let batch_deadline = self.entries.iter()
+            .filter(|(id, _)| **id >= start_id)
+            .map(|(_, e)| e.deadline())
+            .try_fold(None, |latest: Option<Instant>, d| d.map(|d| latest.max(Some(d))))
+            .flatten()
+            .map(|d| d + STEP_DEADLINE_GRACE);
+        let timeout_deadline = self.config.borrow().step_timeout.map(|t| Instant::now() + t);
+        match (batch_deadline, timeout_deadline) {
+            (Some(b), Some(t)) => Some(b.min(t)),
+            (b, t) => b.or(t),
+        }
+    }
+
+    async fn prefill(
         &mut self,
         client: &mut ShardedClient,
         batch: Batch,
-        to_prune: Vec<CachedBatch>,
         // First request id in this batch if it doesn't comprise all current entries
         start_id: Option<u64>,
-        queue: &mut Queue<B>,
+        queue: &mut Queue,
     ) -> Option<CachedBatch> {
         let batch_size = batch.requests.len();
         let batch_tokens = batch.total_tokens;
         let start_time = Instant::now();
-        self._wrap_future(
-            client.prefill(batch, to_prune).map(|r| {
+        let deadline = self.step_deadline(start_id);
+        let (progress_tx, progress) = self.progress_channel(&batch).unzip();
+        let cached_batch = self._wrap_future(
+            client.prefill(batch, deadline, progress_tx).map(|r| {
+                let elapsed = start_time.elapsed();
                 info!(
-                    "Prefill took {:?} for {batch_size} inputs, {batch_tokens} total tokens",
-                    start_time.elapsed(),
+                    "Prefill took {elapsed:?} for {batch_size} inputs, {batch_tokens} total tokens",
                 );
+                if r.is_ok() {
+                    metrics::gauge!(
+                        "tgi_batch_prefill_tokens_per_second", batch_tokens as f64 / elapsed.as_secs_f64(),
+                    );
+                }
                 r
             }),
-            "prefill", start_time, start_id, queue
-        ).await
+            "prefill", start_time, start_id, queue, progress,
+        ).await;
+        self.retry_failed(client, queue, cached_batch).await
     }
 
-    async fn next_token<B: BatchType>(
-        &mut self, client: &mut ShardedClient, batches: Vec<CachedBatch>, queue: &mut Queue<B>,
+    async fn next_token(
+        &mut self, client: &mut ShardedClient, mut batches: Vec<CachedBatch>, queue: &mut Queue,
     ) -> Option<CachedBatch> {
+        batches.append(&mut self.recovered);
         let start_time = Instant::now();
-        self._wrap_future(
-            client.next_token(batches), "next_token", start_time, None, queue
-        ).await
+        let deadline = self.step_deadline(None);
+        let cached_batch = self._wrap_future(
+            client.next_token(batches, deadline), "next_token", start_time, None, queue, None,
+        ).await;
+        self.retry_failed(client, queue, cached_batch).await
+    }
+
+    /// Prefill the requests kept from a failed step again, together at first. If that fails
+    /// too, they're split in halves which are retried separately until the requests causing
+    /// the failure are isolated and failed. Returns the given batch or else one of the
+    /// regenerated batches, any others are added to the running batch on its next step.
+    async fn retry_failed(
+        &mut self, client: &ShardedClient, queue: &mut Queue, batch: Option<CachedBatch>,
+    ) -> Option<CachedBatch> {
+        let Some((error, ids)) = self.failed.take() else {
+            return batch
+        };
+        warn!("Retrying {} request(s) of failed batch: {error}", ids.len());
+        let mut groups = vec![ids];
+        while let Some(ids) = groups.pop() {
+            // Ids are sorted, those of other groups within this range are still to be prefilled
+            let (start_id, end_id) = (ids[0], ids[ids.len() - 1] + 1);
+            let retry_batch = queue.rebatch(&mut self.entries, &ids);
+            let deadline = self.step_deadline(Some(start_id));
+            record_inference("prefill", ids.len());
+            let start_time = Instant::now();
+            let result = self.service_queue(
+                client.prefill(retry_batch, deadline, None), queue, None,
+            ).await;
+            let err = match result {
+                Err(err) => err,
+                result => {
+                    let batch = self.process_result(
+                        result, "prefill", start_time, start_time.elapsed(), ids.len(),
+                        Some(start_id), Some(end_id),
+                    );
+                    self.recovered.extend(batch);
+                    continue
+                },
+            };
+            metrics::increment_counter!("tgi_batch_inference_failure", "method" => "prefill");
+            if ids.len() > 1 && matches!(err, ClientError::Generation(_)) {
+                let (first, second) = ids.split_at(ids.len() / 2);
+                groups.push(second.to_vec());
+                groups.push(first.to_vec());
+                continue
+            }
+            warn!("Failing request(s) {ids:?} which failed when retried: {err}");
+            if ids.len() == 1 {
+                metrics::increment_counter!("tgi_request_isolated_failure");
+            }
+            for id in ids {
+                let mut entry = self.entries.remove(&id).expect("ID not found. This is a bug.");
+                entry.send_final(Err(err.clone())).unwrap_or_default();
+            }
+        }
+        batch.or_else(|| self.recovered.pop())
+    }
+
+    /// Remove the requests listed in the batch's status from the cached batch, or discard it
+    /// if finished. Returns the updated batch, None if no requests remain or if the update
+    /// failed, in which case the batch's state is unknown so all its requests are failed.
+    async fn update_batch(
+        &mut self,
+        client: &mut ShardedClient,
+        batch: CachedBatch,
+        // First request id of a pending batch, whose entries aren't in this batch
+        end_id: u64,
+    ) -> Option<CachedBatch> {
+        let batch_id = batch.batch_id;
+        match client.update_batch(batch).await {
+            Ok(Some(batch_id)) => Some(CachedBatch {
+                batch_id, status: Some(RequestsStatus { completed_ids: vec![] }),
+            }),
+            Ok(None) => None,
+            Err(err) => {
+                error!("Failed to update batch #{batch_id}: {err}");
+                self.generation_health.store(false, Ordering::SeqCst);
+                self.entries.retain(|id, entry| {
+                    if *id >= end_id {
+                        return true
+                    }
+                    entry.send_final(Err(err.clone())).unwrap_or_default();
+                    false
+                });
+                metrics::increment_counter!("tgi_batch_inference_failure", "method" => "update_batch");
+                None
+            },
+        }
+    }
+
+    /// Prefill a new batch concurrently with the next generation step of the running batches,
+    /// whose requests all have ids below `start_id`. Returns the next cached running batch and
+    /// the cached new batch, which can be combined for the following step.
+    async fn prefill_with_next_token(
+        &mut self,
+        client: &mut ShardedClient,
+        batch: Batch,
+        start_id: u64,
+        batches: Vec<CachedBatch>,
+        queue: &mut Queue,
+        controller: &mut WaitingTokensController,
+    ) -> (Option<CachedBatch>, Option<CachedBatch>) {
+        let batch_size = batch.requests.len();
+        let batch_tokens = batch.total_tokens;
+        let running_size = self.entries.len() - batch_size;
+        let prefill_deadline = self.step_deadline(Some(start_id));
+        let step_deadline = self.step_deadline(None);
+        let progress = self.progress_channel(&batch);
+        record_inference("prefill", batch_size);
+        record_inference("next_token", running_size);
+
+        let start_time = Instant::now();
+        let (progress_tx, progress) = progress.unzip();
+        let client = &*client;
+        let (
+            (prefill_result, prefill_duration), (next_token_result, step_duration)
+        ) = self.service_queue(
+            join(
+                client.prefill(batch, prefill_deadline, progress_tx)
+                    .map(|r| (r, start_time.elapsed())),
+                client.next_token(batches, step_deadline)
+                    .map(|r| (r, start_time.elapsed())),
+            ),
+            queue,
+            progress,
+        ).await;
+        info!(
+            "Prefill took {prefill_duration:?} for {batch_size} inputs, {batch_tokens} total tokens, \
+            concurrently with next_token which took {step_duration:?}",
+        );
+        controller.record_prefill(prefill_duration);
+        controller.record_step(step_duration);
+        if prefill_result.is_ok() {
+            metrics::gauge!(
+                "tgi_batch_prefill_tokens_per_second", batch_tokens as f64 / prefill_duration.as_secs_f64(),
+            );
+        }
+
+        let next_batch = self.process_result(
+            next_token_result, "next_token", start_time, step_duration, running_size, None, Some(start_id),
+        );
+        let new_batch = self.process_result(
+            prefill_result, "prefill", start_time, prefill_duration, batch_size, Some(start_id), None,
+        );
+        (self.retry_failed(client, queue, next_batch).await, new_batch)
+    }
+
+    /// Record the prefill duration of the entries with ids in the given range, except
+    /// those resumed after preemption which keep that of their first prefill
+    fn record_prefill_duration(&mut self, duration: Duration, start_id: Option<u64>, end_id: Option<u64>) {
+        let ids = start_id.unwrap_or(0)..end_id.unwrap_or(u64::MAX);
+        for (_, entry) in self.entries.iter_mut().filter(|(id, _)| ids.contains(id)) {
+            if entry.prefill_duration.is_none() {
+                entry.prefill_duration = Some(duration);
+                metrics::histogram!("tgi_request_prefill_duration", duration.as_secs_f64());
+            }
+        }
+    }
+
+    /// Channel for the prefill progress of the batch, if forwarded to any of its requests,
+    /// and the ids of the streaming requests to forward it to
+    fn progress_channel(&self, batch: &Batch) -> Option<(mpsc::UnboundedSender<PrefillProgress>, PrefillProgressUpdates)> {
+        // Only streaming requests are sent progress updates
+        let stream_ids: Vec<u64> = batch.requests.iter().map(|r| r.id)
+            .filter(|id| self.entries.get(id).map_or(false, |e| e.stream_tx.is_some()))
+            .collect();
+        (self.prefill_progress && !stream_ids.is_empty()).then(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (tx, (rx, stream_ids))
+        })
     }
 
     /// Wrap a future inside a match statement to handle errors and send the response to the Batcher
-    async fn _wrap_future<B: BatchType>(
+    async fn _wrap_future(
         &mut self,
-        future: impl Future<Output = Result<
-            Option<(Vec<Token>, Vec<InputTokens>, Vec<GenerateError>, u64)>, ClientError
-        >>,
+        future: impl Future<Output = Result<Option<GenerateTokenResponse>, ClientError>>,
         method: &'static str,
         start_time: Instant,
         // First request id in this batch if it doesn't comprise all current entries
         start_id: Option<u64>,
-        queue: &mut Queue<B>,
+        queue: &mut Queue,
+        // Prefill progress updates and the ids of the streaming requests to forward them to
+        progress: Option<PrefillProgressUpdates>,
     ) -> Option<CachedBatch> {
-        metrics::increment_counter!("tgi_batch_inference_count", "method" => method);
-        metrics::histogram!(
-            "tgi_batch_inference_batch_size", self.entries.len() as f64, "method" => method,
-        );
+        let batch_size = self.entries.len();
+        record_inference(method, batch_size);
+        let result = self.service_queue(future, queue, progress).await;
+        self.process_result(result, method, start_time, start_time.elapsed(), batch_size, start_id, None)
+    }
 
+    /// Await the future, meanwhile servicing the queue and forwarding any prefill progress
+    async fn service_queue<T>(
+        &mut self,
+        future: impl Future<Output = T>,
+        queue: &mut Queue,
+        mut progress: Option<PrefillProgressUpdates>,
+    ) -> T {
         // We process the shared queue while waiting for the response from the python shard(s)
         let queue_servicer = queue.service_queue().fuse();
         pin_mut!(future, queue_servicer);
-        let result = loop {
+        loop {
             select! {
                 result = &mut future => break result,
                 _ = &mut queue_servicer => (),
+                Some(update) = async { progress.as_mut().unwrap().0.recv().await }, if progress.is_some() => {
+                    self.send_prefill_progress(update, &progress.as_ref().unwrap().1);
+                },
             }
-        };
+        }
+    }
 
+    /// Process the generated tokens of a batch and send responses, or fail its requests
+    #[allow(clippy::too_many_arguments)]
+    fn process_result(
+        &mut self,
+        result: Result<Option<GenerateTokenResponse>, ClientError>,
+        method: &'static str,
+        start_time: Instant,
+        forward_duration: Duration,
+        batch_size: usize,
+        // First request id in this batch if it doesn't comprise all current entries
+        start_id: Option<u64>,
+        // First request id after this batch, if any entries aren't in it
+        end_id: Option<u64>,
+    ) -> Option<CachedBatch> {
         match result {
             Ok(
                 Some((generated_tokens, input_tokens, errors, next_batch_id))
             ) => {
+                if method == "prefill" {
+                    self.record_prefill_duration(forward_duration, start_id, end_id);
+                }
+                // Forward pass latency, to correlate with batch growth
+                let forward_duration = forward_duration.as_secs_f64();
+                metrics::histogram!(
+                    "tgi_batch_forward_duration", forward_duration,
+                    "method" => method, "batch_size" => batch_size_bucket(batch_size),
+                );
+                metrics::gauge!(
+                    "tgi_batch_generated_tokens_per_second",
+                    generated_tokens.len() as f64 / forward_duration,
+                    "method" => method,
+                );
                 self.process_input_tokens(input_tokens);
                 let completed_request_ids = self.process_next_tokens(
                     generated_tokens, errors,
@@ -601,53 +1409,334 @@ impl<'a> TokenProcessor<'a> {
             Err(err) => {
                 // Update health
                 self.generation_health.store(false, Ordering::SeqCst);
-                self.send_errors(err, start_id);
+                self.send_errors(err, start_id, end_id);
                 metrics::increment_counter!("tgi_batch_inference_failure", "method" => method);
                 None
             },
         }
     }
 
-    /// Send errors to the Batcher for all `request_ids`
-    fn send_errors(&mut self, error: ClientError, start_id: Option<u64>) {
+    /// Remove the chosen entries from the batch and return them to the queue
+    async fn preempt(
+        &mut self,
+        client: &mut ShardedClient,
+        batches: &mut Vec<CachedBatch>,
+        ids: Vec<u64>,
+        policy: PreemptionPolicy,
+        queue: &mut Queue,
+    ) {
+        match policy {
+            PreemptionPolicy::Offload => {
+                if let Err(err) = offload_requests(client, batches, &ids).await {
+                    warn!("Failed to offload requests {ids:?}, not preempting: {err}");
+                    return
+                }
+            },
+            PreemptionPolicy::Requeue => {
+                let Some(status) = batches[0].status.as_ref() else {
+                    return
+                };
+                let mut batch = batches[0].clone();
+                batch.status = Some(RequestsStatus {
+                    completed_ids: status.completed_ids.iter().chain(&ids).copied().collect(),
+                });
+                match client.update_batch(batch).await {
+                    Ok(Some(batch_id)) => batches[0] = CachedBatch {
+                        batch_id, status: Some(RequestsStatus { completed_ids: vec![] }),
+                    },
+                    Ok(None) => batches.clear(),
+                    Err(err) => {
+                        warn!("Failed to remove requests {ids:?} from batch, not preempting: {err}");
+                        return
+                    },
+                }
+            },
+        }
+        let preempted = ids.into_iter().map(|id| {
+            let mut entry = self.entries.remove(&id).expect("ID not found. This is a bug.");
+            info!("Preempted request id {id} after generating {} token(s)", entry.generated_tokens);
+            entry.debug_event(|| format!("Preempted with policy {policy:?}"));
+            entry.preempted = true;
+            match policy {
+                PreemptionPolicy::Offload => entry.offloaded_id = Some(id),
+                PreemptionPolicy::Requeue => entry.restart(),
+            }
+            entry
+        }).collect::<Vec<_>>();
+        metrics::counter!(
+            "tgi_request_preempted", preempted.len() as u64, "policy" => format!("{policy:?}"),
+        );
+        queue.requeue(preempted);
+    }
+
+    /// Offload the requests whose stream consumers fell behind and hold them in the queue
+    /// until they catch up, so that they don't occupy the batch meanwhile
+    async fn pause_slow_streams(
+        &mut self, client: &mut ShardedClient, batches: &mut Vec<CachedBatch>, queue: &mut Queue,
+    ) {
+        let mut ids = take(&mut self.slow_streams);
+        // Some may have completed since
+        ids.retain(|id| self.entries.contains_key(id));
+        if ids.is_empty() {
+            return
+        }
+        if let Err(err) = offload_requests(client, batches, &ids).await {
+            // They're cancelled if their consumers fall behind again
+            warn!("Failed to offload requests {ids:?}, not pausing: {err}");
+            for id in ids {
+                self.entries.get_mut(&id).expect("ID not found. This is a bug.").paused_at = Some(Instant::now());
+            }
+            return
+        }
+        let paused = ids.into_iter().map(|id| {
+            let mut entry = self.entries.remove(&id).expect("ID not found. This is a bug.");
+            info!("Paused streaming request id {id} with slow consumer after generating {} token(s)",
+                entry.generated_tokens);
+            entry.paused_at = Some(Instant::now());
+            entry.offloaded_id = Some(id);
+            entry
+        }).collect::<Vec<_>>();
+        metrics::counter!("tgi_stream_paused", paused.len() as u64);
+        queue.pause(paused);
+    }
+
+    /// Send errors to the Batcher for all `request_ids`, except those kept to be retried
+    fn send_errors(&mut self, error: ClientError, start_id: Option<u64>, end_id: Option<u64>) {
+        // Only failures of the model are retried, not those to reach the shards
+        let retry = self.retry_failed_batches && matches!(error, ClientError::Generation(_));
+        let mut retry_ids = vec![];
         self.entries.retain(|id, entry| {
-            if matches![start_id, Some(sid) if *id < sid] {
+            if matches![start_id, Some(sid) if *id < sid] || matches![end_id, Some(eid) if *id >= eid] {
                 // Keep entries that weren't in the failed request batch
                 return true
             }
+            if retry && entry.is_retryable() {
+                entry.retried = true;
+                entry.restart();
+                retry_ids.push(*id);
+                return true
+            }
             // unwrap_or is valid here as we don't care if the receiver is gone.
             entry.send_final(Err(error.clone())).unwrap_or_default();
             false
         });
+        if retry_ids.is_empty() {
+            return
+        }
+        metrics::counter!("tgi_request_retried", retry_ids.len() as u64);
+        // Both steps of a pipelined prefill may have failed
+        let (_, ids) = self.failed.get_or_insert_with(|| (error, vec![]));
+        ids.extend(retry_ids);
+        ids.sort_unstable();
     }
 
     fn check_stopping_criteria(
-        e: &Entry, last_token_id: u32, eos_token_id: u32, last_text: Option<&String>,
+        e: &Entry, last_token_id: u32, last_logprob: f32, eos_token_id: u32, last_text: Option<&String>,
     ) -> StopReason {
         let params = &e.request.parameters;
-        match params.deadline {
-            Some(deadline) if Instant::now() > deadline => TimeLimit,
+        match e.deadline() {
+            Some(deadline) if Instant::now() > deadline => TokenProcessor::time_limit_reason(e, deadline),
             _ if e.generated_tokens < params.min_new_tokens => NotFinished,
             _ if last_token_id == eos_token_id => EosToken,
             _ if e.generated_tokens >= params.max_new_tokens =>
                 if params.max_is_token_limit { TokenLimit } else { MaxTokens }
-            _ if TokenProcessor::matches_stop_sequence(e, last_text) => StopSequence,
+            _ if TokenProcessor::matched_stop_sequence(e, last_text).is_some() => StopSequence,
+            _ if TokenProcessor::matched_stop_regex(e, last_text).is_some() => StopSequence,
+            _ if e.matched_stop_token_ids().is_some() => StopSequence,
+            _ if TokenProcessor::below_logprob_threshold(e, last_logprob) => LogprobThreshold,
             _ => NotFinished,
         }
     }
 
-    fn matches_stop_sequence(e: &Entry, last_text: Option<&String>) -> bool {
-        match last_text {
-            Some(text) => {
-                // We compare byte subslices to avoid utf8 boundary problem
-                let output = e.output.as_ref().unwrap().output().as_bytes();
-                let next_off = (output.len() + 1) - text.len();
-                e.request.parameters.stop_seqs.iter().map(|ss| (ss.as_bytes(), ss.len())).any(
-                    |(ss, len)| output[next_off.checked_sub(len).unwrap_or(0)..]
-                        .windows(len).rev().any(|w| w == ss)
-                )
+    /// Stop reason once the entry's deadline has passed, Preempted if it passed while
+    /// the entry was waiting to resume after being preempted
+    fn time_limit_reason(e: &Entry, deadline: Instant) -> StopReason {
+        match e.resumed_at {
+            Some(resumed_at) if resumed_at > deadline => Preempted,
+            _ => TimeLimit,
+        }
+    }
+
+    fn below_logprob_threshold(e: &Entry, last_logprob: f32) -> bool {
+        let params = &e.request.parameters;
+        matches!(params.min_token_logprob, Some(min) if last_logprob < min)
+            || matches!(params.min_mean_logprob,
+                Some(min) if e.logprob_sum / (e.generated_tokens as f32) < min)
+    }
+
+    /// Index of the stop sequence which the output ends with, if any
+    fn matched_stop_sequence(e: &Entry, last_text: Option<&String>) -> Option<usize> {
+        let text = last_text?;
+        e.request.parameters.stop_seqs.iter().position(
+            |ss| TokenProcessor::stop_sequence_window(e, text, ss.len())
+                .windows(ss.len()).rev().any(|w| w == ss.as_bytes())
+        )
+    }
+
+    /// Index of the stop regex which matches text ending within the most recently
+    /// decoded text, if any, and the matched text
+    fn matched_stop_regex<'e>(e: &'e Entry, last_text: Option<&String>) -> Option<(usize, &'e str)> {
+        let text = last_text?;
+        let (window, new_start) = TokenProcessor::stop_regex_window(e, text);
+        e.request.parameters.compiled_stop_regex.iter().enumerate().find_map(|(index, regex)| {
+            regex.find_iter(window).find(|m| m.end() > new_start).map(|m| (index, m.as_str()))
+        })
+    }
+
+    /// Which criterion of the given stop reason was met, and its value
+    fn stop_details(
+        e: &Entry, stop_reason: StopReason, last_logprob: f32, eos_token_id: u32, last_text: Option<&String>,
+    ) -> Option<StopDetails> {
+        let params = &e.request.parameters;
+        let details = match stop_reason {
+            EosToken => StopDetails { eos_token_id: Some(eos_token_id), ..Default::default() },
+            MaxTokens | TokenLimit => StopDetails {
+                max_new_tokens: Some(params.max_new_tokens), ..Default::default()
+            },
+            TimeLimit | QueueTimeLimit => {
+                // The generation budget applies if it ends first
+                let budget_end = params.max_time.zip(e.batch_time).map(|(max, start)| start + max);
+                match (budget_end, params.deadline) {
+                    (Some(end), deadline) if deadline.map_or(true, |d| end <= d) => StopDetails {
+                        max_time_ms: params.max_time.map(|t| t.as_millis() as u32),
+                        ..Default::default()
+                    },
+                    _ => StopDetails {
+                        time_limit_millis: params.time_limit.map(|t| t.as_millis() as u32),
+                        ..Default::default()
+                    },
+                }
+            },
+            StopSequence => if let Some(index) = TokenProcessor::matched_stop_sequence(e, last_text) {
+                StopDetails {
+                    stop_sequence: Some(params.stop_seqs[index].clone()),
+                    stop_sequence_index: Some(index as u32),
+                    ..Default::default()
+                }
+            } else if let Some((index, matched)) = TokenProcessor::matched_stop_regex(e, last_text) {
+                StopDetails {
+                    stop_regex_match: Some(matched.to_string()),
+                    stop_regex_index: Some(index as u32),
+                    ..Default::default()
+                }
+            } else {
+                let index = e.matched_stop_token_ids()?;
+                StopDetails {
+                    stop_token_ids: params.stop_token_ids[index].clone(),
+                    stop_token_ids_index: Some(index as u32),
+                    ..Default::default()
+                }
+            },
+            LogprobThreshold => match params.min_token_logprob {
+                Some(min) if last_logprob < min => StopDetails {
+                    min_token_logprob: Some(min), ..Default::default()
+                },
+                _ => StopDetails { min_mean_logprob: params.min_mean_logprob, ..Default::default() },
+            },
+            Preempted => StopDetails {
+                reason_detail: Some(
+                    "time limit reached while preempted in favour of more urgent requests".to_string()
+                ),
+                ..Default::default()
+            },
+            ServerShutdown => StopDetails {
+                reason_detail: Some("generation stopped because the server is shutting down".to_string()),
+                ..Default::default()
             },
-            None => false,
+            SlowConsumer => StopDetails {
+                reason_detail: Some("streamed responses weren't consumed fast enough".to_string()),
+                ..Default::default()
+            },
+            _ => return None,
+        };
+        Some(details)
+    }
+
+    /// Tail of the output in which a stop sequence of the given length ending
+    /// within the most recently decoded text would be found
+    fn stop_sequence_window<'e>(e: &'e Entry, last_text: &str, len: usize) -> &'e [u8] {
+        // We compare byte subslices to avoid utf8 boundary problem
+        let output = e.output.as_ref().unwrap().output().as_bytes();
+        let next_off = (output.len() + 1) - last_text.len();
+        &output[next_off.saturating_sub(len)..]
+    }
+
+    /// Tail of the output searched for stop regex matches, up to STOP_REGEX_WINDOW bytes
+    /// before the most recently decoded text, and the offset of the latter within it
+    fn stop_regex_window<'e>(e: &'e Entry, last_text: &str) -> (&'e str, usize) {
+        let output = e.output.as_ref().unwrap().output();
+        let new_start = output.len().saturating_sub(last_text.len());
+        let mut start = new_start.saturating_sub(STOP_REGEX_WINDOW);
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        (&output[start..], new_start - start)
+    }
+
+    /// Evaluate each stopping criterion independently, for the generation trace
+    fn trace_stopping_criteria(
+        e: &Entry, last_token_id: u32, last_logprob: f32, eos_token_id: u32, last_text: Option<&String>,
+    ) -> Vec<StopCriterion> {
+        let params = &e.request.parameters;
+        let mut criteria = vec![];
+        if let Some(deadline) = e.deadline() {
+            let now = Instant::now();
+            criteria.push(stop_criterion("time_limit", now > deadline, format!(
+                "{:?} remaining", deadline.saturating_duration_since(now),
+            )));
+        }
+        criteria.push(stop_criterion("eos_token", last_token_id == eos_token_id, format!(
+            "token id {last_token_id}, eos token id {eos_token_id}",
+        )));
+        criteria.push(stop_criterion("max_new_tokens", e.generated_tokens >= params.max_new_tokens, format!(
+            "{} of {} tokens generated", e.generated_tokens, params.max_new_tokens,
+        )));
+        if let Some(text) = last_text {
+            for ss in &params.stop_seqs {
+                let window = TokenProcessor::stop_sequence_window(e, text, ss.len());
+                let met = window.windows(ss.len()).any(|w| w == ss.as_bytes());
+                criteria.push(stop_criterion("stop_sequence", met, format!(
+                    "{ss:?} searched for in {:?}", String::from_utf8_lossy(window),
+                )));
+            }
+        }
+        if let Some(text) = last_text {
+            let (window, new_start) = TokenProcessor::stop_regex_window(e, text);
+            for (pattern, regex) in params.stop_regex.iter().zip(&params.compiled_stop_regex) {
+                let met = regex.find_iter(window).any(|m| m.end() > new_start);
+                criteria.push(stop_criterion("stop_regex", met, format!(
+                    "{pattern:?} searched for in {window:?}",
+                )));
+            }
+        }
+        for ids in &params.stop_token_ids {
+            let recent = e.recent_token_ids.iter().collect::<Vec<_>>();
+            criteria.push(stop_criterion("stop_token_ids", e.generated_ends_with(ids), format!(
+                "{ids:?} compared with recent token ids {recent:?}",
+            )));
+        }
+        if let Some(min) = params.min_token_logprob {
+            criteria.push(stop_criterion("min_token_logprob", last_logprob < min, format!(
+                "token logprob {last_logprob}, minimum {min}",
+            )));
+        }
+        if let Some(min) = params.min_mean_logprob {
+            let mean = e.logprob_sum / e.generated_tokens as f32;
+            criteria.push(stop_criterion("min_mean_logprob", mean < min, format!(
+                "mean logprob {mean}, minimum {min}",
+            )));
+        }
+        criteria
+    }
+
+    /// Send a prefill progress update to the given streaming requests. Failures to send
+    /// are ignored here, cancelled requests are pruned once their first token is generated
+    fn send_prefill_progress(&mut self, update: PrefillProgress, request_ids: &[u64]) {
+        for request_id in request_ids {
+            if let Some(stream) = self.entries.get_mut(request_id).and_then(|e| e.stream_tx.as_mut()) {
+                let response = InferResponse::stream_prefill_progress(update.clone(), *request_id);
+                stream.send(response).unwrap_or_default();
+            }
         }
     }
 
@@ -660,12 +1749,12 @@ impl<'a> TokenProcessor<'a> {
             // This should be before any generated tokens are processed
             assert_eq!(e.generated_tokens, 0);
 
-            if let Some(stream) = e.stream_tx.as_ref() {
+            if let Some(stream) = e.stream_tx.as_mut() {
                 // In progress stream, send individual token response
                 let response = InferResponse::stream_input_info(
-                    input.tokens, request_id
+                    input.tokens, take(&mut e.request.input_offsets), request_id
                 );
-                stream.send(Ok(response)).unwrap_or_default();
+                stream.send(response).unwrap_or_default();
             } else {
                 e.input_tokens = input.tokens;
             }
@@ -678,21 +1767,50 @@ impl<'a> TokenProcessor<'a> {
         &mut self, outputs: Vec<Token>, errors: Vec<GenerateError>,
     ) -> Option<Vec<u64>> {
         let mut completed_ids = vec![];
-        let request_count = outputs.len();
-        for output in outputs.into_iter() {
+        let mut request_count = outputs.len();
+        for mut output in outputs {
             let request_id = output.request_id;
             let next_token_id = output.token_id;
 
             let e = self.entries.get_mut(&request_id)
                 .expect("ID not found. This is a bug.");
 
-            if e.generated_tokens == 0 && !e.request.parameters.stop_seqs.is_empty() {
+            if e.request.parameters.echo {
+                // Only the input is scored, the token generated along the way is discarded
+                let mut e = self.entries.remove(&request_id).unwrap();
+                let response = InferResponse::unary(
+                    &mut e, request_id, &self.decoder.input_separator, MaxTokens, None,
+                );
+                e.send_final(Ok(response)).unwrap_or_default();
+                info!("DEBUG: Completed req id {request_id} with reason {MaxTokens:?}");
+                completed_ids.push(request_id);
+                continue
+            }
+
+            // Traced requests are decoded incrementally to record the text of each step
+            if e.generated_tokens == 0
+                && (e.request.parameters.has_stop_text() || e.trace.is_some()) {
                 e.output = Some(IncrementalDecoderWrapper::for_decoder(
-                    &self.decoder, self.decoder.seq2seq,
+                    self.decoder, self.decoder.seq2seq, DecodeOptions::for_params(&e.request.parameters),
                 ));
             }
 
+            e.record_first_token();
             e.generated_tokens += 1;
+            e.record_token_id(next_token_id);
+            let last_logprob = output.logprob;
+            let trace_token = e.trace.as_ref().map(|_| {
+                let token = output.clone();
+                strip_trace_details(&mut output, &e.request.parameters);
+                token
+            });
+            if e.request.parameters.tracks_logprob_sum() {
+                e.logprob_sum += last_logprob;
+                if !e.request.parameters.include_logprobs {
+                    // Logprobs were only requested from the shard for the router's own use
+                    output.logprob = 0.0;
+                }
+            }
             let is_stream = e.stream_tx.is_some();
             let token = match is_stream {
                 true => Some(output),
@@ -730,11 +1848,36 @@ impl<'a> TokenProcessor<'a> {
 
             // Evaluate stopping criteria
             let mut stop_reason = TokenProcessor::check_stopping_criteria(
-                e, next_token_id, self.decoder.eos_token_id, text.as_ref()
+                e, next_token_id, last_logprob, self.decoder.eos_token_id, text.as_ref()
             );
+            if stop_reason == NotFinished && self.shutting_down.load(Ordering::Relaxed) {
+                stop_reason = ServerShutdown;
+            }
+            e.debug_event(|| format!(
+                "Generated token {next_token_id} ({} of {}) with logprob {last_logprob}, {stop_reason:?}",
+                e.generated_tokens, e.request.parameters.max_new_tokens,
+            ));
+
+            if let Some(token) = trace_token {
+                let criteria = TokenProcessor::trace_stopping_criteria(
+                    e, next_token_id, last_logprob, self.decoder.eos_token_id, text.as_ref()
+                );
+                let penalties = applied_penalties(&e.request.parameters, e.generated_tokens - 1);
+                let batch_time = e.batch_time;
+                e.trace.as_mut().unwrap().record(
+                    token, text.as_ref(), penalties, criteria, stop_reason, batch_time,
+                );
+            }
 
             if stop_reason != NotFinished {
                 // Stop criteria met, send final response for both streaming and unary cases
+                if matches!(stop_reason, TimeLimit | Preempted) {
+                    record_cancellation(Cancellation::Deadline, false);
+                }
+                // Evaluated before the output is flushed, which the stop sequence is matched against
+                let stop_details = TokenProcessor::stop_details(
+                    e, stop_reason, last_logprob, self.decoder.eos_token_id, text.as_ref(),
+                );
                 let mut e = self.entries.remove(&request_id).unwrap();
                 // Flush the output if we are doing incremental decoding
                 let mut decode_err = None;
@@ -744,31 +1887,68 @@ impl<'a> TokenProcessor<'a> {
                         decode_err = Some(err);
                     }
                 }
+                if let (true, Some(t)) = (is_stream, text.as_mut()) {
+                    // Everything withheld is released with the final response
+                    t.insert_str(0, &take(&mut e.held_text));
+                    if let Some(hp) = e.healed_prefix.as_mut() {
+                        hp.strip(t);
+                    }
+                }
                 let response = match decode_err {
                     Some(err) => Err(ClientError::Generation(err.to_string())),
                     _ if is_stream => Ok(InferResponse::stream_final(
-                        token.unwrap(), text, &e, request_id, stop_reason
+                        token.unwrap(), text, &mut e, request_id, stop_reason, stop_details,
                     )),
                     _ => Ok(InferResponse::unary(
-                        &mut e, request_id, self.decoder.seq2seq, stop_reason
+                        &mut e, request_id, &self.decoder.input_separator, stop_reason, stop_details,
                     )),
                 };
                 // unwrap_or is valid here as we don't care if the receiver is gone.
                 e.send_final(response).unwrap_or_default();
 
             } else if is_stream {
+                if let Some(t) = text.as_mut() {
+                    e.release_text(t);
+                    if let Some(hp) = e.healed_prefix.as_mut() {
+                        hp.strip(t);
+                    }
+                }
                 // In progress stream, send individual token response
                 let response = InferResponse::stream_inprog(
                     token.unwrap(), e.generated_tokens, text, request_id
                 );
-                if e.stream_tx.as_ref().unwrap().send(Ok(response)).is_err() {
-                    // If receiver closed (request cancelled), cancel this entry
-                    let e = self.entries.remove(&request_id).unwrap();
-                    stop_reason = Cancelled;
-                    metrics::increment_counter!("tgi_request_failure", "err" => "cancelled");
-                    //TODO include request context
-                    warn!("Aborted streaming request {request_id} cancelled by client \
-                        after generating {} token(s)", e.generated_tokens);
+                match e.stream_tx.as_mut().unwrap().send(response) {
+                    Ok(()) => if let Some(lag) = e.stream_tx.as_mut().unwrap().lag_to_report() {
+                        warn!("Consumer of streaming request {request_id} is falling behind, \
+                            {lag} response(s) not yet received");
+                    },
+                    Err(StreamSendError::Closed) => {
+                        // If receiver closed (request cancelled), cancel this entry
+                        let e = self.entries.remove(&request_id).unwrap();
+                        stop_reason = Cancelled;
+                        metrics::increment_counter!("tgi_request_failure", "err" => "cancelled");
+                        record_cancellation(Cancellation::ClientDisconnect, false);
+                        //TODO include request context
+                        warn!("Aborted streaming request {request_id} cancelled by client \
+                            after generating {} token(s)", e.generated_tokens);
+                    },
+                    Err(StreamSendError::SlowConsumer) if self.pause_slow_streams && e.paused_at.is_none() => {
+                        // Paused before the next step
+                        if !self.slow_streams.contains(&request_id) {
+                            self.slow_streams.push(request_id);
+                        }
+                    },
+                    Err(StreamSendError::SlowConsumer) => {
+                        let mut e = self.entries.remove(&request_id).unwrap();
+                        stop_reason = SlowConsumer;
+                        metrics::increment_counter!("tgi_request_failure", "err" => "slow_consumer");
+                        record_cancellation(Cancellation::SlowConsumer, false);
+                        warn!("Aborted streaming request {request_id} with slow consumer \
+                            after generating {} token(s)", e.generated_tokens);
+                        // Output held back while the buffer was full is included
+                        let response = InferResponse::slow_consumer(&e);
+                        e.send_final(Ok(response)).unwrap_or_default();
+                    },
                 }
             }
 
@@ -778,6 +1958,7 @@ impl<'a> TokenProcessor<'a> {
                 let e = self.entries.remove(&request_id).unwrap();
                 stop_reason = Cancelled;
                 metrics::increment_counter!("tgi_request_failure", "err" => "cancelled");
+                record_cancellation(Cancellation::ClientDisconnect, false);
                 //TODO include request context
                 warn!("Aborted request {request_id} cancelled by client \
                     after generating {} token(s)", e.generated_tokens);
@@ -791,6 +1972,7 @@ impl<'a> TokenProcessor<'a> {
 
         // Process any errors
         for error in errors.into_iter() {
+            request_count += 1;
             let request_id = error.request_id;
 
             let e = self.entries.get_mut(&request_id)
@@ -814,37 +1996,150 @@ impl<'a> TokenProcessor<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Times {
     // Queue start time
     pub(crate) queued: Instant,
     // Generation start time
     pub(crate) start: Instant,
+    // First token generation time, if any were generated
+    pub(crate) first_token: Option<Instant>,
     // Generation end time
     pub(crate) end: Instant,
+    // Time the shards took to prefill the request's batch, unless it failed
+    pub(crate) prefill: Option<Duration>,
+    // Number of batches formed while the request was queued, which it wasn't added to
+    pub(crate) batches_waited: u32,
 }
 
 impl From<&Entry> for Times {
     fn from(entry: &Entry) -> Self {
         Self{
-            queued: entry.queue_time, start: entry.batch_time.unwrap(), end: Instant::now(),
+            queued: entry.queue_time, start: entry.batch_time.unwrap(),
+            first_token: entry.first_token_time, end: Instant::now(),
+            prefill: entry.prefill_duration, batches_waited: entry.batches_waited,
         }
     }
 }
 
+/// Warnings to include in a request's response about adjustments made to its parameters
+fn parameter_warnings(params: &GenerateParameters) -> Vec<String> {
+    let mut warnings = params.policy_warnings.clone();
+    if params.max_is_token_limit {
+        warnings.push(format!(
+            "max_new_tokens reduced to {} so that the input and output fit within the max sequence length",
+            params.max_new_tokens,
+        ));
+    }
+    warnings
+}
+
+/// A request's parameters as applied, if any were changed during validation
+fn normalized_params(params: &GenerateParameters, input_length: usize) -> Option<NormalizedParameters> {
+    let truncated = params.normalized.iter().any(|name| name == "truncate_input_tokens");
+    (!params.normalized.is_empty()).then(|| NormalizedParameters {
+        changed: params.normalized.clone(),
+        max_new_tokens: params.max_new_tokens,
+        truncate_input_tokens: if truncated { input_length as u32 } else { 0 },
+        seed: params.seed,
+    })
+}
+
+/// Summary of a completed request, sent in its unary or final stream response
+#[derive(Debug, Clone)]
+pub(crate) struct RequestUsage {
+    pub(crate) in_token_count: u32,
+    pub(crate) times: Times,
+}
+
+impl From<&Entry> for RequestUsage {
+    fn from(entry: &Entry) -> Self {
+        Self { in_token_count: entry.input_length as u32, times: entry.into() }
+    }
+}
+
+/// Weight of each new observation in the moving average of the time to first token
+const FIRST_TOKEN_LATENCY_WEIGHT: f64 = 0.2;
+
+/// Snapshot of a replica's running batch, published each generation step
+/// for the admin state endpoint and load shedding
+#[derive(Debug, Default)]
+pub(crate) struct BatchState {
+    pub(crate) batch_id: Option<u64>,
+    pub(crate) entries: Vec<EntryState>,
+    /// When the snapshot was taken, a batch whose last step was long ago may be stuck
+    pub(crate) updated: Option<Instant>,
+    /// Moving average of the time from batching to first token of recently
+    /// prefilled requests, used to project the time to first token of new ones
+    pub(crate) first_token_latency: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub(crate) struct EntryState {
+    pub(crate) id: u64,
+    pub(crate) input_length: usize,
+    pub(crate) generated_tokens: u32,
+    pub(crate) max_new_tokens: u32,
+    pub(crate) queue_time: Instant,
+    pub(crate) batch_time: Option<Instant>,
+    pub(crate) preempted: bool,
+}
+
+impl BatchState {
+    /// Replace the snapshot in place, reusing its allocation
+    fn update(&mut self, batch_id: u64, entries: &IntMap<u64, Entry>) {
+        // Include entries which generated their first token since the last update
+        for e in entries.values() {
+            let (Some(batched), Some(first)) = (e.batch_time, e.first_token_time) else {
+                continue
+            };
+            if self.updated.map_or(false, |t| first <= t) {
+                continue
+            }
+            let latency = first.saturating_duration_since(batched);
+            self.first_token_latency = Some(match self.first_token_latency {
+                Some(avg) => avg.mul_f64(1.0 - FIRST_TOKEN_LATENCY_WEIGHT)
+                    + latency.mul_f64(FIRST_TOKEN_LATENCY_WEIGHT),
+                None => latency,
+            });
+        }
+        self.batch_id = Some(batch_id);
+        self.entries.clear();
+        self.entries.extend(entries.iter().map(|(&id, e)| EntryState {
+            id,
+            input_length: e.input_length,
+            generated_tokens: e.generated_tokens,
+            max_new_tokens: e.request.parameters.max_new_tokens,
+            queue_time: e.queue_time,
+            batch_time: e.batch_time,
+            preempted: e.preempted,
+        }));
+        self.entries.sort_unstable_by_key(|e| e.id);
+        self.updated = Some(Instant::now());
+    }
+
+    fn clear(&mut self) {
+        self.batch_id = None;
+        self.entries.clear();
+        self.updated = Some(Instant::now());
+    }
+}
+
 /// This enum initially contains a vec of Token structs
 /// received from the shards and containing token ids.
 /// It is decoded to a vec of TokenInfo structs containing
 /// the token strings, which is sent in the external gRPC response.
-#[derive(Debug)]
+/// Streaming responses hold a single token, stored inline to avoid
+/// an allocation per token.
+#[derive(Debug, Clone)]
 pub(crate) enum TokenInfos {
-    WithIds(Vec<Token>),
+    WithIds(SmallVec<[Token; 1]>),
     WithStrings(Vec<TokenInfo>)
 }
 
 impl Default for TokenInfos {
     fn default() -> Self {
-        WithIds(vec![])
+        WithIds(SmallVec::new())
     }
 }
 
@@ -861,110 +2156,189 @@ impl TokenInfos {
             WithIds(tis) => tis.is_empty(),
         }
     }
-    pub(crate) fn to_final_vec(self) -> Vec<TokenInfo> {
+    fn append(&mut self, other: TokenInfos) {
+        match (self, other) {
+            (WithIds(tis), WithIds(mut other)) => tis.append(&mut other),
+            (WithStrings(tis), WithStrings(mut other)) => tis.append(&mut other),
+            _ => unreachable!("Can't combine decoded and undecoded token infos"),
+        }
+    }
+    pub(crate) fn into_final_vec(self) -> Vec<TokenInfo> {
         match self {
             WithStrings(tis) => tis,
             _ => vec![],
         }
     }
-    fn decode(&mut self, decoder: &Decoder) {
+    fn decode(&mut self, decoder: &Decoder, budget: &mut TopTokenBudget) {
         if let WithIds(toks) = &self {
             *self = WithStrings(toks.iter()
-                .map(|t| TokenInfos::decode_token_info(t, decoder))
+                .map(|t| TokenInfos::decode_token_info(t, decoder, budget))
                 .collect());
         }
     }
-    fn decode_token_info(with_ids: &Token, decoder: &Decoder) -> TokenInfo {
+    pub(crate) fn decode_token_info(
+        with_ids: &Token, decoder: &Decoder, budget: &mut TopTokenBudget,
+    ) -> TokenInfo {
+        let top_tokens = budget.take(&with_ids.top_tokens);
         TokenInfo{
             text: decoder.id_to_token(with_ids.token_id),
             logprob: with_ids.logprob,
             rank: with_ids.rank,
-            top_tokens: with_ids.top_tokens.iter().map(|tt| TopToken{
+            top_tokens: top_tokens.iter().map(|tt| TopToken{
                 text: decoder.id_to_token(tt.token_id),
                 logprob: tt.logprob,
             }).collect(),
+            offset: None,
         }
     }
 }
 
+/// Number of top candidate tokens which may still be decoded for a response,
+/// those beyond it are omitted
+pub(crate) struct TopTokenBudget {
+    remaining: usize,
+    omitted: usize,
+}
 
-#[derive(Debug, Default)]
+impl TopTokenBudget {
+    pub(crate) fn new(decoder: &Decoder) -> Self {
+        Self { remaining: decoder.max_decoded_top_tokens.unwrap_or(usize::MAX), omitted: 0 }
+    }
+
+    /// The leading top tokens which fit within the budget
+    fn take<'t, T>(&mut self, top_tokens: &'t [T]) -> &'t [T] {
+        let count = top_tokens.len().min(self.remaining);
+        self.remaining -= count;
+        self.omitted += top_tokens.len() - count;
+        &top_tokens[..count]
+    }
+}
+
+
+#[derive(Debug, Default, Clone)]
 pub(crate) struct InferResponse {
     pub(crate) output_text: String,
     /// whether or not the token ids have been decoded yet
     pub(crate) is_decoded: bool,
     /// Total generated tokens so far
     pub(crate) gen_token_count: u32,
-    // Set/used only for unary responses
+    // Set/used only for unary responses, retained once decoded
     pub(crate) token_ids: Vec<u32>,
-    // This will be max length 1 in streaming case
+    // This will be max length 1 in streaming case, unless
+    // responses were merged due to a slow consumer
     // Only set in unary case if extra token info is requested
     pub(crate) tokens: TokenInfos,
     pub(crate) in_tokens: TokenInfos,
+    /// Character offsets of the input tokens within the input text, if requested
+    pub(crate) in_token_offsets: Vec<(usize, usize)>,
     pub(crate) reason: StopReason,
     pub(crate) in_token_count: u32,
     pub(crate) times: Option<Times>,
     pub(crate) request_id: Option<u64>,
     /// Random seed used, only applicable to sampling
     pub(crate) seed: u64,
+    /// Sum of generated token logprobs, set in final response only if requested
+    pub(crate) sequence_logprob: Option<f32>,
+    /// Queue position and wait estimate at submission, set in unary
+    /// responses and the first response of a stream
+    pub(crate) queue_estimate: Option<QueueEstimate>,
+    /// Healed prompt text to remove from the start of the output once decoded
+    pub(crate) healed_prefix: Option<HealedPrefix>,
+    /// Generation trace, set in the final response only if requested
+    pub(crate) trace: Option<GenerationTrace>,
+    /// Token counts and timings, set in the final response of a stream
+    pub(crate) usage: Option<RequestUsage>,
+    /// Shards' progress through the inputs, set only in updates streamed during prefill
+    pub(crate) prefill_progress: Option<PrefillProgress>,
+    /// Adjustments made to the request's parameters, set in unary responses
+    /// and the first response of a stream, and top candidate tokens omitted
+    /// from the response
+    pub(crate) warnings: Vec<String>,
+    /// Which stopping criterion was met, set along with the final stop reason
+    pub(crate) stop_details: Option<StopDetails>,
+    /// Parameters as applied, if validation changed any. Set in unary responses
+    /// and the first response of a stream
+    pub(crate) normalized_params: Option<NormalizedParameters>,
+    /// Options for decoding token_ids
+    pub(crate) decode_options: DecodeOptions,
 }
 
 impl InferResponse {
     /// A dedicated message is sent with the input token info, if requested
-    fn stream_input_info(in_tokens: Vec<Token>, request_id: u64) -> Self {
+    fn stream_input_info(
+        in_tokens: Vec<Token>, in_token_offsets: Vec<(usize, usize)>, request_id: u64
+    ) -> Self {
         Self {
             in_token_count: in_tokens.len() as u32,
-            in_tokens: WithIds(in_tokens),
+            in_tokens: WithIds(in_tokens.into()),
+            in_token_offsets,
             is_decoded: true,
             request_id: Some(request_id),
             ..Default::default()
         }
     }
+    /// Progress update sent while a streaming request's batch is prefilled
+    fn stream_prefill_progress(progress: PrefillProgress, request_id: u64) -> Self {
+        Self {
+            is_decoded: true,
+            request_id: Some(request_id),
+            prefill_progress: Some(progress),
+            ..Default::default()
+        }
+    }
     /// Response message for in-progress stream
     fn stream_inprog(token: Token, count: u32, text: Option<String>, request_id: u64) -> Self {
         Self {
             is_decoded: text.is_some(),
             output_text: text.unwrap_or_default(),
             gen_token_count: count,
-            tokens: WithIds(vec![token]),
+            tokens: WithIds(smallvec![token]),
             request_id: Some(request_id),
             ..Default::default()
         }
     }
     /// Final stream response message
     fn stream_final(
-        token: Token, text: Option<String>, entry: &Entry, request_id: u64, stop_reason: StopReason
+        token: Token, text: Option<String>, entry: &mut Entry, request_id: u64,
+        stop_reason: StopReason, stop_details: Option<StopDetails>,
     ) -> Self {
         Self {
             is_decoded: text.is_some(),
             output_text: text.unwrap_or_default(),
             gen_token_count: entry.generated_tokens,
-            tokens: WithIds(vec![token]),
+            tokens: WithIds(smallvec![token]),
             reason: stop_reason,
-            times: Some(entry.into()),
+            times: Some((&*entry).into()),
             request_id: Some(request_id),
             seed: entry.request.parameters.seed.unwrap_or_default(),
+            sequence_logprob: entry.sequence_logprob(),
+            trace: take(&mut entry.trace),
+            usage: Some((&*entry).into()),
+            stop_details,
             ..Default::default()
         }
     }
     /// Unary response message
     fn unary(
-        entry: &mut Entry, request_id: u64, seq2seq: bool, stop_reason: StopReason
+        entry: &mut Entry, request_id: u64, input_separator: &str,
+        stop_reason: StopReason, stop_details: Option<StopDetails>,
     ) -> Self {
         let mut text = String::new();
         if entry.request.parameters.include_input_text {
-            text += &*entry.request.inputs;
-            if seq2seq {
-                text += "\n\n";
-            }
+            text += &original_input(&entry.request);
+            text += input_separator;
         }
         let is_decoded;
         if let Some(out_decoder) = take(&mut entry.output) {
             is_decoded = true;
+            let mut output = out_decoder.into_string();
+            if let Some(healed_prefix) = &mut entry.healed_prefix {
+                healed_prefix.strip(&mut output);
+            }
             if text.is_empty() {
-                text = out_decoder.into_string();
+                text = output;
             } else {
-                text.push_str(out_decoder.output())
+                text.push_str(&output)
             }
         } else {
             is_decoded = false;
@@ -974,41 +2348,116 @@ impl InferResponse {
             is_decoded,
             gen_token_count: entry.generated_tokens,
             token_ids: take(&mut entry.token_ids),
-            tokens: WithIds(take(&mut entry.tokens)),
-            in_tokens: WithIds(take(&mut entry.input_tokens)),
+            tokens: WithIds(take(&mut entry.tokens).into()),
+            in_tokens: WithIds(take(&mut entry.input_tokens).into()),
+            in_token_offsets: take(&mut entry.request.input_offsets),
             reason: stop_reason,
             times: Some((&*entry).into()),
             request_id: Some(request_id),
             in_token_count: entry.input_length as u32,
             seed: entry.request.parameters.seed.unwrap_or_default(),
+            sequence_logprob: entry.sequence_logprob(),
+            queue_estimate: entry.queue_estimate,
+            healed_prefix: take(&mut entry.healed_prefix),
+            trace: take(&mut entry.trace),
+            usage: Some((&*entry).into()),
+            prefill_progress: None,
+            warnings: parameter_warnings(&entry.request.parameters),
+            stop_details,
+            normalized_params: normalized_params(&entry.request.parameters, entry.input_length),
+            decode_options: DecodeOptions::for_params(&entry.request.parameters),
+        }
+    }
+    /// Merge a subsequent streaming response into this one,
+    /// used when the consumer isn't keeping up
+    pub(crate) fn merge(&mut self, next: InferResponse) {
+        self.output_text += &next.output_text;
+        self.is_decoded &= next.is_decoded;
+        self.gen_token_count = next.gen_token_count;
+        self.tokens.append(next.tokens);
+        self.in_tokens.append(next.in_tokens);
+        self.in_token_offsets.extend(next.in_token_offsets);
+        self.reason = next.reason;
+        self.in_token_count = self.in_token_count.max(next.in_token_count);
+        self.times = next.times.or(take(&mut self.times));
+        self.request_id = next.request_id.or(self.request_id);
+        self.seed = next.seed;
+        self.sequence_logprob = next.sequence_logprob;
+        self.queue_estimate = self.queue_estimate.or(next.queue_estimate);
+        self.trace = next.trace.or(take(&mut self.trace));
+        self.usage = next.usage.or(take(&mut self.usage));
+        // Only the latest progress is relevant, and none once tokens are generated
+        self.prefill_progress = next.prefill_progress;
+        self.warnings.extend(next.warnings);
+        self.stop_details = next.stop_details.or(take(&mut self.stop_details));
+        self.normalized_params = take(&mut self.normalized_params).or(next.normalized_params);
+    }
+    /// Final response of a stream cancelled because its consumer fell behind
+    fn slow_consumer(entry: &Entry) -> Self {
+        Self {
+            reason: SlowConsumer,
+            is_decoded: true,
+            gen_token_count: entry.generated_tokens,
+            output_text: entry.held_text.clone(),
+            times: Some(entry.into()),
+            seed: entry.request.parameters.seed.unwrap_or_default(),
+            sequence_logprob: entry.sequence_logprob(),
+            usage: Some(entry.into()),
+            stop_details: TokenProcessor::stop_details(entry, SlowConsumer, 0.0, 0, None),
+            ..Default::default()
         }
     }
+
     /// If time limit is expired before generation starts
     pub(crate) fn early_timeout(entry: &Entry) -> Self {
         Self {
-            reason: TimeLimit,
+            reason: QueueTimeLimit,
             is_decoded: true,
             // We only include input token count in the unary case, since it will have
             // already been sent in the streaming case
             in_token_count: if entry.response_tx.is_some() { entry.input_length as u32 } else { 0 },
-            times: Some((&*entry).into()),
+            // Streamed text withheld in case it was the start of a stop sequence
+            output_text: entry.held_text.clone(),
+            times: Some(entry.into()),
+            seed: entry.request.parameters.seed.unwrap_or_default(),
+            usage: Some(entry.into()),
+            stop_details: TokenProcessor::stop_details(entry, QueueTimeLimit, 0.0, 0, None),
             ..Default::default()
         }
     }
 
     pub(crate) fn decode_output_text(&mut self, decoder: &Decoder) -> Result<(), InferError> {
         if !self.is_decoded {
-            self.output_text += &*decoder.decode(
-                take(&mut self.token_ids), true, true,
-            )?;
+            // The ids are kept for determinism audits and replays to compare
+            let mut output = decoder.decode(self.token_ids.clone(), true, true, self.decode_options)?;
+            if let Some(healed_prefix) = &mut self.healed_prefix {
+                healed_prefix.strip(&mut output);
+            }
+            self.output_text += &output;
             self.is_decoded = true;
         }
         Ok(())
     }
 
     pub(crate) fn decode_token_infos(&mut self, decoder: &Decoder) {
-        self.tokens.decode(decoder);
-        self.in_tokens.decode(decoder);
+        let mut budget = TopTokenBudget::new(decoder);
+        self.tokens.decode(decoder, &mut budget);
+        self.in_tokens.decode(decoder, &mut budget);
+        if let Some(trace) = &mut self.trace {
+            trace.decode(decoder, &mut budget);
+        }
+        if budget.omitted > 0 {
+            metrics::counter!("tgi_top_tokens_omitted", budget.omitted as u64);
+            self.warnings.push(format!(
+                "{} top candidate tokens omitted beyond the limit of {} per response",
+                budget.omitted, decoder.max_decoded_top_tokens.unwrap_or_default(),
+            ));
+        }
+    }
+
+    /// Whether there's nothing left to decode
+    fn is_fully_decoded(&self) -> bool {
+        self.is_decoded && self.tokens.is_empty() && self.in_tokens.is_empty() && self.trace.is_none()
     }
 
     pub(crate) fn ensure_decoded(
@@ -1026,19 +2475,308 @@ pub enum InferError {
     #[error("Request failed during detokenization: {0}")]
     DetokenizationError(String),
     #[error("Server too busy")]
-    RequestQueueFull(),
+    RequestQueueFull(RetryHint),
+    #[error("Server too busy to meet the time to first token objective")]
+    FirstTokenObjective(RetryHint),
+}
+
+/// Bounds of the suggested wait before retrying a rejected request
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Hints returned to callers rejected because the server is too busy,
+/// so that they can back off appropriately
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct RetryHint {
+    /// Number of requests waiting in the queue
+    pub queue_length: u32,
+    /// Configured limit which was reached
+    pub limit: u32,
+    /// Suggested wait before retrying
+    pub retry_after_millis: u64,
+}
+
+impl RetryHint {
+    /// Value for the Retry-After header, in whole seconds
+    pub(crate) fn retry_after_header(&self) -> HeaderValue {
+        HeaderValue::from((self.retry_after_millis + 999) / 1000)
+    }
+}
+
+impl InferError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            // Shard-side failure
+            GenerationError(_) => StatusCode::BAD_GATEWAY,
+            InferError::DetokenizationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RequestQueueFull(_) | FirstTokenObjective(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            GenerationError(_) => "generation_error",
+            InferError::DetokenizationError(_) => "detokenization_error",
+            RequestQueueFull(_) => "queue_full",
+            FirstTokenObjective(_) => "ttft_objective",
+        }
+    }
 }
 
 /// Convert to Axum supported format
 impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
     fn from(err: InferError) -> Self {
-        match err {
-            _ => (
-                StatusCode::FAILED_DEPENDENCY,
-                Json(ErrorResponse {
-                    error: err.to_string(),
-                }),
-            ),
+        (
+            err.status_code(),
+            Json(ErrorResponse {
+                error: err.to_string(),
+                error_code: err.error_code(),
+                retry: match err {
+                    RequestQueueFull(hint) | FirstTokenObjective(hint) => Some(hint),
+                    _ => None,
+                },
+                errors: vec![],
+            }),
+        )
+    }
+}
+
+impl IntoResponse for InferError {
+    fn into_response(self) -> Response {
+        let retry = match &self {
+            RequestQueueFull(hint) | FirstTokenObjective(hint) => Some(hint.retry_after_header()),
+            _ => None,
+        };
+        let mut response = <(StatusCode, Json<ErrorResponse>)>::from(self).into_response();
+        if let Some(retry_after) = retry {
+            response.headers_mut().insert(RETRY_AFTER, retry_after);
+        }
+        response
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use futures::StreamExt;
+    use text_generation_client::mock::{Fault, MockShard, MockShardConfig};
+    use tokio::sync::watch;
+    use tokio::time::{sleep, timeout, Duration};
+    use crate::{default_parameters, GenerateRequest};
+    use crate::batch_types::batch_type_for_name;
+    use crate::decoder::Decoder;
+    use crate::decoder_backends::WordBackend;
+    use crate::pb::fmaas::StopReason;
+    use crate::queue::{BatchingConfig, SchedulingPolicy};
+    use crate::streaming::{SlowStreamPolicy, StreamBufferConfig};
+    use crate::waiting_tokens::WaitingTokensPolicy;
+    use crate::queue::Entry;
+    use super::{Batcher, InferError, InferResponse, TokenProcessor};
+
+    const WORDS: [&str; 6] = ["A", "the", "quick", "brown", "fox", "</s>"];
+    const EOS: u32 = 5;
+
+    fn batching_config() -> BatchingConfig {
+        BatchingConfig {
+            size_limit: 8, weight_limit: 4096, prefill_weight_limit: 4096, max_waiting_tokens: 4,
+            queue_size_limit: 16, step_timeout: None,
         }
     }
-}
\ No newline at end of file
+
+    async fn batcher_for(shard: &MockShard) -> Batcher {
+        batcher_with_config(shard, watch::channel(batching_config()).1).await
+    }
+
+    async fn batcher_with_config(shard: &MockShard, config: watch::Receiver<BatchingConfig>) -> Batcher {
+        let decoder = Decoder::new(
+            Box::new(WordBackend(WORDS.to_vec())), false, EOS, true, String::new(), 0, None,
+        );
+        Batcher::new(
+            vec![shard.client().await.unwrap()], config, 16, None, decoder,
+            batch_type_for_name("flash").unwrap(), false,
+            StreamBufferConfig { capacity: 16, policy: SlowStreamPolicy::Coalesce },
+            None, None, None, SchedulingPolicy::Fifo, None, 1, None, false,
+            WaitingTokensPolicy::Fixed, false, false, None,
+        )
+    }
+
+    fn request(inputs: &str, max_new_tokens: u32) -> GenerateRequest {
+        let mut parameters = default_parameters();
+        parameters.max_new_tokens = max_new_tokens;
+        GenerateRequest { inputs: inputs.to_string(), parameters, ..Default::default() }
+    }
+
+    fn mock_config(tokens: Vec<u32>) -> MockShardConfig {
+        MockShardConfig { eos_token_id: EOS, ..Default::default() }.with_tokens(tokens)
+    }
+
+    #[test]
+    fn stops_only_below_logprob_thresholds() {
+        let mut req = request("jumps over", 10);
+        req.parameters.min_token_logprob = Some(-1.0);
+        req.parameters.min_mean_logprob = Some(-1.0);
+        let mut entry = Entry::new(req, 3, None, None);
+        entry.generated_tokens = 2;
+        entry.logprob_sum = -2.0;
+        // Each threshold is met exactly
+        assert!(!TokenProcessor::below_logprob_threshold(&entry, -1.0));
+        assert!(TokenProcessor::below_logprob_threshold(&entry, -1.5));
+        entry.logprob_sum = -2.5;
+        assert!(TokenProcessor::below_logprob_threshold(&entry, -1.0));
+    }
+
+    #[tokio::test]
+    async fn generates_until_max_new_tokens() {
+        let shard = MockShard::start(mock_config(vec![1, 2, 3, 4, 1, 2])).await.unwrap();
+        let batcher = batcher_for(&shard).await;
+
+        let response = batcher.infer(3, request("jumps over", 4)).await.unwrap();
+        assert_eq!(response.reason, StopReason::MaxTokens);
+        assert_eq!(response.gen_token_count, 4);
+        assert_eq!(response.output_text, " the quick brown fox");
+        let calls = shard.calls();
+        assert_eq!((calls.prefill, calls.next_token), (1, 3));
+    }
+
+    #[tokio::test]
+    async fn keeps_batching_tasks_of_remaining_replicas() {
+        let first = MockShard::start(mock_config(vec![1, 2])).await.unwrap();
+        let second = MockShard::start(mock_config(vec![3, 4])).await.unwrap();
+        let batcher = batcher_for(&first).await;
+        let changed = batcher.with_replicas(&[0], vec![second.client().await.unwrap()], None, false);
+        drop(batcher);
+        let replicas = changed.replicas();
+        assert_eq!((replicas[0].index(), replicas[1].index()), (0, 1));
+
+        // The kept replica is still served by the same batching task
+        replicas[0].generation_health().store(true, Ordering::SeqCst);
+        let response = changed.infer(3, request("jumps over", 2)).await.unwrap();
+        assert_eq!(response.output_text, " the quick");
+        replicas[0].generation_health().store(false, Ordering::SeqCst);
+        replicas[1].generation_health().store(true, Ordering::SeqCst);
+        let response = changed.infer(3, request("jumps over", 2)).await.unwrap();
+        assert_eq!(response.output_text, " brown fox");
+        assert_eq!((first.calls().prefill, second.calls().prefill), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn stops_at_eos_token() {
+        let shard = MockShard::start(mock_config(vec![1, 4])).await.unwrap();
+        let batcher = batcher_for(&shard).await;
+
+        let response = batcher.infer(3, request("jumps over", 10)).await.unwrap();
+        assert_eq!(response.reason, StopReason::EosToken);
+        assert_eq!(response.gen_token_count, 3);
+        assert_eq!(response.output_text, " the fox </s>");
+    }
+
+    #[tokio::test]
+    async fn batches_concurrent_requests() {
+        let shard = MockShard::start(MockShardConfig {
+            step_latency: Duration::from_millis(5), ..mock_config(vec![1, 2, 3])
+        }).await.unwrap();
+        let batcher = batcher_for(&shard).await;
+
+        let responses = batcher.infer_batch(vec![
+            (3, request("first", 3)), (3, request("second", 3)),
+        ]).await.unwrap();
+        for response in futures::future::join_all(responses).await {
+            assert_eq!(response.unwrap().output_text, " the quick brown");
+        }
+        // Both requests were prefilled together
+        assert_eq!(shard.calls().prefill, 1);
+    }
+
+    #[tokio::test]
+    async fn cancels_dropped_stream() {
+        let shard = MockShard::start(MockShardConfig {
+            step_latency: Duration::from_millis(10), script: Arc::new(|_, _| 1), ..mock_config(vec![])
+        }).await.unwrap();
+        let batcher = batcher_for(&shard).await;
+
+        let mut stream = batcher.infer_stream(
+            3, request("jumps over", 1000), |r: Result<InferResponse, InferError>| r,
+            |_, _, _, _, _, _, _| {}, (),
+        ).await.unwrap();
+        // Queue position, then the first generated token
+        stream.next().await.unwrap().unwrap();
+        stream.next().await.unwrap().unwrap();
+        assert_eq!(shard.cached_request_ids().len(), 1);
+        drop(stream);
+
+        timeout(Duration::from_secs(5), async {
+            while !shard.cached_request_ids().is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("cancelled request wasn't removed from the shard's cache");
+    }
+
+    #[tokio::test]
+    async fn stops_generation_for_shutdown() {
+        let shard = MockShard::start(MockShardConfig {
+            step_latency: Duration::from_millis(10), script: Arc::new(|_, _| 1), ..mock_config(vec![])
+        }).await.unwrap();
+        let batcher = batcher_for(&shard).await;
+
+        let running = tokio::spawn({
+            let batcher = batcher.clone();
+            async move { batcher.infer(3, request("jumps over", 1000)).await }
+        });
+        sleep(Duration::from_millis(50)).await;
+        batcher.stop_generation();
+        let response = timeout(Duration::from_secs(5), running).await.unwrap().unwrap().unwrap();
+        assert_eq!(response.reason, StopReason::ServerShutdown);
+    }
+
+    #[tokio::test]
+    async fn applies_reloaded_queue_size_and_step_timeout() {
+        let shard = MockShard::start(mock_config(vec![1, 2])).await.unwrap();
+        let (config_sender, config) = watch::channel(batching_config());
+        let batcher = batcher_with_config(&shard, config).await;
+        assert!(batcher.infer(3, request("jumps over", 2)).await.is_ok());
+
+        config_sender.send_replace(BatchingConfig { queue_size_limit: 0, ..batching_config() });
+        assert!(matches!(
+            batcher.infer(3, request("jumps over", 2)).await,
+            Err(InferError::RequestQueueFull(_)),
+        ));
+
+        config_sender.send_replace(BatchingConfig {
+            step_timeout: Some(Duration::from_millis(20)), ..batching_config()
+        });
+        shard.inject(Fault::Delay(Duration::from_millis(200)));
+        assert!(matches!(
+            batcher.infer(3, request("jumps over", 2)).await,
+            Err(InferError::GenerationError(_)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn fails_requests_of_failed_prefill() {
+        let shard = MockShard::start(mock_config(vec![1, 2])).await.unwrap();
+        let batcher = batcher_for(&shard).await;
+
+        shard.inject(Fault::Prefill);
+        assert!(matches!(
+            batcher.infer(3, request("jumps over", 4)).await,
+            Err(InferError::GenerationError(_)),
+        ));
+        // The shard recovers for subsequent requests
+        assert!(batcher.infer(3, request("jumps over", 4)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fails_only_the_request_with_shard_error() {
+        let shard = MockShard::start(MockShardConfig {
+            step_latency: Duration::from_millis(5), ..mock_config(vec![1, 2, 3])
+        }).await.unwrap();
+        let batcher = batcher_for(&shard).await;
+
+        shard.inject(Fault::RequestError("poison".to_string()));
+        let responses = futures::future::join_all(batcher.infer_batch(vec![
+            (3, request("poison pill", 3)), (3, request("healthy", 3)),
+        ]).await.unwrap()).await;
+        assert!(matches!(responses[0], Err(InferError::GenerationError(_))));
+        assert_eq!(responses[1].as_ref().unwrap().output_text, " the quick brown");
+    }
+}
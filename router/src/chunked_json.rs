@@ -0,0 +1,55 @@
+/// JSON responses serialized incrementally and sent with chunked transfer encoding, so that
+/// large outputs such as the details of every generated token aren't buffered in full
+use std::io::{self, BufWriter, Write};
+use axum::body::StreamBody;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderValue;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+/// Size of the chunks sent, other than the last
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks which may be serialized ahead of those sent, beyond which
+/// serialization waits for the client to read the response
+const BUFFERED_CHUNKS: usize = 4;
+
+pub(crate) struct ChunkedJson<T>(pub(crate) T);
+
+impl<T: Serialize + Send + 'static> IntoResponse for ChunkedJson<T> {
+    fn into_response(self) -> Response {
+        let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+        tokio::task::spawn_blocking(move || {
+            let mut writer = BufWriter::with_capacity(CHUNK_SIZE, ChunkSender(sender));
+            let result = serde_json::to_writer(&mut writer, &self.0)
+                .map_err(io::Error::from)
+                .and_then(|_| writer.flush());
+            if let Err(err) = result {
+                // The client most likely disconnected
+                warn!("Failed to send JSON response: {err}");
+            }
+        });
+        let mut response = StreamBody::new(ReceiverStream::new(receiver)).into_response();
+        response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response
+    }
+}
+
+/// Sends the serialized bytes written to it as chunks of the response body
+struct ChunkSender(mpsc::Sender<io::Result<Vec<u8>>>);
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.blocking_send(Ok(buf.to_vec())).map_err(
+            |_| io::Error::new(io::ErrorKind::BrokenPipe, "response body dropped")
+        )?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+/// Per-client concurrent request limits
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use tonic::Request;
+
+/// Identity used to attribute requests to a client: the x-caller-id header if
+/// provided, otherwise the peer IP address
+pub(crate) fn client_identity(caller_id: Option<&str>, remote_addr: Option<SocketAddr>) -> String {
+    match (caller_id, remote_addr) {
+        (Some(caller_id), _) => caller_id.to_string(),
+        (None, Some(addr)) => addr.ip().to_string(),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+pub(crate) fn grpc_client_identity<T>(request: &Request<T>) -> String {
+    let caller_id = request.metadata().get("x-caller-id").and_then(|mv| mv.to_str().ok());
+    client_identity(caller_id, request.remote_addr())
+}
+
+/// Tracks in-progress requests per client identity
+#[derive(Clone, Debug)]
+pub(crate) struct ClientLimiter {
+    max_per_client: usize,
+    in_progress: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl ClientLimiter {
+    pub(crate) fn new(max_per_client: usize) -> Self {
+        Self { max_per_client, in_progress: Default::default() }
+    }
+
+    pub(crate) fn max_per_client(&self) -> usize {
+        self.max_per_client
+    }
+
+    /// Reserve capacity for the given number of requests from the client,
+    /// returns None if this would exceed its limit
+    pub(crate) fn try_acquire(&self, client: String, count: usize) -> Option<ClientPermit> {
+        let mut in_progress = self.in_progress.lock();
+        let current = in_progress.entry(client.clone()).or_default();
+        if *current + count > self.max_per_client {
+            if *current == 0 {
+                in_progress.remove(&client);
+            }
+            return None
+        }
+        *current += count;
+        Some(ClientPermit { client, count, in_progress: self.in_progress.clone() })
+    }
+}
+
+/// Releases the reserved capacity when dropped
+#[derive(Debug)]
+pub(crate) struct ClientPermit {
+    client: String,
+    count: usize,
+    in_progress: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let mut in_progress = self.in_progress.lock();
+        if let Some(current) = in_progress.get_mut(&self.client) {
+            *current -= self.count;
+            if *current == 0 {
+                in_progress.remove(&self.client);
+            }
+        }
+    }
+}
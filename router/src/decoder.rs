@@ -1,111 +1,195 @@
 use std::mem::take;
-use tokenizers::DecoderWrapper::{BPE, ByteLevel, Metaspace, WordPiece, CTC, Sequence};
-use tokenizers::{Error, Tokenizer};
+use std::sync::Arc;
+use moka::sync::Cache;
+use tokenizers::Error;
 use unicode_segmentation::UnicodeSegmentation;
 use crate::batcher::InferError::DetokenizationError;
 use crate::batcher::InferError;
+use crate::decoder_backends::{Continuation, DecoderBackend};
+use crate::GenerateParameters;
 
-// Note this currently includes a hack where if a Sequence-type decoder is encountered
-// we assume that it's the Llama tokenizer and apply both byte-level and first-diff logic
+/// Per-request decoding options
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct DecodeOptions {
+    /// Overrides the server's default if set
+    pub(crate) skip_special_tokens: Option<bool>,
+    /// Remove spaces before punctuation and contractions, as done by HF transformers
+    pub(crate) clean_up_spaces: bool,
+}
+
+impl DecodeOptions {
+    pub(crate) fn for_params(params: &GenerateParameters) -> Self {
+        Self {
+            skip_special_tokens: params.skip_special_tokens,
+            clean_up_spaces: params.clean_up_tokenization_spaces,
+        }
+    }
+}
+
+fn clean_up_tokenization(text: &str) -> String {
+    text.replace(" .", ".").replace(" ?", "?").replace(" !", "!").replace(" ,", ",")
+        .replace(" ' ", "'").replace(" n't", "n't").replace(" 'm", "'m")
+        .replace(" 's", "'s").replace(" 've", "'ve").replace(" 're", "'re")
+}
+
+/// Interpret the `\n`, `\t` and `\\` escapes in a configured separator, so that
+/// it can be given as an env var
+pub(crate) fn unescape_separator(separator: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(separator.len());
+    let mut chars = separator.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('\\') => '\\',
+                other => return Err(format!(
+                    "invalid escape \\{} in separator, only \\n, \\t and \\\\ are supported",
+                    other.map(String::from).unwrap_or_default(),
+                )),
+            },
+            c => c,
+        });
+    }
+    Ok(unescaped)
+}
 
 pub(crate) struct Decoder {
-    tokenizer: Tokenizer,
+    backend: Box<dyn DecoderBackend>,
+    continuation: Continuation,
     single_tok_id: u32,
     single_tok: String,
     skip_special_toks: bool,
     pub(crate) seq2seq: bool,
     pub(crate) eos_token_id: u32,
+    /// Inserted between the input and output text when the input is included in responses
+    pub(crate) input_separator: String,
+    /// Texts of the individual token ids most recently decoded for token details, if cached
+    token_texts: Option<Cache<u32, Arc<str>>>,
+    /// Max number of top candidate tokens decoded per response, if limited
+    pub(crate) max_decoded_top_tokens: Option<usize>,
 }
 
 impl Decoder {
     pub(crate) fn new(
-        tokenizer: Tokenizer, seq2seq: bool, eos_token_id: u32, skip_special_toks: bool,
+        backend: Box<dyn DecoderBackend>, seq2seq: bool, eos_token_id: u32, skip_special_toks: bool,
+        input_separator: String, token_text_cache_size: u64, max_decoded_top_tokens: Option<usize>,
     ) -> Decoder {
-        let prefix_id = *tokenizer.encode("A", false)
-            .expect("Tokenizer setup error").get_ids().first().unwrap();
+        let prefix_id = backend.placeholder_id().expect("Tokenizer setup error");
         Decoder {
             single_tok_id: prefix_id,
-            single_tok: tokenizer.decode(vec![prefix_id], false).unwrap(),
-            tokenizer,
+            single_tok: backend.decode(vec![prefix_id], false).unwrap(),
+            continuation: backend.continuation(),
+            backend,
             seq2seq,
             eos_token_id,
             skip_special_toks,
+            input_separator,
+            token_texts: (token_text_cache_size > 0).then(|| Cache::new(token_text_cache_size)),
+            max_decoded_top_tokens,
         }
     }
 
-    fn decode_full(&self, ids: Vec<u32>) -> Result<String, InferError> {
-        self.tokenizer.decode(ids, self.skip_special_toks).map_err(Error::into)
+    fn decode_full(&self, ids: Vec<u32>, options: DecodeOptions) -> Result<String, InferError> {
+        let text = self.backend.decode(
+            ids, options.skip_special_tokens.unwrap_or(self.skip_special_toks),
+        )?;
+        Ok(if options.clean_up_spaces { clean_up_tokenization(&text) } else { text })
+    }
+
+    /// Remove the placeholder token text from the start of decoded text, in place
+    fn strip_placeholder_prefix(&self, text: &mut String) -> Result<(), InferError> {
+        if !text.starts_with(&self.single_tok) {
+            return Err(DetokenizationError("Unexpected".into()))
+        }
+        text.drain(..self.single_tok.len());
+        Ok(())
     }
 
+    /// Text of an individual token id, as reported in token details
     pub(crate) fn id_to_token(&self, id: u32) -> String {
-        self.tokenizer.id_to_token(id).unwrap_or_else(String::new)
+        match &self.token_texts {
+            // The same ids recur across positions and responses, particularly among top tokens
+            Some(cache) => cache.get_with(id, || self.backend.id_to_token(id).unwrap_or_default().into())
+                .to_string(),
+            None => self.backend.id_to_token(id).unwrap_or_default(),
+        }
     }
 
     pub(crate) fn decode(
-        &self, mut ids: Vec<u32>, first: bool, last: bool,
+        &self, mut ids: Vec<u32>, first: bool, last: bool, options: DecodeOptions,
     ) -> Result<String, InferError> {
-        let decoder = self.tokenizer.get_decoder();
-        if (first && self.seq2seq) || (last && matches![decoder, Some(BPE(_))])
-            || matches![decoder, Some(ByteLevel(_) | CTC(_))] {
+        let continuation = &self.continuation;
+        if (first && self.seq2seq) || (last && matches![continuation, Continuation::LastDiff])
+            || matches![continuation, Continuation::ByteLevel | Continuation::DeDup] {
             // In these cases we don't need to do anything special for "continuation"
-            let mut text = self.decode_full(ids)?;
+            let mut text = self.decode_full(ids, options)?;
             text.truncate(text.trim_end_matches('�').len()); // Avoid add'l allocation
             return Ok(text)
         }
-        // How we handle continuation depends on the specific decoder's behaviour,
-        // see each one's implementation of decode_chain in the tokenizers library.
-        match self.tokenizer.get_decoder() {
-            Some(Metaspace(_) | WordPiece(_) | Sequence(_)) => {
+        // How we handle continuation depends on the specific decoder's behaviour
+        match continuation {
+            Continuation::FirstDiff | Continuation::ByteLevelFirstDiff => {
                 // For these, the first token in the sequence is treated differently,
                 // so we add and then strip a placeholder token.
                 ids.insert(0, self.single_tok_id);
-                let result = self.decode_full(ids)?;
-                let mut text = result.strip_prefix(&self.single_tok).ok_or_else(
-                    || DetokenizationError("Unexpected".into())
-                )?.to_string();
+                let mut text = self.decode_full(ids, options)?;
+                self.strip_placeholder_prefix(&mut text)?;
                 text.truncate(text.trim_end_matches('�').len()); // Avoid add'l allocation
                 Ok(text)
             },
-            Some(BPE(_)) => {
+            Continuation::LastDiff => {
                 ids.push(self.single_tok_id);
-                let result = self.decode_full(ids)?;
-                Ok(result.strip_suffix(&self.single_tok)
-                    .ok_or_else(|| DetokenizationError("Unexpected".into()))
-                    ?.to_string())
+                let mut text = self.decode_full(ids, options)?;
+                if !text.ends_with(&self.single_tok) {
+                    return Err(DetokenizationError("Unexpected".into()))
+                }
+                text.truncate(text.len() - self.single_tok.len()); // Avoid add'l allocation
+                Ok(text)
             },
-            None => {
+            Continuation::PrependSpace => {
                 // Just prepend a space
-                Ok(format!(" {}", self.decode_full(ids)?))
+                Ok(format!(" {}", self.decode_full(ids, options)?))
             },
-            Some(tok) => {
-                Err(DetokenizationError(format!("Unsupported tokenizer type: {:?}", tok)))
-            }
+            Continuation::Unsupported(tok) => {
+                Err(DetokenizationError(format!("Unsupported tokenizer type: {}", tok)))
+            },
+            Continuation::ByteLevel | Continuation::DeDup => unreachable!(),
         }
     }
 }
 
+/// Single token id vec with room for the placeholder token
+/// to be added without reallocating
+fn single_id(id: u32) -> Vec<u32> {
+    let mut ids = Vec::with_capacity(2);
+    ids.push(id);
+    ids
+}
+
 #[derive(Debug)]
 pub(crate) enum IncrementalDecoderWrapper {
-    ByteLevel(IncrementalBLDecoder), // For ByteLevel
-    FirstDiff(IncrementalFirstDiffDecoder), // For Metaspace, WordPiece, None
-    LastDiff(IncrementalLastDiffDecoder), // For BPE
-    DeDup(IncrementalDeDupDecoder), // For CTE
+    ByteLevel(IncrementalBLDecoder), // For ByteLevel, ByteLevelFirstDiff
+    FirstDiff(IncrementalFirstDiffDecoder), // For FirstDiff, PrependSpace
+    LastDiff(IncrementalLastDiffDecoder), // For LastDiff
+    DeDup(IncrementalDeDupDecoder), // For DeDup
 }
 
 impl IncrementalDecoderWrapper {
-    pub(crate) fn for_decoder(decoder: &Decoder, is_start: bool) -> Self {
-        match decoder.tokenizer.get_decoder() {
-            Some(ByteLevel(_)) => Self::ByteLevel(IncrementalBLDecoder::new(false, false)),
-            Some(Sequence(_)) => Self::ByteLevel(IncrementalBLDecoder::new(true, is_start)),
-            Some(BPE(_)) => Self::LastDiff(IncrementalLastDiffDecoder {
-                output: String::new(), next_id: None,
+    pub(crate) fn for_decoder(decoder: &Decoder, is_start: bool, options: DecodeOptions) -> Self {
+        match decoder.continuation {
+            Continuation::ByteLevel => Self::ByteLevel(IncrementalBLDecoder::new(false, false, options)),
+            Continuation::ByteLevelFirstDiff => Self::ByteLevel(IncrementalBLDecoder::new(true, is_start, options)),
+            Continuation::LastDiff => Self::LastDiff(IncrementalLastDiffDecoder {
+                output: String::new(), next_id: None, options,
             }),
-            Some(CTC(_)) => Self::DeDup(IncrementalDeDupDecoder {
-                output: String::new(), last_id: None,
+            Continuation::DeDup => Self::DeDup(IncrementalDeDupDecoder {
+                output: String::new(), last_id: None, options,
             }),
-            Some(Metaspace(_) | WordPiece(_)) | None | _ => Self::FirstDiff(
+            // FirstDiff, PrependSpace
+            _ => Self::FirstDiff(
                 IncrementalFirstDiffDecoder {
-                    output: String::new(), first: is_start,
+                    output: String::new(), first: is_start, options,
                 }
             ),
         }
@@ -154,18 +238,19 @@ impl IncrementalDecoder for IncrementalDecoderWrapper {
 pub(crate) struct IncrementalFirstDiffDecoder {
     output: String,
     first: bool,
+    options: DecodeOptions,
 }
 
 impl IncrementalDecoder for IncrementalFirstDiffDecoder {
     fn next(&mut self, token: u32, decoder: &Decoder) -> Result<String, InferError> {
-        let text = decoder.decode(vec![token], self.first, false)?;
+        let text = decoder.decode(single_id(token), self.first, false, self.options)?;
         self.first = false;
         self.output += &text;
         Ok(text)
     }
 
     fn output(&self) -> &str {
-        &*self.output
+        &self.output
     }
     fn into_string(self) -> String {
         self.output
@@ -176,13 +261,14 @@ impl IncrementalDecoder for IncrementalFirstDiffDecoder {
 pub(crate) struct IncrementalLastDiffDecoder {
     output: String,
     next_id: Option<u32>,
+    options: DecodeOptions,
 }
 
 impl IncrementalDecoder for IncrementalLastDiffDecoder {
     fn next(&mut self, token: u32, decoder: &Decoder) -> Result<String, InferError> {
         let text = self.next_id.map_or_else(
             || Ok(String::new()),
-            |id| decoder.decode(vec![id], true, false)
+            |id| decoder.decode(single_id(id), true, false, self.options)
         )?;
         self.next_id = Some(token);
         self.output += &text;
@@ -192,7 +278,7 @@ impl IncrementalDecoder for IncrementalLastDiffDecoder {
     fn flush(&mut self, decoder: &Decoder) -> Result<String, InferError> {
         let text = self.next_id.map_or_else(
             || Ok(String::new()),
-            |id| decoder.decode_full(vec![id])
+            |id| decoder.decode_full(vec![id], self.options)
         )?;
         self.next_id = None;
         self.output += &text;
@@ -200,7 +286,7 @@ impl IncrementalDecoder for IncrementalLastDiffDecoder {
     }
 
     fn output(&self) -> &str {
-        &*self.output
+        &self.output
     }
     fn into_string(self) -> String {
         self.output
@@ -211,6 +297,7 @@ impl IncrementalDecoder for IncrementalLastDiffDecoder {
 pub(crate) struct IncrementalDeDupDecoder {
     output: String,
     last_id: Option<u32>,
+    options: DecodeOptions,
 }
 
 impl IncrementalDecoder for IncrementalDeDupDecoder {
@@ -219,13 +306,13 @@ impl IncrementalDecoder for IncrementalDeDupDecoder {
             return Ok(String::new())
         }
         self.last_id = Some(token);
-        let text = decoder.decode_full(vec![token])?;
+        let text = decoder.decode_full(vec![token], self.options)?;
         self.output += &text;
         Ok(text)
     }
 
     fn output(&self) -> &str {
-        &*self.output
+        &self.output
     }
     fn into_string(self) -> String {
         self.output
@@ -240,16 +327,18 @@ pub(crate) struct IncrementalBLDecoder {
     output: String,
     first_diff: bool,
     first: bool,
+    options: DecodeOptions,
 }
 
 impl IncrementalBLDecoder {
-    fn new(first_diff: bool, first: bool) -> Self{
+    fn new(first_diff: bool, first: bool, options: DecodeOptions) -> Self{
         Self {
             id_buffer: vec![],
             str_buffer: String::new(),
             output: String::new(),
             first_diff,
             first,
+            options,
         }
     }
 }
@@ -257,25 +346,25 @@ impl IncrementalBLDecoder {
 impl IncrementalDecoder for IncrementalBLDecoder {
     fn next(&mut self, token: u32, decoder: &Decoder) -> Result<String, InferError> {
         self.id_buffer.push(token);
-        let mut buffer = self.id_buffer.clone();
         let text = if self.first_diff && !self.first {
             // Prepend placeholder token to avoid first-token differences
-            buffer.insert(0, decoder.single_tok_id);
-            let result = decoder.decode_full(buffer)?;
-            result.strip_prefix(&decoder.single_tok).ok_or_else(
-                || DetokenizationError("Unexpected".into())
-            )?.to_string()
+            let mut buffer = Vec::with_capacity(self.id_buffer.len() + 1);
+            buffer.push(decoder.single_tok_id);
+            buffer.extend_from_slice(&self.id_buffer);
+            let mut text = decoder.decode_full(buffer, self.options)?;
+            decoder.strip_placeholder_prefix(&mut text)?;
+            text
         } else {
             self.first = false;
-            decoder.decode_full(buffer)?
+            decoder.decode_full(self.id_buffer.clone(), self.options)?
         };
         // Defer decoding until we have enough bytes for complete UTF-8
         if !text.ends_with('�') {
-            self.output.push_str(&*text);
+            self.output.push_str(&text);
             if self.str_buffer.is_empty() {
                 self.str_buffer = text;
             } else {
-                self.str_buffer.push_str(&*text);
+                self.str_buffer.push_str(&text);
             }
             self.id_buffer.clear();
 
@@ -291,7 +380,7 @@ impl IncrementalDecoder for IncrementalBLDecoder {
     }
     fn flush(&mut self, decoder: &Decoder) -> Result<String, InferError> {
         if !self.id_buffer.is_empty() {
-            let last = decoder.decode_full(self.id_buffer.clone())?;
+            let last = decoder.decode_full(self.id_buffer.clone(), self.options)?;
             let last = last.trim_end_matches('�');
             self.output += last;
             self.str_buffer.push_str(last);
@@ -301,7 +390,7 @@ impl IncrementalDecoder for IncrementalBLDecoder {
     }
 
     fn output(&self) -> &str {
-        &*self.output
+        &self.output
     }
     fn into_string(self) -> String {
         self.output
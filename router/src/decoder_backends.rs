@@ -0,0 +1,246 @@
+/// Tokenizer implementations used to decode generated tokens
+use std::fs;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use nohash_hasher::IntMap;
+use prost::Message;
+use tokenizers::DecoderWrapper::{BPE, ByteLevel, Metaspace, WordPiece, CTC, Sequence};
+use tokenizers::{Error, Tokenizer};
+use crate::batcher::InferError;
+use crate::batcher::InferError::DetokenizationError;
+
+/// How text decoded from a token depends on the tokens around it, which
+/// determines how sequences are decoded incrementally
+#[derive(Clone, Debug)]
+pub(crate) enum Continuation {
+    /// Tokens map to bytes, which may only form valid UTF-8 in combination
+    ByteLevel,
+    /// Byte-level, and the first token in a sequence is decoded differently
+    ByteLevelFirstDiff,
+    /// The first token in a sequence is decoded differently
+    FirstDiff,
+    /// The last token in a sequence is decoded differently
+    LastDiff,
+    /// Consecutive repeated tokens are collapsed
+    DeDup,
+    /// Tokens are separated by spaces
+    PrependSpace,
+    Unsupported(String),
+}
+
+pub(crate) trait DecoderBackend: Send + Sync {
+    fn decode(&self, ids: Vec<u32>, skip_special_tokens: bool) -> Result<String, InferError>;
+    fn id_to_token(&self, id: u32) -> Option<String>;
+    /// Id of a token whose text is the single character "A"
+    fn placeholder_id(&self) -> Option<u32>;
+    fn continuation(&self) -> Continuation;
+}
+
+/// Create the named backend, the huggingface backend uses the router's tokenizer
+/// while the others are loaded from the given model file
+pub(crate) fn load_backend(
+    name: &str, model_path: Option<&str>, tokenizer: &Tokenizer,
+) -> Result<Box<dyn DecoderBackend>, String> {
+    let require_path = || model_path
+        .ok_or_else(|| format!("decoder backend {name} requires a decoder model path"));
+    match name {
+        "huggingface" if model_path.is_some() => Err(
+            "decoder model path is only used by the sentencepiece and tiktoken backends".to_string()
+        ),
+        "huggingface" => Ok(Box::new(HfDecoderBackend { tokenizer: tokenizer.clone() })),
+        "sentencepiece" => Ok(Box::new(SentencePieceBackend::from_file(require_path()?)?)),
+        "tiktoken" => Ok(Box::new(TiktokenBackend::from_file(require_path()?)?)),
+        _ => Err(format!(
+            "invalid decoder backend '{name}', must be huggingface, sentencepiece or tiktoken"
+        )),
+    }
+}
+
+/// HuggingFace tokenizers (tokenizer.json)
+pub(crate) struct HfDecoderBackend {
+    tokenizer: Tokenizer,
+}
+
+impl DecoderBackend for HfDecoderBackend {
+    fn decode(&self, ids: Vec<u32>, skip_special_tokens: bool) -> Result<String, InferError> {
+        self.tokenizer.decode(ids, skip_special_tokens).map_err(Error::into)
+    }
+
+    fn id_to_token(&self, id: u32) -> Option<String> {
+        self.tokenizer.id_to_token(id)
+    }
+
+    fn placeholder_id(&self) -> Option<u32> {
+        self.tokenizer.encode("A", false).ok()?.get_ids().first().copied()
+    }
+
+    // Note this currently includes a hack where if a Sequence-type decoder is encountered
+    // we assume that it's the Llama tokenizer and apply both byte-level and first-diff logic
+    fn continuation(&self) -> Continuation {
+        // See each decoder's implementation of decode_chain in the tokenizers library
+        match self.tokenizer.get_decoder() {
+            Some(ByteLevel(_)) => Continuation::ByteLevel,
+            Some(Sequence(_)) => Continuation::ByteLevelFirstDiff,
+            Some(Metaspace(_) | WordPiece(_)) => Continuation::FirstDiff,
+            Some(BPE(_)) => Continuation::LastDiff,
+            Some(CTC(_)) => Continuation::DeDup,
+            None => Continuation::PrependSpace,
+            Some(tok) => Continuation::Unsupported(format!("{tok:?}")),
+        }
+    }
+}
+
+// Subset of SentencePiece's ModelProto needed for decoding, other fields are skipped
+#[derive(Clone, PartialEq, Message)]
+struct SentencePieceModel {
+    #[prost(message, repeated, tag = "1")]
+    pieces: Vec<SentencePiece>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SentencePiece {
+    #[prost(string, tag = "1")]
+    piece: String,
+    #[prost(int32, tag = "3")]
+    piece_type: i32,
+}
+
+const PIECE_UNKNOWN: i32 = 2;
+const PIECE_CONTROL: i32 = 3;
+const PIECE_BYTE: i32 = 6;
+/// Text SentencePiece substitutes for unknown pieces
+const UNKNOWN_SURFACE: &str = " \u{2047} ";
+const SPACE_SYMBOL: char = '\u{2581}';
+
+/// SentencePiece models (.model), decoded with byte fallback and
+/// the leading space of the sequence removed
+pub(crate) struct SentencePieceBackend {
+    pieces: Vec<SentencePiece>,
+}
+
+impl SentencePieceBackend {
+    fn from_file(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path)
+            .map_err(|e| format!("couldn't read sentencepiece model {path}: {e}"))?;
+        let model = SentencePieceModel::decode(bytes.as_slice())
+            .map_err(|e| format!("invalid sentencepiece model {path}: {e}"))?;
+        Ok(Self { pieces: model.pieces })
+    }
+
+    fn piece(&self, id: u32) -> Result<&SentencePiece, InferError> {
+        self.pieces.get(id as usize)
+            .ok_or_else(|| DetokenizationError(format!("token id {id} out of range")))
+    }
+}
+
+/// Parse byte pieces of the form <0xAB>
+fn piece_byte(piece: &str) -> Option<u8> {
+    let hex = piece.strip_prefix("<0x")?.strip_suffix('>')?;
+    u8::from_str_radix(hex, 16).ok()
+}
+
+impl DecoderBackend for SentencePieceBackend {
+    fn decode(&self, ids: Vec<u32>, skip_special_tokens: bool) -> Result<String, InferError> {
+        let mut bytes = Vec::with_capacity(ids.len() * 4);
+        for id in ids {
+            let piece = self.piece(id)?;
+            match piece.piece_type {
+                PIECE_CONTROL if skip_special_tokens => {},
+                PIECE_UNKNOWN => bytes.extend_from_slice(UNKNOWN_SURFACE.as_bytes()),
+                PIECE_BYTE => match piece_byte(&piece.piece) {
+                    Some(byte) => bytes.push(byte),
+                    None => return Err(DetokenizationError(
+                        format!("invalid byte piece {}", piece.piece)
+                    )),
+                },
+                _ => bytes.extend(
+                    piece.piece.replace(SPACE_SYMBOL, " ").into_bytes()
+                ),
+            }
+        }
+        // Incomplete UTF-8 sequences become replacement characters
+        let text = String::from_utf8_lossy(&bytes);
+        Ok(text.strip_prefix(' ').unwrap_or(&text).to_string())
+    }
+
+    fn id_to_token(&self, id: u32) -> Option<String> {
+        self.pieces.get(id as usize).map(|p| p.piece.clone())
+    }
+
+    fn placeholder_id(&self) -> Option<u32> {
+        let with_space = format!("{SPACE_SYMBOL}A");
+        let position = |text: &str| self.pieces.iter().position(|p| p.piece == text);
+        position(&with_space).or_else(|| position("A")).map(|id| id as u32)
+    }
+
+    fn continuation(&self) -> Continuation {
+        Continuation::ByteLevelFirstDiff
+    }
+}
+
+/// tiktoken BPE files, one base64-encoded token and its rank (id) per line.
+/// Ids outside the file are special tokens whose text isn't known, they
+/// decode to nothing
+pub(crate) struct TiktokenBackend {
+    tokens: IntMap<u32, Vec<u8>>,
+}
+
+impl TiktokenBackend {
+    fn from_file(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read tiktoken file {path}: {e}"))?;
+        let invalid = |line: &str| format!("invalid line in tiktoken file {path}: {line}");
+        let tokens = contents.lines().filter(|l| !l.is_empty()).map(|line| {
+            let (token, rank) = line.split_once(' ').ok_or_else(|| invalid(line))?;
+            Ok((
+                rank.parse::<u32>().map_err(|_| invalid(line))?,
+                BASE64.decode(token).map_err(|_| invalid(line))?,
+            ))
+        }).collect::<Result<_, String>>()?;
+        Ok(Self { tokens })
+    }
+}
+
+impl DecoderBackend for TiktokenBackend {
+    fn decode(&self, ids: Vec<u32>, _skip_special_tokens: bool) -> Result<String, InferError> {
+        let bytes = ids.iter()
+            .filter_map(|id| self.tokens.get(id))
+            .flatten().copied().collect::<Vec<u8>>();
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn id_to_token(&self, id: u32) -> Option<String> {
+        self.tokens.get(&id).map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn placeholder_id(&self) -> Option<u32> {
+        self.tokens.iter().find(|(_, bytes)| bytes.as_slice() == b"A").map(|(id, _)| *id)
+    }
+
+    fn continuation(&self) -> Continuation {
+        Continuation::ByteLevel
+    }
+}
+
+/// Whitespace-separated words, id 0 being the placeholder "A", for tests
+#[cfg(test)]
+pub(crate) struct WordBackend(pub(crate) Vec<&'static str>);
+
+#[cfg(test)]
+impl DecoderBackend for WordBackend {
+    fn decode(&self, ids: Vec<u32>, _skip_special_tokens: bool) -> Result<String, InferError> {
+        Ok(ids.iter().filter_map(|&id| self.0.get(id as usize)).copied().collect::<Vec<_>>().join(" "))
+    }
+
+    fn id_to_token(&self, id: u32) -> Option<String> {
+        self.0.get(id as usize).map(|word| word.to_string())
+    }
+
+    fn placeholder_id(&self) -> Option<u32> {
+        (self.0.first() == Some(&"A")).then_some(0)
+    }
+
+    fn continuation(&self) -> Continuation {
+        Continuation::PrependSpace
+    }
+}
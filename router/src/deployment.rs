@@ -0,0 +1,545 @@
+/// Model-specific serving state, which can be replaced at runtime by that of a new
+/// model version while requests submitted to the previous one run to completion
+use std::sync::Arc;
+use std::time::Duration;
+use text_generation_client::{ChannelConfig, ShardCapabilities, ShardedClient};
+use tokenizers::Tokenizer;
+use tokio::sync::{Mutex, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, interval};
+use tracing::{info, warn};
+use crate::batch_types::BatchType;
+use crate::batcher::Batcher;
+use crate::decoder::Decoder;
+use crate::decoder_backends::load_backend;
+use crate::embeddings::{EmbeddingBatchConfig, EmbeddingBatcher};
+use crate::health::Health;
+use crate::kv_cache::KvCacheModel;
+use crate::preemption::{Preemption, PreemptionPolicy};
+use crate::queue::{BatchingConfig, LaneConfig, SchedulingPolicy};
+use crate::replicas::Replica;
+use crate::response_cache::{InMemoryResponseCache, ResponseCache, ResponseCacheStore};
+use crate::server::{connect_shards, load_tokenizer};
+use crate::streaming::StreamBufferConfig;
+use crate::validation::{FimSentinels, ShardSupport, TokenLimitPolicy, TopNTokens, Validation};
+use crate::warmup::warmup;
+use crate::hooks::RequestHooks;
+use crate::waiting_tokens::WaitingTokensPolicy;
+use crate::parameter_policy::ParameterPolicy;
+use crate::models::ModelCapabilities;
+use crate::tools::ChatTemplate;
+
+/// How often a replaced deployment is checked for remaining requests
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings applied to every deployment, fixed at startup
+pub(crate) struct DeploymentConfig {
+    pub(crate) batch_type: Arc<dyn BatchType>,
+    pub(crate) batching_config: watch::Receiver<BatchingConfig>,
+    pub(crate) max_concurrent_requests: usize,
+    /// Max total bytes of the prompts of queued requests, if limited
+    pub(crate) max_queued_prompt_bytes: Option<usize>,
+    pub(crate) max_sequence_length: usize,
+    pub(crate) max_new_tokens: usize,
+    pub(crate) top_n_tokens: TopNTokens,
+    pub(crate) token_limit_policy: TokenLimitPolicy,
+    pub(crate) validation_workers: usize,
+    pub(crate) detokenization_workers: usize,
+    pub(crate) output_special_tokens: bool,
+    /// Inserted between the input and output text of seq2seq models' responses
+    /// which include the input
+    pub(crate) seq2seq_input_separator: String,
+    pub(crate) decoder_backend: String,
+    /// Max number of token id texts cached for token details, 0 disables the cache
+    pub(crate) token_text_cache_size: u64,
+    /// Max number of top candidate tokens decoded per response, if limited
+    pub(crate) max_decoded_top_tokens: Option<usize>,
+    pub(crate) fim_sentinels: Option<FimSentinels>,
+    pub(crate) kv_cache_capacity_bytes: Option<u64>,
+    pub(crate) coalesce_requests: bool,
+    pub(crate) stream_config: StreamBufferConfig,
+    /// Externally provided store, shared by all deployments
+    pub(crate) response_cache_store: Option<Arc<dyn ResponseCacheStore>>,
+    /// Max entries of the in-memory store created for each deployment, 0 disables it
+    pub(crate) response_cache_size: u64,
+    pub(crate) response_cache_ttl: Duration,
+    pub(crate) preemption: Option<Preemption>,
+    pub(crate) scheduling: SchedulingPolicy,
+    /// Thresholds of the latency lane, if short requests are batched separately
+    pub(crate) lanes: Option<LaneConfig>,
+    pub(crate) embedding_batch: Option<EmbeddingBatchConfig>,
+    /// Zero disables the shard health monitors
+    pub(crate) shard_health_check_interval: Duration,
+    /// Time to first token objective, used to shed load
+    pub(crate) ttft_slo: Option<Duration>,
+    /// Whether the shards support streaming prefill with progress updates
+    pub(crate) shard_prefill_progress: bool,
+    pub(crate) waiting_tokens_policy: WaitingTokensPolicy,
+    /// Whether batches are prefilled concurrently with the running batch's next token
+    pub(crate) pipeline_prefill: bool,
+    /// Whether the requests of failed batches are regenerated rather than failed
+    pub(crate) retry_failed_batches: bool,
+    /// Shared by all deployments, present only if any hooks are registered
+    pub(crate) request_hooks: Option<RequestHooks>,
+    /// Whether a fraction of greedy requests are re-run to audit determinism
+    pub(crate) determinism_audits: bool,
+    /// Defaults and limits of the served model's generation parameters
+    pub(crate) parameter_policy: Option<ParameterPolicy>,
+    /// Identity of the model served at startup, used where the shards don't report it
+    pub(crate) model: ModelIdentity,
+}
+
+/// Id and version of the model which a deployment serves, empty if unknown
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ModelIdentity {
+    pub(crate) id: String,
+    pub(crate) version: String,
+}
+
+impl ModelIdentity {
+    /// Identity reported by the shards, each part falling back to the given one if unreported
+    pub(crate) async fn query(client: &mut ShardedClient, fallback: ModelIdentity) -> Result<Self, String> {
+        let (id, version) = client.model_identity().await
+            .map_err(|e| format!("couldn't get model identity from shards: {e}"))?;
+        Ok(Self {
+            id: if id.is_empty() { fallback.id } else { id },
+            version: if version.is_empty() { fallback.version } else { version },
+        })
+    }
+}
+
+/// Model files of a deployment, besides the tokenizer
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ModelPaths {
+    pub(crate) decoder_model_path: Option<String>,
+    pub(crate) model_config_path: Option<String>,
+    pub(crate) tokenizer_config_path: Option<String>,
+}
+
+/// A model's tokenizer and the shards serving it, along with the
+/// request processing state tied to them
+pub(crate) struct Deployment {
+    pub(crate) validation: Validation,
+    pub(crate) batcher: Batcher,
+    pub(crate) tokenizer: Arc<Tokenizer>,
+    pub(crate) seq2seq: bool,
+    pub(crate) health: Health,
+    /// Client of each replica, the primary first
+    pub(crate) clients: Vec<ShardedClient>,
+    // batching of embedding requests, if enabled
+    pub(crate) embeddings: Option<Arc<EmbeddingBatcher>>,
+    /// Included in responses and request logs
+    pub(crate) model: Arc<ModelIdentity>,
+    /// Features which requests can use, reported by the ListModels and ModelInfo methods
+    pub(crate) capabilities: ModelCapabilities,
+    /// Whether greedy requests may be re-run to audit determinism
+    pub(crate) determinism_audits: bool,
+    health_monitors: Vec<JoinHandle<()>>,
+    eos_token_id: u32,
+    paths: ModelPaths,
+}
+
+impl Deployment {
+    /// Set up serving of the model whose shards are reachable via `clients`,
+    /// the first of which is the primary replica
+    pub(crate) fn new(
+        config: &DeploymentConfig,
+        tokenizer: Tokenizer,
+        clients: Vec<ShardedClient>,
+        seq2seq: bool,
+        eos_token_id: u32,
+        paths: &ModelPaths,
+        model: Arc<ModelIdentity>,
+    ) -> Result<Self, String> {
+        Self::build(config, tokenizer, clients, seq2seq, eos_token_id, paths, model, None)
+    }
+
+    /// Set up serving of the same model by a changed set of replicas, given the positions
+    /// of those kept followed by the clients of those added. The batching tasks of those
+    /// kept are shared with this deployment, since only one may drive a replica's shards.
+    fn with_replicas(
+        &self, config: &DeploymentConfig, kept: &[usize], added: Vec<ShardedClient>,
+    ) -> Result<Self, String> {
+        let clients = kept.iter().map(|&i| self.clients[i].clone()).chain(added).collect();
+        Self::build(
+            config, (*self.tokenizer).clone(), clients, self.seq2seq, self.eos_token_id, &self.paths,
+            self.model.clone(), Some((&self.batcher, kept)),
+        )
+    }
+
+    /// Set up serving via the given replicas, the first of which is the primary, reusing
+    /// the batching tasks of a previous deployment's replicas at the given positions
+    #[allow(clippy::too_many_arguments)]
+    fn build(
+        config: &DeploymentConfig,
+        tokenizer: Tokenizer,
+        clients: Vec<ShardedClient>,
+        seq2seq: bool,
+        eos_token_id: u32,
+        paths: &ModelPaths,
+        model: Arc<ModelIdentity>,
+        previous: Option<(&Batcher, &[usize])>,
+    ) -> Result<Self, String> {
+        let features = ShardFeatures::new(config, &clients)?;
+        let kv_cache = config.kv_cache_capacity_bytes.map(|capacity| {
+            let config_path = paths.model_config_path.as_deref()
+                .ok_or("kv cache admission control requires model_config_path")?;
+            KvCacheModel::load(config_path, capacity)
+        }).transpose()?;
+        // Fails if the tokenizer config is invalid, but models without a chat
+        // template are still served, rejecting requests with tools
+        let chat_template = paths.tokenizer_config_path.as_deref()
+            .map(ChatTemplate::load).transpose()?.flatten().map(Arc::new);
+        if paths.tokenizer_config_path.is_some() && chat_template.is_none() {
+            warn!("Requests with tools will be rejected, the model doesn't have a chat template");
+        }
+
+        let batcher = match previous {
+            Some((batcher, kept)) => batcher.with_replicas(
+                kept, clients[kept.len()..].to_vec(), features.preemption, features.prefill_progress,
+            ),
+            None => {
+                let decoder_backend = load_backend(
+                    &config.decoder_backend, paths.decoder_model_path.as_deref(), &tokenizer,
+                )?;
+                let decoder = Decoder::new(
+                    decoder_backend, seq2seq, eos_token_id, !config.output_special_tokens,
+                    if seq2seq { config.seq2seq_input_separator.clone() } else { String::new() },
+                    config.token_text_cache_size, config.max_decoded_top_tokens,
+                );
+                let response_cache_store = config.response_cache_store.clone().or_else(|| {
+                    (config.response_cache_size > 0).then(|| Arc::new(InMemoryResponseCache::new(
+                        config.response_cache_size, config.response_cache_ttl,
+                    )) as Arc<dyn ResponseCacheStore>)
+                });
+                Batcher::new(
+                    clients.clone(),
+                    config.batching_config.clone(),
+                    config.max_concurrent_requests,
+                    config.max_queued_prompt_bytes,
+                    decoder,
+                    config.batch_type.clone(),
+                    config.coalesce_requests,
+                    config.stream_config,
+                    response_cache_store.map(ResponseCache::new),
+                    features.preemption,
+                    kv_cache,
+                    config.scheduling,
+                    config.lanes,
+                    config.detokenization_workers,
+                    config.ttft_slo,
+                    features.prefill_progress,
+                    config.waiting_tokens_policy,
+                    config.pipeline_prefill,
+                    config.retry_failed_batches,
+                    config.request_hooks.clone(),
+                )
+            },
+        };
+        // Each replica's generation health is updated by its batching task, health checks
+        // and health monitor, and used to route requests
+        let replica_health: Vec<_> = batcher.replicas().iter()
+            .map(|replica| (replica.index(), replica.generation_health()))
+            .collect();
+        let health = Health::new(
+            clients.iter().cloned().zip(replica_health.clone())
+                .map(|(client, (index, generation_health))| (index, client, generation_health))
+                .collect(),
+            &tokenizer,
+        );
+        let health_monitors = if config.shard_health_check_interval.is_zero() {
+            vec![]
+        } else {
+            clients.iter().zip(replica_health).map(|(client, (index, generation_health))| {
+                client.spawn_health_monitor(config.shard_health_check_interval, generation_health, index)
+            }).collect()
+        };
+        let embeddings = features.embedding_batch.map(|batch_config| EmbeddingBatcher::new(
+            &clients, batch_config, config.max_concurrent_requests,
+        ));
+        let validation = Validation::new(
+            config.validation_workers,
+            tokenizer.clone(),
+            clients[0].clone(),
+            config.max_sequence_length,
+            config.max_new_tokens,
+            features.top_n_tokens,
+            config.token_limit_policy,
+            config.fim_sentinels.clone(),
+            chat_template,
+            kv_cache,
+            config.parameter_policy.clone(),
+            features.support,
+        );
+        let capabilities = ModelCapabilities {
+            embeddings: embeddings.is_some(),
+            prefill_progress: features.prefill_progress,
+            speculative_decoding: features.shards.as_ref().map_or(false, |c| c.speculative_decoding),
+            adapters: features.shards.as_ref().map_or(false, |c| c.adapters),
+            // Enabled independently of the deployment
+            generation_jobs: false,
+        };
+        Ok(Self {
+            validation,
+            batcher,
+            tokenizer: Arc::new(tokenizer),
+            seq2seq,
+            health,
+            clients,
+            embeddings,
+            model,
+            capabilities,
+            determinism_audits: features.determinism_audits,
+            health_monitors,
+            eos_token_id,
+            paths: paths.clone(),
+        })
+    }
+}
+
+/// Configured features which depend on the shards, with those which not all of
+/// them support disabled
+struct ShardFeatures {
+    top_n_tokens: TopNTokens,
+    prefill_progress: bool,
+    preemption: Option<Preemption>,
+    embedding_batch: Option<EmbeddingBatchConfig>,
+    determinism_audits: bool,
+    /// Generation parameters which requests can use
+    support: ShardSupport,
+    /// Capabilities reported by all of the shards, if any report them
+    shards: Option<ShardCapabilities>,
+}
+
+impl ShardFeatures {
+    /// Fails if the shards can't serve requests of the max sequence length. Shards which
+    /// don't report their capabilities are assumed to support only the original features
+    fn new(config: &DeploymentConfig, clients: &[ShardedClient]) -> Result<Self, String> {
+        let reported = ShardCapabilities::combine(
+            clients.iter().map(|client| client.capabilities().cloned())
+        );
+        let capabilities = match &reported {
+            Some(capabilities) => {
+                info!("Shard capabilities: {capabilities:?}");
+                capabilities.clone()
+            },
+            None => {
+                info!("Shards don't report their capabilities, assuming only the original features");
+                ShardCapabilities::legacy()
+            },
+        };
+        let mut features = Self {
+            top_n_tokens: config.top_n_tokens,
+            prefill_progress: config.shard_prefill_progress,
+            preemption: config.preemption,
+            embedding_batch: config.embedding_batch,
+            determinism_audits: config.determinism_audits,
+            support: ShardSupport::from(&capabilities),
+            shards: None,
+        };
+
+        if let Some(max) = capabilities.max_batch_tokens.filter(|&max| (max as usize) < config.max_sequence_length) {
+            return Err(format!(
+                "shards (versions {:?}) accept at most {max} tokens per batch, fewer than \
+                max_sequence_length ({})", capabilities.versions, config.max_sequence_length,
+            ))
+        }
+        let unsupported = |feature: &str, enabled: bool, supported: bool| {
+            let disable = enabled && !supported;
+            if disable {
+                warn!("Disabling {feature}, which not all shards support (versions {:?})", capabilities.versions);
+            }
+            disable
+        };
+        if unsupported("top_n_tokens", features.top_n_tokens.max > 0, capabilities.top_n_tokens) {
+            features.top_n_tokens = TopNTokens { default: 0, max: 0 };
+        }
+        if unsupported("prefill progress", features.prefill_progress, capabilities.prefill_progress) {
+            features.prefill_progress = false;
+        }
+        let offload = matches!(features.preemption, Some(p) if p.policy == PreemptionPolicy::Offload);
+        if unsupported("offload preemption (requeuing instead)", offload, capabilities.offload) {
+            features.preemption = features.preemption
+                .map(|p| Preemption { policy: PreemptionPolicy::Requeue, ..p });
+        }
+        if unsupported("embeddings", features.embedding_batch.is_some(), capabilities.embeddings) {
+            features.embedding_batch = None;
+        }
+        if unsupported("determinism audits", features.determinism_audits, capabilities.deterministic) {
+            features.determinism_audits = false;
+        }
+        features.shards = reported;
+        Ok(features)
+    }
+}
+
+impl Drop for Deployment {
+    fn drop(&mut self) {
+        for monitor in &self.health_monitors {
+            monitor.abort();
+        }
+    }
+}
+
+/// Where to find a new model version to swap in
+#[derive(Debug)]
+pub(crate) struct SwapTarget {
+    pub(crate) tokenizer_path: String,
+    pub(crate) master_shard_uds_path: String,
+    pub(crate) replica_master_shard_uds_paths: Vec<String>,
+    /// Those used at startup if unset
+    pub(crate) decoder_model_path: Option<String>,
+    pub(crate) model_config_path: Option<String>,
+    pub(crate) tokenizer_config_path: Option<String>,
+}
+
+/// Replaces the current deployment with one of a new model version. Requests already
+/// submitted to the previous deployment are served by its shards until they complete.
+pub(crate) struct ModelSwapper {
+    config: DeploymentConfig,
+    current: watch::Sender<Arc<Deployment>>,
+    /// Batch type setting at startup, "auto" to match the shards' memory model
+    batch_type_setting: String,
+    batch_type_name: String,
+    startup_paths: ModelPaths,
+    channel_config: ChannelConfig,
+    shard_compression: bool,
+    warmup: bool,
+    /// Held for the duration of a swap so that only one happens at a time
+    swapping: Mutex<()>,
+}
+
+impl ModelSwapper {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        config: DeploymentConfig,
+        current: watch::Sender<Arc<Deployment>>,
+        batch_type_setting: String,
+        batch_type_name: String,
+        startup_paths: ModelPaths,
+        channel_config: ChannelConfig,
+        shard_compression: bool,
+        warmup: bool,
+    ) -> Self {
+        Self {
+            config, current, batch_type_setting, batch_type_name, startup_paths,
+            channel_config, shard_compression, warmup, swapping: Mutex::new(()),
+        }
+    }
+
+    /// Load the target model's tokenizer, connect to its shards and, once they're
+    /// ready, route new requests to them. The previous deployment is left to drain.
+    pub(crate) async fn swap(&self, target: SwapTarget) -> Result<(), String> {
+        let _swapping = self.swapping.try_lock()
+            .map_err(|_| "a model swap is already in progress".to_string())?;
+        info!("Swapping model: {target:?}");
+        let start_time = Instant::now();
+
+        let tokenizer = load_tokenizer(&target.tokenizer_path, self.config.max_sequence_length)?;
+        let mut clients = Vec::with_capacity(1 + target.replica_master_shard_uds_paths.len());
+        for path in std::iter::once(target.master_shard_uds_path)
+            .chain(target.replica_master_shard_uds_paths) {
+            clients.push(connect_shards(path, &self.channel_config, self.shard_compression).await
+                .map_err(|e| format!("couldn't connect to shards: {e}"))?);
+        }
+        let (seq2seq, eos_token_id, use_padding) = clients[0].model_info().await
+            .map_err(|e| format!("couldn't get model info from shards: {e}"))?;
+        // The configured version is that of the model served at startup
+        let fallback = ModelIdentity { id: self.config.model.id.clone(), version: String::new() };
+        let model = ModelIdentity::query(&mut clients[0], fallback).await?;
+        let shard_batch_type = if use_padding { "padded" } else { "flash" };
+        if self.batch_type_setting == "auto" && shard_batch_type != self.batch_type_name {
+            return Err(format!(
+                "new shards' batch type {shard_batch_type} differs from the current batch type {}, \
+                which requires a restart", self.batch_type_name,
+            ))
+        }
+
+        let tokenizer = if self.warmup {
+            self.verify_capacity(&clients[0], tokenizer).await?
+        } else {
+            tokenizer
+        };
+
+        let paths = ModelPaths {
+            decoder_model_path: target.decoder_model_path
+                .or_else(|| self.startup_paths.decoder_model_path.clone()),
+            model_config_path: target.model_config_path
+                .or_else(|| self.startup_paths.model_config_path.clone()),
+            tokenizer_config_path: target.tokenizer_config_path
+                .or_else(|| self.startup_paths.tokenizer_config_path.clone()),
+        };
+        info!("New model: id = {}, version = {}", model.id, model.version);
+        let deployment = Deployment::new(
+            &self.config, tokenizer, clients, seq2seq, eos_token_id, &paths, Arc::new(model),
+        )?;
+        let previous = self.current.send_replace(Arc::new(deployment));
+        metrics::increment_counter!("tgi_model_swap_count");
+        info!("Model swapped in {:?}, draining previous deployment", start_time.elapsed());
+        let replicas = previous.batcher.replicas().to_vec();
+        tokio::spawn(drain(previous, replicas));
+        Ok(())
+    }
+
+    /// Route new requests to a changed set of replicas of the current model, e.g. when
+    /// replicas discovered via DNS are added or removed, given the positions of the current
+    /// replicas which remain followed by the clients of those added. Those which remain keep
+    /// their queue and batching task, while those removed are left to drain.
+    pub(crate) async fn swap_replicas(&self, kept: &[usize], added: Vec<ShardedClient>) -> Result<(), String> {
+        let _swapping = self.swapping.try_lock()
+            .map_err(|_| "a model swap is in progress".to_string())?;
+        let current = self.current.borrow().clone();
+        let deployment = current.with_replicas(&self.config, kept, added)?;
+        drop(current);
+        let previous = self.current.send_replace(Arc::new(deployment));
+        metrics::increment_counter!("tgi_replica_set_change_count");
+        info!("Replica set changed, draining removed replicas");
+        let removed = previous.batcher.replicas().iter().enumerate()
+            .filter(|(i, _)| !kept.contains(i))
+            .map(|(_, replica)| replica.clone())
+            .collect();
+        tokio::spawn(drain(previous, removed));
+        Ok(())
+    }
+
+    /// Warm up the new shards, failing if they can't accommodate the current max batch weight
+    async fn verify_capacity(
+        &self, client: &ShardedClient, tokenizer: Tokenizer,
+    ) -> Result<Tokenizer, String> {
+        let BatchingConfig { size_limit, weight_limit, .. } = *self.config.batching_config.borrow();
+        let batch_type = self.config.batch_type.clone();
+        let max_sequence_length = self.config.max_sequence_length;
+        let mut client = client.clone();
+        // Warm-up panics if the shards fail, which is confined to this task
+        let (tokenizer, supported_weight) = tokio::spawn(async move {
+            let supported_weight = warmup(
+                batch_type.as_ref(), &mut client, &tokenizer,
+                max_sequence_length, size_limit, weight_limit,
+            ).await;
+            (tokenizer, supported_weight)
+        }).await.map_err(|e| format!("warm-up of new shards failed: {e}"))?;
+        if supported_weight < weight_limit {
+            return Err(format!(
+                "new shards can't accommodate the current max_batch_weight ({weight_limit}), \
+                only ({supported_weight})"
+            ))
+        }
+        Ok(tokenizer)
+    }
+}
+
+/// Wait for the requests of a replaced deployment's replicas which aren't used by the
+/// current one to complete, after which their batching tasks stop and the connections
+/// to their shards are closed
+async fn drain(previous: Arc<Deployment>, replicas: Vec<Replica>) {
+    let start_time = Instant::now();
+    let mut ticker = interval(DRAIN_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        // Requests are submitted while the deployment is held by their handler
+        if Arc::strong_count(&previous) == 1 && replicas.iter().all(|replica| replica.load() == 0) {
+            break
+        }
+    }
+    info!("Previous deployment drained after {:?}", start_time.elapsed());
+}
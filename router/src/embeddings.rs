@@ -0,0 +1,150 @@
+/// Batching of embedding requests, separately from generation
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use futures::future::try_join_all;
+use text_generation_client::{EmbedInput, ShardedClient};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use crate::batcher::InferError;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EmbeddingBatchConfig {
+    /// Upper bound on number of inputs in a batch
+    pub(crate) max_batch_size: usize,
+    /// Upper bound on total input tokens in a batch, exceeded only by a single input
+    pub(crate) max_batch_tokens: usize,
+}
+
+struct EmbedEntry {
+    inputs: String,
+    /// Number of tokens in the input, after any truncation
+    input_length: usize,
+    truncate: u32,
+    response_tx: oneshot::Sender<Result<Vec<f32>, InferError>>,
+}
+
+/// Queues embedding inputs for per-replica batching tasks, which run
+/// embedding batches as soon as the previous one completes
+pub(crate) struct EmbeddingBatcher {
+    senders: Vec<mpsc::Sender<EmbedEntry>>,
+    next_replica: AtomicUsize,
+}
+
+impl EmbeddingBatcher {
+    pub(crate) fn new(
+        clients: &[ShardedClient], config: EmbeddingBatchConfig, queue_size: usize,
+    ) -> Arc<Self> {
+        let senders = clients.iter().map(|client| {
+            let (sender, receiver) = mpsc::channel(queue_size);
+            tokio::spawn(batching_task(client.clone(), config, receiver));
+            sender
+        }).collect();
+        Arc::new(Self { senders, next_replica: AtomicUsize::new(0) })
+    }
+
+    /// Embeddings of the given inputs and their token counts, each truncated to
+    /// `truncate` tokens by the shards if non-zero
+    pub(crate) async fn embed(
+        &self, inputs: Vec<(String, usize)>, truncate: u32,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        // Inputs of a request are kept together so that they're likely batched together
+        let index = self.next_replica.fetch_add(1, Ordering::Relaxed) % self.senders.len();
+        let sender = &self.senders[index];
+        let mut receivers = Vec::with_capacity(inputs.len());
+        for (inputs, input_length) in inputs {
+            let (response_tx, response_rx) = oneshot::channel();
+            sender.send(EmbedEntry { inputs, input_length, truncate, response_tx }).await
+                .map_err(|_| InferError::GenerationError("embedding task stopped".to_string()))?;
+            receivers.push(async move {
+                response_rx.await.unwrap_or_else(|_| Err(InferError::GenerationError(
+                    "embedding task stopped".to_string()
+                )))
+            });
+        }
+        try_join_all(receivers).await
+    }
+}
+
+async fn batching_task(
+    mut client: ShardedClient,
+    config: EmbeddingBatchConfig,
+    mut receiver: mpsc::Receiver<EmbedEntry>,
+) {
+    // Input which didn't fit in the previous batch
+    let mut carried = None;
+    loop {
+        let first = match carried.take() {
+            Some(entry) => entry,
+            None => match receiver.recv().await {
+                Some(entry) => entry,
+                None => return,
+            },
+        };
+        let mut tokens = first.input_length;
+        let mut batch = vec![first];
+        while batch.len() < config.max_batch_size {
+            match receiver.try_recv() {
+                Ok(entry) if tokens + entry.input_length > config.max_batch_tokens => {
+                    carried = Some(entry);
+                    break
+                },
+                Ok(entry) => {
+                    tokens += entry.input_length;
+                    batch.push(entry);
+                },
+                Err(_) => break,
+            }
+        }
+        // Skip inputs whose callers have gone away
+        batch.retain(|entry| !entry.response_tx.is_closed());
+        if batch.is_empty() {
+            continue
+        }
+
+        metrics::histogram!("tgi_embedding_batch_size", batch.len() as f64);
+        metrics::histogram!("tgi_embedding_batch_tokens", tokens as f64);
+        let start_time = Instant::now();
+        let inputs = batch.iter().enumerate().map(|(id, entry)| EmbedInput {
+            id: id as u64,
+            inputs: entry.inputs.clone(),
+            input_length: entry.input_length as u32,
+            truncate: entry.truncate,
+        }).collect();
+        let mut senders = batch.into_iter().map(|entry| Some(entry.response_tx)).collect::<Vec<_>>();
+        match client.embed(inputs).await {
+            Ok((embeddings, errors)) => {
+                metrics::histogram!("tgi_embedding_batch_duration", start_time.elapsed().as_secs_f64());
+                for embedding in embeddings {
+                    if let Some(sender) = senders.get_mut(embedding.id as usize).and_then(Option::take) {
+                        sender.send(Ok(embedding.values)).unwrap_or_default();
+                    }
+                }
+                for error in errors {
+                    if let Some(sender) = senders.get_mut(error.request_id as usize).and_then(Option::take) {
+                        sender.send(Err(InferError::GenerationError(error.message))).unwrap_or_default();
+                    }
+                }
+                for sender in senders.into_iter().flatten() {
+                    sender.send(Err(InferError::GenerationError(
+                        "no embedding returned for input".to_string()
+                    ))).unwrap_or_default();
+                }
+            },
+            Err(err) => {
+                metrics::increment_counter!("tgi_embedding_batch_failure");
+                tracing::error!("Embedding batch failed: {err}");
+                for sender in senders.into_iter().flatten() {
+                    sender.send(Err(InferError::GenerationError(err.to_string()))).unwrap_or_default();
+                }
+            },
+        }
+    }
+}
+
+/// Scale the embedding to unit length
+pub(crate) fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+}
@@ -2,45 +2,96 @@ use std::borrow::Cow;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::ops::Add;
-use futures::future::try_join_all;
-use tokenizers::tokenizer::Tokenizer;
-use futures::TryFutureExt;
+use std::pin::Pin;
+use std::sync::Arc;
+use futures::future::{join_all, ready, try_join_all};
+use futures::stream::once;
+use futures::{FutureExt, Stream, TryFutureExt, TryStreamExt};
 use tokio::fs::read;
-use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::{OwnedSemaphorePermit, watch};
 use tokio::task::JoinHandle;
-use tokio::time::{Instant, Duration};
-use tonic::{Request, Response, Status};
+use tokio::time::{Instant, Duration, interval, MissedTickBehavior, sleep, timeout};
+use prost::Message;
+use tonic::{Code, Request, Response, Status};
+use tonic::codec::CompressionEncoding;
+use tonic::server::NamedService;
+use tonic::service::interceptor::InterceptedService;
 use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic_health::ServingStatus;
+use tonic_health::server::HealthReporter;
 use tracing::{info_span, instrument, Span};
-use crate::{default_parameters, GenerateParameters, GenerateRequest};
-use crate::batcher::{InferError, InferResponse, ResponseStream, Times};
+use crate::{default_parameters, GenerateParameters, GenerateRequest, TruncationSide};
+use crate::pb::fmaas::TruncationSide as ProtoTruncationSide;
+use crate::batcher::{InferError, InferResponse, RetryHint, Times};
 use crate::pb::fmaas::{
     BatchedGenerationRequest, BatchedGenerationResponse, GenerationResponse,
     SingleGenerationRequest, BatchedTokenizeRequest, BatchedTokenizeResponse,
-    TokenizeResponse, Parameters, DecodingMethod, StopReason, ModelInfoRequest, ModelInfoResponse
+    TokenizeResponse, Parameters, DecodingMethod, StopReason, ModelInfoRequest, ModelInfoResponse,
+    GenerateBatchResponse, GenerateBatchResult, GenerationError, generate_batch_result,
+    OverloadedDetails, SubmitGenerationResponse, GetGenerationRequest, GetGenerationResponse, TokenInfo, TokenOffset,
+    BatchedEmbeddingRequest, BatchedEmbeddingResponse, EmbeddingResponse, GenerationUsage,
+    SwapModelRequest, SwapModelResponse, PrefillProgress, ResponseOptions,
+    BatchedScoreRequest, BatchedScoreResponse, GenerationRequest, ScoreResponse,
+    ListModelsRequest, ListModelsResponse, RegisterPromptRequest, RegisterPromptResponse,
+    ReleasePromptRequest, ReleasePromptResponse,
+};
+use crate::pb::fmaas::StopReason::{
+    Error, Cancelled, Preempted, QueueTimeLimit, ServerShutdown, SlowConsumer, TokenLimit,
 };
-use crate::pb::fmaas::StopReason::{Error, Cancelled, TokenLimit};
 
 use crate::pb::fmaas::generation_service_server::{GenerationService, GenerationServiceServer};
+use crate::pb::fmaas::admin_service_server::{AdminService, AdminServiceServer};
+use crate::pb::fmaas::v2::generation_service_server::GenerationServiceServer as GenerationServiceServerV2;
+use crate::grpc_server_v2::GenerationServicerV2;
 use crate::server::ServerState;
-use unicode_truncate::UnicodeTruncateStr;
-use crate::pb::fmaas::model_info_response::ModelKind;
-use crate::validation::ValidationError;
+use crate::deployment::{Deployment, ModelIdentity, ModelSwapper, SwapTarget};
+use crate::audit::{should_audit, spawn_audit};
+use crate::request_log::{CallerInfo, prompt_hash, RequestLogger};
+use crate::log_redaction::redact;
+use crate::validation::{Input, ValidationError};
+use crate::tools::{parse_tool_call, ToolDefinition};
+use crate::client_limits::{ClientPermit, grpc_client_identity};
+use crate::embeddings::normalize;
+use crate::auth::Authenticator;
+use crate::request_metrics::{record_rejection, Rejection};
+use crate::jobs::GenerationJobs;
+use crate::job_journal::{JobJournal, JournaledRequest, QueuedRecord, Unfinished};
+use crate::grpc_web::GrpcWebConfig;
+use crate::models::{list_models_response, ModelDescription};
+use crate::admin::matches_token;
+use crate::safety::{filtered_response, screen_output, screen_prompt, screen_prompts, screen_stream};
 
 /// Whether to fail if sampling parameters are provided in greedy-mode requests
 /// or to silently ignore them.
 const STRICT_PARAMETER_VALIDATION: bool = false;
 
+const DEFAULT_WATERMARK_GAMMA: f32 = 0.25;
+const DEFAULT_WATERMARK_DELTA: f32 = 2.0;
+
+/// Encoded descriptors of the external API, for the reflection service
+const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/fmaas_descriptor.bin"));
+
+/// How often the readiness of the generation service is re-evaluated
+const READINESS_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Wait before retrying the resubmission of a journaled request while the server is overloaded
+const JOB_RESUBMIT_BACKOFF: Duration = Duration::from_secs(1);
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn start_grpc_server<F: Future<Output = ()> + Send +'static> (
     grpc_addr: SocketAddr,
     tls_key_pair: Option<(String, String)>,
     tls_client_ca_cert: Option<String>,
+    compression: bool,
+    grpc_web: Option<GrpcWebConfig>,
     shared_state: ServerState,
-    tokenizer: Tokenizer,
+    model_swapper: Option<Arc<ModelSwapper>>,
+    authenticator: Authenticator,
     signal: F,
 ) -> JoinHandle<()> {
 
-    let mut builder = Server::builder();
+    // gRPC-Web calls from browsers may be made over HTTP/1.1
+    let mut builder = Server::builder().accept_http1(grpc_web.is_some());
 
     // Configure TLS if requested
     if let Some((cert_path, key_path)) = tls_key_pair {
@@ -56,13 +107,66 @@ pub(crate) async fn start_grpc_server<F: Future<Output = ()> + Send +'static> (
     }
 
     // Build and start server
-    let grpc_service = GenerationServicer {
+    let deployment = shared_state.deployment.clone();
+    let admin_token_digest = shared_state.admin_token_digest.clone();
+    let grpc_service = Arc::new(GenerationServicer {
         state: shared_state,
-        tokenizer,
         input_counter: metrics::register_counter!("tgi_request_input_count"),
+    });
+    if let Some(jobs) = &grpc_service.state.generation_jobs {
+        let unfinished = jobs.take_unfinished();
+        if !unfinished.queued.is_empty() || !unfinished.interrupted.is_empty() {
+            tokio::spawn(resubmit_jobs(grpc_service.clone(), jobs.clone(), unfinished));
+        }
+    }
+    let mut service = GenerationServiceServer::from_arc(grpc_service.clone())
+        .accept_compressed(CompressionEncoding::Gzip);
+    // Version 2 of the API is served by translating to version 1
+    let mut service_v2 = GenerationServiceServerV2::new(GenerationServicerV2::new(grpc_service))
+        .accept_compressed(CompressionEncoding::Gzip);
+    if compression {
+        // Only applied to calls whose client advertises gzip support
+        service = service.send_compressed(CompressionEncoding::Gzip);
+        service_v2 = service_v2.send_compressed(CompressionEncoding::Gzip);
+    }
+
+    // Standard health service: the overall ("") status reports liveness and is serving
+    // as long as the router is up, the generation service's status reports readiness
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter.set_not_serving::<GenerationServiceServer<GenerationServicer>>().await;
+    health_reporter.set_not_serving::<GenerationServiceServerV2<GenerationServicerV2>>().await;
+    spawn_readiness_reporter(deployment, health_reporter);
+
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()
+        .expect("failed to build gRPC reflection service");
+
+    let admin_service = model_swapper.map(|swapper| InterceptedService::new(
+        AdminServiceServer::new(AdminServicer {
+            swapper, token_digest: admin_token_digest.expect("admin_api requires admin_token"),
+        }),
+        authenticator.clone(),
+    ));
+
+    let service = InterceptedService::new(service, authenticator.clone());
+    let service_v2 = InterceptedService::new(service_v2, authenticator);
+    // Only the generation services are served to browsers, the others stay gRPC-only
+    let (web_services, services) = match &grpc_web {
+        Some(config) => (Some((config.enable(service), config.enable(service_v2))), None),
+        None => (None, Some((service, service_v2))),
     };
+
+    // Health and reflection services don't require authentication
     let grpc_server = builder
-        .add_service(GenerationServiceServer::new(grpc_service))
+        .add_optional_service(web_services.as_ref().map(|s| s.0.clone()))
+        .add_optional_service(web_services.map(|s| s.1))
+        .add_optional_service(services.as_ref().map(|s| s.0.clone()))
+        .add_optional_service(services.map(|s| s.1))
+        .add_service(health_service)
+        .add_service(reflection_service)
+        .add_optional_service(admin_service)
         .serve_with_shutdown(grpc_addr, signal);
 
     // Await in spawned task
@@ -73,37 +177,175 @@ pub(crate) async fn start_grpc_server<F: Future<Output = ()> + Send +'static> (
     })
 }
 
+/// Resubmit journaled requests which were still queued when the router last stopped, as
+/// generation jobs under their generation ids, and report jobs which had left the queue as
+/// failed. Resubmission is retried while the server is at its concurrent request limit
+async fn resubmit_jobs(
+    servicer: Arc<GenerationServicer>, jobs: Arc<GenerationJobs>, unfinished: Unfinished,
+) {
+    for generation_id in unfinished.interrupted {
+        metrics::increment_counter!("tgi_generation_job_resubmit_failure");
+        jobs.insert_failed(generation_id, "interrupted by a router restart".to_string()).await;
+    }
+    for queued in unfinished.queued {
+        // Requests which weren't submitted as jobs are journaled again under their generation id
+        if queued.id != queued.generation_id {
+            if let Some(journal) = &jobs.journal {
+                journal.dequeued(queued.id.clone());
+            }
+        }
+        if jobs.contains(&queued.generation_id) {
+            continue
+        }
+        let result = loop {
+            let Some(request) = queued.to_request() else {
+                break Err(Status::deadline_exceeded("time limit passed before the router restarted"))
+            };
+            match servicer.stream_generation(request, Some(queued.generation_id.clone())).await {
+                Err(status) if status.code() == Code::ResourceExhausted => sleep(JOB_RESUBMIT_BACKOFF).await,
+                result => break result,
+            }
+        };
+        match result {
+            Ok(stream) => {
+                metrics::increment_counter!("tgi_generation_job_resubmitted");
+                jobs.submit(queued.generation_id, stream.into_inner()).await;
+            },
+            Err(status) => {
+                metrics::increment_counter!("tgi_generation_job_resubmit_failure");
+                tracing::warn!("Couldn't resubmit generation job {}: {}", queued.generation_id, status.message());
+                jobs.insert_failed(queued.generation_id, status.message().to_string()).await;
+            },
+        }
+    }
+}
+
+/// Periodically update the generation service's health status with the outcome of the
+/// same generation and shard connectivity check used by the HTTP health endpoint
+fn spawn_readiness_reporter(
+    deployment: watch::Receiver<Arc<Deployment>>, mut reporter: HealthReporter,
+) {
+    tokio::spawn(async move {
+        let mut ticker = interval(READINESS_CHECK_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_status = ServingStatus::NotServing;
+        loop {
+            ticker.tick().await;
+            let mut health = deployment.borrow().health.clone();
+            let status = match timeout(READINESS_CHECK_INTERVAL, health.check()).await {
+                Ok(true) => ServingStatus::Serving,
+                Ok(false) | Err(_) => ServingStatus::NotServing,
+            };
+            if status != last_status {
+                tracing::info!("Generation service readiness is now {status:?}");
+                last_status = status;
+            }
+            reporter.set_service_status(
+                <GenerationServiceServer<GenerationServicer> as NamedService>::NAME, status,
+            ).await;
+            reporter.set_service_status(
+                <GenerationServiceServerV2<GenerationServicerV2> as NamedService>::NAME, status,
+            ).await;
+        }
+    });
+}
+
 async fn load_pem(path: String, name: &str) -> Vec<u8> {
-    read(&path).await.expect(&*format!("couldn't load {name} from {path}"))
+    read(&path).await.unwrap_or_else(|_| panic!("couldn't load {name} from {path}"))
 }
 
 //  #[derive(Debug, Default)]
 pub struct GenerationServicer {
     state: ServerState,
-    tokenizer: Tokenizer,
     input_counter: metrics::Counter,
 }
 
+pub struct AdminServicer {
+    swapper: Arc<ModelSwapper>,
+    /// Digest of the admin token, which calls must present in x-admin-token
+    token_digest: Arc<[u8]>,
+}
+
+#[tonic::async_trait]
+impl AdminService for AdminServicer {
+    async fn swap_model(
+        &self, request: Request<SwapModelRequest>
+    ) -> Result<Response<SwapModelResponse>, Status> {
+        let authorized = request.metadata().get("x-admin-token")
+            .and_then(|mv| mv.to_str().ok())
+            .map_or(false, |token| matches_token(token, &self.token_digest));
+        if !authorized {
+            tracing::warn!("Rejected SwapModel call without the admin token");
+            return Err(Status::permission_denied("invalid or missing x-admin-token"))
+        }
+        let sr = request.into_inner();
+        if sr.tokenizer_path.is_empty() || sr.master_shard_uds_path.is_empty() {
+            return Err(Status::invalid_argument("tokenizer_path and master_shard_uds_path are required"))
+        }
+        self.swapper.swap(SwapTarget {
+            tokenizer_path: sr.tokenizer_path,
+            master_shard_uds_path: sr.master_shard_uds_path,
+            replica_master_shard_uds_paths: sr.replica_master_shard_uds_paths,
+            decoder_model_path: sr.decoder_model_path,
+            model_config_path: sr.model_config_path,
+            tokenizer_config_path: sr.tokenizer_config_path,
+        }).await.map_err(|err| {
+            metrics::increment_counter!("tgi_model_swap_failure");
+            tracing::error!("Model swap failed: {err}");
+            Status::failed_precondition(err)
+        })?;
+        Ok(Response::new(SwapModelResponse {}))
+    }
+}
+
 #[tonic::async_trait]
 impl GenerationService for GenerationServicer {
     #[instrument(
         skip_all,
         fields(
-            input=?request.get_ref().requests.iter().map(|r| truncate(&r.text, 32)).collect::<Vec<Cow<'_,str>>>(),
+            input=?request.get_ref().requests.iter().map(|r| redact(&r.text)).collect::<Vec<Cow<'_,str>>>(),
             correlation_id=?request.metadata().get("x-correlation-id").map(|mv| mv.to_str().unwrap_or("<non-ascii>")).unwrap_or("<none>"),
             input_bytes=?request.get_ref().requests.iter().map(|r| r.text.len()).collect::<Vec<usize>>(),
             params=?request.get_ref().params,
+            debug_request=tracing::field::Empty,
         )
     )]
     async fn generate(&self, request: Request<BatchedGenerationRequest>)
         -> Result<Response<BatchedGenerationResponse>, Status> {
         let start_time = Instant::now();
-        let br = request.into_inner();
+        let deployment = self.state.deployment();
+        let request_log = self.state.request_log.as_ref()
+            .map(|rl| (rl, CallerInfo::from_request(&request)));
+        let tenant = tenant_id(&request);
+        let priority = priority(&request)?;
+        let debug = self.debug_requested(&request);
+        let _client_permit = self.client_permit(&request, request.get_ref().requests.len())?;
+        let mut journaled = match self.journal() {
+            Some(_) => journaled_requests(&request),
+            None => vec![],
+        };
+        let mut br = request.into_inner();
+        for req in br.requests.iter_mut() {
+            self.decode_input_token_ids(&deployment, req)?;
+            self.expand_template(req)?;
+            self.compose_registered_prompt(req)?;
+        }
+        let safety_filter = self.state.safety_filter.as_deref();
+        let rejected = match safety_filter {
+            Some(filter) => screen_prompts(filter, &mut br.requests).await,
+            None => vec![],
+        };
+        if !journaled.is_empty() {
+            for (index, _) in rejected.iter().rev() {
+                journaled.remove(*index);
+            }
+        }
         let batch_size = br.requests.len();
         let kind = if batch_size == 1 { "single" } else { "batch" };
         metrics::increment_counter!("tgi_request_count", "kind" => kind);
         if batch_size == 0 {
-            return Ok(Response::new(BatchedGenerationResponse{ responses: vec![] }));
+            let responses = rejected.into_iter().map(|(_, reason)| filtered_response(0, reason)).collect();
+            return Ok(Response::new(BatchedGenerationResponse{ responses }));
         }
         self.input_counter.increment(batch_size as u64);
         // Limit concurrent requests by acquiring a permit from the semaphore
@@ -111,101 +353,730 @@ impl GenerationService for GenerationServicer {
             .try_acquire_many(batch_size as u32)
             .map_err(|_| {
                 metrics::increment_counter!("tgi_request_failure", "err" => "conc_limit");
+                record_rejection(Rejection::ConcurrencyLimit, batch_size);
                 tracing::error!("Model is overloaded");
-                Status::resource_exhausted("Model is overloaded")
+                self.overloaded_status(
+                    "Model is overloaded",
+                    deployment.batcher.retry_hint(self.state.max_concurrent_requests),
+                )
             })?;
 
-        let valids = self.validate(
+        let prompt_hashes = match request_log {
+            Some(_) => br.requests.iter().map(|r| prompt_hash(&r.text)).collect(),
+            None => vec![],
+        };
+
+        let mut valids = self.validate(
+            &deployment,
             br.prefix_id,
             br.params,
-            br.requests.into_iter().map(move |r| r.text).collect(),
+            br.requests.into_iter().map(validation_input).collect(),
             start_time,
         ).await?;
+        let mut journaled = journaled.into_iter();
+        for (_, request) in valids.iter_mut() {
+            request.tenant = tenant.clone();
+            request.priority = priority;
+            request.debug = debug;
+            request.journal = self.journal_queued(journaled.next()).await?;
+        }
+        // Parameters are shared by all requests in the batch
+        let tools = valids[0].1.parameters.tools.clone();
 
-        if batch_size == 1 {
+        let responses = if batch_size == 1 {
             // Single request case
             let (input_length, request) = valids.into_iter().next().unwrap();
-            self.state.batcher.infer(input_length, request)
+            let audit_request = should_audit(&self.state, &deployment, &request).then(|| request.clone());
+            let replay_request = self.state.replay_buffer.as_ref().map(|_| request.clone());
+            deployment.batcher.infer(input_length, request)
                 .map_ok(|response| {
                     log_response(
                         &response.times, input_length, response.gen_token_count, response.reason,
                         &response.output_text, start_time, "single", "Request", response.request_id
                     );
-                    vec![response.into()]
+                    if let Some((rl, caller)) = &request_log {
+                        rl.log(
+                            caller, "single", response.request_id, prompt_hashes[0].clone(),
+                            input_length, response.gen_token_count, response.reason,
+                            &response.times, start_time, &deployment.model,
+                        );
+                    }
+                    if let Some(audit_request) = audit_request {
+                        spawn_audit(
+                            &self.state, &deployment, input_length, audit_request, &response,
+                        );
+                    }
+                    if let (Some(rb), Some(replay_request)) = (&self.state.replay_buffer, replay_request) {
+                        rb.record(input_length, replay_request, &response);
+                    }
+                    vec![with_model(with_tool_call(response.into(), &tools), &deployment.model)]
                 }).await
         } else {
             // Batch size > 1
             let input_tokens = valids.iter().map(|r| r.0).collect::<Vec<usize>>();
-            match self.state.batcher.infer_batch(valids).await {
+            let replay_requests = match &self.state.replay_buffer {
+                Some(_) => valids.iter().map(|(_, r)| Some(r.clone())).collect(),
+                None => vec![None; batch_size],
+            };
+            match deployment.batcher.infer_batch(valids).await {
                 Ok(response_chans) => {
-                    try_join_all(response_chans.into_iter().zip(input_tokens).enumerate()
-                        .map(|(i, (f, in_len))| f.map_ok(move |r| {
+                    let request_log = &request_log;
+                    let prompt_hashes = &prompt_hashes;
+                    let tools = &tools;
+                    let replay_buffer = &self.state.replay_buffer;
+                    let model = &deployment.model;
+                    try_join_all(response_chans.into_iter().zip(input_tokens).zip(replay_requests).enumerate()
+                        .map(|(i, ((f, in_len), replay_request))| f.map_ok(move |r| {
                             log_response(
                                 &r.times, in_len, r.gen_token_count, r.reason,&r.output_text, start_time,
                                 "batch", &format!("Sub-request {} from batch of {}", i + 1, batch_size), r.request_id
                             );
-                            r.into()
+                            if let Some((rl, caller)) = request_log {
+                                rl.log(
+                                    caller, "batch", r.request_id, prompt_hashes[i].clone(),
+                                    in_len, r.gen_token_count, r.reason, &r.times, start_time, model,
+                                );
+                            }
+                            if let (Some(rb), Some(replay_request)) = (replay_buffer, replay_request) {
+                                rb.record(in_len, replay_request, &r);
+                            }
+                            with_model(with_tool_call(r.into(), tools), model)
                         }))
                     ).await
                 },
                 Err(err) => Err(err),
             }
         }.map_err(|err| match err {
-            InferError::RequestQueueFull() => {
+            InferError::RequestQueueFull(hint) => {
                 metrics::increment_counter!("tgi_request_failure", "err" => "queue_full");
-                Status::resource_exhausted(err.to_string())
+                self.overloaded_status(err.to_string(), hint)
+            },
+            InferError::FirstTokenObjective(hint) => {
+                metrics::increment_counter!("tgi_request_failure", "err" => "ttft_objective");
+                self.overloaded_status(err.to_string(), hint)
             },
             _ => {
                 metrics::increment_counter!("tgi_request_failure", "err" => "generate");
                 tracing::error!("{err}");
                 Status::from_error(Box::new(err))
             },
-        }).map(
-            |responses| Response::new(BatchedGenerationResponse{ responses })
-        )
-    }
+        });
 
-    type GenerateStreamStream = ResponseStream<Result<GenerationResponse, Status>, StreamContext>;
+        let mut responses = responses?;
+        if let Some(filter) = safety_filter {
+            for response in responses.iter_mut() {
+                screen_output(filter, response).await;
+            }
+            // Rejected prompts keep their positions in the response
+            for (index, reason) in rejected {
+                responses.insert(index, filtered_response(0, reason));
+            }
+        }
+        Ok(Response::new(BatchedGenerationResponse{ responses }))
+    }
 
     #[instrument(
         skip_all,
         fields(
-            input=?truncate(&request.get_ref().request.as_ref().map(|r| &*r.text).unwrap_or(""), 32),
+            batch_size=request.get_ref().requests.len(),
             correlation_id=?request.metadata().get("x-correlation-id").map(|mv| mv.to_str().unwrap_or("<non-ascii>")).unwrap_or("<none>"),
-            input_bytes=?request.get_ref().request.as_ref().map(|r| r.text.len()).unwrap_or(0),
             params=?request.get_ref().params,
+            debug_request=tracing::field::Empty,
         )
     )]
+    async fn generate_batch(&self, request: Request<BatchedGenerationRequest>)
+        -> Result<Response<GenerateBatchResponse>, Status> {
+        let start_time = Instant::now();
+        let deployment = self.state.deployment();
+        let request_log = self.state.request_log.as_ref()
+            .map(|rl| (rl, CallerInfo::from_request(&request)));
+        let tenant = tenant_id(&request);
+        let priority = priority(&request)?;
+        let debug = self.debug_requested(&request);
+        let _client_permit = self.client_permit(&request, request.get_ref().requests.len())?;
+        let mut journaled: Vec<_> = match self.journal() {
+            Some(_) => journaled_requests(&request).into_iter().map(Some).collect(),
+            None => vec![],
+        };
+        let br = request.into_inner();
+        let batch_size = br.requests.len();
+        metrics::increment_counter!("tgi_request_count", "kind" => "bulk");
+        self.input_counter.increment(batch_size as u64);
+        // Limit concurrent requests by acquiring a permit from the semaphore
+        let _permit = self.state.limit_concurrent_requests
+            .try_acquire_many(batch_size as u32)
+            .map_err(|_| {
+                metrics::increment_counter!("tgi_request_failure", "err" => "conc_limit");
+                record_rejection(Rejection::ConcurrencyLimit, batch_size);
+                tracing::error!("Model is overloaded");
+                self.overloaded_status(
+                    "Model is overloaded",
+                    deployment.batcher.retry_hint(self.state.max_concurrent_requests),
+                )
+            })?;
+
+        // Parameters are shared by all requests, so invalid ones fail the whole call
+        let params = convert_params(br.params).map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            record_rejection(Rejection::Validation, batch_size);
+            tracing::error!("{err}");
+            Status::invalid_argument(err.to_string())
+        })?;
+        let safety_filter = self.state.safety_filter.as_deref();
+
+        // Per-request outcomes, filled in as each stage completes
+        let mut results: Vec<Option<Result<GenerationResponse, Status>>> =
+            (0..batch_size).map(|_| None).collect();
+
+        let mut inputs = vec![];
+        for (index, mut req) in br.requests.into_iter().enumerate() {
+            if let Err(status) = self.decode_input_token_ids(&deployment, &mut req)
+                .and_then(|_| self.expand_template(&mut req))
+                .and_then(|_| self.compose_registered_prompt(&mut req)) {
+                results[index] = Some(Err(status));
+                continue
+            }
+            if let Some(filter) = safety_filter {
+                if let Err(reason) = screen_prompt(filter, &mut req).await {
+                    results[index] = Some(Ok(filtered_response(0, reason)));
+                    continue
+                }
+            }
+            inputs.push((index, req));
+        }
+
+        // Validate each input separately so that failures only affect that request
+        let validated = join_all(inputs.into_iter().map(|(index, req)| {
+            let hash = request_log.as_ref().map(|_| prompt_hash(&req.text));
+            deployment.validation.validate(
+                br.prefix_id.clone(), params.clone(), vec![validation_input(req)],
+            ).map(move |result| (index, hash, result))
+        })).await;
+        metrics::histogram!("tgi_request_validation_duration", start_time.elapsed().as_secs_f64());
+
+        let mut valids = vec![];
+        let mut valid_info = vec![];
+        for (index, hash, result) in validated {
+            match result {
+                Ok(mut requests) => {
+                    let (input_length, mut request) = requests.pop().unwrap();
+                    request.tenant = tenant.clone();
+                    request.priority = priority;
+                    request.debug = debug;
+                    match self.journal_queued(journaled.get_mut(index).and_then(Option::take)).await {
+                        Ok(record) => request.journal = record,
+                        Err(status) => {
+                            results[index] = Some(Err(status));
+                            continue
+                        },
+                    }
+                    valids.push((input_length, request));
+                    valid_info.push((index, hash, input_length));
+                },
+                Err(err) => {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                    record_rejection(Rejection::Validation, 1);
+                    tracing::error!("Request {} from bulk batch of {batch_size}: {err}", index + 1);
+                    results[index] = Some(Err(Status::invalid_argument(err.to_string())));
+                },
+            }
+        }
+
+        if !valids.is_empty() {
+            let response_chans = deployment.batcher.infer_batch(valids).await
+                .map_err(|err| match err {
+                    InferError::RequestQueueFull(hint) => {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "queue_full");
+                        self.overloaded_status(err.to_string(), hint)
+                    },
+                    InferError::FirstTokenObjective(hint) => {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "ttft_objective");
+                        self.overloaded_status(err.to_string(), hint)
+                    },
+                    _ => {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "generate");
+                        tracing::error!("{err}");
+                        Status::from_error(Box::new(err))
+                    },
+                })?;
+            let outputs = join_all(response_chans).await;
+            for ((index, hash, in_len), output) in valid_info.into_iter().zip(outputs) {
+                results[index] = Some(match output {
+                    Ok(r) => {
+                        log_response(
+                            &r.times, in_len, r.gen_token_count, r.reason, &r.output_text, start_time,
+                            "bulk", &format!("Request {} from bulk batch of {}", index + 1, batch_size),
+                            r.request_id,
+                        );
+                        if let (Some((rl, caller)), Some(hash)) = (&request_log, hash) {
+                            rl.log(
+                                caller, "bulk", r.request_id, hash,
+                                in_len, r.gen_token_count, r.reason, &r.times, start_time, &deployment.model,
+                            );
+                        }
+                        let mut response = with_model(with_tool_call(r.into(), &params.tools), &deployment.model);
+                        if let Some(filter) = safety_filter {
+                            screen_output(filter, &mut response).await;
+                        }
+                        Ok(response)
+                    },
+                    Err(err) => {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "generate");
+                        tracing::error!("Request {} from bulk batch of {batch_size}: {err}", index + 1);
+                        Err(Status::from_error(Box::new(err)))
+                    },
+                });
+            }
+        }
+
+        let results = results.into_iter().map(|result| GenerateBatchResult {
+            result: Some(match result.expect("missing bulk request result") {
+                Ok(response) => generate_batch_result::Result::Response(response),
+                Err(status) => generate_batch_result::Result::Error(GenerationError {
+                    code: status.code() as i32,
+                    message: status.message().to_string(),
+                }),
+            }),
+        }).collect();
+        Ok(Response::new(GenerateBatchResponse { results }))
+    }
+
+    type GenerateStreamStream = Pin<Box<dyn Stream<Item = Result<GenerationResponse, Status>> + Send>>;
+
     async fn generate_stream(
         &self, request: Request<SingleGenerationRequest>
     ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        self.stream_generation(request, None).await
+    }
+
+    async fn tokenize(
+        &self, request: Request<BatchedTokenizeRequest>
+    ) -> Result<Response<BatchedTokenizeResponse>, Status> {
+        let deployment = self.state.deployment();
+        let br = request.into_inner();
+
+        // Tokenization is CPU-bound so is kept off the async runtime
+        let tokenizer = deployment.tokenizer.clone();
+        let texts = br.requests.into_iter().map(|tr| tr.text).collect();
+        let encodings = tokio::task::spawn_blocking(
+            move || tokenizer.encode_batch_char_offsets(texts, true)
+        ).await
+            .map_err(|e| Status::internal(format!("tokenization task failed: {e}")))?
+            .map_err(Status::from_error)?;
+
+        let responses = encodings.into_iter().map(|e| TokenizeResponse {
+            token_count: e.len() as u32,
+            tokens: if br.return_tokens { e.get_tokens().to_vec() } else { vec![] },
+            offsets: if br.return_offsets { token_offsets(e.get_offsets()) } else { vec![] },
+        }).collect();
+
+        Ok(Response::new(BatchedTokenizeResponse { responses }))
+    }
+
+    async fn model_info(
+        &self, _request: Request<ModelInfoRequest>
+    ) -> Result<Response<ModelInfoResponse>, Status> {
+        Ok(Response::new(ModelDescription::of(&self.state).into()))
+    }
+
+    async fn list_models(
+        &self, _request: Request<ListModelsRequest>
+    ) -> Result<Response<ListModelsResponse>, Status> {
+        Ok(Response::new(list_models_response(&self.state)))
+    }
+
+    async fn register_prompt(
+        &self, request: Request<RegisterPromptRequest>
+    ) -> Result<Response<RegisterPromptResponse>, Status> {
+        let Some(registry) = &self.state.prompt_registry else {
+            return Err(Status::failed_precondition("prompt registration isn't enabled"))
+        };
+        let text = request.into_inner().text;
+
+        // Tokenization is CPU-bound so is kept off the async runtime
+        let tokenizer = self.state.deployment().tokenizer.clone();
+        let (text, encoding) = tokio::task::spawn_blocking(move || {
+            let encoding = tokenizer.encode(&text[..], true);
+            (text, encoding)
+        }).await
+            .map_err(|e| Status::internal(format!("tokenization task failed: {e}")))?;
+        let token_count = encoding.map_err(Status::from_error)?.len();
+        // Requests composed from it would always fail validation
+        if token_count >= self.state.max_sequence_length {
+            return Err(Status::invalid_argument(format!(
+                "prompt tokens ({token_count}) must be < {}", self.state.max_sequence_length,
+            )))
+        }
+        let prompt_id = registry.register(text).map_err(Status::invalid_argument)?;
+        Ok(Response::new(RegisterPromptResponse { prompt_id, token_count: token_count as u32 }))
+    }
+
+    async fn release_prompt(
+        &self, request: Request<ReleasePromptRequest>
+    ) -> Result<Response<ReleasePromptResponse>, Status> {
+        let Some(registry) = &self.state.prompt_registry else {
+            return Err(Status::failed_precondition("prompt registration isn't enabled"))
+        };
+        let prompt_id = request.into_inner().prompt_id;
+        if !registry.release(&prompt_id) {
+            return Err(Status::not_found(format!("prompt '{prompt_id}' not found")))
+        }
+        Ok(Response::new(ReleasePromptResponse {}))
+    }
+
+    async fn submit_generation(
+        &self, request: Request<SingleGenerationRequest>
+    ) -> Result<Response<SubmitGenerationResponse>, Status> {
+        let Some(jobs) = self.state.generation_jobs.clone() else {
+            return Err(Status::failed_precondition("generation jobs aren't enabled"))
+        };
+        let generation_id = GenerationJobs::new_generation_id();
+        // Runs as a stream, whose responses are accumulated by the job
+        let stream = match self.stream_generation(request, Some(generation_id.clone())).await {
+            Ok(stream) => stream.into_inner(),
+            Err(status) => {
+                if let Some(journal) = &jobs.journal {
+                    journal.done(generation_id);
+                }
+                return Err(status)
+            },
+        };
+        jobs.submit(generation_id.clone(), stream).await;
+        Ok(Response::new(SubmitGenerationResponse { generation_id }))
+    }
+
+    async fn get_generation(
+        &self, request: Request<GetGenerationRequest>
+    ) -> Result<Response<GetGenerationResponse>, Status> {
+        let Some(jobs) = &self.state.generation_jobs else {
+            return Err(Status::failed_precondition("generation jobs aren't enabled"))
+        };
+        let GetGenerationRequest { generation_id, wait_millis } = request.into_inner();
+        jobs.get(&generation_id, Duration::from_millis(wait_millis as u64)).await
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found(format!("generation '{generation_id}' not found")))
+    }
+
+    #[instrument(skip_all, fields(input_count = request.get_ref().requests.len()))]
+    async fn embed(
+        &self, request: Request<BatchedEmbeddingRequest>
+    ) -> Result<Response<BatchedEmbeddingResponse>, Status> {
         let start_time = Instant::now();
+        let deployment = self.state.deployment();
+        let Some(embeddings) = deployment.embeddings.clone() else {
+            return Err(Status::failed_precondition("embeddings aren't enabled"))
+        };
+        let br = request.into_inner();
+        metrics::increment_counter!("tgi_embedding_request_count");
+        metrics::counter!("tgi_embedding_input_count", br.requests.len() as u64);
+
+        // Tokenization is CPU-bound so is kept off the async runtime
+        let tokenizer = deployment.tokenizer.clone();
+        let texts = br.requests.into_iter().map(|er| er.text).collect::<Vec<_>>();
+        let (texts, encodings) = tokio::task::spawn_blocking(move || {
+            let encodings = tokenizer.encode_batch(texts.clone(), true);
+            (texts, encodings)
+        }).await
+            .map_err(|e| Status::internal(format!("tokenization task failed: {e}")))?;
+        let encodings = encodings.map_err(Status::from_error)?;
+
+        let truncate = br.truncate_input_tokens as usize;
+        let inputs = texts.into_iter().zip(encodings).map(|(text, encoding)| {
+            let input_length = match encoding.len() {
+                length if truncate > 0 => length.min(truncate),
+                length => length,
+            };
+            if input_length > self.state.max_sequence_length {
+                let err = ValidationError::EmbedInputLength(input_length, self.state.max_sequence_length);
+                metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                record_rejection(Rejection::Validation, 1);
+                return Err(Status::invalid_argument(err.to_string()))
+            }
+            Ok((text, input_length))
+        }).collect::<Result<Vec<_>, _>>()?;
+        let input_lengths = inputs.iter().map(|(_, length)| *length as u32).collect::<Vec<_>>();
+
+        let vectors = embeddings.embed(inputs, br.truncate_input_tokens).await.map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "embedding");
+            tracing::error!("{err}");
+            Status::from_error(Box::new(err))
+        })?;
+        let responses = vectors.into_iter().zip(input_lengths).map(|(mut embedding, input_token_count)| {
+            if br.normalize {
+                normalize(&mut embedding);
+            }
+            EmbeddingResponse { embedding, input_token_count }
+        }).collect();
+        metrics::histogram!("tgi_embedding_request_duration", start_time.elapsed().as_secs_f64());
+        Ok(Response::new(BatchedEmbeddingResponse { responses }))
+    }
+
+    async fn score(
+        &self, request: Request<BatchedScoreRequest>
+    ) -> Result<Response<BatchedScoreResponse>, Status> {
+        if self.state.deployment().seq2seq {
+            return Err(Status::failed_precondition("scoring is only supported for decoder-only models"))
+        }
+        // Scored as echo requests, whose input is processed without generating
+        let request = request.map(|r| BatchedGenerationRequest {
+            model_id: r.model_id,
+            prefix_id: r.prefix_id,
+            requests: r.requests.into_iter()
+                .map(|r| GenerationRequest { text: r.text, ..Default::default() })
+                .collect(),
+            params: Some(Parameters {
+                response: Some(ResponseOptions {
+                    input_tokens: true,
+                    token_logprobs: true,
+                    token_ranks: true,
+                    top_n_tokens: r.top_n_tokens,
+                    echo: true,
+                    ..Default::default()
+                }),
+                truncate_input_tokens: r.truncate_input_tokens,
+                ..Default::default()
+            }),
+        });
+        let response = self.generate(request).await?;
+        Ok(response.map(|r| BatchedScoreResponse {
+            responses: r.responses.into_iter().map(score_response).collect(),
+        }))
+    }
+}
+
+/// Score of an input from the response to its echo request
+fn score_response(response: GenerationResponse) -> ScoreResponse {
+    // The first token isn't scored since there's nothing preceding it
+    let logprobs = response.input_tokens.iter()
+        .map(|t| t.logprob).filter(|lp| lp.is_finite()).collect::<Vec<_>>();
+    let nll = -logprobs.iter().sum::<f32>();
+    ScoreResponse {
+        tokens: response.input_tokens,
+        input_token_count: response.input_token_count,
+        nll,
+        perplexity: if logprobs.is_empty() { 1.0 } else { (nll / logprobs.len() as f32).exp() },
+    }
+}
+
+pub struct StreamContext {
+    span: Span,
+    input_token_count: usize,
+    start_time: Instant,
+    request_log: Option<(RequestLogger, CallerInfo, String)>,
+    model: Arc<ModelIdentity>,
+    _permit: OwnedSemaphorePermit, // dropped (released) when the stream is dropped
+    _client_permit: Option<ClientPermit>,
+}
+
+impl GenerationServicer {
+    /// Resource exhausted status including the current queue length and
+    /// estimated wait as metadata, and backoff hints as details, so that
+    /// callers can decide when to retry
+    fn overloaded_status(&self, message: impl Into<String>, hint: RetryHint) -> Status {
+        let estimate = self.state.deployment().batcher.queue_estimate();
+        let details = OverloadedDetails {
+            queue_length: hint.queue_length,
+            limit: hint.limit,
+            retry_after_millis: hint.retry_after_millis as u32,
+        };
+        let mut status = Status::with_details(
+            Code::ResourceExhausted, message, details.encode_to_vec().into(),
+        );
+        let metadata = status.metadata_mut();
+        metadata.insert("x-retry-after-ms", hint.retry_after_millis.into());
+        metadata.insert("x-queue-length", (estimate.position - 1).into());
+        if let Some(wait) = estimate.wait {
+            metadata.insert("x-estimated-wait-ms", (wait.as_millis() as u64).into());
+        }
+        status
+    }
+
+    /// Fill in the text of a pre-tokenized request with its decoded input token ids, so
+    /// that it's seen by the safety filter and request log. The ids are still what's passed
+    /// to the model, unless the safety filter redacts the text
+    fn decode_input_token_ids(
+        &self, deployment: &Deployment, request: &mut GenerationRequest,
+    ) -> Result<(), Status> {
+        if request.input_token_ids.is_empty() {
+            return Ok(())
+        }
+        let conflicting = [
+            ("text", !request.text.is_empty()),
+            ("suffix", request.suffix.is_some()),
+            ("template", request.template.is_some()),
+            ("prompt_id", request.prompt_id.is_some()),
+        ].into_iter().find(|(_, set)| *set);
+        let decoded = match conflicting {
+            Some((field, _)) => Err(format!("{field} can't be used with input_token_ids")),
+            // Ids outside the vocabulary are skipped here, and rejected by validation
+            None => deployment.tokenizer.decode(request.input_token_ids.clone(), false)
+                .map_err(|err| ValidationError::Tokenizer(err.to_string()).to_string()),
+        };
+        request.text = decoded.map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            record_rejection(Rejection::Validation, 1);
+            tracing::error!("{err}");
+            Status::invalid_argument(err)
+        })?;
+        Ok(())
+    }
+
+    /// Replace the text of a request which references a prompt template with the
+    /// template's expansion
+    fn expand_template(&self, request: &mut GenerationRequest) -> Result<(), Status> {
+        let Some(template) = request.template.take() else {
+            return Ok(())
+        };
+        let expanded = match &self.state.prompt_templates {
+            _ if !request.text.is_empty() => Err("text must be empty when a template is used".to_string()),
+            Some(templates) => templates.expand(&template.name, &template.variables),
+            None => Err("prompt templates aren't configured".to_string()),
+        };
+        request.text = expanded.map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            record_rejection(Rejection::Validation, 1);
+            tracing::error!("{err}");
+            Status::invalid_argument(err)
+        })?;
+        Ok(())
+    }
+
+    /// Prepend the text of the registered prompt which a request references, if any
+    fn compose_registered_prompt(&self, request: &mut GenerationRequest) -> Result<(), Status> {
+        let Some(prompt_id) = request.prompt_id.take() else {
+            return Ok(())
+        };
+        let Some(registry) = &self.state.prompt_registry else {
+            return Err(Status::failed_precondition("prompt registration isn't enabled"))
+        };
+        let Some(prompt) = registry.get(&prompt_id) else {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            record_rejection(Rejection::Validation, 1);
+            tracing::error!("Registered prompt '{prompt_id}' not found");
+            return Err(Status::not_found(format!("prompt '{prompt_id}' not found, it may have expired")))
+        };
+        request.text.insert_str(0, &prompt.text);
+        Ok(())
+    }
+
+    /// Whether the request presents the admin token in x-debug-request, in which case it's
+    /// logged at DEBUG level throughout its processing. Recorded in the current span
+    fn debug_requested<T>(&self, request: &Request<T>) -> bool {
+        let Some(value) = request.metadata().get("x-debug-request") else {
+            return false
+        };
+        let authorized = value.to_str().ok().zip(self.state.admin_token_digest.as_deref())
+            .map_or(false, |(token, digest)| matches_token(token, digest));
+        if authorized {
+            Span::current().record("debug_request", true);
+            tracing::debug!("Request flagged for debugging");
+        } else {
+            tracing::warn!("Ignoring x-debug-request header which isn't the admin token");
+        }
+        authorized
+    }
+
+    /// Reserve capacity for the requests within the calling client's concurrency limit, if any
+    fn client_permit<T>(&self, request: &Request<T>, count: usize) -> Result<Option<ClientPermit>, Status> {
+        let Some(limiter) = &self.state.client_limiter else {
+            return Ok(None)
+        };
+        let client = grpc_client_identity(request);
+        limiter.try_acquire(client.clone(), count).map(Some).ok_or_else(|| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "client_conc_limit");
+            record_rejection(Rejection::ClientConcurrencyLimit, count);
+            tracing::error!("Concurrent request limit exceeded for client {client}");
+            self.overloaded_status(
+                "Too many concurrent requests from this client",
+                self.state.deployment().batcher.retry_hint(limiter.max_per_client()),
+            )
+        })
+    }
+
+    /// Run a streaming generation, journaled as a generation job if it has a generation id
+    #[instrument(
+        skip_all,
+        fields(
+            input=?redact(request.get_ref().request.as_ref().map(|r| &*r.text).unwrap_or("")),
+            correlation_id=?request.metadata().get("x-correlation-id").map(|mv| mv.to_str().unwrap_or("<non-ascii>")).unwrap_or("<none>"),
+            input_bytes=?request.get_ref().request.as_ref().map(|r| r.text.len()).unwrap_or(0),
+            params=?request.get_ref().params,
+            debug_request=tracing::field::Empty,
+        )
+    )]
+    pub(crate) async fn stream_generation(
+        &self, request: Request<SingleGenerationRequest>, generation_id: Option<String>,
+    ) -> Result<Response<<Self as GenerationService>::GenerateStreamStream>, Status> {
+        let start_time = Instant::now();
+        let deployment = self.state.deployment();
         metrics::increment_counter!("tgi_request_count", "kind" => "stream");
         self.input_counter.increment(1);
         let permit = self.state.limit_concurrent_requests.clone()
             .try_acquire_owned().map_err(|_| {
                 metrics::increment_counter!("tgi_request_failure", "err" => "conc_limit");
+                record_rejection(Rejection::ConcurrencyLimit, 1);
                 tracing::error!("Model is overloaded");
-                Status::resource_exhausted("Model is overloaded")
+                self.overloaded_status(
+                    "Model is overloaded",
+                    deployment.batcher.retry_hint(self.state.max_concurrent_requests),
+                )
         })?;
+        let caller = self.state.request_log.as_ref().map(|_| CallerInfo::from_request(&request));
+        let tenant = tenant_id(&request);
+        let priority = priority(&request)?;
+        let debug = self.debug_requested(&request);
+        let client_permit = self.client_permit(&request, 1)?;
+        let journaled = self.journal().map(|_| match generation_id {
+            Some(generation_id) => JournaledRequest::job(generation_id, request.metadata(), request.get_ref()),
+            None => JournaledRequest::queued(request.metadata(), request.get_ref(), None),
+        });
         let sr = request.into_inner();
-        let req = sr.request.ok_or_else(
+        let mut req = sr.request.ok_or_else(
             || Status::invalid_argument("missing request")
         )?;
+        self.decode_input_token_ids(&deployment, &mut req)?;
+        self.expand_template(&mut req)?;
+        self.compose_registered_prompt(&mut req)?;
+        if let Some(filter) = self.state.safety_filter.as_deref() {
+            if let Err(reason) = screen_prompt(filter, &mut req).await {
+                return Ok(Response::new(Box::pin(once(ready(Ok(filtered_response(0, reason)))))))
+            }
+        }
+        let request_log = caller.map(|caller| (
+            self.state.request_log.clone().unwrap(), caller, prompt_hash(&req.text),
+        ));
+
+        let unsupported = sr.params.as_ref().and_then(|p| if !p.tools.is_empty() {
+            Some(ValidationError::ToolStreaming)
+        } else if p.response.as_ref().map_or(false, |r| r.echo) {
+            Some(ValidationError::EchoStreaming)
+        } else {
+            None
+        });
+        if let Some(err) = unsupported {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            record_rejection(Rejection::Validation, 1);
+            tracing::error!("{err}");
+            return Err(Status::invalid_argument(err.to_string()))
+        }
 
         // Validate request
-        let (input_length, validated_request) = self
-            .validate(sr.prefix_id, sr.params, vec![req.text], start_time)
+        let (input_length, mut validated_request) = self
+            .validate(&deployment, sr.prefix_id, sr.params, vec![validation_input(req)], start_time)
             .await?
             .pop().unwrap();
+        validated_request.tenant = tenant;
+        validated_request.priority = priority;
+        validated_request.debug = debug;
+        validated_request.journal = self.journal_queued(journaled).await?;
 
-        let stream = self.state.batcher
+        let stream = deployment.batcher
             .infer_stream(input_length, validated_request, |r| match r {
                 Ok(resp) => Ok(resp.into()),
                 Err(err) => Err(Status::from_error(Box::new(err))),
             }, |ctx, count, reason, request_id, times, out, err| {
                 let _enter = ctx.span.enter();
-                if let Some(e) = err {
+                if let Some(e) = &err {
                     metrics::increment_counter!("tgi_request_failure", "err" => "generate");
                     tracing::error!("Streaming response failed after {count} tokens, \
                         output so far: '{out}': {e}");
@@ -216,17 +1087,31 @@ impl GenerationService for GenerationServicer {
                         "stream", "Streaming response", request_id
                     );
                 }
+                if let Some((rl, caller, prompt_hash)) = &ctx.request_log {
+                    let reason = if err.is_some() { Error } else { reason };
+                    rl.log(
+                        caller, "stream", request_id, prompt_hash.clone(),
+                        ctx.input_token_count, count, reason, &times, ctx.start_time, &ctx.model,
+                    );
+                }
             }, StreamContext {
                 span: Span::current(),
                 input_token_count: input_length,
                 start_time,
+                request_log,
+                model: deployment.model.clone(),
                 _permit: permit,
+                _client_permit: client_permit,
             })
             .await
             .map_err(|err| match err {
-                InferError::RequestQueueFull() => {
+                InferError::RequestQueueFull(hint) => {
                     metrics::increment_counter!("tgi_request_failure", "err" => "queue_full");
-                    Status::resource_exhausted(err.to_string())
+                    self.overloaded_status(err.to_string(), hint)
+                },
+                InferError::FirstTokenObjective(hint) => {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "ttft_objective");
+                    self.overloaded_status(err.to_string(), hint)
                 },
                 _ => {
                     metrics::increment_counter!("tgi_request_failure", "err" => "unknown");
@@ -235,64 +1120,50 @@ impl GenerationService for GenerationServicer {
                 },
             })?;
 
+        // Progress updates have no other fields set
+        let model = deployment.model.clone();
+        let stream = stream.map_ok(move |response: GenerationResponse| match response.prefill_progress {
+            Some(_) => response,
+            None => with_model(response, &model),
+        });
+
         // Inference
-        Ok(Response::new(stream))
+        Ok(Response::new(match self.state.safety_filter.clone() {
+            Some(filter) => Box::pin(screen_stream(filter, stream)),
+            None => Box::pin(stream),
+        }))
     }
 
-    async fn tokenize(
-        &self, request: Request<BatchedTokenizeRequest>
-    ) -> Result<Response<BatchedTokenizeResponse>, Status> {
-        let br = request.into_inner();
-
-        let responses = self.tokenizer.encode_batch(
-            br.requests.into_iter().map(|tr| tr.text).collect(), true
-        )
-            .map_err(Status::from_error)?
-            .into_iter().map(|e| TokenizeResponse {
-                token_count: e.len() as u32,
-                tokens: if br.return_tokens { e.get_tokens().to_vec() } else { vec![] },
-        }).collect();
-
-        Ok(Response::new(BatchedTokenizeResponse { responses }))
+    /// Journal of queued requests, if there is one
+    fn journal(&self) -> Option<&Arc<JobJournal>> {
+        self.state.generation_jobs.as_ref()?.journal.as_ref()
     }
 
-    async fn model_info(
-        &self, _request: Request<ModelInfoRequest>
-    ) -> Result<Response<ModelInfoResponse>, Status> {
-        Ok(Response::new(ModelInfoResponse {
-            model_kind: i32::from(if self.state.seq2seq {
-                ModelKind::EncoderDecoder
-            } else {
-                ModelKind::DecoderOnly
-            }),
-            max_sequence_length: self.state.max_sequence_length as u32,
-            max_new_tokens: self.state.max_new_tokens as u32,
-        }))
+    /// Record a request in the journal before it's queued, if it's journaled
+    async fn journal_queued(&self, request: Option<JournaledRequest>) -> Result<QueuedRecord, Status> {
+        match (self.journal(), request) {
+            (Some(journal), Some(request)) => journal.queued(request).await.map_err(Status::internal),
+            _ => Ok(QueuedRecord::default()),
+        }
     }
-}
-
-pub struct StreamContext {
-    span: Span,
-    input_token_count: usize,
-    start_time: Instant,
-    _permit: OwnedSemaphorePermit, // dropped (released) when the stream is dropped
-}
 
-impl GenerationServicer {
     pub(crate) async fn validate(
         &self,
+        deployment: &Deployment,
         prefix_id: Option<String>,
         parameters: Option<Parameters>,
-        inputs: Vec<String>,
+        inputs: Vec<Input>,
         start_time: Instant,
     ) -> Result<Vec<(usize, GenerateRequest)>, Status> {
+        let input_count = inputs.len();
         match convert_params(parameters) {
-            Ok(params) => self.state.validation.validate(
+            Ok(params) => deployment.validation.validate(
                 prefix_id, params, inputs
             ).await,
             Err(err) => Err(err),
         }.map_err(|err| {
             metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            record_rejection(Rejection::Validation, input_count);
             tracing::error!("{err}");
             Status::invalid_argument(err.to_string())
         }).map(|requests| {
@@ -302,6 +1173,47 @@ impl GenerationServicer {
     }
 }
 
+/// Each of a batch of requests in the form in which it's journaled, to be resubmitted alone
+fn journaled_requests(request: &Request<BatchedGenerationRequest>) -> Vec<JournaledRequest> {
+    let br = request.get_ref();
+    let parts = br.requests.len() > 1;
+    br.requests.iter().enumerate().map(|(index, req)| JournaledRequest::queued(
+        request.metadata(),
+        &SingleGenerationRequest {
+            model_id: br.model_id.clone(),
+            prefix_id: br.prefix_id.clone(),
+            request: Some(req.clone()),
+            params: br.params.clone(),
+        },
+        parts.then_some(index),
+    )).collect()
+}
+
+/// Input of a request to validate, its token ids if it was pre-tokenized
+fn validation_input(request: GenerationRequest) -> Input {
+    if request.input_token_ids.is_empty() {
+        Input::Text(request.text, request.suffix)
+    } else {
+        Input::TokenIds(request.input_token_ids, request.text)
+    }
+}
+
+/// Tenant that the request is attributed to for queue fairness purposes
+fn tenant_id<T>(request: &Request<T>) -> Option<String> {
+    request.metadata().get("x-tenant-id")
+        .and_then(|mv| mv.to_str().ok()).map(str::to_string)
+}
+
+/// Scheduling priority of the request, higher is more urgent. Defaults to 0
+fn priority<T>(request: &Request<T>) -> Result<i32, Status> {
+    match request.metadata().get("x-priority") {
+        Some(mv) => mv.to_str().ok().and_then(|p| p.parse().ok())
+            .ok_or_else(|| Status::invalid_argument("x-priority must be an integer")),
+        None => Ok(0),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn log_response(
     times: &Option<Times>,
     input_tokens: usize,
@@ -344,7 +1256,8 @@ fn log_response(
     // Metrics
     match reason {
         Error => metrics::increment_counter!("tgi_request_failure", "err" => "generate"),
-        Cancelled => (), // recorded where cancellation is detected
+        // recorded where cancellation or the queue timeout is detected
+        Cancelled | SlowConsumer | QueueTimeLimit => (),
         _ => {
             metrics::increment_counter!(
                 "tgi_request_success", "stop_reason" => reason.as_str_name(), "kind" => kind
@@ -358,12 +1271,12 @@ fn log_response(
     }
 
     let len = output.len();
-    let output = truncate(output, 32);
+    let output = redact(output);
     match reason {
         Error => tracing::error!(
             "{kind_log} generated {generated_tokens} tokens before {reason:?}, output {len} bytes: {output:?}",
         ),
-        Cancelled | TokenLimit => tracing::warn!(
+        Cancelled | SlowConsumer | Preempted | ServerShutdown | QueueTimeLimit | TokenLimit => tracing::warn!(
             "{kind_log} generated {generated_tokens} tokens before {reason:?}, output {len} bytes: {output:?}",
         ),
         _ => tracing::info!(
@@ -372,64 +1285,99 @@ fn log_response(
     };
 }
 
-fn truncate(string: &str, len: usize) -> Cow<str> {
-    let orig_len = string.len();
-    let (string, tlen) = string.unicode_truncate(len);
-    if tlen == orig_len {
-        string.into()
-    } else {
-       [string, "..."].concat().into()
-    }
-}
-
 fn convert_params(params: Option<Parameters>) -> Result<GenerateParameters, ValidationError> {
     match params {
         Some(p) => {
             let mut gp = default_parameters();
             // Input token truncation
             gp.truncate_input_tokens = p.truncate_input_tokens as usize;
+            gp.truncation_side = match p.truncation_side() {
+                ProtoTruncationSide::Left => TruncationSide::Left,
+                ProtoTruncationSide::Right => TruncationSide::Right,
+                ProtoTruncationSide::Middle => TruncationSide::Middle,
+            };
             // Response Options
             if let Some(r) = p.response {
                 gp.include_input_text = r.input_text;
                 gp.include_input_tokens = r.input_tokens;
+                gp.include_input_offsets = r.input_token_offsets;
+                gp.echo = r.echo;
+                gp.stream_chunk_tokens = r.stream_chunk_tokens;
                 gp.include_gen_tokens = r.generated_tokens;
                 gp.include_logprobs = r.token_logprobs;
                 gp.include_ranks = r.token_ranks;
                 gp.include_top_n = r.top_n_tokens;
+                gp.include_sequence_logprob = r.sequence_logprob;
+                gp.include_trace = r.trace;
+                gp.skip_special_tokens = r.skip_special_tokens;
+                gp.clean_up_tokenization_spaces = r.clean_up_tokenization_spaces;
             }
             // Decoding Parameters
             if let Some(d) = p.decoding {
                 if d.repetition_penalty != 0.0 {
                     gp.repetition_penalty = d.repetition_penalty;
-                    gp.length_penalty = d.length_penalty
-                        .map(|lp| (lp.start_index, lp.decay_factor));
                 }
+                gp.length_penalty = d.length_penalty
+                    .map(|lp| (lp.start_index, lp.decay_factor));
+                gp.repetition_penalty_range = d.repetition_penalty_range;
+                gp.no_repeat_ngram_size = d.no_repeat_ngram_size;
+                gp.token_healing = d.token_healing;
+                gp.bad_words = d.bad_words;
+                gp.banned_token_ids = d.banned_token_ids;
+                gp.watermark = d.watermark.map(|wm| (
+                    if wm.gamma == 0.0 { DEFAULT_WATERMARK_GAMMA } else { wm.gamma },
+                    if wm.delta == 0.0 { DEFAULT_WATERMARK_DELTA } else { wm.delta },
+                ));
             }
             // Stopping Criteria
             if let Some(s) = p.stopping {
-                if s.max_new_tokens != 0 { gp.max_new_tokens = s.max_new_tokens }
+                if s.max_new_tokens != 0 {
+                    gp.max_new_tokens = s.max_new_tokens;
+                    gp.unset.max_new_tokens = false;
+                }
                 gp.min_new_tokens = s.min_new_tokens;
                 gp.stop_seqs = s.stop_sequences;
+                gp.stop_token_ids = s.stop_token_ids.into_iter().map(|sts| sts.token_ids).collect();
+                gp.stop_regex = s.stop_regex;
+                gp.min_token_logprob = s.min_token_logprob;
+                gp.min_mean_logprob = s.min_mean_logprob;
                 if s.time_limit_millis > 0 {
-                    gp.deadline = Some(Instant::now()
-                        .add(Duration::from_millis(s.time_limit_millis as u64)));
+                    let time_limit = Duration::from_millis(s.time_limit_millis as u64);
+                    gp.deadline = Some(Instant::now().add(time_limit));
+                    gp.time_limit = Some(time_limit);
+                }
+                if s.max_time_ms > 0 {
+                    gp.max_time = Some(Duration::from_millis(s.max_time_ms as u64));
                 }
             }
+            // Tools
+            gp.tools = p.tools.into_iter().map(|t| match serde_json::from_str(&t.parameters) {
+                Ok(parameters) => Ok(ToolDefinition { name: t.name, description: t.description, parameters }),
+                Err(_) => Err(ValidationError::ToolSchema(t.name)),
+            }).collect::<Result<_, _>>()?;
             // Sampling Parameters
             if p.method == DecodingMethod::Sample as i32 {
+                gp.unset.top_p = true;
                 if let Some(s) = p.sampling {
                     gp.temperature = s.temperature;
                     gp.top_k = s.top_k as i32;
-                    if s.top_p != 0.0 { gp.top_p = s.top_p }
+                    if s.top_p != 0.0 {
+                        gp.top_p = s.top_p;
+                        gp.unset.top_p = false;
+                    }
                     gp.typical_p = s.typical_p;
                     gp.seed = s.seed;
+                    gp.temperature_schedule = s.temperature_schedule.into_iter()
+                        .map(|b| (b.token_index, b.temperature)).collect();
                 }
                 if gp.temperature == 0.0 {
                     gp.temperature = 1.0; // sampling and temp 0 => disabled i.e. temp 1
+                    gp.unset.temperature = true;
                 }
             } else if STRICT_PARAMETER_VALIDATION {
                 if let Some(s) = p.sampling {
-                    if s.temperature != 0.0 || s.top_p != 0.0 || s.top_k != 0 || s.seed.is_some() {
+                    if s.temperature != 0.0 || s.top_p != 0.0 || s.top_k != 0 || s.seed.is_some()
+                        || !s.temperature_schedule.is_empty() {
                         return Err(ValidationError::SampleParametersGreedy)
                     }
                 }
@@ -441,16 +1389,79 @@ fn convert_params(params: Option<Parameters>) -> Result<GenerateParameters, Vali
     }
 }
 
+/// Set the parsed tool call in the response if tools were provided
+fn with_tool_call(mut response: GenerationResponse, tools: &[ToolDefinition]) -> GenerationResponse {
+    if !tools.is_empty() {
+        response.tool_call = parse_tool_call(tools, &response.text);
+    }
+    response
+}
+
+/// Set the id and version of the model which produced the response
+fn with_model(mut response: GenerationResponse, model: &ModelIdentity) -> GenerationResponse {
+    response.model_id = model.id.clone();
+    response.model_version = model.version.clone();
+    response
+}
+
+/// Convert tokenizer offsets, special tokens have empty offsets
+fn token_offsets(offsets: &[(usize, usize)]) -> Vec<TokenOffset> {
+    offsets.iter().map(|&(start, end)| TokenOffset { start: start as u32, end: end as u32 }).collect()
+}
+
+/// Attach offsets to the input tokens. These are aligned from the end since
+/// the shards may have truncated the start of the input
+fn with_offsets(mut tokens: Vec<TokenInfo>, offsets: &[(usize, usize)]) -> Vec<TokenInfo> {
+    for (token, offset) in tokens.iter_mut().rev().zip(token_offsets(offsets).into_iter().rev()) {
+        token.offset = Some(offset);
+    }
+    tokens
+}
+
 impl From<InferResponse> for GenerationResponse {
     fn from(resp: InferResponse) -> Self {
+        let usage = resp.usage.as_ref().map(|usage| {
+            let times = &usage.times;
+            GenerationUsage {
+                input_token_count: usage.in_token_count,
+                generated_token_count: resp.gen_token_count,
+                stop_reason: resp.reason as i32,
+                queue_time_millis: (times.start - times.queued).as_millis() as u64,
+                time_to_first_token_millis: times.first_token
+                    .map(|first| first.saturating_duration_since(times.start).as_millis() as u64),
+                generation_time_millis: (times.end - times.start).as_millis() as u64,
+                prefill_time_millis: times.prefill.map(|p| p.as_millis() as u64),
+                batches_waited: times.batches_waited,
+            }
+        });
         Self{
             input_token_count: resp.in_token_count,
             text: resp.output_text,
             generated_token_count: resp.gen_token_count,
             stop_reason: resp.reason as i32,
-            tokens: resp.tokens.to_final_vec(),
-            input_tokens: resp.in_tokens.to_final_vec(),
+            tokens: resp.tokens.into_final_vec(),
+            input_tokens: with_offsets(resp.in_tokens.into_final_vec(), &resp.in_token_offsets),
             seed: resp.seed,
+            sequence_logprob: resp.sequence_logprob,
+            perplexity: resp.sequence_logprob
+                .filter(|_| resp.gen_token_count > 0)
+                .map(|lp| (-lp / resp.gen_token_count as f32).exp()),
+            tool_call: None,
+            queue_position: resp.queue_estimate.map(|qe| qe.position),
+            estimated_wait_millis: resp.queue_estimate
+                .and_then(|qe| qe.wait).map(|w| w.as_millis() as u32),
+            trace: resp.trace.map(Into::into),
+            usage,
+            prefill_progress: resp.prefill_progress.map(|p| PrefillProgress {
+                chunks_processed: p.chunks_processed,
+                total_chunks: p.total_chunks,
+            }),
+            warnings: resp.warnings,
+            stop_details: resp.stop_details,
+            normalized_params: resp.normalized_params,
+            // Set by the caller, which knows the deployment
+            model_id: String::new(),
+            model_version: String::new(),
         }
     }
 }
\ No newline at end of file
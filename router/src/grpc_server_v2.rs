@@ -0,0 +1,141 @@
+/// Version 2 of the external gRPC API, served alongside version 1 by translating
+/// requests to it and its responses and errors back
+use std::pin::Pin;
+use std::sync::Arc;
+use futures::{Stream, StreamExt};
+use prost::Message;
+use tonic::{Code, Request, Response, Status};
+use crate::grpc_server::GenerationServicer;
+use crate::pb::fmaas::{
+    BatchedGenerationRequest, GenerationResponse, OverloadedDetails, SingleGenerationRequest,
+};
+use crate::pb::fmaas::generation_service_server::GenerationService as GenerationServiceV1;
+use crate::pb::fmaas::v2::{
+    Error, GenerateRequest, GenerateResponse, GenerateStreamRequest, GenerateStreamResponse,
+    GenerationResult, Usage,
+};
+use crate::pb::fmaas::v2::error::Kind;
+use crate::pb::fmaas::v2::generate_stream_response::Event;
+use crate::pb::fmaas::v2::generation_service_server::GenerationService;
+
+pub(crate) struct GenerationServicerV2 {
+    v1: Arc<GenerationServicer>,
+}
+
+impl GenerationServicerV2 {
+    pub(crate) fn new(v1: Arc<GenerationServicer>) -> Self {
+        Self { v1 }
+    }
+}
+
+#[tonic::async_trait]
+impl GenerationService for GenerationServicerV2 {
+    async fn generate(&self, request: Request<GenerateRequest>)
+        -> Result<Response<GenerateResponse>, Status> {
+        // Metadata and extensions such as the caller's address are passed through
+        let request = request.map(|r| BatchedGenerationRequest {
+            model_id: r.model_id,
+            prefix_id: r.prefix_id,
+            requests: r.requests,
+            params: r.params,
+        });
+        let response = GenerationServiceV1::generate(&*self.v1, request).await
+            .map_err(into_v2_status)?;
+        Ok(response.map(|r| GenerateResponse {
+            results: r.responses.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    type GenerateStreamStream = Pin<Box<dyn Stream<Item = Result<GenerateStreamResponse, Status>> + Send>>;
+
+    async fn generate_stream(&self, request: Request<GenerateStreamRequest>)
+        -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let request = request.map(|r| SingleGenerationRequest {
+            model_id: r.model_id,
+            prefix_id: r.prefix_id,
+            request: r.request,
+            params: r.params,
+        });
+        let response = GenerationServiceV1::generate_stream(&*self.v1, request).await
+            .map_err(into_v2_status)?;
+        Ok(response.map(|stream| Box::pin(stream.map(|item| item
+            .map(|r| GenerateStreamResponse { event: Some(r.into()) })
+            .map_err(into_v2_status)
+        )) as Self::GenerateStreamStream))
+    }
+}
+
+impl From<GenerationResponse> for Event {
+    fn from(response: GenerationResponse) -> Self {
+        match response.prefill_progress {
+            Some(progress) => Event::PrefillProgress(progress),
+            None => Event::Result(response.into()),
+        }
+    }
+}
+
+impl From<GenerationResponse> for GenerationResult {
+    fn from(response: GenerationResponse) -> Self {
+        let usage = match response.usage {
+            Some(usage) => Usage {
+                input_tokens: usage.input_token_count,
+                generated_tokens: usage.generated_token_count,
+                queue_time_millis: Some(usage.queue_time_millis),
+                time_to_first_token_millis: usage.time_to_first_token_millis,
+                generation_time_millis: Some(usage.generation_time_millis),
+                prefill_time_millis: usage.prefill_time_millis,
+                batches_waited: Some(usage.batches_waited),
+            },
+            None => Usage {
+                input_tokens: response.input_token_count,
+                generated_tokens: response.generated_token_count,
+                ..Default::default()
+            },
+        };
+        Self {
+            text: response.text,
+            // Stop reasons have the same values in both versions
+            stop_reason: response.stop_reason,
+            usage: Some(usage),
+            seed: response.seed,
+            tokens: response.tokens,
+            input_tokens: response.input_tokens,
+            sequence_logprob: response.sequence_logprob,
+            perplexity: response.perplexity,
+            tool_call: response.tool_call,
+            queue_position: response.queue_position,
+            estimated_wait_millis: response.estimated_wait_millis,
+            trace: response.trace,
+            warnings: response.warnings,
+            stop_details: response.stop_details,
+            model_id: response.model_id,
+            model_version: response.model_version,
+        }
+    }
+}
+
+/// Replace the details of a version 1 error status with a structured error
+fn into_v2_status(status: Status) -> Status {
+    let overloaded = match status.code() {
+        Code::ResourceExhausted => OverloadedDetails::decode(status.details()).ok(),
+        _ => None,
+    };
+    let kind = match status.code() {
+        Code::InvalidArgument => Kind::InvalidRequest,
+        Code::ResourceExhausted => Kind::Overloaded,
+        Code::DeadlineExceeded => Kind::DeadlineExceeded,
+        Code::NotFound => Kind::NotFound,
+        Code::FailedPrecondition | Code::Unimplemented => Kind::Unsupported,
+        _ => Kind::Internal,
+    };
+    let error = Error {
+        kind: kind as i32,
+        message: status.message().to_string(),
+        retryable: kind == Kind::Overloaded,
+        retry_after_millis: overloaded.as_ref().map(|d| d.retry_after_millis),
+        queue_length: overloaded.map(|d| d.queue_length),
+    };
+    Status::with_details_and_metadata(
+        status.code(), status.message(), error.encode_to_vec().into(), status.metadata().clone(),
+    )
+}
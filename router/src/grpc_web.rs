@@ -0,0 +1,103 @@
+/// gRPC-Web for the external services, so that browser-based apps can use their unary and
+/// server-streaming methods without a proxy. Calls are translated by tonic-web, with CORS
+/// restricted to an explicit list of origins
+use std::task::{Context, Poll};
+use std::time::Duration;
+use hyper::Body;
+use hyper::http::{HeaderValue, Method, Request, Response};
+use hyper::http::header::HeaderName;
+use tonic::body::BoxBody;
+use tonic::codegen::Service;
+use tonic::server::NamedService;
+use tonic_web::GrpcWebLayer;
+use tower_http::cors::{AllowHeaders, AllowOrigin, Cors, CorsLayer};
+use tower_layer::Layer;
+
+/// Response headers which browser apps are allowed to read
+const EXPOSED_HEADERS: [&str; 3] = ["grpc-status", "grpc-message", "grpc-status-details-bin"];
+
+/// How long browsers may cache the result of a preflight request
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct GrpcWebConfig {
+    /// Origins allowed to call the services
+    allowed_origins: Vec<HeaderValue>,
+}
+
+impl GrpcWebConfig {
+    /// Parse a comma-separated list of allowed origins
+    pub(crate) fn parse(origins: &str) -> Result<Self, String> {
+        let allowed_origins = origins.split(',').map(|origin| {
+            let origin = origin.trim().trim_end_matches('/');
+            if origin == "*" {
+                return Err("gRPC-Web origins must be listed explicitly, * isn't allowed".to_string())
+            }
+            if !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(format!("invalid gRPC-Web origin {origin:?}, expected scheme://host[:port]"))
+            }
+            HeaderValue::from_str(origin).map_err(|_| format!("invalid gRPC-Web origin {origin:?}"))
+        }).collect::<Result<_, _>>()?;
+        Ok(Self { allowed_origins })
+    }
+
+    /// Serve gRPC-Web calls to the service, as well as regular gRPC calls
+    pub(crate) fn enable<S>(&self, service: S) -> GrpcWeb<S>
+    where
+        S: Service<Request<Body>, Response = Response<BoxBody>> + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError> + Send,
+    {
+        let cors = CorsLayer::new()
+            .allow_origin(AllowOrigin::list(self.allowed_origins.clone()))
+            .allow_methods(Method::POST)
+            .allow_headers(AllowHeaders::mirror_request())
+            .expose_headers(EXPOSED_HEADERS.map(HeaderName::from_static))
+            .max_age(PREFLIGHT_MAX_AGE);
+        GrpcWeb(cors.layer(GrpcWebLayer::new().layer(service)))
+    }
+}
+
+/// Service wrapped to serve gRPC-Web, which keeps the name of the service
+/// so that it can be added to the gRPC server's router
+#[derive(Clone)]
+pub(crate) struct GrpcWeb<S>(Cors<tonic_web::GrpcWebService<S>>);
+
+impl<S> Service<Request<Body>> for GrpcWeb<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError> + Send,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = <Cors<tonic_web::GrpcWebService<S>> as Service<Request<Body>>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        self.0.call(request)
+    }
+}
+
+impl<S: NamedService> NamedService for GrpcWeb<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GrpcWebConfig;
+
+    #[test]
+    fn requires_explicit_origins() {
+        let config = GrpcWebConfig::parse("https://app.example.com/, http://localhost:8080").unwrap();
+        assert_eq!(config.allowed_origins, ["https://app.example.com", "http://localhost:8080"]);
+        assert!(GrpcWebConfig::parse("*").is_err());
+        assert!(GrpcWebConfig::parse("https://app.example.com,*").is_err());
+        assert!(GrpcWebConfig::parse("app.example.com").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use futures::future::join_all;
 use tokenizers::Tokenizer;
 use text_generation_client::{Batch, NextTokenChooserParameters, Request, ShardedClient};
 
@@ -7,24 +8,38 @@ const TEST_INPUT: &str = "liveness";
 
 #[derive(Clone, Debug)]
 pub(crate) struct Health {
-    client: ShardedClient,
-    generation_health: Arc<AtomicBool>,
-    test_input_tokens: u32,
+    replicas: Vec<ReplicaHealth>,
 }
 
 impl Health {
-    pub(crate) fn new(
-        client: ShardedClient, generation_health: Arc<AtomicBool>, tokenizer: &Tokenizer
-    ) -> Self {
+    /// Given the index, client and generation health flag of each replica
+    pub(crate) fn new(replicas: Vec<(usize, ShardedClient, Arc<AtomicBool>)>, tokenizer: &Tokenizer) -> Self {
+        let test_input_tokens = tokenizer.encode(TEST_INPUT, true)
+            .expect("Tokenization error").len() as u32;
         Self {
-            client,
-            generation_health,
-            test_input_tokens: tokenizer.encode(TEST_INPUT, true)
-                .expect("Tokenization error").len() as u32
+            replicas: replicas.into_iter().map(|(index, client, generation_health)| {
+                ReplicaHealth { index, client, generation_health, test_input_tokens }
+            }).collect(),
         }
     }
 
+    /// Check every replica, updating each one's generation health.
+    /// Healthy only if all of them are.
     pub(crate) async fn check(&mut self) -> bool {
+        join_all(self.replicas.iter_mut().map(ReplicaHealth::check)).await.into_iter().all(|healthy| healthy)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ReplicaHealth {
+    index: usize,
+    client: ShardedClient,
+    generation_health: Arc<AtomicBool>,
+    test_input_tokens: u32,
+}
+
+impl ReplicaHealth {
+    async fn check(&mut self) -> bool {
         if self.generation_health.load(Ordering::SeqCst) {
             // Generation is healthy, we only check that the shards are answering gRPC calls
             self.client.health().await.is_ok()
@@ -45,6 +60,9 @@ impl Health {
                 }),
                 stream_response: false,
                 details: None,
+                healing_prefix: String::new(),
+                resumed_id: None,
+                input_ids: vec![],
             };
             let batch = Batch {
                 id: u64::MAX,
@@ -52,8 +70,8 @@ impl Health {
                 total_tokens: 1,
             };
             // Skips the queue
-            let value = self.client.prefill(batch, vec![]).await
-                .map_err(|err| tracing::error!("Healthcheck error: {err}"))
+            let value = self.client.prefill(batch, None, None).await
+                .map_err(|err| tracing::error!("Healthcheck error of replica {}: {err}", self.index))
                 .is_ok();
             // Update generation health
             self.generation_health.store(value, Ordering::SeqCst);
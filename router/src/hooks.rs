@@ -0,0 +1,216 @@
+/// Pluggable hooks run at each stage of a generation request, e.g. for billing or analytics
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::Instant;
+use tracing::{info, warn};
+use crate::GenerateRequest;
+
+/// A generation request as seen by hooks
+#[derive(Debug)]
+pub struct HookRequest {
+    /// Unique within the process, the same for each of the request's events
+    pub id: u64,
+    pub tenant: Option<String>,
+    pub priority: i32,
+    pub input_tokens: usize,
+    pub max_new_tokens: u32,
+    pub streaming: bool,
+    /// When the request was submitted to the batcher
+    pub submitted: Instant,
+}
+
+/// Outcome of a request which completed
+#[derive(Debug)]
+pub struct HookCompletion {
+    pub generated_tokens: u32,
+    /// Name of the stop reason, e.g. "EOS_TOKEN"
+    pub stop_reason: &'static str,
+    pub time_to_first_token: Option<Duration>,
+    /// Time from submission until completion
+    pub total_time: Duration,
+}
+
+/// Request hooks which can be provided via [`crate::server::ServerRunArgs`].
+/// All hooks do nothing by default.
+///
+/// Hooks are run in the background in the order of events, after the request has
+/// progressed, so they don't delay generation. They're run for requests submitted to
+/// the batcher, not those rejected earlier, served from the response cache or coalesced
+/// with an identical request in progress.
+#[async_trait]
+pub trait RequestHook: Send + Sync {
+    /// The request was submitted to the batcher
+    async fn on_request(&self, _request: &HookRequest) {}
+
+    /// The first token of the request was generated
+    async fn on_first_token(&self, _request: &HookRequest, _time_to_first_token: Duration) {}
+
+    /// The request completed, including if stopped early, e.g. by a time limit
+    async fn on_complete(&self, _request: &HookRequest, _completion: &HookCompletion) {}
+
+    /// The request failed, or was abandoned, e.g. because a streaming client disconnected
+    async fn on_error(&self, _request: &HookRequest, _error: &str) {}
+}
+
+enum HookEvent {
+    Request,
+    FirstToken(Duration),
+    Complete(HookCompletion),
+    Error(String),
+}
+
+/// Dispatches request events to the registered hooks from a background task
+#[derive(Clone)]
+pub(crate) struct RequestHooks {
+    sender: UnboundedSender<(Arc<HookRequest>, HookEvent)>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl RequestHooks {
+    pub(crate) fn new(hooks: Vec<Arc<dyn RequestHook>>) -> Self {
+        let (sender, mut receiver) = unbounded_channel::<(Arc<HookRequest>, HookEvent)>();
+        tokio::spawn(async move {
+            while let Some((request, event)) = receiver.recv().await {
+                for hook in &hooks {
+                    match &event {
+                        HookEvent::Request => hook.on_request(&request).await,
+                        HookEvent::FirstToken(ttft) => hook.on_first_token(&request, *ttft).await,
+                        HookEvent::Complete(completion) => hook.on_complete(&request, completion).await,
+                        HookEvent::Error(error) => hook.on_error(&request, error).await,
+                    }
+                }
+            }
+        });
+        Self { sender, next_id: Default::default() }
+    }
+
+    /// Handle through which the events of a request being submitted are dispatched
+    pub(crate) fn handle(&self, request: &GenerateRequest, input_length: usize, streaming: bool) -> HookHandle {
+        let request = HookRequest {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            tenant: request.tenant.clone(),
+            priority: request.priority,
+            input_tokens: input_length,
+            max_new_tokens: request.parameters.max_new_tokens,
+            streaming,
+            submitted: Instant::now(),
+        };
+        HookHandle { hooks: self.clone(), request: Arc::new(request), finished: false }
+    }
+}
+
+/// Events of a single request, held by its queue entry. If it's dropped before the
+/// request completes or fails, the request is reported as abandoned.
+pub(crate) struct HookHandle {
+    hooks: RequestHooks,
+    request: Arc<HookRequest>,
+    finished: bool,
+}
+
+impl HookHandle {
+    fn send(&self, event: HookEvent) {
+        // Only fails if the runtime is shutting down
+        self.hooks.sender.send((self.request.clone(), event)).unwrap_or_default();
+    }
+
+    pub(crate) fn request(&self) {
+        self.send(HookEvent::Request);
+    }
+
+    pub(crate) fn first_token(&self) {
+        self.send(HookEvent::FirstToken(self.request.submitted.elapsed()));
+    }
+
+    pub(crate) fn complete(&mut self, generated_tokens: u32, stop_reason: &'static str, first_token: Option<Instant>) {
+        self.finished = true;
+        self.send(HookEvent::Complete(HookCompletion {
+            generated_tokens,
+            stop_reason,
+            time_to_first_token: first_token
+                .map(|first| first.saturating_duration_since(self.request.submitted)),
+            total_time: self.request.submitted.elapsed(),
+        }));
+    }
+
+    pub(crate) fn error(&mut self, error: String) {
+        self.finished = true;
+        self.send(HookEvent::Error(error));
+    }
+}
+
+impl Drop for HookHandle {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.send(HookEvent::Error("request abandoned".to_string()));
+        }
+    }
+}
+
+impl std::fmt::Debug for HookHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("HookHandle").field(&self.request.id).finish()
+    }
+}
+
+/// Built-in hook which logs each event
+pub struct LoggingHook;
+
+#[async_trait]
+impl RequestHook for LoggingHook {
+    async fn on_request(&self, request: &HookRequest) {
+        info!(
+            "Request hook {}: submitted with {} input token(s), max_new_tokens {}, tenant {:?}",
+            request.id, request.input_tokens, request.max_new_tokens, request.tenant,
+        );
+    }
+
+    async fn on_first_token(&self, request: &HookRequest, time_to_first_token: Duration) {
+        info!("Request hook {}: first token after {time_to_first_token:?}", request.id);
+    }
+
+    async fn on_complete(&self, request: &HookRequest, completion: &HookCompletion) {
+        info!(
+            "Request hook {}: completed with {} generated token(s) and reason {} after {:?}",
+            request.id, completion.generated_tokens, completion.stop_reason, completion.total_time,
+        );
+    }
+
+    async fn on_error(&self, request: &HookRequest, error: &str) {
+        warn!("Request hook {}: failed: {error}", request.id);
+    }
+}
+
+/// Built-in hook which counts requests and tokens per tenant, for usage accounting
+pub struct MetricsHook;
+
+fn tenant(request: &HookRequest) -> String {
+    request.tenant.clone().unwrap_or_else(|| "none".to_string())
+}
+
+#[async_trait]
+impl RequestHook for MetricsHook {
+    async fn on_complete(&self, request: &HookRequest, completion: &HookCompletion) {
+        let tenant = tenant(request);
+        metrics::increment_counter!("tgi_tenant_request_success", "tenant" => tenant.clone());
+        metrics::counter!("tgi_tenant_input_tokens", request.input_tokens as u64, "tenant" => tenant.clone());
+        metrics::counter!(
+            "tgi_tenant_generated_tokens", completion.generated_tokens as u64, "tenant" => tenant,
+        );
+    }
+
+    async fn on_error(&self, request: &HookRequest, _error: &str) {
+        metrics::increment_counter!("tgi_tenant_request_failure", "tenant" => tenant(request));
+    }
+}
+
+/// Built-in hooks by name, as configured on the command line
+pub(crate) fn builtin_hook(name: &str) -> Result<Arc<dyn RequestHook>, String> {
+    match name {
+        "logging" => Ok(Arc::new(LoggingHook)),
+        "metrics" => Ok(Arc::new(MetricsHook)),
+        _ => Err(format!("unknown request hook '{name}', must be logging or metrics")),
+    }
+}
@@ -0,0 +1,319 @@
+/// Write-ahead log of queued generation requests, so that those which hadn't left the
+/// queue when the router stopped are resubmitted when it restarts
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::Request;
+use crate::jobs::GenerationJobs;
+use crate::pb::fmaas::SingleGenerationRequest;
+
+/// Request headers which are retained, used for client identity and scheduling
+const JOURNALED_HEADERS: [&str; 4] = ["x-caller-id", "x-tenant-id", "x-priority", "x-correlation-id"];
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JournalRecord {
+    Queued(JournaledRequest),
+    /// The request was batched, or otherwise removed from the queue
+    Dequeued { id: String },
+    /// The generation job has finished
+    Done { id: String },
+}
+
+/// A queued request, with what's needed to submit it again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct JournaledRequest {
+    pub(crate) id: String,
+    /// Id of the generation job it's resubmitted as. The same as the id for requests
+    /// submitted as jobs, otherwise the request's correlation id if it has one, since
+    /// its client is disconnected by the restart
+    pub(crate) generation_id: String,
+    /// Whether it was submitted as a generation job
+    job: bool,
+    /// Base64 encoded request message
+    request: String,
+    headers: Vec<(String, String)>,
+    /// Unix time in millis at which the request's time limit ends, if it has one
+    deadline_ms: Option<u128>,
+}
+
+impl JournaledRequest {
+    /// A request submitted as a generation job
+    pub(crate) fn job(generation_id: String, metadata: &MetadataMap, message: &SingleGenerationRequest) -> Self {
+        Self::new(generation_id.clone(), generation_id, true, metadata, message)
+    }
+
+    /// A request whose client waits for its response. Part is the request's index,
+    /// if it's one of several sent together
+    pub(crate) fn queued(metadata: &MetadataMap, message: &SingleGenerationRequest, part: Option<usize>) -> Self {
+        let id = GenerationJobs::new_generation_id();
+        let generation_id = match metadata.get("x-correlation-id").and_then(|v| v.to_str().ok()) {
+            Some(correlation_id) => match part {
+                Some(index) => format!("{correlation_id}-{}", index + 1),
+                None => correlation_id.to_string(),
+            },
+            None => id.clone(),
+        };
+        Self::new(id, generation_id, false, metadata, message)
+    }
+
+    fn new(
+        id: String, generation_id: String, job: bool, metadata: &MetadataMap, message: &SingleGenerationRequest,
+    ) -> Self {
+        let time_limit = message.params.as_ref()
+            .and_then(|p| p.stopping.as_ref()).map_or(0, |s| s.time_limit_millis);
+        let headers = JOURNALED_HEADERS.iter().filter_map(|&name| {
+            let value = metadata.get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        }).collect();
+        Self {
+            id,
+            generation_id,
+            job,
+            request: BASE64.encode(message.encode_to_vec()),
+            headers,
+            deadline_ms: (time_limit > 0).then(|| unix_millis() + time_limit as u128),
+        }
+    }
+
+    /// The request, with its time limit reduced to what remains of the original.
+    /// None if it has passed or the request can't be decoded
+    pub(crate) fn to_request(&self) -> Option<Request<SingleGenerationRequest>> {
+        let bytes = BASE64.decode(&self.request).ok()?;
+        let mut message = SingleGenerationRequest::decode(bytes.as_slice()).ok()?;
+        if let Some(deadline) = self.deadline_ms {
+            let remaining = deadline.checked_sub(unix_millis()).filter(|&r| r > 0)?;
+            if let Some(stopping) = message.params.as_mut().and_then(|p| p.stopping.as_mut()) {
+                stopping.time_limit_millis = remaining.min(u32::MAX as u128) as u32;
+            }
+        }
+        let mut request = Request::new(message);
+        for (name, value) in &self.headers {
+            if let (Some(name), Ok(value)) = (
+                JOURNALED_HEADERS.iter().find(|&&h| h == name), MetadataValue::try_from(value),
+            ) {
+                request.metadata_mut().insert(*name, value);
+            }
+        }
+        Some(request)
+    }
+}
+
+/// Journaled requests which hadn't finished when the router last stopped
+#[derive(Debug, Default)]
+pub(crate) struct Unfinished {
+    /// Those which were still queued, to resubmit
+    pub(crate) queued: Vec<JournaledRequest>,
+    /// Ids of generation jobs which had left the queue, which aren't run again
+    pub(crate) interrupted: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct JobJournal {
+    file: Mutex<File>,
+    /// Set when the router stops, after which nothing more is recorded
+    closed: AtomicBool,
+}
+
+impl JobJournal {
+    /// Open the journal, creating it if it doesn't exist, and return the requests which
+    /// hadn't finished. The journal is compacted to contain only those still queued, each once
+    pub(crate) fn open(path: &str) -> Result<(Arc<Self>, Unfinished), String> {
+        let mut queued: Vec<Option<JournaledRequest>> = vec![];
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let mut interrupted = vec![];
+        match File::open(path) {
+            Ok(file) => for (i, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.map_err(|e| format!("couldn't read job journal {path}: {e}"))?;
+                match serde_json::from_str::<JournalRecord>(&line) {
+                    // Requests queued more than once, when resubmitted, are deduplicated
+                    Ok(JournalRecord::Queued(request)) => match positions.get(&request.id) {
+                        Some(&index) => if queued[index].is_some() {
+                            queued[index] = Some(request);
+                        },
+                        None => {
+                            positions.insert(request.id.clone(), queued.len());
+                            queued.push(Some(request));
+                        },
+                    },
+                    Ok(JournalRecord::Dequeued { id }) => if let Some(&index) = positions.get(&id) {
+                        // Jobs which had started are reported as failed unless they finished
+                        if let Some(request) = queued[index].take() {
+                            if request.job {
+                                interrupted.push(id);
+                            }
+                        }
+                    },
+                    Ok(JournalRecord::Done { id }) => {
+                        interrupted.retain(|i| i != &id);
+                        if let Some(&index) = positions.get(&id) {
+                            queued[index] = None;
+                        }
+                    },
+                    // The last record may be incomplete if the router was killed while writing it
+                    Err(e) => tracing::warn!("Skipping invalid record at line {} of job journal {path}: {e}", i + 1),
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("couldn't open job journal {path}: {e}")),
+        }
+        let queued: Vec<JournaledRequest> = queued.into_iter().flatten().collect();
+
+        // Replace the journal with the queued requests' records
+        let compacted_path = format!("{path}.compacting");
+        let mut compacted = File::create(&compacted_path)
+            .map_err(|e| format!("couldn't create {compacted_path}: {e}"))?;
+        for request in &queued {
+            write_record(&mut compacted, &JournalRecord::Queued(request.clone()))
+                .map_err(|e| format!("couldn't write {compacted_path}: {e}"))?;
+        }
+        compacted.sync_all().map_err(|e| format!("couldn't write {compacted_path}: {e}"))?;
+        std::fs::rename(&compacted_path, path)
+            .map_err(|e| format!("couldn't replace job journal {path}: {e}"))?;
+
+        let file = OpenOptions::new().append(true).open(path)
+            .map_err(|e| format!("couldn't open job journal {path}: {e}"))?;
+        tracing::info!(
+            "Job journal {path} opened, {} queued request(s) to resubmit and {} interrupted job(s)",
+            queued.len(), interrupted.len(),
+        );
+        let journal = Arc::new(Self { file: Mutex::new(file), closed: AtomicBool::new(false) });
+        Ok((journal, Unfinished { queued, interrupted }))
+    }
+
+    /// Record a queued request, returning once the record is durable. The returned
+    /// record marks it as having left the queue when dropped
+    pub(crate) async fn queued(self: &Arc<Self>, request: JournaledRequest) -> Result<QueuedRecord, String> {
+        let journal = self.clone();
+        let id = request.id.clone();
+        tokio::task::spawn_blocking(move || journal.write(&JournalRecord::Queued(request))).await
+            .map_err(|e| e.to_string())??;
+        Ok(QueuedRecord(Some((self.clone(), id))))
+    }
+
+    /// Record that a request has left the queue, so it isn't resubmitted
+    pub(crate) fn dequeued(self: &Arc<Self>, id: String) {
+        self.write_in_background(JournalRecord::Dequeued { id })
+    }
+
+    /// Record that a job has finished
+    pub(crate) fn done(self: &Arc<Self>, id: String) {
+        self.write_in_background(JournalRecord::Done { id })
+    }
+
+    /// Stop recording, so that requests still queued or in progress when the
+    /// router stops are treated as such when it restarts
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn write_in_background(self: &Arc<Self>, record: JournalRecord) {
+        // Records of requests dropped when the runtime stops aren't written
+        if self.closed.load(Ordering::SeqCst) || tokio::runtime::Handle::try_current().is_err() {
+            return
+        }
+        let journal = self.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = journal.write(&record) {
+                tracing::error!("Failed to write job journal record: {e}");
+            }
+        });
+    }
+
+    fn write(&self, record: &JournalRecord) -> Result<(), String> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(())
+        }
+        let mut file = self.file.lock().unwrap();
+        write_record(&mut *file, record).and_then(|_| file.sync_data())
+            .map_err(|e| format!("couldn't write job journal: {e}"))
+    }
+}
+
+/// Marks a journaled request as having left the queue when dropped. Clones of the
+/// request, e.g. those retained for audits, don't carry it
+#[derive(Debug, Default)]
+pub(crate) struct QueuedRecord(Option<(Arc<JobJournal>, String)>);
+
+impl Clone for QueuedRecord {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Drop for QueuedRecord {
+    fn drop(&mut self) {
+        if let Some((journal, id)) = self.0.take() {
+            journal.dequeued(id);
+        }
+    }
+}
+
+fn write_record(writer: &mut impl Write, record: &JournalRecord) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+fn unix_millis() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::metadata::MetadataMap;
+    use crate::pb::fmaas::SingleGenerationRequest;
+    use super::{JobJournal, JournaledRequest, JournalRecord, write_record};
+
+    #[test]
+    fn replays_only_unstarted_requests() {
+        let path = std::env::temp_dir().join(format!("job_journal_{}.jsonl", rand::random::<u32>()));
+        let path = path.to_str().unwrap();
+        let message = SingleGenerationRequest::default();
+        let mut metadata = MetadataMap::new();
+        let unstarted_job = JournaledRequest::job("a".to_string(), &metadata, &message);
+        let started_job = JournaledRequest::job("b".to_string(), &metadata, &message);
+        let finished_job = JournaledRequest::job("c".to_string(), &metadata, &message);
+        let started = JournaledRequest::queued(&metadata, &message, None);
+        metadata.insert("x-correlation-id", "corr".parse().unwrap());
+        let unstarted = JournaledRequest::queued(&metadata, &message, Some(1));
+        assert_eq!(unstarted.generation_id, "corr-2");
+
+        let mut file = std::fs::File::create(path).unwrap();
+        for record in [
+            JournalRecord::Queued(unstarted_job.clone()),
+            JournalRecord::Queued(started_job.clone()),
+            JournalRecord::Queued(finished_job.clone()),
+            JournalRecord::Queued(started.clone()),
+            JournalRecord::Queued(unstarted.clone()),
+            // Resubmitted under the same id
+            JournalRecord::Queued(unstarted_job.clone()),
+            JournalRecord::Dequeued { id: started_job.id.clone() },
+            JournalRecord::Dequeued { id: finished_job.id.clone() },
+            JournalRecord::Done { id: finished_job.id.clone() },
+            JournalRecord::Dequeued { id: started.id.clone() },
+        ] {
+            write_record(&mut file, &record).unwrap();
+        }
+        drop(file);
+
+        let (_, unfinished) = JobJournal::open(path).unwrap();
+        let queued: Vec<_> = unfinished.queued.iter().map(|r| &r.id).collect();
+        assert_eq!(queued, [&unstarted_job.id, &unstarted.id]);
+        assert_eq!(unfinished.interrupted, ["b"]);
+
+        // Compacted to the requests still queued
+        let (_, unfinished) = JobJournal::open(path).unwrap();
+        assert_eq!(unfinished.queued.len(), 2);
+        assert!(unfinished.interrupted.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+/// Generations run in the background, whose progress and results clients fetch by id
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use futures::{Stream, StreamExt};
+use moka::future::Cache;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tonic::Status;
+use crate::pb::fmaas::{GenerationResponse, GetGenerationResponse};
+use crate::pb::fmaas::get_generation_response::State;
+use crate::job_journal::{JobJournal, Unfinished};
+
+/// Retains the status of submitted generations until they expire or, if there
+/// are more than max_jobs, are evicted
+pub(crate) struct GenerationJobs {
+    jobs: Cache<String, watch::Receiver<GetGenerationResponse>>,
+    /// Journal of queued requests, if they're to be resubmitted after a restart
+    pub(crate) journal: Option<Arc<JobJournal>>,
+    /// Journaled requests which hadn't finished when the router last stopped
+    unfinished: Mutex<Unfinished>,
+}
+
+impl GenerationJobs {
+    pub(crate) fn new(
+        max_jobs: u64, ttl: Duration, journal: Option<(Arc<JobJournal>, Unfinished)>,
+    ) -> Arc<Self> {
+        let (journal, unfinished) = journal.map_or((None, Unfinished::default()), |(j, u)| (Some(j), u));
+        Arc::new(Self {
+            jobs: Cache::builder().max_capacity(max_jobs).time_to_live(ttl).build(),
+            journal,
+            unfinished: Mutex::new(unfinished),
+        })
+    }
+
+    pub(crate) fn new_generation_id() -> String {
+        format!("{:032x}", rand::random::<u128>())
+    }
+
+    /// Journaled requests to resubmit or report as interrupted, returned only once
+    pub(crate) fn take_unfinished(&self) -> Unfinished {
+        std::mem::take(&mut self.unfinished.lock().unwrap())
+    }
+
+    pub(crate) fn contains(&self, generation_id: &str) -> bool {
+        self.jobs.contains_key(generation_id)
+    }
+
+    /// Record a job which couldn't be run, e.g. when resubmitted after a restart
+    pub(crate) async fn insert_failed(&self, generation_id: String, error: String) {
+        let (_, receiver) = watch::channel(GetGenerationResponse {
+            state: State::Failed as i32,
+            response: Some(GenerationResponse::default()),
+            error,
+        });
+        self.jobs.insert(generation_id.clone(), receiver).await;
+        if let Some(journal) = &self.journal {
+            journal.done(generation_id);
+        }
+    }
+
+    /// Start consuming the response stream in the background
+    pub(crate) async fn submit<S>(self: &Arc<Self>, generation_id: String, stream: S)
+    where S: Stream<Item = Result<GenerationResponse, Status>> + Send + 'static {
+        let (sender, receiver) = watch::channel(GetGenerationResponse {
+            state: State::InProgress as i32,
+            response: Some(GenerationResponse::default()),
+            error: String::new(),
+        });
+        self.jobs.insert(generation_id.clone(), receiver).await;
+        metrics::increment_counter!("tgi_generation_job_submitted");
+        tokio::spawn(run_job(Arc::downgrade(self), generation_id, stream, sender));
+    }
+
+    /// Status of the generation, waiting up to `wait` for it to finish if it's
+    /// still in progress. None if the id is unknown or has expired.
+    pub(crate) async fn get(&self, generation_id: &str, wait: Duration) -> Option<GetGenerationResponse> {
+        let mut receiver = self.jobs.get(generation_id)?;
+        if !wait.is_zero() {
+            // Errors only if the job was abandoned, in which case its last status is returned
+            let _ = timeout(
+                wait, receiver.wait_for(|status| status.state != State::InProgress as i32),
+            ).await;
+        }
+        let status = receiver.borrow().clone();
+        Some(status)
+    }
+}
+
+async fn run_job<S>(
+    jobs: Weak<GenerationJobs>,
+    generation_id: String,
+    stream: S,
+    sender: watch::Sender<GetGenerationResponse>,
+) where S: Stream<Item = Result<GenerationResponse, Status>> {
+    let mut stream = Box::pin(stream);
+    let mut state = State::Completed;
+    while let Some(next) = stream.next().await {
+        if sender.is_closed() {
+            // Expired or evicted with nobody waiting on it, dropping the
+            // stream cancels the generation
+            metrics::increment_counter!("tgi_generation_job_abandoned");
+            if let Some(journal) = jobs.upgrade().and_then(|jobs| jobs.journal.clone()) {
+                journal.done(generation_id);
+            }
+            return
+        }
+        match next {
+            Ok(response) => sender.send_modify(|status| {
+                append_response(status.response.get_or_insert_with(Default::default), response)
+            }),
+            Err(err) => {
+                sender.send_modify(|status| status.error = err.message().to_string());
+                state = State::Failed;
+                break
+            },
+        }
+    }
+    sender.send_modify(|status| status.state = state as i32);
+    // Re-insert so that the result is retained for the full TTL after completion
+    if let Some(jobs) = jobs.upgrade() {
+        if let Some(journal) = &jobs.journal {
+            journal.done(generation_id.clone());
+        }
+        if jobs.jobs.contains_key(&generation_id) {
+            jobs.jobs.insert(generation_id, sender.subscribe()).await;
+        }
+    }
+}
+
+/// Accumulate a streamed response into the output so far
+fn append_response(output: &mut GenerationResponse, next: GenerationResponse) {
+    output.text.push_str(&next.text);
+    output.generated_token_count = next.generated_token_count;
+    output.stop_reason = next.stop_reason;
+    output.tokens.extend(next.tokens);
+    output.input_tokens.extend(next.input_tokens);
+    // Only set in the first response
+    if next.input_token_count != 0 {
+        output.input_token_count = next.input_token_count;
+    }
+    if next.queue_position.is_some() {
+        output.queue_position = next.queue_position;
+        output.estimated_wait_millis = next.estimated_wait_millis;
+    }
+    output.warnings.extend(next.warnings);
+    output.normalized_params = output.normalized_params.take().or(next.normalized_params);
+    if next.seed != 0 {
+        output.seed = next.seed;
+    }
+    // Only set in the final response
+    if next.sequence_logprob.is_some() {
+        output.sequence_logprob = next.sequence_logprob;
+        output.perplexity = next.perplexity;
+    }
+    if next.trace.is_some() {
+        output.trace = next.trace;
+    }
+    if next.usage.is_some() {
+        output.usage = next.usage;
+    }
+    if next.stop_details.is_some() {
+        output.stop_details = next.stop_details;
+    }
+    if !next.model_id.is_empty() {
+        output.model_id = next.model_id;
+        output.model_version = next.model_version;
+    }
+}
@@ -0,0 +1,74 @@
+/// Estimation of the KV cache memory used by requests, from the model's geometry
+use std::fs;
+use serde::Deserialize;
+
+/// Attention geometry section of the model's config.json
+#[derive(Debug, Deserialize)]
+struct ModelGeometry {
+    #[serde(alias = "n_layer", alias = "num_layers")]
+    num_hidden_layers: u64,
+    #[serde(alias = "n_head", alias = "num_heads")]
+    num_attention_heads: u64,
+    #[serde(default, alias = "num_kv_heads", alias = "n_head_kv")]
+    num_key_value_heads: Option<u64>,
+    #[serde(default)]
+    multi_query: bool,
+    #[serde(alias = "n_embd", alias = "d_model")]
+    hidden_size: u64,
+    #[serde(default)]
+    head_dim: Option<u64>,
+    #[serde(default)]
+    torch_dtype: Option<String>,
+}
+
+impl ModelGeometry {
+    fn bytes_per_token(&self) -> u64 {
+        let kv_heads = if self.multi_query {
+            1
+        } else {
+            self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+        };
+        let head_dim = self.head_dim.unwrap_or(self.hidden_size / self.num_attention_heads);
+        let dtype_bytes = match self.torch_dtype.as_deref() {
+            Some("float32") => 4,
+            _ => 2,
+        };
+        // A key and a value for every layer
+        2 * self.num_hidden_layers * kv_heads * head_dim * dtype_bytes
+    }
+}
+
+/// KV cache memory per token and the memory available for it across the shards
+/// of a replica, used to reject or defer requests which wouldn't fit
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct KvCacheModel {
+    bytes_per_token: u64,
+    capacity_bytes: u64,
+}
+
+impl KvCacheModel {
+    pub(crate) fn load(config_path: &str, capacity_bytes: u64) -> Result<Self, String> {
+        let contents = fs::read_to_string(config_path)
+            .map_err(|e| format!("couldn't read model config {config_path}: {e}"))?;
+        let geometry: ModelGeometry = serde_json::from_str(&contents)
+            .map_err(|e| format!("model config {config_path} lacks attention geometry: {e}"))?;
+        let bytes_per_token = geometry.bytes_per_token();
+        if bytes_per_token == 0 {
+            return Err(format!("invalid attention geometry in model config {config_path}"))
+        }
+        Ok(Self { bytes_per_token, capacity_bytes })
+    }
+
+    /// Estimated memory used by the cache of this many tokens
+    pub(crate) fn footprint(&self, tokens: usize) -> u64 {
+        tokens as u64 * self.bytes_per_token
+    }
+
+    pub(crate) fn fits(&self, tokens: usize) -> bool {
+        self.footprint(tokens) <= self.capacity_bytes
+    }
+
+    pub(crate) fn capacity_bytes(&self) -> u64 {
+        self.capacity_bytes
+    }
+}
@@ -5,14 +5,51 @@ pub mod server;
 pub mod grpc_server;
 mod validation;
 mod decoder;
+mod decoder_backends;
+mod jobs;
+mod embeddings;
+mod deployment;
+mod admin;
+mod kv_cache;
+#[allow(clippy::large_enum_variant)]
 mod pb;
 mod queue;
 mod batch_types;
+mod audit;
+mod warmup;
+mod request_log;
+mod runtime_config;
+mod tools;
+mod streaming;
+pub mod safety;
+mod client_limits;
+mod token_healing;
+pub mod response_cache;
+mod replicas;
+mod trace;
+mod preemption;
+mod request_metrics;
+mod replay;
+mod shard_discovery;
+mod log_redaction;
+mod grpc_server_v2;
+pub mod hooks;
+mod waiting_tokens;
+mod parameter_policy;
+mod auth;
+mod job_journal;
+mod prompt_templates;
+mod prompt_registry;
+mod grpc_web;
+mod models;
+mod chunked_json;
 
-use batcher::Batcher;
+use batcher::RetryHint;
+use job_journal::QueuedRecord;
+use parameter_policy::UnsetParameters;
 use serde::{Deserialize, Serialize};
-use tokio::time::Instant;
-use validation::Validation;
+use tokio::time::{Duration, Instant};
+use tools::ToolDefinition;
 
 #[derive(Clone, Debug, Deserialize, Default)]
 pub(crate) struct GenerateParameters {
@@ -24,6 +61,10 @@ pub(crate) struct GenerateParameters {
     pub top_p: f32,
     #[serde(default = "default_typical_p")]
     pub typical_p: f32,
+    // Breakpoints of (generated token index, temperature) which the sampling
+    // temperature is interpolated between
+    #[serde(default)]
+    pub temperature_schedule: Vec<(u32, f32)>,
     #[serde(default = "default_max_new_tokens")]
     pub max_new_tokens: u32,
     // Indicates whether max_new_tokens is a hard
@@ -33,31 +74,139 @@ pub(crate) struct GenerateParameters {
     pub repetition_penalty: f32,
 
     pub length_penalty: Option<(u32, f32)>,
+    // Green list fraction (gamma) and logit bias (delta) of the watermark, if enabled
+    #[serde(default)]
+    pub watermark: Option<(f32, f32)>,
+    // Number of most recent tokens subject to the repetition penalty, 0 means all
+    #[serde(default)]
+    pub repetition_penalty_range: u32,
+    // 0 means disabled
+    #[serde(default)]
+    pub no_repeat_ngram_size: u32,
+    // Back off the last prompt token and have generation complete it
+    #[serde(default)]
+    pub token_healing: bool,
+    // Words and token ids which must never be generated
+    #[serde(default)]
+    pub bad_words: Vec<String>,
+    #[serde(default)]
+    pub banned_token_ids: Vec<u32>,
+    // Token id sequences of the bad words and banned token ids, set by validation
+    #[serde(skip)]
+    pub bad_words_ids: Vec<Vec<u32>>,
     
     pub min_new_tokens: u32,
     #[serde(skip)]
     pub deadline: Option<Instant>,
+    // Requested time limit which the deadline was set from
+    #[serde(skip)]
+    pub time_limit: Option<Duration>,
+    // Generation time budget, counted from when the request is first batched
+    #[serde(skip)]
+    pub max_time: Option<Duration>,
+    // Parameters left unset by the request, to which the model's policy defaults apply
+    #[serde(skip)]
+    pub unset: UnsetParameters,
+    // Adjustments made by the model's parameter policy, reported in the response
+    #[serde(skip)]
+    pub policy_warnings: Vec<String>,
+    // Names of the parameters changed during validation, reported in the response
+    #[serde(skip)]
+    pub normalized: Vec<String>,
 
     pub truncate_input_tokens: usize,
+    #[serde(default)]
+    pub truncation_side: TruncationSide,
 
     #[serde(default)]
     pub include_input_text: bool,
     #[serde(default)]
     pub include_input_tokens: bool,
+    // Character offsets of each input token, applicable only with include_input_tokens
+    #[serde(default)]
+    pub include_input_offsets: bool,
+    // Score the input tokens instead of generating
+    #[serde(default)]
+    pub echo: bool,
+    // Number of generated tokens included in each streamed response
+    #[serde(default)]
+    pub stream_chunk_tokens: u32,
     #[serde(default)]
     pub include_gen_tokens: bool,
     #[serde(default)]
     pub include_logprobs: bool,
     #[serde(default)]
     pub include_ranks: bool,
+    // Overrides the server's default number of top candidate tokens to include
+    #[serde(default)]
+    pub include_top_n: Option<u32>,
+    #[serde(default)]
+    pub include_sequence_logprob: bool,
+    // Record each generation step in the final response
     #[serde(default)]
-    pub include_top_n: u32,
+    pub include_trace: bool,
+    // Overrides the server's default of whether to omit special tokens from output text
+    #[serde(default)]
+    pub skip_special_tokens: Option<bool>,
+    #[serde(default)]
+    pub clean_up_tokenization_spaces: bool,
 
     #[serde(default)]
     pub seed: Option<u64>,
 
     #[serde(default)]
     pub stop_seqs: Vec<String>,
+    // Stop sequences of token ids, matched without decoding
+    #[serde(default)]
+    pub stop_token_ids: Vec<Vec<u32>>,
+    // Regular expressions matched against the most recently generated text
+    #[serde(default)]
+    pub stop_regex: Vec<String>,
+    // Compiled stop_regex, set during validation
+    #[serde(skip)]
+    pub compiled_stop_regex: Vec<regex::Regex>,
+    #[serde(default)]
+    pub min_token_logprob: Option<f32>,
+    #[serde(default)]
+    pub min_mean_logprob: Option<f32>,
+
+    // Tools which the model may call, output is then constrained to a tool call
+    #[serde(skip)]
+    pub tools: Vec<ToolDefinition>,
+}
+
+impl GenerateParameters {
+    pub(crate) fn has_logprob_threshold(&self) -> bool {
+        self.min_token_logprob.is_some() || self.min_mean_logprob.is_some()
+    }
+
+    /// Whether generation may be cut short by a deadline or time budget
+    pub(crate) fn has_time_limit(&self) -> bool {
+        self.deadline.is_some() || self.max_time.is_some()
+    }
+
+    /// Whether the output must be decoded as it's generated, to check for stop text
+    pub(crate) fn has_stop_text(&self) -> bool {
+        !self.stop_seqs.is_empty() || !self.stop_regex.is_empty()
+    }
+
+    /// Whether the generated token logprobs need to be summed by the router
+    pub(crate) fn tracks_logprob_sum(&self) -> bool {
+        self.include_sequence_logprob || self.has_logprob_threshold()
+    }
+}
+
+/// Which tokens are removed when truncating input to truncate_input_tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TruncationSide {
+    /// Done by the shards
+    #[default]
+    Left,
+    /// Done by the router
+    Right,
+    /// Done by the router
+    Middle,
 }
 
 fn default_temperature() -> f32 {
@@ -91,6 +240,7 @@ fn default_parameters() -> GenerateParameters {
         top_p: default_top_p(),
         repetition_penalty: default_repetition_penalty(),
         max_new_tokens: default_max_new_tokens(),
+        unset: UnsetParameters { max_new_tokens: true, ..Default::default() },
 
         ..Default::default()
     }
@@ -100,23 +250,92 @@ fn default_parameters() -> GenerateParameters {
 pub(crate) struct GenerateRequest {
     pub prefix_id: Option<String>,
     pub inputs: String,
+    // Pre-tokenized inputs, used by the shards in place of inputs which is then
+    // their decoded text
+    #[serde(skip)]
+    pub input_token_ids: Vec<u32>,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
+    // Used to share batch capacity fairly between tenants
+    #[serde(default)]
+    pub tenant: Option<String>,
+    // Requests with higher priority are batched first, and may preempt
+    // running requests with lower priority
+    #[serde(default)]
+    pub priority: i32,
+    // Text removed from the end of inputs by token healing
+    #[serde(skip)]
+    pub healed_prefix: Option<String>,
+    // Character offsets of the input tokens within inputs, computed during
+    // validation if requested
+    #[serde(skip)]
+    pub input_offsets: Vec<(usize, usize)>,
+    // Whether to log the request's processing at DEBUG level, regardless of the log filter
+    #[serde(skip)]
+    pub debug: bool,
+    // Journal record of the request, marked as having left the queue when it's first batched
+    #[serde(skip)]
+    pub journal: QueuedRecord,
 }
 
 #[derive(Serialize)]
 pub(crate) struct Details {
     pub finish_reason: String,
     pub generated_tokens: u32,
-    pub tokens: Vec<(u32, String, f32)>,
+    pub tokens: Vec<TokenDetails>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TokenDetails {
+    pub text: String,
+    // Zero unless requested, as are rank and top_tokens
+    pub logprob: f32,
+    pub rank: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_tokens: Vec<(String, f32)>,
 }
 
 #[derive(Serialize)]
 pub(crate) struct GeneratedText {
     pub generated_text: String,
+    // Parameters as applied, if any were changed during validation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalized_params: Option<NormalizedParams>,
+    // Generated tokens, if requested via include_gen_tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Details>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NormalizedParams {
+    pub changed: Vec<String>,
+    pub max_new_tokens: u32,
+    // 0 if the input wasn't truncated
+    pub truncate_input_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl From<pb::fmaas::NormalizedParameters> for NormalizedParams {
+    fn from(params: pb::fmaas::NormalizedParameters) -> Self {
+        Self {
+            changed: params.changed,
+            max_new_tokens: params.max_new_tokens,
+            truncate_input_tokens: params.truncate_input_tokens,
+            seed: params.seed,
+        }
+    }
 }
 
 #[derive(Serialize)]
 pub(crate) struct ErrorResponse {
     pub error: String,
+    // Machine-readable error category
+    pub error_code: &'static str,
+    // Backoff hints, if the request was rejected because the server is too busy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryHint>,
+    // Each invalid parameter, if the request failed validation
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
 }
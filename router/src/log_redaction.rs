@@ -0,0 +1,79 @@
+/// Policy for how user text, i.e. prompts and generated outputs, appears in logs and traces
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use unicode_truncate::UnicodeTruncateStr;
+use crate::request_log::prompt_hash;
+
+/// Length that text is truncated to if no policy is set
+const DEFAULT_TRUNCATE_LENGTH: usize = 32;
+
+static TEXT_LOG_POLICY: OnceLock<TextLogPolicy> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TextLogPolicy {
+    /// Log text as-is
+    Full,
+    /// Log a truncated SHA-256 digest of the text, so that repeats can be correlated
+    Hash,
+    /// Log at most this many chars of the text
+    Truncate(usize),
+    /// Log only the length of the text
+    Omit,
+}
+
+impl std::str::FromStr for TextLogPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "hash" => Ok(Self::Hash),
+            "omit" => Ok(Self::Omit),
+            _ => match s.strip_prefix("truncate:").map(str::parse) {
+                Some(Ok(len)) => Ok(Self::Truncate(len)),
+                _ => Err(format!(
+                    "invalid text log policy '{s}', must be full, hash, truncate:<chars> or omit"
+                )),
+            },
+        }
+    }
+}
+
+/// Set the policy for the process, must be called at most once before any text is logged
+pub(crate) fn set_text_log_policy(policy: TextLogPolicy) {
+    TEXT_LOG_POLICY.set(policy).expect("text log policy already set");
+}
+
+fn policy() -> TextLogPolicy {
+    *TEXT_LOG_POLICY.get_or_init(|| TextLogPolicy::Truncate(DEFAULT_TRUNCATE_LENGTH))
+}
+
+/// Prompt or output text as it may be included in logs and traces
+pub(crate) fn redact(text: &str) -> Cow<str> {
+    match policy() {
+        TextLogPolicy::Full => text.into(),
+        TextLogPolicy::Hash => format!("<sha256:{}>", prompt_hash(text)).into(),
+        TextLogPolicy::Truncate(len) => truncate(text, len),
+        TextLogPolicy::Omit => format!("<{} bytes>", text.len()).into(),
+    }
+}
+
+/// Token ids as they may be included in logs, since they can be decoded to the text
+pub(crate) fn redact_token_ids(ids: &[u32]) -> String {
+    match policy() {
+        TextLogPolicy::Full => format!("{ids:?}"),
+        TextLogPolicy::Truncate(len) if ids.len() > len => format!("{:?}...", &ids[..len]),
+        TextLogPolicy::Truncate(_) => format!("{ids:?}"),
+        TextLogPolicy::Hash | TextLogPolicy::Omit => format!("<{} token(s)>", ids.len()),
+    }
+}
+
+fn truncate(string: &str, len: usize) -> Cow<str> {
+    let orig_len = string.len();
+    let (string, tlen) = string.unicode_truncate(len);
+    if tlen == orig_len {
+        string.into()
+    } else {
+       [string, "..."].concat().into()
+    }
+}
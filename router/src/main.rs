@@ -1,11 +1,12 @@
 /// Text Generation Inference external gRPC server entrypoint
 use clap::Parser;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use text_generation_client::ShardedClient;
+use std::time::Duration;
+use text_generation_client::ChannelConfig;
 use text_generation_router::server;
-use tokenizers::Tokenizer;
-use tracing::warn;
-use text_generation_router::server::ServerRunArgs;
+use text_generation_router::server::{DEBUG_REQUEST_DIRECTIVE, LogLevelSetter, ServerRunArgs};
+use tracing_subscriber::{fmt, reload, EnvFilter};
+use tracing_subscriber::prelude::*;
 
 /// App Configuration
 #[derive(Parser, Debug)]
@@ -17,6 +18,31 @@ struct Args {
     max_sequence_length: usize,
     #[clap(default_value = "1024", long, env)]
     max_new_tokens: usize,
+    // Max top candidate tokens per returned token, and the number included
+    // when token details are requested without specifying top_n_tokens
+    #[clap(default_value = "5", long, env)]
+    max_top_n_tokens: u32,
+    #[clap(default_value = "0", long, env)]
+    default_top_n_tokens: u32,
+    // What to do when a request's input plus max_new_tokens exceeds max_sequence_length:
+    // truncate (reduce max_new_tokens to fit, with a warning) or reject
+    #[clap(default_value = "truncate", long, env)]
+    token_limit_policy: String,
+    // Name of the served model, used to select its section of the parameter policy file
+    // and returned in responses if the shards don't report the model's id
+    #[clap(long, env)]
+    model_name: Option<String>,
+    // Version of the served model, returned in responses if the shards don't report it
+    #[clap(long, env)]
+    model_version: Option<String>,
+    // JSON file of per-model defaults and clamps applied to temperature, top_p and
+    // max_new_tokens during validation
+    #[clap(long, env)]
+    parameter_policy_path: Option<String>,
+    // JSON file of named prompt templates which requests can reference instead of
+    // providing text, reloaded when modified
+    #[clap(long, env)]
+    prompt_templates_path: Option<String>,
     #[clap(default_value = "12", long, env)]
     max_batch_size: usize,
     #[clap(default_value = None, long, env)]
@@ -25,6 +51,18 @@ struct Args {
     max_prefill_weight: Option<usize>,
     #[clap(default_value = "24", long, env)]
     max_waiting_tokens: usize,
+    // fixed (always wait max_waiting_tokens steps before adding to a running batch) or
+    // adaptive (based on recent prefill and step durations and the queue length)
+    #[clap(default_value = "adaptive", long, env)]
+    waiting_tokens_policy: String,
+    // Prefill requests added to the running batch concurrently with its next generation
+    // step instead of before it, the shards must accept concurrent Prefill and NextToken calls
+    #[clap(long, env)]
+    pipeline_prefill: bool,
+    // Regenerate the requests of a failed prefill or generation step rather than failing them
+    // all, retrying once and then splitting the batch to isolate the requests causing the failure
+    #[clap(long, env)]
+    retry_failed_batches: bool,
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
     #[clap(default_value = "8033", long, short, env)]
@@ -35,6 +73,9 @@ struct Args {
     tokenizer_path: String,
     #[clap(default_value = "2", long, env)]
     validation_workers: usize,
+    // Max number of unary responses decoded concurrently, off the async runtime threads
+    #[clap(default_value = "2", long, env)]
+    detokenization_workers: usize,
     #[clap(long, env)]
     json_output: bool,
     #[clap(long, env)]
@@ -43,23 +84,261 @@ struct Args {
     tls_key_path: Option<String>,
     #[clap(long, env)]
     tls_client_ca_cert_path: Option<String>,
+    // JSON file of API keys, as a list of {"key", "caller", "tenant"} objects, accepted
+    // by the external gRPC services, which require authentication if this or
+    // jwt_key_path is set
+    #[clap(long, env)]
+    api_keys_path: Option<String>,
+    // PEM public key (RSA or EC), or otherwise HMAC secret, with which accepted JWTs are signed
+    #[clap(long, env)]
+    jwt_key_path: Option<String>,
+    // Required iss and aud claims of accepted JWTs
+    #[clap(long, env)]
+    jwt_issuer: Option<String>,
+    #[clap(long, env)]
+    jwt_audience: Option<String>,
+    // JWT claim holding the caller's tenant, their subject is used if absent
+    #[clap(default_value = "tenant", long, env)]
+    jwt_tenant_claim: String,
     #[clap(long, env)]
     output_special_tokens: bool,
+    // Inserted between the input and output text of seq2seq models' responses which include
+    // the input, with \n, \t and \\ escapes. Empty for none
+    #[clap(default_value = "\\n\\n", long, env)]
+    seq2seq_input_separator: String,
+    // Fraction of greedy requests re-run to audit determinism, if all shards report
+    // that greedy decoding is deterministic
+    #[clap(default_value = "0.0", long, env)]
+    determinism_audit_fraction: f32,
+    #[clap(long, env)]
+    warmup: bool,
+    // Structured per-request log: stdout, file:<path> or grpc:<url> of a
+    // RequestLogCollector service (see proto/request_log.proto)
+    #[clap(long, env)]
+    request_log_sink: Option<String>,
+    // How prompts and outputs appear in logs and traces, one of
+    // full, hash, truncate:<chars> or omit
+    #[clap(default_value = "truncate:32", long, env)]
+    log_text_policy: String,
+    // Comma-separated built-in request hooks to enable: logging, metrics
+    #[clap(long, env)]
+    request_hooks: Option<String>,
+    // gzip compression of external gRPC responses, when accepted by the caller
+    #[clap(long, env)]
+    grpc_compression: bool,
+    // gzip compression of gRPC messages exchanged with the shards
+    #[clap(long, env)]
+    shard_grpc_compression: bool,
+    // Serve gRPC-Web to browser apps, from a comma-separated list of origins
+    #[clap(long, env)]
+    grpc_web_origins: Option<String>,
+    // Comma-separated fill-in-the-middle sentinel tokens: <prefix>,<suffix>,<middle>
+    #[clap(long, env)]
+    fim_sentinel_tokens: Option<String>,
+    #[clap(long, env)]
+    runtime_config_path: Option<String>,
+    #[clap(long, env)]
+    coalesce_requests: bool,
+    // Max number of undelivered responses buffered per streaming request
+    #[clap(default_value = "32", long, env)]
+    stream_buffer_size: usize,
+    // What to do when a stream's buffer is full: coalesce, warn, pause or cancel
+    #[clap(default_value = "coalesce", long, env)]
+    slow_stream_policy: String,
+    // How often to check that all shards are responding, 0 to disable
+    #[clap(default_value = "10", long, env)]
+    shard_health_check_interval_secs: u64,
+    // Max concurrent requests per client, identified by the x-caller-id
+    // header if provided, otherwise by IP address
+    #[clap(long, env)]
+    max_concurrent_requests_per_client: Option<usize>,
+    // Max total bytes of the prompts of queued requests, beyond which requests
+    // are rejected as if the queue were full
+    #[clap(long, env)]
+    max_queued_prompt_bytes: Option<usize>,
+    // Batching strategy: flash, padded, or auto to match the shards' memory model
+    #[clap(default_value = "auto", long, env)]
+    batch_type: String,
+    // Max number of responses to deterministic requests to cache, 0 disables caching
+    #[clap(default_value = "0", long, env)]
+    response_cache_size: u64,
+    #[clap(default_value = "300", long, env)]
+    response_cache_ttl_secs: u64,
+    // Max total bytes of the prompts clients can register to reference in subsequent
+    // requests, 0 disables prompt registration
+    #[clap(default_value = "0", long, env)]
+    prompt_registry_capacity_bytes: u64,
+    #[clap(default_value = "3600", long, env)]
+    registered_prompt_idle_timeout_secs: u64,
+    // Comma-separated master shard sockets of additional data-parallel replica groups
+    #[clap(long, env, value_delimiter = ',')]
+    replica_master_shard_uds_paths: Vec<String>,
+    // host:port of a DNS name, such as a headless service, resolving to the addresses of
+    // single-shard replicas. Used instead of the shard sockets, and re-resolved periodically
+    // to add and remove replicas
+    #[clap(long, env)]
+    shard_dns_name: Option<String>,
+    #[clap(default_value = "30", long, env)]
+    shard_dns_refresh_secs: u64,
+    // HTTP/2 settings of the gRPC channels to the shards, tonic's defaults if unset
+    #[clap(long, env)]
+    shard_keepalive_interval_secs: Option<u64>,
+    #[clap(long, env)]
+    shard_keepalive_timeout_secs: Option<u64>,
+    #[clap(long, env)]
+    shard_initial_stream_window_size: Option<u32>,
+    #[clap(long, env)]
+    shard_initial_connection_window_size: Option<u32>,
+    // Max size in bytes of gRPC messages exchanged with the shards
+    #[clap(long, env)]
+    shard_max_message_size: Option<usize>,
+    // Time to wait for each connection attempt to a shard, and how many times to retry failed ones
+    #[clap(long, env)]
+    shard_connect_timeout_secs: Option<u64>,
+    #[clap(default_value = "0", long, env)]
+    shard_connect_retries: u32,
+    #[clap(long, env)]
+    shard_tcp_nodelay: Option<bool>,
+    // Max time to wait for the shards to complete a generation step before failing
+    // the batch's requests, the latest deadline of the batch's requests also applies
+    #[clap(long, env)]
+    shard_step_timeout_secs: Option<u64>,
+    // Time to let in-progress requests continue after a shutdown signal, after which
+    // their generation is stopped with the SERVER_SHUTDOWN stop reason. If unset
+    // they're left to complete
+    #[clap(long, env)]
+    shutdown_grace_period_secs: Option<u64>,
+    // Time to first token objective. New requests whose projected time to first token,
+    // from the queue wait and recent prefill times, exceeds it are rejected immediately
+    #[clap(long, env)]
+    ttft_slo_millis: Option<u64>,
+    // Use the shards' streaming prefill method, to send streaming requests prefill_progress
+    // updates while long inputs are processed. Requires shard support
+    #[clap(long, env)]
+    shard_prefill_progress: bool,
+    // How long-running requests are preempted when more urgent ones can't fit
+    // in the batch: none, offload (requires shard support) or requeue
+    #[clap(default_value = "none", long, env)]
+    preemption_policy: String,
+    // Requests are only preempted once they've generated this many tokens
+    #[clap(default_value = "256", long, env)]
+    preemption_min_generated_tokens: u32,
+    // Tokenizer implementation used to decode generated tokens: huggingface (the
+    // tokenizer at tokenizer_path), sentencepiece or tiktoken
+    #[clap(default_value = "huggingface", long, env)]
+    decoder_backend: String,
+    // sentencepiece .model or tiktoken BPE file, required by those decoder backends
+    #[clap(long, env)]
+    decoder_model_path: Option<String>,
+    // Max number of token ids whose text is cached for token details, 0 disables the cache
+    #[clap(default_value = "65536", long, env)]
+    token_text_cache_size: u64,
+    // Max number of top candidate tokens decoded for each response, beyond which they're
+    // omitted with a warning, 0 for no limit
+    #[clap(default_value = "65536", long, env)]
+    max_decoded_top_tokens: usize,
+    // Max number of background generations whose progress and results are retained,
+    // 0 disables the generation job API
+    #[clap(default_value = "0", long, env)]
+    max_generation_jobs: u64,
+    // How long generation job results are retained after completion
+    #[clap(default_value = "600", long, env)]
+    generation_job_ttl_secs: u64,
+    // File to which queued requests are journaled, so that those which haven't left the
+    // queue when the router stops are resubmitted as generation jobs when it restarts
+    #[clap(long, env)]
+    generation_job_journal_path: Option<String>,
+    // Model's config.json, used to estimate the KV cache memory of requests
+    #[clap(long, env)]
+    model_config_path: Option<String>,
+    // Model's tokenizer_config.json, whose chat template is used to render the
+    // definitions of tools provided with requests into their prompts
+    #[clap(long, env)]
+    tokenizer_config_path: Option<String>,
+    // Memory available for the KV cache across the shards of a replica, requests whose
+    // estimated cache can't fit are rejected or wait until the batch has room for them
+    #[clap(long, env)]
+    kv_cache_capacity_bytes: Option<u64>,
+    // Order in which waiting requests are batched: fifo, or sjf to favour requests
+    // with the fewest max_new_tokens left to generate
+    #[clap(default_value = "fifo", long, env)]
+    scheduling_policy: String,
+    // With the sjf policy, expected remaining tokens deducted from a request for
+    // each second it waits so that long requests are eventually batched
+    #[clap(default_value = "20", long, env)]
+    sjf_aging_rate: f64,
+    // Requests with at most this many input tokens and latency_lane_max_new_tokens
+    // are batched separately from longer ones, as soon as they fit. Either may be omitted
+    #[clap(long, env)]
+    latency_lane_max_input_tokens: Option<usize>,
+    #[clap(long, env)]
+    latency_lane_max_new_tokens: Option<u32>,
+    // Max number of inputs in a batch of embedding requests, which are batched
+    // separately from generation. 0 disables the embeddings API
+    #[clap(default_value = "0", long, env)]
+    max_embedding_batch_size: usize,
+    // Max total input tokens in a batch of embedding requests
+    #[clap(default_value = "16384", long, env)]
+    max_embedding_batch_tokens: usize,
+    // Serve the AdminService gRPC service, with which the router can be switched to
+    // a new tokenizer and shards without downtime. Requires admin_token, which calls
+    // must present in an x-admin-token header
+    #[clap(long, env)]
+    admin_api: bool,
+    // Bearer token required by the /admin/state HTTP endpoint, which reports batches,
+    // queues and shard status and is only served if this is set, and by AdminService
+    #[clap(long, env)]
+    admin_token: Option<String>,
+    // Number of recently completed generations whose parameters, seed and token ids are
+    // recorded so that they can be replayed via the admin HTTP endpoints, 0 disables
+    #[clap(default_value = "0", long, env)]
+    replay_buffer_size: usize,
 }
 
 fn main() -> Result<(), std::io::Error> {
     // Get args
     let args = Args::parse();
 
+    // Log filter can be changed at runtime via the runtime config file or admin endpoint.
+    // Requests flagged for debugging are logged at DEBUG level whatever the filter
+    let log_filter = |directives: &str| EnvFilter::try_new(format!("{directives},{DEBUG_REQUEST_DIRECTIVE}"));
+    let (level_filter, level_handle) = reload::Layer::new(log_filter("info").unwrap());
+    let registry = tracing_subscriber::registry().with(level_filter);
     if args.json_output {
-        tracing_subscriber::fmt().json().with_current_span(false).init();
+        registry.with(fmt::layer().json().with_current_span(false)).init();
     } else {
-        tracing_subscriber::fmt().compact().init();
+        registry.with(fmt::layer().compact()).init();
     }
+    let log_level_setter: LogLevelSetter = Box::new(move |directives: &str| {
+        let filter = log_filter(directives).map_err(|e| format!("invalid log filter: {e}"))?;
+        level_handle.reload(filter).map_err(|e| e.to_string())
+    });
 
     if args.validation_workers == 0 {
         panic!("validation_workers must be > 0");
     }
+    if args.detokenization_workers == 0 {
+        panic!("detokenization_workers must be > 0");
+    }
+
+    if args.shard_dns_name.is_some() && !args.replica_master_shard_uds_paths.is_empty() {
+        panic!("replica_master_shard_uds_paths can't be used with shard_dns_name");
+    }
+    if args.shard_dns_refresh_secs == 0 {
+        panic!("shard_dns_refresh_secs must be > 0");
+    }
+
+    if args.default_top_n_tokens > args.max_top_n_tokens {
+        panic!("default_top_n_tokens must be <= max_top_n_tokens");
+    }
+
+    if args.stream_buffer_size == 0 {
+        panic!("stream_buffer_size must be > 0");
+    }
+
+    if !(0.0..=1.0).contains(&args.determinism_audit_fraction) {
+        panic!("determinism_audit_fraction must be between 0.0 and 1.0");
+    }
 
     if args.tls_key_path.is_some() != args.tls_cert_path.is_some() {
         panic!("tls: must provide both cert and key")
@@ -69,20 +348,17 @@ fn main() -> Result<(), std::io::Error> {
         panic!("tls: cannot provide client ca cert without keypair")
     }
 
-    // Instantiate tokenizer
-    let mut tokenizer = Tokenizer::from_file(args.tokenizer_path)
-        .expect("Problem loading tokenizer for model");
+    if (args.jwt_issuer.is_some() || args.jwt_audience.is_some()) && args.jwt_key_path.is_none() {
+        panic!("jwt_issuer and jwt_audience require jwt_key_path")
+    }
 
-    if let Some(tp) = tokenizer.get_truncation() {
-        if tp.max_length < args.max_sequence_length {
-            warn!(
-                "Ignoring fast tokenizer truncation configuration with max_length {}, \
-                max_sequence_length is set to {}",
-                tp.max_length, args.max_sequence_length,
-            );
-        }
+    if args.admin_api && args.admin_token.is_none() {
+        panic!("admin_api requires admin_token")
     }
-    tokenizer.with_truncation(None).with_padding(None);
+
+    // Instantiate tokenizer
+    let tokenizer = server::load_tokenizer(&args.tokenizer_path, args.max_sequence_length)
+        .expect("Problem loading tokenizer for model");
 
     // Launch Tokio runtime
     tokio::runtime::Builder::new_multi_thread()
@@ -90,16 +366,39 @@ fn main() -> Result<(), std::io::Error> {
         .build()
         .unwrap()
         .block_on(async {
-            // Instantiate sharded client from the master unix socket
-            let mut sharded_client = ShardedClient::connect_uds(args.master_shard_uds_path)
-                .await
-                .expect("Could not connect to server");
-            // Clear the cache; useful if the webserver rebooted
-            sharded_client
-                .clear_cache()
-                .await
-                .expect("Unable to clear cache");
-            tracing::info!("Connected");
+            let channel_config = ChannelConfig {
+                keepalive_interval: args.shard_keepalive_interval_secs.map(Duration::from_secs),
+                keepalive_timeout: args.shard_keepalive_timeout_secs.map(Duration::from_secs),
+                initial_stream_window_size: args.shard_initial_stream_window_size,
+                initial_connection_window_size: args.shard_initial_connection_window_size,
+                max_message_size: args.shard_max_message_size,
+                connect_timeout: args.shard_connect_timeout_secs.map(Duration::from_secs),
+                tcp_nodelay: args.shard_tcp_nodelay,
+                connect_retries: args.shard_connect_retries,
+            };
+            let (sharded_client, replica_clients, shard_discovery) = match args.shard_dns_name {
+                Some(name) => {
+                    let discovery = server::discover_shards(
+                        name, Duration::from_secs(args.shard_dns_refresh_secs),
+                        &channel_config, args.shard_grpc_compression,
+                    ).await.unwrap_or_else(|e| panic!("{e}"));
+                    let mut clients = discovery.clients();
+                    (clients.remove(0), clients, Some(discovery))
+                },
+                None => {
+                    let sharded_client = server::connect_shards(
+                        args.master_shard_uds_path, &channel_config, args.shard_grpc_compression,
+                    ).await.expect("Could not connect to server");
+                    let mut replica_clients = Vec::with_capacity(args.replica_master_shard_uds_paths.len());
+                    for path in args.replica_master_shard_uds_paths {
+                        replica_clients.push(
+                            server::connect_shards(path, &channel_config, args.shard_grpc_compression).await
+                                .expect("Could not connect to server")
+                        );
+                    }
+                    (sharded_client, replica_clients, None)
+                },
+            };
 
             let grpc_addr = SocketAddr::new(
                 IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), args.grpc_port
@@ -115,18 +414,88 @@ fn main() -> Result<(), std::io::Error> {
                 max_concurrent_requests: args.max_concurrent_requests,
                 max_sequence_length: args.max_sequence_length,
                 max_new_tokens: args.max_new_tokens,
+                max_top_n_tokens: args.max_top_n_tokens,
+                default_top_n_tokens: args.default_top_n_tokens,
+                token_limit_policy: args.token_limit_policy,
+                model_name: args.model_name,
+                model_version: args.model_version,
+                parameter_policy_path: args.parameter_policy_path,
+                prompt_templates_path: args.prompt_templates_path,
                 max_batch_size: args.max_batch_size,
                 max_batch_weight: args.max_batch_weight,
                 max_prefill_weight: args.max_prefill_weight,
                 max_waiting_tokens: args.max_waiting_tokens,
+                waiting_tokens_policy: args.waiting_tokens_policy,
+                pipeline_prefill: args.pipeline_prefill,
+                retry_failed_batches: args.retry_failed_batches,
                 client: sharded_client,
                 tokenizer,
                 validation_workers: args.validation_workers,
+                detokenization_workers: args.detokenization_workers,
                 addr,
                 grpc_addr,
                 tls_key_pair: args.tls_cert_path.map(|cp| (cp, args.tls_key_path.unwrap())),
                 tls_client_ca_cert: args.tls_client_ca_cert_path,
+                api_keys_path: args.api_keys_path,
+                jwt_key_path: args.jwt_key_path,
+                jwt_issuer: args.jwt_issuer,
+                jwt_audience: args.jwt_audience,
+                jwt_tenant_claim: args.jwt_tenant_claim,
                 output_special_tokens: args.output_special_tokens,
+                seq2seq_input_separator: args.seq2seq_input_separator,
+                determinism_audit_fraction: args.determinism_audit_fraction,
+                warmup: args.warmup,
+                request_log_sink: args.request_log_sink,
+                log_text_policy: args.log_text_policy,
+                grpc_compression: args.grpc_compression,
+                grpc_web_origins: args.grpc_web_origins,
+                fim_sentinel_tokens: args.fim_sentinel_tokens,
+                runtime_config_path: args.runtime_config_path,
+                log_level_setter: Some(log_level_setter),
+                coalesce_requests: args.coalesce_requests,
+                stream_buffer_size: args.stream_buffer_size,
+                slow_stream_policy: args.slow_stream_policy,
+                shard_health_check_interval_secs: args.shard_health_check_interval_secs,
+                shard_step_timeout_secs: args.shard_step_timeout_secs,
+                shutdown_grace_period_secs: args.shutdown_grace_period_secs,
+                ttft_slo_millis: args.ttft_slo_millis,
+                shard_prefill_progress: args.shard_prefill_progress,
+                safety_filter: None,
+                request_hooks: vec![],
+                builtin_request_hooks: args.request_hooks,
+                max_concurrent_requests_per_client: args.max_concurrent_requests_per_client,
+                max_queued_prompt_bytes: args.max_queued_prompt_bytes,
+                batch_type: args.batch_type,
+                response_cache_size: args.response_cache_size,
+                response_cache_ttl_secs: args.response_cache_ttl_secs,
+                response_cache_store: None,
+                prompt_registry_capacity_bytes: args.prompt_registry_capacity_bytes,
+                registered_prompt_idle_timeout_secs: args.registered_prompt_idle_timeout_secs,
+                replica_clients,
+                shard_discovery,
+                preemption_policy: args.preemption_policy,
+                preemption_min_generated_tokens: args.preemption_min_generated_tokens,
+                decoder_backend: args.decoder_backend,
+                decoder_model_path: args.decoder_model_path,
+                token_text_cache_size: args.token_text_cache_size,
+                max_decoded_top_tokens: args.max_decoded_top_tokens,
+                max_generation_jobs: args.max_generation_jobs,
+                generation_job_ttl_secs: args.generation_job_ttl_secs,
+                generation_job_journal_path: args.generation_job_journal_path,
+                model_config_path: args.model_config_path,
+                tokenizer_config_path: args.tokenizer_config_path,
+                kv_cache_capacity_bytes: args.kv_cache_capacity_bytes,
+                scheduling_policy: args.scheduling_policy,
+                sjf_aging_rate: args.sjf_aging_rate,
+                latency_lane_max_input_tokens: args.latency_lane_max_input_tokens,
+                latency_lane_max_new_tokens: args.latency_lane_max_new_tokens,
+                max_embedding_batch_size: args.max_embedding_batch_size,
+                max_embedding_batch_tokens: args.max_embedding_batch_tokens,
+                shard_channel_config: channel_config,
+                shard_grpc_compression: args.shard_grpc_compression,
+                admin_api: args.admin_api,
+                admin_token: args.admin_token,
+                replay_buffer_size: args.replay_buffer_size,
             })
             .await;
             Ok(())
@@ -0,0 +1,101 @@
+/// Description of the served models, so that clients can discover model ids,
+/// limits and supported features rather than hardcoding them
+use axum::extract::Extension;
+use axum::Json;
+use serde::Serialize;
+use crate::pb::fmaas::{ListModelsResponse, ModelCapabilities as ProtoCapabilities, ModelInfoResponse};
+use crate::pb::fmaas::model_info_response::ModelKind;
+use crate::server::ServerState;
+
+/// Optional features which requests to a model can use, given the router's
+/// configuration and what the shards support
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub(crate) struct ModelCapabilities {
+    pub(crate) embeddings: bool,
+    pub(crate) prefill_progress: bool,
+    pub(crate) generation_jobs: bool,
+    pub(crate) speculative_decoding: bool,
+    pub(crate) adapters: bool,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    DecoderOnly,
+    EncoderDecoder,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ModelDescription {
+    /// Empty if unknown
+    id: String,
+    version: String,
+    kind: Kind,
+    max_sequence_length: u32,
+    max_new_tokens: u32,
+    max_top_n_tokens: u32,
+    capabilities: ModelCapabilities,
+}
+
+impl ModelDescription {
+    /// The model served by the current deployment
+    pub(crate) fn of(state: &ServerState) -> Self {
+        let deployment = state.deployment();
+        Self {
+            id: deployment.model.id.clone(),
+            version: deployment.model.version.clone(),
+            kind: if deployment.seq2seq { Kind::EncoderDecoder } else { Kind::DecoderOnly },
+            max_sequence_length: state.max_sequence_length as u32,
+            max_new_tokens: state.max_new_tokens as u32,
+            max_top_n_tokens: state.max_top_n_tokens,
+            capabilities: ModelCapabilities {
+                generation_jobs: state.generation_jobs.is_some(),
+                ..deployment.capabilities
+            },
+        }
+    }
+}
+
+/// Models which can currently be requested. A single model is served at a time,
+/// which may be replaced by a model swap
+pub(crate) fn served_models(state: &ServerState) -> Vec<ModelDescription> {
+    vec![ModelDescription::of(state)]
+}
+
+impl From<ModelDescription> for ModelInfoResponse {
+    fn from(model: ModelDescription) -> Self {
+        let capabilities = model.capabilities;
+        Self {
+            model_kind: i32::from(match model.kind {
+                Kind::DecoderOnly => ModelKind::DecoderOnly,
+                Kind::EncoderDecoder => ModelKind::EncoderDecoder,
+            }),
+            max_sequence_length: model.max_sequence_length,
+            max_new_tokens: model.max_new_tokens,
+            max_top_n_tokens: model.max_top_n_tokens,
+            model_id: model.id,
+            model_version: model.version,
+            capabilities: Some(ProtoCapabilities {
+                embeddings: capabilities.embeddings,
+                prefill_progress: capabilities.prefill_progress,
+                generation_jobs: capabilities.generation_jobs,
+                speculative_decoding: capabilities.speculative_decoding,
+                adapters: capabilities.adapters,
+            }),
+        }
+    }
+}
+
+pub(crate) fn list_models_response(state: &ServerState) -> ListModelsResponse {
+    ListModelsResponse { models: served_models(state).into_iter().map(Into::into).collect() }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ModelList {
+    models: Vec<ModelDescription>,
+}
+
+/// REST equivalent of the ListModels RPC
+pub(crate) async fn list_models(state: Extension<ServerState>) -> Json<ModelList> {
+    Json(ModelList { models: served_models(&state) })
+}
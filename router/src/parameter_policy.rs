@@ -0,0 +1,154 @@
+/// Per-model defaults and limits of generation parameters, set centrally by the platform
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::GenerateParameters;
+
+/// Policy of each model, keyed by model name
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ParameterPolicies {
+    #[serde(default)]
+    models: HashMap<String, ParameterPolicy>,
+}
+
+impl ParameterPolicies {
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read parameter policy file {path}: {e}"))?;
+        let policies: Self = serde_json::from_str(&contents)
+            .map_err(|e| format!("invalid parameter policy file {path}: {e}"))?;
+        for (model, policy) in &policies.models {
+            policy.check().map_err(|e| format!("invalid parameter policy for model {model}: {e}"))?;
+        }
+        Ok(policies)
+    }
+
+    /// Policy of the given model, if there is one
+    pub(crate) fn for_model(mut self, model_name: &str) -> Option<ParameterPolicy> {
+        self.models.remove(model_name)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ParameterPolicy {
+    /// Applied in place of the server's defaults to parameters which requests leave unset
+    #[serde(default)]
+    defaults: ParameterDefaults,
+    /// Limits which request parameters are reduced or raised to
+    #[serde(default)]
+    clamps: ParameterClamps,
+    /// Include a warning in the response for each clamped parameter
+    #[serde(default = "default_report_clamps")]
+    report_clamps: bool,
+}
+
+fn default_report_clamps() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParameterDefaults {
+    /// Temperature and top_p apply only to sampling
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_new_tokens: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParameterClamps {
+    max_new_tokens: Option<u32>,
+    /// Temperature and top_p limits apply only to sampling
+    min_temperature: Option<f32>,
+    max_temperature: Option<f32>,
+    max_top_p: Option<f32>,
+}
+
+/// Parameters which requests left unset, to which a model's defaults apply
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct UnsetParameters {
+    pub(crate) temperature: bool,
+    pub(crate) top_p: bool,
+    pub(crate) max_new_tokens: bool,
+}
+
+impl ParameterPolicy {
+    fn check(&self) -> Result<(), String> {
+        let (d, c) = (&self.defaults, &self.clamps);
+        if matches!(c.max_new_tokens, Some(0)) || matches!(d.max_new_tokens, Some(0)) {
+            return Err("max_new_tokens must be > 0".to_string())
+        }
+        if [d.temperature, c.min_temperature, c.max_temperature].iter().flatten().any(|&t| t < 0.05) {
+            return Err("temperatures must be >= 0.05".to_string())
+        }
+        if [d.top_p, c.max_top_p].iter().flatten().any(|&p| p <= 0.0 || p > 1.0) {
+            return Err("top_p values must be in (0, 1]".to_string())
+        }
+        if matches!((c.min_temperature, c.max_temperature), (Some(min), Some(max)) if min > max) {
+            return Err("min_temperature exceeds max_temperature".to_string())
+        }
+        Ok(())
+    }
+
+    /// Apply the defaults to unset parameters and then the clamps, returning a
+    /// warning for each clamped parameter if they're to be reported
+    pub(crate) fn apply(&self, params: &mut GenerateParameters) -> Vec<String> {
+        let sampling = params.temperature != 0.0;
+        let (d, c) = (&self.defaults, &self.clamps);
+        if sampling && params.unset.temperature {
+            params.temperature = d.temperature.unwrap_or(params.temperature);
+        }
+        if sampling && params.unset.top_p {
+            params.top_p = d.top_p.unwrap_or(params.top_p);
+        }
+        if params.unset.max_new_tokens {
+            params.max_new_tokens = d.max_new_tokens.unwrap_or(params.max_new_tokens);
+        }
+
+        let mut clamped = vec![];
+        let mut changed = vec![];
+        let mut clamp = |name: &str, value: String, limit: String| {
+            metrics::increment_counter!("tgi_parameter_clamped", "parameter" => name.to_string());
+            clamped.push(format!("{name} changed from {value} to {limit} by the model's parameter policy"));
+            if !changed.iter().any(|c| c == name) {
+                changed.push(name.to_string());
+            }
+        };
+        if let Some(max) = c.max_new_tokens.filter(|&max| params.max_new_tokens > max) {
+            clamp("max_new_tokens", params.max_new_tokens.to_string(), max.to_string());
+            params.max_new_tokens = max;
+            params.min_new_tokens = params.min_new_tokens.min(max);
+        }
+        if sampling {
+            if let Some(min) = c.min_temperature.filter(|&min| params.temperature < min) {
+                clamp("temperature", params.temperature.to_string(), min.to_string());
+                params.temperature = min;
+            }
+            if let Some(max) = c.max_temperature.filter(|&max| params.temperature > max) {
+                clamp("temperature", params.temperature.to_string(), max.to_string());
+                params.temperature = max;
+            }
+            for (_, temperature) in params.temperature_schedule.iter_mut() {
+                let limited = temperature.clamp(
+                    c.min_temperature.unwrap_or(f32::MIN), c.max_temperature.unwrap_or(f32::MAX),
+                );
+                if limited != *temperature {
+                    clamp("temperature_schedule", temperature.to_string(), limited.to_string());
+                    *temperature = limited;
+                }
+            }
+            if let Some(max) = c.max_top_p.filter(|&max| params.top_p > max) {
+                clamp("top_p", params.top_p.to_string(), max.to_string());
+                params.top_p = max;
+            }
+        }
+        if self.report_clamps {
+            params.normalized.extend(changed);
+            clamped
+        } else {
+            vec![]
+        }
+    }
+}
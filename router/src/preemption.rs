@@ -0,0 +1,75 @@
+/// Preemption of long-running requests in favour of more urgent waiting ones
+use std::cmp::Reverse;
+use std::str::FromStr;
+use nohash_hasher::IntMap;
+use crate::queue::Entry;
+
+/// How preempted requests are removed from the running batch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PreemptionPolicy {
+    /// The shards move the request's cache to host memory, and generation
+    /// resumes where it left off once the request is batched again
+    Offload,
+    /// The request is returned to the queue and generated again from the start.
+    /// Streaming requests aren't preempted since their output has already been sent
+    Requeue,
+}
+
+impl FromStr for PreemptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "offload" => Ok(Self::Offload),
+            "requeue" => Ok(Self::Requeue),
+            _ => Err(format!("invalid preemption policy '{s}', must be none, offload or requeue")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Preemption {
+    pub(crate) policy: PreemptionPolicy,
+    /// Only requests which have generated at least this many tokens are preempted
+    pub(crate) min_generated_tokens: u32,
+}
+
+impl Preemption {
+    /// Parse the policy name, returns None if preemption is disabled
+    pub(crate) fn for_policy(name: &str, min_generated_tokens: u32) -> Result<Option<Self>, String> {
+        match name {
+            "none" => Ok(None),
+            name => Ok(Some(Self { policy: name.parse()?, min_generated_tokens })),
+        }
+    }
+
+    fn is_preemptible(&self, entry: &Entry) -> bool {
+        entry.generated_tokens >= self.min_generated_tokens
+            && !entry.preempted
+            && (self.policy == PreemptionPolicy::Offload || entry.stream_tx.is_none())
+    }
+
+    /// Choose running entries to preempt, one for each waiting request with a higher
+    /// priority. The lowest priority entries with the most tokens left to generate
+    /// are chosen first.
+    pub(crate) fn select(&self, waiting_priorities: &[i32], entries: &IntMap<u64, Entry>) -> Vec<u64> {
+        let mut candidates = entries.iter()
+            .filter(|(_, e)| self.is_preemptible(e))
+            .map(|(id, e)| (
+                e.request.priority,
+                Reverse(e.request.parameters.max_new_tokens - e.generated_tokens),
+                *id,
+            ))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        let mut candidates = candidates.into_iter().peekable();
+        let mut chosen = vec![];
+        for &priority in waiting_priorities {
+            match candidates.next_if(|(p, _, _)| *p < priority) {
+                Some((_, _, id)) => chosen.push(id),
+                None => break,
+            }
+        }
+        chosen
+    }
+}
@@ -0,0 +1,60 @@
+/// Prompts registered by clients, so that long prompts shared by many requests
+/// needn't be sent with each of them
+use std::sync::Arc;
+use std::time::Duration;
+use moka::sync::Cache;
+
+/// A registered prompt, which requests referencing it are composed from
+#[derive(Debug)]
+pub(crate) struct RegisteredPrompt {
+    pub text: String,
+}
+
+/// Registered prompts by id. Those unused for longer than the idle timeout expire, as
+/// do the least recently used ones when their total size exceeds the capacity
+pub(crate) struct PromptRegistry {
+    prompts: Cache<String, Arc<RegisteredPrompt>>,
+    max_prompt_bytes: usize,
+}
+
+impl PromptRegistry {
+    pub(crate) fn new(capacity_bytes: u64, idle_timeout: Duration) -> Self {
+        let prompts = Cache::builder()
+            .weigher(|_, prompt: &Arc<RegisteredPrompt>| prompt.text.len().try_into().unwrap_or(u32::MAX))
+            .max_capacity(capacity_bytes)
+            .time_to_idle(idle_timeout)
+            .build();
+        // A larger prompt would evict all the others
+        let max_prompt_bytes = (capacity_bytes / 4) as usize;
+        Self { prompts, max_prompt_bytes }
+    }
+
+    /// Register the prompt, returning its id
+    pub(crate) fn register(&self, text: String) -> Result<String, String> {
+        if text.is_empty() {
+            return Err("prompt text must be non-empty".to_string())
+        }
+        if text.len() > self.max_prompt_bytes {
+            return Err(format!(
+                "prompt of {} bytes exceeds the limit of {} bytes", text.len(), self.max_prompt_bytes,
+            ))
+        }
+        let prompt_id = format!("{:032x}", rand::random::<u128>());
+        self.prompts.insert(prompt_id.clone(), Arc::new(RegisteredPrompt { text }));
+        metrics::increment_counter!("tgi_prompt_registered");
+        Ok(prompt_id)
+    }
+
+    /// The registered prompt with the given id, None if it's unknown or has expired
+    pub(crate) fn get(&self, prompt_id: &str) -> Option<Arc<RegisteredPrompt>> {
+        let prompt = self.prompts.get(prompt_id);
+        let result = if prompt.is_some() { "hit" } else { "miss" };
+        metrics::increment_counter!("tgi_prompt_registry_lookup", "result" => result);
+        prompt
+    }
+
+    /// Release the prompt, returns false if it isn't known
+    pub(crate) fn release(&self, prompt_id: &str) -> bool {
+        self.prompts.remove(prompt_id).is_some()
+    }
+}
@@ -0,0 +1,169 @@
+/// Registry of named prompt templates, expanded by the router so that prompts can be
+/// updated centrally without changing clients
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use rand::distributions::{Distribution, WeightedIndex};
+use serde::Deserialize;
+use tracing::{error, info};
+use crate::runtime_config::watch_file;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplatesFile {
+    templates: HashMap<String, TemplateConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateConfig {
+    /// Alternative texts, one of which is chosen at random for each request
+    variants: Vec<VariantConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VariantConfig {
+    /// Text with `{name}` placeholders, `{{` and `}}` for literal braces
+    text: String,
+    /// Relative likelihood of this variant being chosen
+    #[serde(default = "default_weight")]
+    weight: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Debug)]
+enum Segment {
+    Text(String),
+    Placeholder(String),
+}
+
+#[derive(Debug)]
+struct Template {
+    variants: Vec<Vec<Segment>>,
+    weights: WeightedIndex<f64>,
+    /// Names of the placeholders, the same in every variant
+    placeholders: BTreeSet<String>,
+}
+
+impl Template {
+    fn new(config: TemplateConfig) -> Result<Self, String> {
+        if config.variants.is_empty() {
+            return Err("no variants".to_string())
+        }
+        let weights = WeightedIndex::new(config.variants.iter().map(|v| v.weight))
+            .map_err(|e| format!("invalid weights: {e}"))?;
+        let variants = config.variants.iter().map(|v| parse(&v.text)).collect::<Result<Vec<_>, _>>()?;
+        let placeholders = placeholder_names(&variants[0]);
+        if variants.iter().any(|v| placeholder_names(v) != placeholders) {
+            return Err("all variants must have the same placeholders".to_string())
+        }
+        Ok(Self { variants, weights, placeholders })
+    }
+}
+
+/// Split template text into literal text and placeholders
+fn parse(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => { chars.next(); literal.push('{') },
+            '}' if chars.peek() == Some(&'}') => { chars.next(); literal.push('}') },
+            '{' => {
+                let mut name = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break
+                    }
+                    name.push(c);
+                }
+                if !closed {
+                    return Err(format!("unterminated placeholder {{{name}"))
+                }
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(format!("invalid placeholder {{{name}}}, names must be alphanumeric"))
+                }
+                if !literal.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Placeholder(name));
+            },
+            '}' => return Err("unmatched }, use }} for a literal brace".to_string()),
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Text(literal));
+    }
+    Ok(segments)
+}
+
+fn placeholder_names(segments: &[Segment]) -> BTreeSet<String> {
+    segments.iter().filter_map(|s| match s {
+        Segment::Placeholder(name) => Some(name.clone()),
+        Segment::Text(_) => None,
+    }).collect()
+}
+
+fn load(path: &PathBuf) -> Result<HashMap<String, Template>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("couldn't read prompt templates file {path:?}: {e}"))?;
+    let file: TemplatesFile = serde_json::from_str(&contents)
+        .map_err(|e| format!("invalid prompt templates file {path:?}: {e}"))?;
+    file.templates.into_iter().map(|(name, config)| {
+        let template = Template::new(config).map_err(|e| format!("invalid prompt template {name}: {e}"))?;
+        Ok((name, template))
+    }).collect()
+}
+
+pub(crate) struct PromptTemplates {
+    templates: RwLock<Arc<HashMap<String, Template>>>,
+}
+
+impl PromptTemplates {
+    /// Load the templates file, and reload it whenever it's modified or SIGHUP is received.
+    /// Invalid changes are logged and otherwise ignored
+    pub(crate) fn watch(path: String) -> Result<Arc<Self>, String> {
+        let path = PathBuf::from(path);
+        let templates = load(&path)?;
+        info!("Loaded {} prompt template(s) from {path:?}", templates.len());
+        let registry = Arc::new(Self { templates: RwLock::new(Arc::new(templates)) });
+        let reloaded = registry.clone();
+        watch_file(path, "prompt templates", move |path| match load(path) {
+            Ok(templates) => {
+                info!("Reloaded {} prompt template(s) from {path:?}", templates.len());
+                *reloaded.templates.write().unwrap() = Arc::new(templates);
+            },
+            Err(err) => error!("Prompt templates not reloaded: {err}"),
+        });
+        Ok(registry)
+    }
+
+    /// Text of a randomly chosen variant of the named template, with its placeholders
+    /// replaced by the given variables, each of which must be used
+    pub(crate) fn expand(&self, name: &str, variables: &HashMap<String, String>) -> Result<String, String> {
+        let templates = self.templates.read().unwrap().clone();
+        let template = templates.get(name).ok_or_else(|| format!("unknown prompt template {name}"))?;
+        if let Some(missing) = template.placeholders.iter().find(|p| !variables.contains_key(*p)) {
+            return Err(format!("missing variable {missing} of prompt template {name}"))
+        }
+        if let Some(unknown) = variables.keys().find(|v| !template.placeholders.contains(*v)) {
+            return Err(format!("prompt template {name} has no variable {unknown}"))
+        }
+        let index = template.weights.sample(&mut rand::thread_rng());
+        metrics::increment_counter!(
+            "tgi_prompt_template_expanded", "template" => name.to_string(), "variant" => index.to_string(),
+        );
+        Ok(template.variants[index].iter().map(|segment| match segment {
+            Segment::Text(text) => text.as_str(),
+            Segment::Placeholder(name) => variables[name].as_str(),
+        }).collect())
+    }
+}
@@ -1,26 +1,52 @@
 use crate::{GenerateParameters, GenerateRequest};
+use std::cmp::Reverse;
 use std::collections::{BTreeSet, VecDeque};
-use std::marker::PhantomData;
 use std::mem::take;
 use std::ops::Add;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::time::Duration;
 use nohash_hasher::IntMap;
-use tokio::sync::mpsc::{Receiver, UnboundedSender};
-use tokio::sync::mpsc::error::TryRecvError::{Disconnected, Empty};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
 use text_generation_client::{
-    Batch, ClientError, LengthPenalty, NextTokenChooserParameters, Request, RequestedDetails, Token
+    Batch, ClientError, LengthPenalty, NextTokenChooserParameters, Request, RequestedDetails, Token,
+    TemperatureBreakpoint, TokenSequence, Watermark,
 };
 use tokio::sync::oneshot::Sender;
-use tokio::time::Instant;
-use tracing::info;
-use crate::batch_types::BatchType;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{debug, info, Span};
+use crate::batch_types::{BatchStats, BatchType};
 use crate::batcher::InferResponse;
+use crate::tools::tool_call_schema;
+use crate::streaming::StreamSender;
 use crate::decoder::IncrementalDecoderWrapper;
+use crate::token_healing::HealedPrefix;
+use crate::replicas::LoadGuard;
+use crate::trace::{GenerationTrace, TRACE_TOP_N};
+use crate::kv_cache::KvCacheModel;
+use crate::request_metrics::{record_cancellation, Cancellation};
+use crate::hooks::HookHandle;
 
 // Requests that fit into the next batch can overtake others
 // that don't as long as they arrive within this amount of time after
 const CUTOFF_DURATION: Duration = Duration::from_secs(1);
 
+// Ids of requests and batches are unique across all queues rather than per queue,
+// since the replicas of successive deployments may share shards
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+static NEXT_BATCH_ID: AtomicU64 = AtomicU64::new(1);
+
+// Period over which the rate of requests leaving the queue is measured
+const ADMISSION_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// Streaming requests paused for a slow consumer are resumed after this long even if
+// it hasn't caught up, then cancelled if it still can't keep up
+const MAX_STREAM_PAUSE: Duration = Duration::from_secs(30);
+
+// How often paused streams are checked while there are no other requests
+const PAUSED_STREAM_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
 
 /// Queue entry / in-progress request state
 #[derive(Debug)]
@@ -30,13 +56,15 @@ pub(crate) struct Entry {
     /// Response senders to communicate between the Batcher and the batching_task
     /// Exactly one of these will be non-None
     pub response_tx: Option<Sender<Result<InferResponse, ClientError>>>,
-    pub stream_tx: Option<UnboundedSender<Result<InferResponse, ClientError>>>,
+    pub stream_tx: Option<StreamSender>,
     /// Number of tokens in the input
     pub input_length: usize,
     /// Instant when this entry was queued
     pub queue_time: Instant,
     /// Instant when this entry was added to a batch (queue end time)
     pub batch_time: Option<Instant>,
+    /// Instant when the first token was generated
+    pub first_token_time: Option<Instant>,
     /// Generated token ids, populated only in non-streaming case
     pub token_ids: Vec<u32>,
     /// Generated tokens
@@ -45,8 +73,46 @@ pub(crate) struct Entry {
     pub input_tokens: Vec<Token>,
     /// Accumulates output, used only when stop sequences are provided
     pub output: Option<IncrementalDecoderWrapper>,
+    /// Trailing streamed text withheld because it could be the start of a stop sequence
+    pub held_text: String,
+    /// Most recently generated token ids, as many as the longest stop token
+    /// sequence, kept only if any were provided
+    pub recent_token_ids: VecDeque<u32>,
     /// Generated token count
     pub generated_tokens: u32,
+    /// Sum of generated token logprobs, used only when a logprob threshold
+    /// or the sequence logprob is requested
+    pub logprob_sum: f32,
+    /// Queue position and estimated wait at the time this entry was submitted
+    pub queue_estimate: Option<QueueEstimate>,
+    /// Healed prompt text still to be removed from the start of streamed output
+    pub healed_prefix: Option<HealedPrefix>,
+    /// Share of the load of the replica this entry was routed to
+    pub load_guard: Option<LoadGuard>,
+    /// Generation steps recorded so far, present only if a trace was requested
+    pub trace: Option<GenerationTrace>,
+    /// Whether this entry has been preempted, which happens at most once
+    pub preempted: bool,
+    /// Id under which the shards hold this entry's offloaded cache, while it's preempted
+    pub offloaded_id: Option<u64>,
+    /// Instant when this entry was batched again after being preempted
+    pub resumed_at: Option<Instant>,
+    /// Whether this entry has been regenerated after a failed generation step, which
+    /// happens at most once
+    pub retried: bool,
+    /// When this entry's generation was paused for its stream's consumer to catch up,
+    /// which happens at most once
+    pub paused_at: Option<Instant>,
+    /// Dispatches this entry's events to the request hooks, if any are registered
+    pub hooks: Option<HookHandle>,
+    /// Time the shards took to prefill this entry's batch, when it was first prefilled
+    pub prefill_duration: Option<Duration>,
+    /// Number of batches formed while this entry was queued, which it wasn't added to
+    pub batches_waited: u32,
+    /// Share of the queued prompts' memory, held until the entry is first batched
+    pub queue_memory: Option<QueueMemoryGuard>,
+    /// Span of the request's handler, if the request is flagged for debugging
+    pub debug_span: Option<Span>,
 }
 
 impl Entry {
@@ -54,8 +120,12 @@ impl Entry {
         request: GenerateRequest,
         input_length: usize,
         response_tx: Option<Sender<Result<InferResponse, ClientError>>>,
-        stream_tx: Option<UnboundedSender<Result<InferResponse, ClientError>>>,
+        stream_tx: Option<StreamSender>,
     ) -> Self {
+        let healed_prefix = request.healed_prefix.clone().map(HealedPrefix::new);
+        let trace = request.parameters.include_trace.then(GenerationTrace::default);
+        // Entries are created in the context of the request's handler
+        let debug_span = request.debug.then(Span::current);
         Self {
             request,
             response_tx,
@@ -64,13 +134,113 @@ impl Entry {
             input_tokens: vec![],
             queue_time: Instant::now(),
             batch_time: None,
+            first_token_time: None,
             token_ids: vec![],
             tokens: vec![],
             output: None,
+            held_text: String::new(),
+            recent_token_ids: VecDeque::new(),
             generated_tokens: 0,
+            logprob_sum: 0.0,
+            queue_estimate: None,
+            healed_prefix,
+            load_guard: None,
+            trace,
+            preempted: false,
+            retried: false,
+            paused_at: None,
+            offloaded_id: None,
+            resumed_at: None,
+            hooks: None,
+            queue_memory: None,
+            prefill_duration: None,
+            batches_waited: 0,
+            debug_span,
+        }
+    }
+
+    /// Log a DEBUG-level event in the request's span if it's flagged for debugging.
+    /// The message is only formatted if it is
+    pub(crate) fn debug_event(&self, message: impl FnOnce() -> String) {
+        if let Some(span) = &self.debug_span {
+            span.in_scope(|| debug!("{}", message()));
         }
     }
 
+    /// Approximate memory held for this entry's prompt while it's queued
+    fn prompt_bytes(&self) -> usize {
+        self.request.inputs.len()
+            + self.request.input_token_ids.len() * std::mem::size_of::<u32>()
+            + self.request.healed_prefix.as_ref().map_or(0, String::len)
+            + self.request.input_offsets.len() * std::mem::size_of::<(usize, usize)>()
+    }
+
+    /// Discard generated output so that the request can be generated again from the start
+    pub(crate) fn restart(&mut self) {
+        self.token_ids.clear();
+        self.tokens.clear();
+        self.input_tokens.clear();
+        self.output = None;
+        self.held_text.clear();
+        self.recent_token_ids.clear();
+        self.generated_tokens = 0;
+        self.logprob_sum = 0.0;
+        self.healed_prefix = self.request.healed_prefix.clone().map(HealedPrefix::new);
+        self.trace = self.request.parameters.include_trace.then(GenerationTrace::default);
+    }
+
+    /// Whether this entry can be generated again from the start after its batch failed.
+    /// Streams which already sent tokens can't be regenerated
+    pub(crate) fn is_retryable(&self) -> bool {
+        !self.retried
+            && self.offloaded_id.is_none()
+            && !(self.stream_tx.is_some() && self.generated_tokens > 0)
+    }
+
+    /// Record a generated token id for matching stop token sequences
+    pub(crate) fn record_token_id(&mut self, token_id: u32) {
+        let stop_token_ids = &self.request.parameters.stop_token_ids;
+        if let Some(longest) = stop_token_ids.iter().map(Vec::len).max() {
+            if self.recent_token_ids.len() == longest {
+                self.recent_token_ids.pop_front();
+            }
+            self.recent_token_ids.push_back(token_id);
+        }
+    }
+
+    /// Replace newly decoded text with the part of it, and of any previously withheld text,
+    /// which can no longer be part of a stop sequence. The rest is withheld so that a stop
+    /// sequence is never partially streamed.
+    pub(crate) fn release_text(&mut self, text: &mut String) {
+        self.held_text.push_str(text);
+        let held = self.held_text.as_bytes();
+        // Longest suffix which is a proper prefix of a stop sequence, since one which
+        // isn't can't become part of a stop sequence as more text is generated
+        let keep = (1..held.len() + 1).rev().find(|&n| {
+            let suffix = &held[held.len() - n..];
+            self.request.parameters.stop_seqs.iter()
+                .any(|ss| ss.len() > n && ss.as_bytes().starts_with(suffix))
+        }).unwrap_or(0);
+        let release_len = held.len() - keep;
+        *text = self.held_text.drain(..release_len).collect();
+    }
+
+    /// Whether the most recently generated tokens match one of the stop token sequences
+    /// Index of the stop token id sequence which the output ends with, if any
+    pub(crate) fn matched_stop_token_ids(&self) -> Option<usize> {
+        self.request.parameters.stop_token_ids.iter().position(|ids| self.generated_ends_with(ids))
+    }
+
+    pub(crate) fn generated_ends_with(&self, ids: &[u32]) -> bool {
+        let recent = &self.recent_token_ids;
+        ids.len() <= recent.len() && recent.iter().skip(recent.len() - ids.len()).eq(ids)
+    }
+
+    /// Cumulative generated logprob to return, if requested
+    pub(crate) fn sequence_logprob(&self) -> Option<f32> {
+        self.request.parameters.include_sequence_logprob.then_some(self.logprob_sum)
+    }
+
     pub(crate) fn is_cancelled(&self) -> bool {
         if self.response_tx.is_some() {
             self.response_tx.as_ref().unwrap().is_closed()
@@ -79,25 +249,55 @@ impl Entry {
         }
     }
 
+    /// Time after which generation stops with the TimeLimit stop reason, the earlier
+    /// of the request's deadline and the end of its time budget once batched
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        let params = &self.request.parameters;
+        let budget_end = params.max_time.zip(self.batch_time).map(|(max, start)| start + max);
+        match (params.deadline, budget_end) {
+            (Some(deadline), Some(end)) => Some(deadline.min(end)),
+            (deadline, end) => deadline.or(end),
+        }
+    }
+
     pub(crate) fn deadline_exceeded(&self) -> bool {
-        matches![self.request.parameters.deadline, Some(d) if d < Instant::now()]
+        matches![self.deadline(), Some(d) if d < Instant::now()]
+    }
+
+    /// Record the time of the first generated token, if it's not already set
+    pub(crate) fn record_first_token(&mut self) {
+        if self.first_token_time.is_none() {
+            self.first_token_time = Some(Instant::now());
+            if let Some(hooks) = &self.hooks {
+                hooks.first_token();
+            }
+        }
     }
 
     // Convenience method for sending a terminating response
+    #[allow(clippy::result_large_err)]
     pub(crate) fn send_final(
         &mut self, result: Result<InferResponse, ClientError>
     ) -> Result<(), Result<InferResponse, ClientError>> {
+        if let Some(hooks) = &mut self.hooks {
+            match &result {
+                Ok(response) => hooks.complete(
+                    self.generated_tokens, response.reason.as_str_name(), self.first_token_time,
+                ),
+                Err(err) => hooks.error(err.to_string()),
+            }
+        }
         if self.response_tx.is_some() {
             let rtx = take( &mut self.response_tx );
             rtx.unwrap().send(result)
         } else {
-            self.stream_tx.as_mut().unwrap().send(result).map_err(|s| s.0)
+            self.stream_tx.as_mut().unwrap().send_final(result)
         }
     }
 }
 
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct BatchingConfig {
     /// Upper bound on number of requests in a batch
     pub(crate) size_limit: usize,
@@ -105,61 +305,277 @@ pub(crate) struct BatchingConfig {
     pub(crate) weight_limit: usize,
     /// Maximum weight of individual prefill batches
     pub(crate) prefill_weight_limit: usize,
+    /// Number of generation steps after which waiting requests are added
+    /// to the running batch regardless of min batch size
+    pub(crate) max_waiting_tokens: usize,
+    /// Max number of requests waiting in each replica's queue, no more than
+    /// the capacity of its channel
+    pub(crate) queue_size_limit: usize,
+    /// Max time to wait for the shards to complete a generation step
+    pub(crate) step_timeout: Option<Duration>,
+}
+
+/// Order in which each tenant's waiting requests are considered for the next batch
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SchedulingPolicy {
+    /// Arrival order
+    Fifo,
+    /// Fewest remaining tokens to generate first. The expected remaining tokens are
+    /// reduced by aging_rate for each second a request waits so that long requests
+    /// aren't starved
+    ShortestJobFirst { aging_rate: f64 },
+}
+
+impl SchedulingPolicy {
+    pub(crate) fn for_policy(name: &str, aging_rate: f64) -> Result<Self, String> {
+        match name {
+            "fifo" => Ok(Self::Fifo),
+            "sjf" if aging_rate >= 0.0 => Ok(Self::ShortestJobFirst { aging_rate }),
+            "sjf" => Err("sjf aging rate must be >= 0".to_string()),
+            _ => Err(format!("invalid scheduling policy '{name}', must be fifo or sjf")),
+        }
+    }
+}
+
+/// Expected remaining tokens of a waiting entry, less its aging credit
+fn aged_remaining_tokens(entry: &Entry, now: Instant, aging_rate: f64) -> f64 {
+    let remaining = entry.request.parameters.max_new_tokens.saturating_sub(entry.generated_tokens);
+    let waited = now.saturating_duration_since(entry.queue_time).as_secs_f64();
+    remaining as f64 - aging_rate * waited
+}
+
+/// Class of waiting requests which are batched separately from each other, so that short
+/// requests aren't held up by or prefilled alongside long ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Lane {
+    /// Short prompts with few tokens to generate, batched as soon as they fit
+    Latency,
+    /// All other requests, batched with others of similar prompt lengths
+    Throughput,
+}
+
+impl Lane {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Latency => "latency",
+            Self::Throughput => "throughput",
+        }
+    }
+}
+
+/// Thresholds of the latency lane, requests exceeding either are in the throughput lane
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LaneConfig {
+    pub(crate) max_input_tokens: usize,
+    pub(crate) max_new_tokens: u32,
+}
+
+impl LaneConfig {
+    /// None if neither threshold is set, in which case all requests share a single lane
+    pub(crate) fn new(max_input_tokens: Option<usize>, max_new_tokens: Option<u32>) -> Option<Self> {
+        (max_input_tokens.is_some() || max_new_tokens.is_some()).then(|| Self {
+            max_input_tokens: max_input_tokens.unwrap_or(usize::MAX),
+            max_new_tokens: max_new_tokens.unwrap_or(u32::MAX),
+        })
+    }
+
+    fn lane(&self, entry: &Entry) -> Lane {
+        if entry.input_length <= self.max_input_tokens
+            && entry.request.parameters.max_new_tokens <= self.max_new_tokens {
+            Lane::Latency
+        } else {
+            Lane::Throughput
+        }
+    }
+}
+
+/// Snapshot of the queue published for estimating the wait of new requests,
+/// and reported by the admin state endpoint
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct QueueStatus {
+    /// Number of requests waiting in the queue
+    pub(crate) queued: usize,
+    /// Recent rate at which requests are added to batches, per second
+    pub(crate) admission_rate: f64,
+    /// Total input tokens of the waiting requests
+    pub(crate) queued_tokens: usize,
+    /// Number of waiting requests which were preempted
+    pub(crate) preempted: usize,
+    /// When the longest-waiting request was queued
+    pub(crate) oldest: Option<Instant>,
+}
+
+impl QueueStatus {
+    /// Estimate for a request submitted now, offset is its position among
+    /// other requests submitted together
+    pub(crate) fn estimate(&self, offset: usize) -> QueueEstimate {
+        let position = self.queued + offset + 1;
+        QueueEstimate {
+            position: position as u32,
+            wait: (self.admission_rate > 0.0)
+                .then(|| Duration::from_secs_f64(self.queued as f64 / self.admission_rate)),
+        }
+    }
+}
+
+/// Memory held by the prompts of requests waiting in all of a deployment's queues,
+/// which is limited independently of the number of requests. Clones share the same usage
+#[derive(Clone, Debug)]
+pub(crate) struct QueueMemory {
+    used: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl QueueMemory {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self { used: Default::default(), limit }
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Account for the entries' prompts until they're batched, returns false if they would
+    /// exceed the limit. Entries are always accepted if nothing else is queued, so that
+    /// a single prompt larger than the limit isn't rejected indefinitely
+    pub(crate) fn reserve(&self, entries: &mut [Entry]) -> bool {
+        let bytes: Vec<usize> = entries.iter().map(Entry::prompt_bytes).collect();
+        let total: usize = bytes.iter().sum();
+        let reserved = self.used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+            (used == 0 || used + total <= self.limit).then_some(used + total)
+        });
+        let Ok(used) = reserved else {
+            return false
+        };
+        metrics::gauge!("tgi_queue_prompt_bytes", (used + total) as f64);
+        for (entry, bytes) in entries.iter_mut().zip(bytes) {
+            entry.queue_memory = Some(QueueMemoryGuard { used: self.used.clone(), bytes });
+        }
+        true
+    }
+}
+
+/// Releases the memory accounted for a queued entry's prompt when dropped
+#[derive(Debug)]
+pub(crate) struct QueueMemoryGuard {
+    used: Arc<AtomicUsize>,
+    bytes: usize,
+}
+
+impl Drop for QueueMemoryGuard {
+    fn drop(&mut self) {
+        let used = self.used.fetch_sub(self.bytes, Ordering::SeqCst) - self.bytes;
+        metrics::gauge!("tgi_queue_prompt_bytes", used as f64);
+    }
+}
+
+/// Queue position (1-based) and estimated wait before generation starts
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct QueueEstimate {
+    pub(crate) position: u32,
+    /// None if there isn't enough recent throughput to estimate from
+    pub(crate) wait: Option<Duration>,
 }
 
 /// Request Queue
 #[derive(Debug)]
-pub(crate) struct Queue<B: BatchType> {
-    /// Batching config
-    config: BatchingConfig,
-    /// Just for type inference
-    batch_type: PhantomData<B>,
+pub(crate) struct Queue {
+    /// Batching config, may be updated at runtime
+    config: watch::Receiver<BatchingConfig>,
+    /// Batching strategy used to compute batch weights
+    batch_type: Arc<dyn BatchType>,
+    /// Memory available for the KV cache, if admission is limited by it
+    kv_cache: Option<KvCacheModel>,
+    /// Order of each tenant's waiting requests
+    scheduling: SchedulingPolicy,
+    /// Thresholds of the latency lane, if requests are split into lanes
+    lanes: Option<LaneConfig>,
+    /// Lane from which the previous batch was drawn
+    last_lane: Option<Lane>,
 
     receiver: Receiver<Vec<Entry>>,
     // Staging buffer, filled until max_size is reached
     buffer: VecDeque<Entry>,
-    /// Id of the next entry
-    next_id: u64,
-    /// Id of the next batch
-    next_batch_id: u64,
+    /// Offloaded streaming requests waiting for their consumers to catch up,
+    /// which are returned to the buffer once they have
+    paused: Vec<Entry>,
+
+    /// Times and counts of requests recently added to batches
+    admissions: VecDeque<(Instant, usize)>,
+    /// Publishes the queue length and admission rate
+    status: watch::Sender<QueueStatus>,
 
     /// Just a constant empty map to reuse
     empty_map: IntMap<u64, Entry>,
 }
 
-impl<B: BatchType> Queue<B> {
+impl Queue {
     pub(crate) fn new(
-        config: BatchingConfig, _batch_type: B, receiver: Receiver<Vec<Entry>>
+        config: watch::Receiver<BatchingConfig>,
+        batch_type: Arc<dyn BatchType>,
+        kv_cache: Option<KvCacheModel>,
+        scheduling: SchedulingPolicy,
+        lanes: Option<LaneConfig>,
+        receiver: Receiver<Vec<Entry>>,
+        status: watch::Sender<QueueStatus>,
     ) -> Self {
         Self {
             config,
             receiver,
             buffer: VecDeque::new(),
-            next_id: 0,
-            next_batch_id: 1,
-            batch_type: PhantomData,
+            paused: vec![],
+            admissions: VecDeque::new(),
+            status,
+            batch_type,
+            kv_cache,
+            scheduling,
+            lanes,
+            last_lane: None,
             empty_map: IntMap::default(),
         }
     }
 
+    /// Replace this queue following a failure of the batching loop, failing the requests
+    /// already taken from the channel. Those still in the channel are kept.
+    pub(crate) fn restart(self, error: &ClientError) -> Self {
+        for mut entry in self.buffer.into_iter().chain(self.paused) {
+            entry.send_final(Err(error.clone())).unwrap_or_default();
+        }
+        let mut queue = Self::new(
+            self.config, self.batch_type, self.kv_cache, self.scheduling, self.lanes,
+            self.receiver, self.status,
+        );
+        queue.admissions = self.admissions;
+        queue.publish_status();
+        queue
+    }
+
     /// Get the next batch, blocking until available
     /// Corresponding entries are added to the entries map
     /// Returns None only if the queue has been closed
     pub(crate) async fn next_batch(&mut self, entries: &mut IntMap<u64, Entry>) -> Option<Batch> {
         loop {
+            if self.buffer.is_empty() && !self.paused.is_empty() {
+                // Await new requests or for paused streams to catch up
+                match timeout(PAUSED_STREAM_CHECK_INTERVAL, self.receiver.recv()).await {
+                    Ok(Some(ents)) => self.add_to_buffer(ents),
+                    Ok(None) => sleep(PAUSED_STREAM_CHECK_INTERVAL).await,
+                    Err(_) => (),
+                }
+                self.resume_paused();
+                continue
+            }
             if self.buffer.is_empty() {
                 // Await on the queue while the buffer is empty
                 match self.receiver.recv().await {
                     Some(ents) => self.add_to_buffer(ents),
-                    // Queue closed, we must be shutting down
+                    // Queue closed and drained, we must be shutting down
                     None => return None,
                 }
-                loop {
-                    match self.receiver.try_recv() {
-                        Ok(ents) => self.add_to_buffer(ents),
-                        Err(Empty) => break,
-                        Err(Disconnected) => return None,
-                    }
+                // Entries already received are batched even if the queue has been
+                // closed, e.g. while a replaced deployment drains
+                while let Ok(ents) = self.receiver.try_recv() {
+                    self.add_to_buffer(ents);
                 }
             }
             // We have at least one entry in the buffer
@@ -173,18 +589,38 @@ impl<B: BatchType> Queue<B> {
     /// shared channel into it's internal buffer. The future never completes.
     pub(crate) async fn service_queue(&mut self) {
         // First prune existing cancelled or expired requests
+        self.prune();
+
+        while let Some(ents) = self.receiver.recv().await {
+            self.add_to_buffer(ents);
+        }
+    }
+
+    /// Remove entries whose client has gone away or whose deadline has passed, so that
+    /// they aren't prefilled only to be discarded
+    fn prune(&mut self) {
+        self.resume_paused();
         let mut pruned = false;
         self.buffer.retain_mut(|entry| match entry {
+            // These are pruned once batched again, so that the shards
+            // can release any offloaded cache
+            entry if entry.preempted || entry.offloaded_id.is_some() => true,
             entry if entry.is_cancelled() => {
                 metrics::increment_counter!("tgi_request_failure", "err" => "cancelled");
+                record_cancellation(Cancellation::ClientDisconnect, true);
+                // Prefill which was avoided
+                metrics::counter!("tgi_queue_cancelled_input_tokens", entry.input_length as u64);
                 pruned = true;
                 false
             },
             entry if entry.deadline_exceeded() => {
+                entry.debug_event(|| "Deadline passed while queued".to_string());
                 // Send timeout response
                 metrics::increment_counter!("tgi_request_failure", "err" => "timeout");
+                metrics::increment_counter!("tgi_request_queue_time_limit");
+                record_cancellation(Cancellation::Deadline, true);
                 entry.batch_time = Some(Instant::now());
-                entry.send_final(Ok(InferResponse::early_timeout(&entry)))
+                entry.send_final(Ok(InferResponse::early_timeout(entry)))
                     .unwrap_or_default();
                 pruned = true;
                 false
@@ -194,16 +630,165 @@ impl<B: BatchType> Queue<B> {
 
         if pruned {
             metrics::gauge!("tgi_queue_size", self.buffer.len() as f64);
+            self.publish_status();
         }
+    }
 
-        while let Some(ents) = self.receiver.recv().await {
-            self.add_to_buffer(ents);
+    /// Buffer indices of the lane's entries (all if None) in the order they should be
+    /// considered for the next batch. Each tenant's entries are ordered according to the
+    /// scheduling policy, and tenants take turns in order of their oldest waiting entry.
+    fn fair_order(&self, lane: Option<Lane>) -> Vec<usize> {
+        let mut tenant_queues: Vec<(Option<&str>, VecDeque<usize>)> = vec![];
+        for (index, entry) in self.buffer.iter().enumerate() {
+            if let (Some(lane), Some(lanes)) = (lane, &self.lanes) {
+                if lanes.lane(entry) != lane {
+                    continue
+                }
+            }
+            let tenant = entry.request.tenant.as_deref();
+            match tenant_queues.iter_mut().find(|(t, _)| *t == tenant) {
+                Some((_, queue)) => queue.push_back(index),
+                None => tenant_queues.push((tenant, VecDeque::from([index]))),
+            }
+        }
+        if let SchedulingPolicy::ShortestJobFirst { aging_rate } = self.scheduling {
+            let now = Instant::now();
+            let cost = |index: usize| aged_remaining_tokens(&self.buffer[index], now, aging_rate);
+            for (_, queue) in tenant_queues.iter_mut() {
+                // Stable, so equal costs remain in arrival order
+                queue.make_contiguous().sort_by(|&a, &b| cost(a).total_cmp(&cost(b)));
+            }
+        }
+        let mut order = if tenant_queues.len() <= 1 {
+            // Just the single tenant's order
+            tenant_queues.pop().map(|(_, queue)| queue.into()).unwrap_or_default()
+        } else {
+            let mut order = Vec::with_capacity(self.buffer.len());
+            while !tenant_queues.is_empty() {
+                tenant_queues.retain_mut(|(_, queue)| match queue.pop_front() {
+                    Some(index) => {
+                        order.push(index);
+                        true
+                    },
+                    None => false,
+                });
+            }
+            order
+        };
+        // Higher priority entries go first, the sort is stable so otherwise order is unchanged
+        order.sort_by_key(|&index| Reverse(self.buffer[index].request.priority));
+        if lane == Some(Lane::Throughput) && order.len() > 2 {
+            // Pack the batch with prompts of similar length to the first entry's, which
+            // keeps its place so that no request waits indefinitely
+            let first_length = self.buffer[order[0]].input_length;
+            order[1..].sort_by_key(|&index| {
+                let entry = &self.buffer[index];
+                (Reverse(entry.request.priority), entry.input_length.abs_diff(first_length))
+            });
         }
+        order
+    }
+
+    /// Lanes to draw the next batch from, in order of preference. The latency lane is
+    /// preferred, but the throughput lane's requests are offered every other batch
+    fn lane_order(&self) -> Vec<Option<Lane>> {
+        match (self.lanes, self.last_lane) {
+            (None, _) => vec![None],
+            (Some(_), Some(Lane::Latency)) => vec![Some(Lane::Throughput), Some(Lane::Latency)],
+            (Some(_), _) => vec![Some(Lane::Latency), Some(Lane::Throughput)],
+        }
+    }
+
+    /// Priorities of waiting entries, highest first
+    pub(crate) fn waiting_priorities(&self) -> Vec<i32> {
+        let mut priorities = self.buffer.iter().map(|e| e.request.priority).collect::<Vec<_>>();
+        priorities.sort_unstable_by_key(|&p| Reverse(p));
+        priorities
+    }
+
+    /// Return preempted entries to the front of the queue
+    pub(crate) fn requeue(&mut self, entries: Vec<Entry>) {
+        for entry in entries.into_iter().rev() {
+            self.buffer.push_front(entry);
+        }
+        metrics::gauge!("tgi_queue_size", self.buffer.len() as f64);
+        self.publish_status();
+    }
+
+    /// Hold offloaded streaming requests until their consumers catch up
+    pub(crate) fn pause(&mut self, entries: Vec<Entry>) {
+        self.paused.extend(entries);
+        metrics::gauge!("tgi_queue_paused_streams", self.paused.len() as f64);
+    }
+
+    /// Return paused requests to the front of the queue once their consumers have caught
+    /// up, or they've been paused for too long. Those cancelled or past their deadline are
+    /// also returned, to be pruned once batched so that the shards release their cache.
+    fn resume_paused(&mut self) {
+        let now = Instant::now();
+        let mut resumed = vec![];
+        for mut entry in take(&mut self.paused) {
+            let paused_for = entry.paused_at.map_or(Duration::ZERO, |t| now - t);
+            if paused_for < MAX_STREAM_PAUSE && !entry.is_cancelled() && !entry.deadline_exceeded()
+                && entry.stream_tx.as_mut().map_or(false, StreamSender::is_lagging) {
+                self.paused.push(entry);
+                continue
+            }
+            metrics::histogram!("tgi_stream_pause_duration", paused_for.as_secs_f64());
+            resumed.push(entry);
+        }
+        if !resumed.is_empty() {
+            metrics::gauge!("tgi_queue_paused_streams", self.paused.len() as f64);
+            self.requeue(resumed);
+        }
+    }
+
+    pub(crate) fn max_waiting_tokens(&self) -> usize {
+        self.config.borrow().max_waiting_tokens
+    }
+
+    /// Number of requests waiting in the buffer
+    pub(crate) fn len(&self) -> usize {
+        self.buffer.len()
     }
 
     fn add_to_buffer(&mut self, new_entries: Vec<Entry>) {
+        for entry in &new_entries {
+            let queued = self.buffer.len();
+            entry.debug_event(|| format!(
+                "Queued with {} input tokens behind {queued} requests", entry.input_length,
+            ));
+        }
         self.buffer.extend(new_entries);
         metrics::gauge!("tgi_queue_size", self.buffer.len() as f64);
+        self.publish_status();
+    }
+
+    /// Record requests leaving the queue, used to compute the admission rate
+    fn record_admissions(&mut self, now: Instant, count: usize) {
+        self.admissions.push_back((now, count));
+        while matches!(self.admissions.front(), Some((t, _)) if now - *t > ADMISSION_RATE_WINDOW) {
+            self.admissions.pop_front();
+        }
+    }
+
+    fn publish_status(&self) {
+        let admission_rate = match self.admissions.front() {
+            Some((oldest, _)) => {
+                let admitted: usize = self.admissions.iter().map(|(_, c)| c).sum();
+                // Avoid overestimating from a single recent burst
+                let period = (Instant::now() - *oldest).max(CUTOFF_DURATION);
+                admitted as f64 / period.as_secs_f64()
+            },
+            None => 0.0,
+        };
+        self.status.send_replace(QueueStatus {
+            queued: self.buffer.len(),
+            admission_rate,
+            queued_tokens: self.buffer.iter().map(|e| e.input_length).sum(),
+            preempted: self.buffer.iter().filter(|e| e.preempted).count(),
+            oldest: self.buffer.iter().map(|e| e.queue_time).min(),
+        });
     }
 
     /// Get the next batch without blocking.
@@ -211,15 +796,42 @@ impl<B: BatchType> Queue<B> {
     pub(crate) fn try_next_batch(
         &mut self, entries: &mut IntMap<u64, Entry>, min_size: usize,
     ) -> Option<Batch> {
+        // Clients may have gone away since the entries were last checked, e.g. while
+        // the running batch's next token was generated
+        self.prune();
+
+        let config = self.config.borrow().clone();
+        let mut chosen = None;
+        for lane in self.lane_order() {
+            // Latency lane requests are batched as soon as they fit
+            let lane_min_size = if lane == Some(Lane::Latency) { 1 } else { min_size };
+            if let Some(lane_chosen) = self.choose_entries(entries, &config, lane, lane_min_size) {
+                chosen = Some((lane, lane_chosen));
+                break
+            }
+        }
+        let (lane, chosen_indices) = chosen?;
+        if let Some(lane) = lane {
+            metrics::increment_counter!("tgi_queue_lane_batch", "lane" => lane.name());
+            self.last_lane = Some(lane);
+        }
+        Some(self.take_batch(entries, chosen_indices))
+    }
 
-        let buffer_size = self.buffer.len();
-        if buffer_size < min_size {
+    /// Choose waiting entries of the lane (all if None) to add to the next batch, None if
+    /// fewer than min_size fit. Returns their buffer indices
+    fn choose_entries(
+        &self, entries: &IntMap<u64, Entry>, config: &BatchingConfig, lane: Option<Lane>, min_size: usize,
+    ) -> Option<Vec<usize>> {
+        let order = self.fair_order(lane);
+        let candidates = order.len();
+        if candidates < min_size {
             // Not enough requests waiting to reach min_size
             return None
         }
 
         let mut total_count = entries.len();
-        if total_count + min_size >= self.config.size_limit {
+        if total_count + min_size >= config.size_limit {
             // Not enough space to fit min_size within max batch size
             return None
         }
@@ -229,25 +841,27 @@ impl<B: BatchType> Queue<B> {
         let mut btree = None;
         let mut time_cutoff = None;
 
-        let now = Instant::now();
-        let mut batch_stats = <B>::compute_stats(entries);
-        let mut prefill_stats = <B>::compute_stats(&self.empty_map);
+        let mut batch_stats = BatchStats::compute(entries);
+        let mut prefill_stats = BatchStats::compute(&self.empty_map);
         // We first do a read-only pass over the queue to allow skipping over large entries
-        // that don't fit in the current batch to reach smaller entries that do
-        for (index, entry) in self.buffer.iter().enumerate() {
-            let config = &self.config;
+        // that don't fit in the current batch to reach smaller entries that do.
+        // Entries are visited round-robin across tenants so that no single tenant
+        // can monopolize the batch.
+        for (position, index) in order.into_iter().enumerate() {
+            let entry = &self.buffer[index];
             if matches!(time_cutoff, Some(t) if entry.queue_time > t) {
-                break
+                // Visit order isn't strictly by arrival time when there are multiple tenants
+                continue
             }
 
-            let input_len = entry.input_length;
-            let output_len = entry.request.parameters.max_new_tokens as usize;
-            let next_stats = <B>::update_stats(
-                &batch_stats, input_len, output_len
-            );
+            // Preempted entries may already have generated tokens
+            let generated_count = entry.generated_tokens as usize;
+            let input_len = entry.input_length + generated_count;
+            let output_len = entry.request.parameters.max_new_tokens as usize - generated_count;
+            let next_stats = batch_stats.update(input_len, output_len);
 
             // Avoid more granular analysis if possible
-            if <B>::batch_weight(&batch_stats, total_count + 1) > config.weight_limit {
+            if self.batch_type.batch_weight(&batch_stats, total_count + 1) > config.weight_limit {
                 // We aren't sure whether this next request will fit, so populate
                 // a btree with the current batch of requests, the set of
                 // requests already evaluated, and this one, and perform more
@@ -256,7 +870,7 @@ impl<B: BatchType> Queue<B> {
 
                 // Allocate btree the first time it's required
                 let tree = btree.get_or_insert_with(|| {
-                    let mut t = Box::new(BTreeSet::new());
+                    let mut t = Box::<BTreeSet<_>>::default();
                     // Populate with records corresponding to all existing and pending entries
                     let pending = chosen_indices.iter()
                         .map(|i| (&0, self.buffer.get(*i).unwrap()));
@@ -274,10 +888,10 @@ impl<B: BatchType> Queue<B> {
                 tree.insert((output_len, input_len, tree.len()));
 
                 // Perform analysis
-                if <B>::exceeds_weight(
+                if self.batch_type.exceeds_weight(
                     tree, config.weight_limit, output_len,
                 ) {
-                    if chosen_indices.len() + buffer_size < min_size + index + 1 {
+                    if chosen_indices.len() + candidates < min_size + position + 1 {
                         // We don't have enough remaining to meet min_size
                         return None
                     }
@@ -291,6 +905,20 @@ impl<B: BatchType> Queue<B> {
                 // If we initialized the btree for a prior request, keep it updated
                 tree.insert((output_len, input_len, tree.len()));
             }
+            // Defer requests whose cache wouldn't fit alongside those of the batch
+            if let Some(kv_cache) = &self.kv_cache {
+                let cache_tokens = self.batch_type.max_cache_tokens(&next_stats, total_count + 1);
+                if !kv_cache.fits(cache_tokens) {
+                    if let Some(tree) = btree.as_mut() {
+                        // Remove our tuple from the set
+                        tree.remove(&(output_len, input_len, tree.len() - 1));
+                    }
+                    time_cutoff.get_or_insert_with(|| entry.queue_time.add(CUTOFF_DURATION));
+                    metrics::increment_counter!("tgi_kv_cache_admission_deferred");
+                    continue
+                }
+            }
+
             // Here, we can add this request to the batch without breaching memory limit
             if time_cutoff.is_some() {
                 metrics::increment_counter!("tgi_queue_jump");
@@ -300,10 +928,8 @@ impl<B: BatchType> Queue<B> {
             // too expensive latency-wise to perform in a single forward-pass.
             let mut prefill_weight_exceeded = false;
             if config.prefill_weight_limit > 0 {
-                let next_prefill_stats = <B>::update_stats(
-                    &prefill_stats, input_len, 0
-                );
-                let prefill_weight = <B>::prefill_weight(
+                let next_prefill_stats = prefill_stats.update(input_len, 0);
+                let prefill_weight = self.batch_type.prefill_weight(
                     &next_prefill_stats, chosen_indices.len() + 1
                 );
                 if prefill_weight > config.prefill_weight_limit {
@@ -332,50 +958,99 @@ impl<B: BatchType> Queue<B> {
         }
 
         let chosen_count = chosen_indices.len();
-        info!("Chose {chosen_count} out of {buffer_size} requests from buffer, \
+        info!("Chose {chosen_count} out of {candidates} requests from buffer, \
                 total now {total_count}");
-        if chosen_count == 0 {
-            return None
-        }
+        (chosen_count > 0).then_some(chosen_indices)
+    }
 
+    /// Finish assembling the next batch from the chosen entries
+    fn take_batch(
+        &mut self, entries: &mut IntMap<u64, Entry>, mut chosen_indices: Vec<usize>,
+    ) -> Batch {
+        // Entries are removed from the buffer in order below
+        chosen_indices.sort_unstable();
+        let chosen_count = chosen_indices.len();
+        let now = Instant::now();
         let some_now = Some(now);
         let requests = chosen_indices.iter().enumerate().map(|(i, index)| {
             let mut entry = self.buffer.remove(index - i).expect("bug");
             // Allocate new id
-            let id = self.next_id;
-            self.next_id += 1;
-            let request = Request {
-                id,
-                prefix_id: entry.request.prefix_id.clone().unwrap_or_default(),
-                inputs: entry.request.inputs.clone(),
-                input_length: entry.input_length as u32,
-                max_output_length: entry.request.parameters.max_new_tokens,
-                truncate: entry.request.parameters.truncate_input_tokens > 0,
-                parameters: Some((&entry.request.parameters).into()),
-                stream_response: entry.stream_tx.is_some(),
-                details: (&entry.request.parameters).into(),
-            };
-            // Set batch_time
-            entry.batch_time = some_now;
-            metrics::histogram!("tgi_request_queue_duration", (now - entry.queue_time).as_secs_f64());
+            let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+            let request = batch_request(id, &mut entry);
+            entry.debug_event(|| format!(
+                "Added to next batch as request id {id} after queueing for {:?}", now - entry.queue_time,
+            ));
+            // Set batch_time, preempted entries keep the time they were first batched
+            if entry.batch_time.is_none() {
+                entry.batch_time = some_now;
+                entry.queue_memory = None;
+                entry.request.journal = Default::default();
+                metrics::histogram!("tgi_request_queue_duration", (now - entry.queue_time).as_secs_f64());
+                metrics::histogram!("tgi_request_queue_batches_waited", entry.batches_waited as f64);
+            } else if entry.preempted {
+                entry.resumed_at = some_now;
+            }
             // Insert into entries IntMap
             entries.insert(id, entry);
             request
         }).collect::<Vec<Request>>();
+        // Preempted entries were counted when first queued
+        for entry in self.buffer.iter_mut().filter(|e| e.batch_time.is_none()) {
+            entry.batches_waited += 1;
+        }
 
-        let batch_tokens = <B>::count_tokens(
-            requests.iter().map(|r| r.input_length as usize),
+        let batch_tokens = self.batch_type.count_tokens(
+            &mut requests.iter().map(|r| r.input_length as usize),
             chosen_count,
         );
         metrics::histogram!("tgi_batch_next_tokens", batch_tokens as f64);
         let chosen_count = chosen_count as f64;
         metrics::gauge!("tgi_queue_size", self.buffer.len() as f64);
         metrics::histogram!("tgi_batch_next_size", chosen_count);
+        self.record_admissions(now, requests.len());
+        self.publish_status();
+
+        let id = NEXT_BATCH_ID.fetch_add(1, Ordering::Relaxed);
+        Batch { id, requests, total_tokens: batch_tokens as u32 }
+    }
+
+    /// Assemble a new batch to prefill in-progress entries again, keeping their ids
+    pub(crate) fn rebatch(&self, entries: &mut IntMap<u64, Entry>, ids: &[u64]) -> Batch {
+        let requests = ids.iter().map(
+            |id| batch_request(*id, entries.get_mut(id).expect("ID not found. This is a bug."))
+        ).collect::<Vec<Request>>();
+        let batch_tokens = self.batch_type.count_tokens(
+            &mut requests.iter().map(|r| r.input_length as usize), requests.len(),
+        );
+        let id = NEXT_BATCH_ID.fetch_add(1, Ordering::Relaxed);
+        Batch { id, requests, total_tokens: batch_tokens as u32 }
+    }
+}
 
-        let batch = Batch { id: self.next_batch_id, requests, total_tokens: batch_tokens as u32 };
-        // Increment batch id
-        self.next_batch_id += 1;
-        Some(batch)
+/// Build the shard request for an entry, resuming its offloaded cache if it was preempted
+fn batch_request(id: u64, entry: &mut Entry) -> Request {
+    let resumed_id = take(&mut entry.offloaded_id);
+    let mut details: Option<RequestedDetails> = (&entry.request.parameters).into();
+    if let (Some(details), Some(_)) = (details.as_mut(), resumed_id) {
+        // Input tokens were already returned before the entry was preempted
+        details.input_toks = false;
+    }
+    Request {
+        id,
+        prefix_id: entry.request.prefix_id.clone().unwrap_or_default(),
+        inputs: entry.request.inputs.clone(),
+        input_length: entry.input_length as u32,
+        max_output_length: entry.request.parameters.max_new_tokens,
+        truncate: entry.request.parameters.truncate_input_tokens > 0,
+        parameters: Some((&entry.request.parameters).into()),
+        stream_response: entry.stream_tx.is_some(),
+        details,
+        healing_prefix: match resumed_id {
+            Some(_) => String::new(),
+            None => entry.request.healed_prefix.clone().unwrap_or_default(),
+        },
+        resumed_id,
+        input_ids: entry.request.input_token_ids.clone(),
     }
 }
 
@@ -397,6 +1072,24 @@ impl From<&GenerateParameters> for NextTokenChooserParameters {
                     start_index: lp.0,
                     decay_factor: lp.1,
                 }),
+            json_schema: (!parameters.tools.is_empty())
+                .then(|| tool_call_schema(&parameters.tools)),
+            repetition_penalty_range: match parameters.repetition_penalty_range {
+                0 => None,
+                range => Some(range),
+            },
+            no_repeat_ngram_size: match parameters.no_repeat_ngram_size {
+                0 => None,
+                size => Some(size),
+            },
+            watermark: parameters.watermark
+                .map(|(gamma, delta)| Watermark { gamma, delta }),
+            bad_words_ids: parameters.bad_words_ids.iter()
+                .map(|ids| TokenSequence { token_ids: ids.clone() })
+                .collect(),
+            temperature_schedule: parameters.temperature_schedule.iter()
+                .map(|&(token_index, temperature)| TemperatureBreakpoint { token_index, temperature })
+                .collect(),
         }
     }
 }
@@ -405,9 +1098,30 @@ impl From<&GenerateParameters> for Option<RequestedDetails> {
     fn from(parameters: &GenerateParameters) -> Self {
         Some(RequestedDetails {
             input_toks: parameters.include_input_tokens,
-            logprobs: parameters.include_logprobs,
-            ranks: parameters.include_ranks,
-            top_n_toks: parameters.include_top_n,
+            // Also needed by the router to evaluate logprob stopping thresholds
+            // and sequence logprobs
+            logprobs: parameters.include_logprobs || parameters.tracks_logprob_sum()
+                || parameters.include_trace,
+            ranks: parameters.include_ranks || parameters.include_trace,
+            top_n_toks: match parameters.include_trace {
+                true => parameters.include_top_n.unwrap_or_default().max(TRACE_TOP_N),
+                false => parameters.include_top_n.unwrap_or_default(),
+            },
+            input_logprobs: parameters.echo,
+            input_ranks: parameters.echo,
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::GenerateRequest;
+    use super::Entry;
+
+    #[test]
+    fn counts_pre_tokenized_inputs_in_prompt_bytes() {
+        let request = GenerateRequest {
+            inputs: "abc".to_string(), input_token_ids: vec![1, 2, 3, 4], ..Default::default()
+        };
+        assert_eq!(Entry::new(request, 4, None, None).prompt_bytes(), 3 + 4 * 4);
+    }
+}
@@ -0,0 +1,158 @@
+/// Recording of completed generations and admin endpoints to replay them, for debugging
+/// numerical drift between shard versions
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use axum::extract::{Extension, Path};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use parking_lot::Mutex;
+use serde::Serialize;
+use crate::{ErrorResponse, GenerateRequest};
+use crate::admin::AdminState;
+use crate::audit::first_divergence;
+use crate::batcher::InferResponse;
+
+/// A completed generation, with everything needed to reproduce it
+struct ReplayRecord {
+    request_id: u64,
+    timestamp_ms: u128,
+    input_length: usize,
+    request: GenerateRequest,
+    token_ids: Vec<u32>,
+    stop_reason: &'static str,
+}
+
+/// Bounded ring buffer of the most recent completed generations
+pub(crate) struct ReplayBuffer {
+    capacity: usize,
+    records: Mutex<VecDeque<Arc<ReplayRecord>>>,
+}
+
+impl ReplayBuffer {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { capacity, records: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    /// Record a completed generation, evicting the oldest record if the buffer is full.
+    /// The request must be the validated one, so that it includes the seed used.
+    pub(crate) fn record(&self, input_length: usize, request: GenerateRequest, response: &InferResponse) {
+        let Some(request_id) = response.request_id else {
+            return
+        };
+        let record = Arc::new(ReplayRecord {
+            request_id,
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis()).unwrap_or_default(),
+            input_length,
+            request,
+            token_ids: response.token_ids.clone(),
+            stop_reason: response.reason.as_str_name(),
+        });
+        let mut records = self.records.lock();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    fn get(&self, request_id: u64) -> Option<Arc<ReplayRecord>> {
+        self.records.lock().iter().find(|r| r.request_id == request_id).cloned()
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReplayRecordSummary {
+    request_id: u64,
+    timestamp_ms: u128,
+    input_tokens: usize,
+    seed: Option<u64>,
+    temperature: f32,
+    max_new_tokens: u32,
+    stop_reason: &'static str,
+    token_ids: Vec<u32>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReplayResult {
+    request_id: u64,
+    replay_request_id: Option<u64>,
+    matched: bool,
+    // Index of the first generated token which differs, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    divergence_index: Option<usize>,
+    original_stop_reason: &'static str,
+    replay_stop_reason: &'static str,
+    original_token_ids: Vec<u32>,
+    replay_token_ids: Vec<u32>,
+}
+
+type AdminError = (StatusCode, Json<ErrorResponse>);
+
+fn error(status: StatusCode, error_code: &'static str, error: String) -> AdminError {
+    (status, Json(ErrorResponse { error, error_code, retry: None, errors: vec![] }))
+}
+
+fn replay_buffer(state: &AdminState) -> Result<&ReplayBuffer, AdminError> {
+    state.server.replay_buffer.as_deref().ok_or_else(|| error(
+        StatusCode::NOT_FOUND, "replay_disabled", "generation recording is disabled".to_string(),
+    ))
+}
+
+/// List the recorded generations, oldest first. Prompts aren't included
+pub(crate) async fn list_replay_records(
+    state: Extension<AdminState>, headers: HeaderMap,
+) -> Result<Json<Vec<ReplayRecordSummary>>, AdminError> {
+    state.authorize(&headers)?;
+    let records = replay_buffer(&state)?.records.lock().iter().map(|r| ReplayRecordSummary {
+        request_id: r.request_id,
+        timestamp_ms: r.timestamp_ms,
+        input_tokens: r.input_length,
+        seed: r.request.parameters.seed,
+        temperature: r.request.parameters.temperature,
+        max_new_tokens: r.request.parameters.max_new_tokens,
+        stop_reason: r.stop_reason,
+        token_ids: r.token_ids.clone(),
+    }).collect();
+    Ok(Json(records))
+}
+
+/// Re-submit a recorded generation with the same parameters and seed to the current
+/// deployment, and compare the generated token ids with those recorded. Greedy and
+/// seeded sampling requests should reproduce the same tokens on deterministic shards.
+pub(crate) async fn replay(
+    state: Extension<AdminState>, headers: HeaderMap, Path(request_id): Path<u64>,
+) -> Result<Json<ReplayResult>, AdminError> {
+    state.authorize(&headers)?;
+    let record = replay_buffer(&state)?.get(request_id).ok_or_else(|| error(
+        StatusCode::NOT_FOUND, "not_found", format!("no recorded generation with id {request_id}"),
+    ))?;
+    // Replays share the concurrent request limit, but aren't queued behind it
+    let _permit = state.server.limit_concurrent_requests.try_acquire().map_err(|_| error(
+        StatusCode::SERVICE_UNAVAILABLE, "overloaded", "Model is overloaded".to_string(),
+    ))?;
+    let mut request = record.request.clone();
+    // Original deadline has likely passed
+    request.parameters.deadline = None;
+    request.parameters.max_time = None;
+    metrics::increment_counter!("tgi_replay_count");
+    let response = state.server.deployment().batcher.infer(record.input_length, request).await
+        .map_err(|err| {
+            metrics::increment_counter!("tgi_replay_failure");
+            error(StatusCode::INTERNAL_SERVER_ERROR, "generate", err.to_string())
+        })?;
+    let divergence_index = first_divergence(&record.token_ids, &response.token_ids);
+    if divergence_index.is_some() {
+        metrics::increment_counter!("tgi_replay_divergence");
+    }
+    Ok(Json(ReplayResult {
+        request_id,
+        replay_request_id: response.request_id,
+        matched: divergence_index.is_none(),
+        divergence_index,
+        original_stop_reason: record.stop_reason,
+        replay_stop_reason: response.reason.as_str_name(),
+        original_token_ids: record.token_ids.clone(),
+        replay_token_ids: response.token_ids,
+    }))
+}
@@ -0,0 +1,134 @@
+/// Load-aware routing of requests across data-parallel replica groups
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use crate::batcher::BatchState;
+use crate::queue::{Entry, QueueStatus};
+
+/// A group of tensor-parallel shards with its own queue and batching task
+#[derive(Debug, Clone)]
+pub(crate) struct Replica {
+    index: usize,
+    pub(crate) sender: Sender<Vec<Entry>>,
+    queue_status: watch::Receiver<QueueStatus>,
+    batch_state: watch::Receiver<BatchState>,
+    /// Tokens of requests queued or in progress in this replica
+    load: Arc<AtomicUsize>,
+    /// Whether this replica's shards last succeeded in generating
+    generation_health: Arc<AtomicBool>,
+}
+
+impl Replica {
+    pub(crate) fn new(
+        index: usize,
+        sender: Sender<Vec<Entry>>,
+        queue_status: watch::Receiver<QueueStatus>,
+        batch_state: watch::Receiver<BatchState>,
+        generation_health: Arc<AtomicBool>,
+    ) -> Self {
+        Self { index, sender, queue_status, batch_state, load: Default::default(), generation_health }
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        self.index
+    }
+
+    pub(crate) fn load(&self) -> usize {
+        self.load.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn healthy(&self) -> bool {
+        self.generation_health.load(Ordering::SeqCst)
+    }
+
+    /// Flag updated by the replica's batching task, health checks and health monitor
+    pub(crate) fn generation_health(&self) -> Arc<AtomicBool> {
+        self.generation_health.clone()
+    }
+
+    pub(crate) fn queue_status(&self) -> QueueStatus {
+        *self.queue_status.borrow()
+    }
+
+    pub(crate) fn batch_state(&self) -> watch::Ref<'_, BatchState> {
+        self.batch_state.borrow()
+    }
+
+    /// Add the entries' tokens to this replica's load until they complete
+    pub(crate) fn assign(&self, entries: &mut [Entry]) {
+        for entry in entries {
+            let tokens = entry.input_length + entry.request.parameters.max_new_tokens as usize;
+            let load = self.load.fetch_add(tokens, Ordering::SeqCst) + tokens;
+            metrics::gauge!("tgi_replica_load_tokens", load as f64, "replica" => self.index.to_string());
+            entry.load_guard = Some(LoadGuard { index: self.index, load: self.load.clone(), tokens });
+        }
+    }
+}
+
+/// Releases a request's share of its replica's load when dropped
+#[derive(Debug)]
+pub(crate) struct LoadGuard {
+    index: usize,
+    load: Arc<AtomicUsize>,
+    tokens: usize,
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        let load = self.load.fetch_sub(self.tokens, Ordering::SeqCst) - self.tokens;
+        metrics::gauge!("tgi_replica_load_tokens", load as f64, "replica" => self.index.to_string());
+    }
+}
+
+/// Choose the replica for a group of entries, the least loaded healthy replica,
+/// or the least loaded of all if none are healthy.
+pub(crate) fn select_replica(replicas: &[Replica]) -> &Replica {
+    if replicas.len() == 1 {
+        return &replicas[0]
+    }
+    replicas.iter().filter(|r| r.healthy()).min_by_key(|r| r.load())
+        .or_else(|| replicas.iter().min_by_key(|r| r.load()))
+        .unwrap()
+}
+
+/// Combined queue length and admission rate of all replicas
+pub(crate) fn combined_queue_status(replicas: &[Replica]) -> QueueStatus {
+    replicas.iter().map(Replica::queue_status).fold(QueueStatus::default(), |total, status| {
+        QueueStatus {
+            queued: total.queued + status.queued,
+            admission_rate: total.admission_rate + status.admission_rate,
+            queued_tokens: total.queued_tokens + status.queued_tokens,
+            preempted: total.preempted + status.preempted,
+            oldest: total.oldest.into_iter().chain(status.oldest).min(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::sync::{mpsc, watch};
+    use super::{select_replica, Replica};
+
+    fn replica(index: usize, load: usize, healthy: bool) -> Replica {
+        let replica = Replica::new(
+            index, mpsc::channel(1).0, watch::channel(Default::default()).1,
+            watch::channel(Default::default()).1, Arc::new(AtomicBool::new(healthy)),
+        );
+        replica.load.store(load, Ordering::SeqCst);
+        replica
+    }
+
+    #[test]
+    fn routes_to_least_loaded_healthy_replica() {
+        let replicas = [replica(0, 20, true), replica(1, 0, false), replica(2, 10, true)];
+        assert_eq!(select_replica(&replicas).index, 2);
+        replicas[2].generation_health.store(false, Ordering::SeqCst);
+        assert_eq!(select_replica(&replicas).index, 0);
+        // Least loaded of all if none are healthy
+        replicas[0].generation_health.store(false, Ordering::SeqCst);
+        assert_eq!(select_replica(&replicas).index, 1);
+    }
+}
@@ -0,0 +1,254 @@
+/// Structured per-request audit log
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{Duration, Instant};
+use tonic::Request;
+use tonic::transport::Endpoint;
+use crate::batcher::Times;
+use crate::deployment::ModelIdentity;
+use crate::pb::fmaas::StopReason;
+
+mod pb {
+    tonic::include_proto!("fmaas.requestlog");
+}
+
+use pb::request_log_collector_client::RequestLogCollectorClient;
+
+/// Number of hex chars of the prompt SHA-256 digest to include
+const PROMPT_HASH_LENGTH: usize = 16;
+
+/// Max number of records waiting to be written, beyond which they're dropped
+/// so that a slow sink can't hold up the completion of requests
+const BUFFER_SIZE: usize = 4096;
+
+/// Max number of records sent to a collector in each call
+const MAX_COLLECT_BATCH: usize = 256;
+
+/// Max time to wait for a collector to accept a batch of records
+const COLLECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where request log records are written, one JSON object per line
+/// to stdout or a file, or sent in batches to a gRPC collector
+#[derive(Clone, Debug)]
+pub(crate) enum RequestLogSink {
+    Stdout,
+    File(String),
+    Grpc(String),
+}
+
+impl std::str::FromStr for RequestLogSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(Self::Stdout),
+            _ => match s.split_once(':') {
+                Some(("file", path)) if !path.is_empty() => Ok(Self::File(path.to_string())),
+                Some(("grpc", url)) if Endpoint::from_shared(url.to_string()).is_ok() =>
+                    Ok(Self::Grpc(url.to_string())),
+                _ => Err(format!(
+                    "invalid request log sink '{s}', must be stdout, file:<path> or grpc:<url>"
+                )),
+            },
+        }
+    }
+}
+
+/// Identity of the caller and correlation id of a request, captured from the gRPC metadata
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CallerInfo {
+    caller: String,
+    correlation_id: Option<String>,
+}
+
+impl CallerInfo {
+    pub(crate) fn from_request<T>(request: &Request<T>) -> Self {
+        let metadata = request.metadata();
+        let header = |name| metadata.get(name)
+            .and_then(|mv| mv.to_str().ok()).map(str::to_string);
+        Self {
+            caller: header("x-caller-id")
+                .or_else(|| request.remote_addr().map(|a| a.to_string()))
+                .unwrap_or_else(|| "unknown".to_string()),
+            correlation_id: header("x-correlation-id"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RequestLogRecord {
+    timestamp_ms: u128,
+    request_id: Option<u64>,
+    kind: &'static str,
+    caller: String,
+    correlation_id: Option<String>,
+    prompt_hash: String,
+    input_token_count: usize,
+    generated_token_count: u32,
+    stop_reason: &'static str,
+    /// Model which produced the output, empty if unknown
+    model_id: String,
+    model_version: String,
+    validation_time_ms: Option<f64>,
+    queue_time_ms: Option<f64>,
+    inference_time_ms: Option<f64>,
+    total_time_ms: f64,
+}
+
+impl From<RequestLogRecord> for pb::RequestLogRecord {
+    fn from(record: RequestLogRecord) -> Self {
+        Self {
+            timestamp_ms: record.timestamp_ms as u64,
+            request_id: record.request_id,
+            kind: record.kind.to_string(),
+            caller: record.caller,
+            correlation_id: record.correlation_id,
+            prompt_hash: record.prompt_hash,
+            input_token_count: record.input_token_count as u32,
+            generated_token_count: record.generated_token_count,
+            stop_reason: record.stop_reason.to_string(),
+            model_id: record.model_id,
+            model_version: record.model_version,
+            validation_time_ms: record.validation_time_ms,
+            queue_time_ms: record.queue_time_ms,
+            inference_time_ms: record.inference_time_ms,
+            total_time_ms: record.total_time_ms,
+        }
+    }
+}
+
+/// Handle used to submit request log records to the background writer
+#[derive(Clone, Debug)]
+pub(crate) struct RequestLogger {
+    sender: Sender<RequestLogRecord>,
+}
+
+impl RequestLogger {
+    /// Open the sink and start the writer thread, or the task sending to the collector
+    pub(crate) fn new(sink: RequestLogSink) -> Self {
+        let (sender, receiver) = channel::<RequestLogRecord>(BUFFER_SIZE);
+        match &sink {
+            RequestLogSink::Stdout => write_records(Box::new(std::io::stdout()), receiver),
+            RequestLogSink::File(path) => write_records(Box::new(
+                OpenOptions::new().create(true).append(true).open(path)
+                    .unwrap_or_else(|e| panic!("couldn't open request log file {path}: {e}"))
+            ), receiver),
+            RequestLogSink::Grpc(url) => {
+                // Connects on the first call, and reconnects after failures
+                let channel = Endpoint::from_shared(url.clone()).unwrap()
+                    .timeout(COLLECT_TIMEOUT)
+                    .connect_lazy();
+                tokio::spawn(collect_records(RequestLogCollectorClient::new(channel), receiver));
+            },
+        }
+        tracing::info!("Request log enabled, writing to {sink:?}");
+        Self { sender }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn log(
+        &self,
+        caller: &CallerInfo,
+        kind: &'static str,
+        request_id: Option<u64>,
+        prompt_hash: String,
+        input_tokens: usize,
+        generated_tokens: u32,
+        reason: StopReason,
+        times: &Option<Times>,
+        start_time: Instant,
+        model: &ModelIdentity,
+    ) {
+        let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+        let record = RequestLogRecord {
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis()).unwrap_or_default(),
+            request_id,
+            kind,
+            caller: caller.caller.clone(),
+            correlation_id: caller.correlation_id.clone(),
+            prompt_hash,
+            input_token_count: input_tokens,
+            generated_token_count: generated_tokens,
+            stop_reason: reason.as_str_name(),
+            model_id: model.id.clone(),
+            model_version: model.version.clone(),
+            validation_time_ms: times.as_ref().map(|t| ms(t.queued - start_time)),
+            queue_time_ms: times.as_ref().map(|t| ms(t.start - t.queued)),
+            inference_time_ms: times.as_ref().map(|t| ms(t.end - t.start)),
+            total_time_ms: ms(start_time.elapsed()),
+        };
+        match self.sender.try_send(record) {
+            Err(TrySendError::Full(_)) => metrics::increment_counter!("tgi_request_log_dropped"),
+            // Can only be closed if the writer has died
+            Err(TrySendError::Closed(_)) | Ok(()) => (),
+        }
+    }
+}
+
+/// Write records from a dedicated thread to avoid blocking I/O on the async runtime
+fn write_records(writer: Box<dyn Write + Send>, mut receiver: Receiver<RequestLogRecord>) {
+    std::thread::spawn(move || {
+        let mut writer = BufWriter::new(writer);
+        while let Some(record) = receiver.blocking_recv() {
+            let result = serde_json::to_writer(&mut writer, &record)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.write_all(b"\n"))
+                .and_then(|_| writer.flush());
+            if let Err(err) = result {
+                tracing::error!("Failed to write request log record: {err}");
+            }
+        }
+    });
+}
+
+/// Send records to the collector in batches of those waiting. Batches the collector
+/// fails to accept are dropped rather than retried, so that the buffer keeps draining
+async fn collect_records(
+    mut client: RequestLogCollectorClient<tonic::transport::Channel>,
+    mut receiver: Receiver<RequestLogRecord>,
+) {
+    while let Some(record) = receiver.recv().await {
+        let mut records = vec![record.into()];
+        while records.len() < MAX_COLLECT_BATCH {
+            match receiver.try_recv() {
+                Ok(record) => records.push(record.into()),
+                Err(_) => break,
+            }
+        }
+        let count = records.len();
+        if let Err(status) = client.collect(pb::CollectRequest { records }).await {
+            metrics::counter!("tgi_request_log_dropped", count as u64);
+            tracing::error!("Failed to send {count} request log record(s) to the collector: {status}");
+        }
+    }
+}
+
+/// Truncated hex SHA-256 digest of the prompt text
+pub(crate) fn prompt_hash(prompt: &str) -> String {
+    let digest = Sha256::digest(prompt.as_bytes());
+    let mut hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+    hex.truncate(PROMPT_HASH_LENGTH);
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestLogSink;
+
+    #[test]
+    fn parses_sinks() {
+        assert!(matches!("stdout".parse(), Ok(RequestLogSink::Stdout)));
+        assert!(matches!("file:/tmp/requests.log".parse(), Ok(RequestLogSink::File(p)) if p == "/tmp/requests.log"));
+        assert!(matches!(
+            "grpc:http://collector:8080".parse(), Ok(RequestLogSink::Grpc(u)) if u == "http://collector:8080"
+        ));
+        assert!("grpc:".parse::<RequestLogSink>().is_err());
+        assert!("file:".parse::<RequestLogSink>().is_err());
+    }
+}
@@ -0,0 +1,65 @@
+/// Counters of requests which don't run to completion, by cause, for capacity planning.
+/// These complement the tgi_request_failure counter, which counts failed calls.
+
+/// Why a request was rejected without being queued
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Rejection {
+    /// The replica's request queue is full
+    QueueFull,
+    /// Invalid parameters or inputs
+    Validation,
+    /// The server's limit of concurrent requests was reached
+    ConcurrencyLimit,
+    /// The calling client's limit of concurrent requests was reached
+    ClientConcurrencyLimit,
+    /// Shed because its projected time to first token exceeded the objective
+    FirstTokenObjective,
+}
+
+impl Rejection {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::QueueFull => "queue_full",
+            Self::Validation => "validation",
+            Self::ConcurrencyLimit => "conc_limit",
+            Self::ClientConcurrencyLimit => "client_conc_limit",
+            Self::FirstTokenObjective => "ttft_objective",
+        }
+    }
+}
+
+/// Why a request was stopped before completing normally
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Cancellation {
+    /// The client disconnected or stopped waiting
+    ClientDisconnect,
+    /// The client didn't consume streamed responses fast enough
+    SlowConsumer,
+    /// The request's deadline or time budget passed
+    Deadline,
+}
+
+impl Cancellation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ClientDisconnect => "client_disconnect",
+            Self::SlowConsumer => "slow_consumer",
+            Self::Deadline => "deadline",
+        }
+    }
+}
+
+/// Count `count` requests rejected for the given reason
+pub(crate) fn record_rejection(reason: Rejection, count: usize) {
+    metrics::counter!("tgi_request_rejected", count as u64, "reason" => reason.as_str());
+}
+
+/// Count a cancelled request, `queued` if it was cancelled before generation started.
+/// Deadline cancellations of queued requests are the early timeouts.
+pub(crate) fn record_cancellation(cause: Cancellation, queued: bool) {
+    metrics::increment_counter!(
+        "tgi_request_cancelled",
+        "cause" => cause.as_str(),
+        "stage" => if queued { "queued" } else { "generating" },
+    );
+}
@@ -0,0 +1,132 @@
+/// Caching of responses to deterministic requests
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::batcher::InferResponse;
+use crate::GenerateRequest;
+use crate::pb::fmaas::StopReason;
+
+/// Generated output stored in the response cache
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub output_text: String,
+    pub input_token_count: u32,
+    pub generated_token_count: u32,
+    pub stop_reason: i32,
+    pub sequence_logprob: Option<f32>,
+}
+
+/// Storage for cached responses, which can be provided via [`crate::server::ServerRunArgs`]
+/// to use an external cache. Expiry and size bounds are up to the implementation.
+#[async_trait]
+pub trait ResponseCacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Option<CachedResponse>;
+    async fn put(&self, key: String, response: CachedResponse);
+}
+
+/// In-memory store bounded by entry count, with entries expiring after a TTL
+pub(crate) struct InMemoryResponseCache {
+    cache: Cache<String, CachedResponse>,
+}
+
+impl InMemoryResponseCache {
+    pub(crate) fn new(max_entries: u64, ttl: Duration) -> Self {
+        Self { cache: Cache::builder().max_capacity(max_entries).time_to_live(ttl).build() }
+    }
+}
+
+#[async_trait]
+impl ResponseCacheStore for InMemoryResponseCache {
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        self.cache.get(key)
+    }
+
+    async fn put(&self, key: String, response: CachedResponse) {
+        self.cache.insert(key, response).await
+    }
+}
+
+/// Response cache used by the batcher
+#[derive(Clone)]
+pub(crate) struct ResponseCache {
+    store: Arc<dyn ResponseCacheStore>,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(store: Arc<dyn ResponseCacheStore>) -> Self {
+        Self { store }
+    }
+
+    /// Cache key for the request, None if its response isn't cacheable. Only greedy
+    /// requests without time limits or token details are cached.
+    pub(crate) fn key(request: &GenerateRequest) -> Option<String> {
+        let params = &request.parameters;
+        if params.temperature != 0.0 || params.has_time_limit()
+            || params.include_input_tokens || params.include_gen_tokens || params.include_trace {
+            return None
+        }
+        let digest = Sha256::digest(request_key(request).as_bytes());
+        Some(digest.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> Option<InferResponse> {
+        match self.store.get(key).await {
+            Some(cached) => {
+                metrics::increment_counter!("tgi_response_cache_hit");
+                Some(cached.into())
+            },
+            None => {
+                metrics::increment_counter!("tgi_response_cache_miss");
+                None
+            },
+        }
+    }
+
+    /// Store a completed response in the background, unless it didn't finish normally
+    pub(crate) fn put(&self, key: String, response: &InferResponse) {
+        if matches!(response.reason, StopReason::NotFinished | StopReason::Cancelled
+            | StopReason::TimeLimit | StopReason::Error | StopReason::Preempted
+            | StopReason::ServerShutdown | StopReason::QueueTimeLimit) {
+            return
+        }
+        let store = self.store.clone();
+        let cached = CachedResponse::from(response);
+        tokio::spawn(async move { store.put(key, cached).await });
+    }
+}
+
+/// Key identifying requests whose output is fully determined by their content
+pub(crate) fn request_key(request: &GenerateRequest) -> String {
+    format!(
+        "{:?}|{:?}|{:?}|{}", request.prefix_id, request.parameters, request.input_token_ids, request.inputs,
+    )
+}
+
+impl From<&InferResponse> for CachedResponse {
+    fn from(response: &InferResponse) -> Self {
+        Self {
+            output_text: response.output_text.clone(),
+            input_token_count: response.in_token_count,
+            generated_token_count: response.gen_token_count,
+            stop_reason: response.reason as i32,
+            sequence_logprob: response.sequence_logprob,
+        }
+    }
+}
+
+impl From<CachedResponse> for InferResponse {
+    fn from(cached: CachedResponse) -> Self {
+        Self {
+            output_text: cached.output_text,
+            is_decoded: true,
+            in_token_count: cached.input_token_count,
+            gen_token_count: cached.generated_token_count,
+            reason: StopReason::from_i32(cached.stop_reason).unwrap_or_default(),
+            sequence_logprob: cached.sequence_logprob,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,74 @@
+/// Live-reloadable runtime configuration
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use serde::Deserialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::interval;
+use tracing::{error, info};
+
+/// How often to check watched files for modifications
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Settings which can be changed without restarting the router.
+/// Unset fields revert to the value specified at startup.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RuntimeConfig {
+    pub(crate) max_batch_size: Option<usize>,
+    pub(crate) max_batch_weight: Option<usize>,
+    pub(crate) max_prefill_weight: Option<usize>,
+    pub(crate) max_waiting_tokens: Option<usize>,
+    /// Max number of requests waiting in each replica's queue, at most
+    /// the max concurrent requests set at startup
+    pub(crate) max_queue_size: Option<usize>,
+    pub(crate) shard_step_timeout_secs: Option<u64>,
+    pub(crate) log_level: Option<String>,
+}
+
+impl RuntimeConfig {
+    fn load(path: &PathBuf) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read runtime config file {path:?}: {e}"))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("invalid runtime config file {path:?}: {e}"))
+    }
+}
+
+/// Load the config file initially and again whenever it's modified or SIGHUP is received,
+/// passing the result to `apply`. Invalid configs are logged and otherwise ignored.
+pub(crate) fn watch_runtime_config<F>(path: String, apply: F)
+where F: Fn(RuntimeConfig) -> Result<(), String> + Send + 'static {
+    let path = PathBuf::from(path);
+    let reload = move |path: &PathBuf| match RuntimeConfig::load(path).and_then(&apply) {
+        Ok(()) => info!("Applied runtime config from {path:?}"),
+        Err(err) => error!("Runtime config not applied: {err}"),
+    };
+
+    reload(&path);
+    watch_file(path, "runtime config", reload);
+}
+
+/// Call `reload` whenever the file is modified or SIGHUP is received
+pub(crate) fn watch_file<F>(path: PathBuf, description: &'static str, reload: F)
+where F: Fn(&PathBuf) + Send + 'static {
+    let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified: Option<SystemTime> = modified(&path);
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    tokio::spawn(async move {
+        let mut poll = interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("Received SIGHUP, reloading {description}"),
+                _ = poll.tick() => {
+                    let current = modified(&path);
+                    if current == last_modified {
+                        continue
+                    }
+                    info!("File of {description} modified, reloading");
+                },
+            }
+            last_modified = modified(&path);
+            reload(&path);
+        }
+    });
+}
@@ -0,0 +1,158 @@
+/// Pluggable content-safety filter hooks
+use std::sync::Arc;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use tonic::Status;
+use crate::pb::fmaas::{GenerationRequest, GenerationResponse, StopDetails};
+use crate::pb::fmaas::StopReason::Filtered;
+
+/// Outcome of a safety check
+#[derive(Clone, Debug)]
+pub enum FilterVerdict {
+    /// Leave the text as-is
+    Allow,
+    /// Replace the text with the provided text
+    Redact(String),
+    /// Reject the request, or stop generation, for the provided reason
+    Reject(String),
+}
+
+/// Content-safety filter which can be provided via [`crate::server::ServerRunArgs`].
+/// All hooks allow everything by default.
+#[async_trait]
+pub trait SafetyFilter: Send + Sync {
+    /// Check a prompt before it's queued. Rejected prompts aren't generated from.
+    async fn check_prompt(&self, _prompt: &str) -> FilterVerdict {
+        FilterVerdict::Allow
+    }
+
+    /// Check newly streamed text, given the output streamed so far including it.
+    /// Rejection stops generation, and redaction applies only to the new text.
+    async fn check_stream_output(&self, _output: &str, _new_text: &str) -> FilterVerdict {
+        FilterVerdict::Allow
+    }
+
+    /// Check the complete output of a unary request
+    async fn check_output(&self, _output: &str) -> FilterVerdict {
+        FilterVerdict::Allow
+    }
+}
+
+fn record_verdict(stage: &'static str, verdict: &FilterVerdict) {
+    let verdict = match verdict {
+        FilterVerdict::Allow => return,
+        FilterVerdict::Redact(_) => "redact",
+        FilterVerdict::Reject(_) => "reject",
+    };
+    metrics::increment_counter!("tgi_safety_filter", "stage" => stage, "verdict" => verdict);
+}
+
+/// Check a prompt, applying any redaction in place. Returns the reason if it's rejected.
+pub(crate) async fn screen_prompt(
+    filter: &dyn SafetyFilter, request: &mut GenerationRequest,
+) -> Result<(), String> {
+    let verdict = filter.check_prompt(&request.text).await;
+    record_verdict("prompt", &verdict);
+    match verdict {
+        FilterVerdict::Allow => Ok(()),
+        FilterVerdict::Redact(text) => {
+            request.text = text;
+            // The redacted text is tokenized in place of any pre-tokenized input
+            request.input_token_ids.clear();
+            Ok(())
+        },
+        FilterVerdict::Reject(reason) => {
+            tracing::warn!("Prompt rejected by safety filter: {reason}");
+            Err(reason)
+        },
+    }
+}
+
+/// Check prompts, applying any redactions in place and removing rejected ones.
+/// Returns the original indices of the rejected prompts, with the reasons.
+pub(crate) async fn screen_prompts(
+    filter: &dyn SafetyFilter, requests: &mut Vec<GenerationRequest>,
+) -> Vec<(usize, String)> {
+    let mut rejected = vec![];
+    for (index, request) in requests.iter_mut().enumerate() {
+        if let Err(reason) = screen_prompt(filter, request).await {
+            rejected.push((index, reason));
+        }
+    }
+    let mut index = 0;
+    requests.retain(|_| {
+        index += 1;
+        !rejected.iter().any(|(i, _)| *i == index - 1)
+    });
+    rejected
+}
+
+/// Response returned in place of generated output which was rejected for the given reason
+pub(crate) fn filtered_response(generated_token_count: u32, reason: String) -> GenerationResponse {
+    GenerationResponse {
+        generated_token_count,
+        stop_reason: Filtered as i32,
+        stop_details: Some(StopDetails { reason_detail: Some(reason), ..Default::default() }),
+        ..Default::default()
+    }
+}
+
+/// Check the complete output of a unary response, applying any redaction
+pub(crate) async fn screen_output(filter: &dyn SafetyFilter, response: &mut GenerationResponse) {
+    let verdict = filter.check_output(&response.text).await;
+    record_verdict("output", &verdict);
+    match verdict {
+        FilterVerdict::Allow => {},
+        FilterVerdict::Redact(text) => {
+            response.text = text;
+            // Token details would otherwise reveal the redacted text
+            response.tokens.clear();
+            response.tool_call = None;
+            response.trace = None;
+        },
+        FilterVerdict::Reject(reason) => {
+            tracing::warn!("Output rejected by safety filter: {reason}");
+            *response = GenerationResponse {
+                input_token_count: response.input_token_count,
+                ..filtered_response(response.generated_token_count, reason)
+            };
+        },
+    }
+}
+
+/// Check streamed output as it's generated. If rejected, a final response with
+/// the filtered stop reason is sent and the stream ends, which cancels generation.
+pub(crate) fn screen_stream<S>(
+    filter: Arc<dyn SafetyFilter>, stream: S,
+) -> impl Stream<Item = Result<GenerationResponse, Status>>
+where S: Stream<Item = Result<GenerationResponse, Status>> + Unpin {
+    futures::stream::unfold(Some((stream, String::new())), move |state| {
+        let filter = filter.clone();
+        async move {
+            let (mut stream, mut output) = state?;
+            let mut item = stream.next().await?;
+            if let Ok(response) = &mut item {
+                // The first response may contain the input text rather than generated text
+                if response.generated_token_count > 0 && !response.text.is_empty() {
+                    output.push_str(&response.text);
+                    let verdict = filter.check_stream_output(&output, &response.text).await;
+                    record_verdict("stream", &verdict);
+                    match verdict {
+                        FilterVerdict::Allow => {},
+                        FilterVerdict::Redact(text) => {
+                            response.text = text;
+                            response.tokens.clear();
+                        },
+                        FilterVerdict::Reject(reason) => {
+                            tracing::warn!("Streamed output rejected by safety filter: {reason}");
+                            // Dropping the inner stream cancels the request
+                            let filtered = filtered_response(response.generated_token_count, reason);
+                            return Some((Ok(filtered), None))
+                        },
+                    }
+                }
+            }
+            Some((item, Some((stream, output))))
+        }
+    })
+}
@@ -1,50 +1,106 @@
-use std::marker::PhantomData;
-use crate::{
-    Batcher, Details, ErrorResponse, GenerateRequest, GeneratedText, Validation,
-};
-use axum::extract::Extension;
+use crate::{Details, ErrorResponse, GenerateRequest, GeneratedText, TokenDetails};
+use axum::extract::{ConnectInfo, Extension};
 use axum::http::{HeaderMap, StatusCode};
-use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use axum::http::header::RETRY_AFTER;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
 use axum::{Json, Router};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::mem::take;
 use std::time::Duration;
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
-use text_generation_client::ShardedClient;
+use text_generation_client::{ChannelConfig, ClientError, CompressionEncoding, ShardedClient};
 use tokenizers::Tokenizer;
 use tokio::signal;
-use tokio::sync::{Notify, Semaphore};
+use tokio::sync::{Notify, Semaphore, watch};
 use tokio::time::{Instant, sleep, timeout};
-use tracing::{instrument, warn};
-use crate::batch_types::{BatchType, FlashBatch, PaddedBatch};
-use crate::decoder::Decoder;
+use tracing::{info, instrument, warn};
+use crate::batch_types::{batch_type_for_name, BatchStats, BatchType};
 use crate::grpc_server::start_grpc_server;
-use crate::health::Health;
-use crate::queue::BatchingConfig;
+use crate::decoder::unescape_separator;
+use crate::queue::{BatchingConfig, LaneConfig, SchedulingPolicy};
+use crate::preemption::Preemption;
+use crate::validation::{FimSentinels, Input, TokenLimitPolicy, TopNTokens};
+use crate::warmup::warmup;
+use crate::log_redaction::{redact, set_text_log_policy, TextLogPolicy};
+use crate::request_log::{RequestLogger, RequestLogSink};
+use crate::runtime_config::{RuntimeConfig, watch_runtime_config};
+use crate::prompt_templates::PromptTemplates;
+use crate::prompt_registry::PromptRegistry;
+use crate::grpc_web::GrpcWebConfig;
+use crate::models::list_models;
+use crate::chunked_json::ChunkedJson;
+use crate::parameter_policy::ParameterPolicies;
+use crate::auth::{Authenticator, JwtConfig};
+use crate::streaming::{SlowStreamPolicy, StreamBufferConfig};
+use crate::safety::SafetyFilter;
+use crate::hooks::{builtin_hook, RequestHook, RequestHooks};
+use crate::waiting_tokens::WaitingTokensPolicy;
+use crate::client_limits::{client_identity, ClientLimiter};
+use crate::jobs::GenerationJobs;
+use crate::job_journal::JobJournal;
+use crate::embeddings::EmbeddingBatchConfig;
+use crate::response_cache::ResponseCacheStore;
+use crate::deployment::{Deployment, DeploymentConfig, ModelIdentity, ModelPaths, ModelSwapper};
+use crate::admin::{admin_state, set_log_filter, token_digest, AdminState};
+use crate::request_metrics::{record_rejection, Rejection};
+use crate::replay::{list_replay_records, replay, ReplayBuffer};
+use crate::shard_discovery::ShardDiscovery;
 
 // Server shared state
 #[derive(Clone)]
 pub(crate) struct ServerState {
-    pub(crate) validation: Validation,
-    pub(crate) batcher: Batcher,
+    // model-specific state, replaced when the model is swapped
+    pub(crate) deployment: watch::Receiver<Arc<Deployment>>,
     pub(crate) limit_concurrent_requests: Arc<Semaphore>,
+    pub(crate) max_concurrent_requests: usize,
     // metadata exposed by the ModelInfo endpoint
     pub(crate) max_sequence_length: usize,
     pub(crate) max_new_tokens: usize,
-    pub(crate) seq2seq: bool,
+    pub(crate) max_top_n_tokens: u32,
+    // fraction of greedy requests to re-run for determinism auditing
+    pub(crate) determinism_audit_fraction: f32,
+    // structured per-request log, if enabled
+    pub(crate) request_log: Option<RequestLogger>,
+    // content-safety filter applied to prompts and outputs, if provided
+    pub(crate) safety_filter: Option<Arc<dyn SafetyFilter>>,
+    // per-client concurrent request limits, if configured
+    pub(crate) client_limiter: Option<ClientLimiter>,
+    // background generations submitted via the job API, if enabled
+    pub(crate) generation_jobs: Option<Arc<GenerationJobs>>,
+    // recently completed generations which can be replayed via the admin API, if enabled
+    pub(crate) replay_buffer: Option<Arc<ReplayBuffer>>,
+    // named prompt templates which requests can reference, if configured
+    pub(crate) prompt_templates: Option<Arc<PromptTemplates>>,
+    // prompts registered by clients which requests can reference, if enabled
+    pub(crate) prompt_registry: Option<Arc<PromptRegistry>>,
+    // digest of the admin token if set, which requests can present to be debug-logged
+    pub(crate) admin_token_digest: Option<Arc<[u8]>>,
+}
+
+impl ServerState {
+    /// The current deployment, which a request should use throughout
+    pub(crate) fn deployment(&self) -> Arc<Deployment> {
+        self.deployment.borrow().clone()
+    }
 }
 
 /// Health check method
-#[instrument(skip(health))]
-async fn health(mut health: Extension<Health>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+#[instrument(skip(deployment))]
+async fn health(
+    deployment: Extension<watch::Receiver<Arc<Deployment>>>,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let mut health = deployment.borrow().health.clone();
     match timeout(Duration::from_secs(5), health.check()).await {
         Ok(true) => Ok(()),
         Ok(false) => Err((
             StatusCode::SERVICE_UNAVAILABLE,
             Json(ErrorResponse {
                 error: "unhealthy".to_string(),
+                error_code: "unhealthy",
+                retry: None,
+                errors: vec![],
             }),
         )),
         Err(_) => {
@@ -53,6 +109,9 @@ async fn health(mut health: Extension<Health>) -> Result<(), (StatusCode, Json<E
                 StatusCode::REQUEST_TIMEOUT,
                 Json(ErrorResponse {
                     error: "Healthcheck timed-out".to_string(),
+                    error_code: "health_check_timeout",
+                    retry: None,
+                    errors: vec![],
                 }),
             ))
         }
@@ -61,8 +120,10 @@ async fn health(mut health: Extension<Health>) -> Result<(), (StatusCode, Json<E
 
 /// Generate method
 #[instrument(
-    skip(state),
+    skip(state, req),
     fields(
+        input = ?redact(&req.inputs),
+        params = ?req.parameters,
         total_time,
         validation_time,
         queue_time,
@@ -73,59 +134,85 @@ async fn health(mut health: Extension<Health>) -> Result<(), (StatusCode, Json<E
 )]
 async fn generate(
     state: Extension<ServerState>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
     req: Json<GenerateRequest>,
-) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<impl IntoResponse, Response> {
     let start_time = Instant::now();
+    // Limit concurrent requests from the same client
+    let _client_permit = match &state.client_limiter {
+        Some(limiter) => {
+            let caller_id = request_headers.get("x-caller-id").and_then(|v| v.to_str().ok());
+            let client = client_identity(caller_id, Some(remote_addr));
+            Some(limiter.try_acquire(client, 1).ok_or_else(|| {
+                tracing::error!("Concurrent request limit exceeded for client");
+                record_rejection(Rejection::ClientConcurrencyLimit, 1);
+                let hint = state.deployment().batcher.retry_hint(limiter.max_per_client());
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, hint.retry_after_header())],
+                    Json(ErrorResponse {
+                        error: "Too many concurrent requests from this client".to_string(),
+                        error_code: "client_overloaded",
+                        retry: Some(hint),
+                        errors: vec![],
+                    }),
+                ).into_response()
+            })?)
+        },
+        None => None,
+    };
+    let deployment = state.deployment();
     // Limit concurrent requests by acquiring a permit from the semaphore
     let _permit = state.limit_concurrent_requests.try_acquire().map_err(|_| {
         tracing::error!("Model is overloaded");
+        record_rejection(Rejection::ConcurrencyLimit, 1);
+        let hint = deployment.batcher.retry_hint(state.max_concurrent_requests);
         (
             StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, hint.retry_after_header())],
             Json(ErrorResponse {
                 error: "Model is overloaded".to_string(),
+                error_code: "overloaded",
+                retry: Some(hint),
+                errors: vec![],
             }),
-        )
+        ).into_response()
     })?;
 
     // Validate request
-    //let details = req.0.parameters.details;
-    let GenerateRequest {inputs, prefix_id, parameters} = req.0;
+    let GenerateRequest {inputs, prefix_id, parameters, ..} = req.0;
+    let include_gen_tokens = parameters.include_gen_tokens;
     let (input_length, validated_request) =
-        state.validation.validate(
-            prefix_id, parameters, vec![inputs]
+        deployment.validation.validate(
+            prefix_id, parameters, vec![Input::Text(inputs, None)]
         ).await.map_err(|err| {
             tracing::error!("{err}");
-            err
+            record_rejection(Rejection::Validation, 1);
+            <(StatusCode, Json<ErrorResponse>)>::from(err).into_response()
         })?.pop().unwrap();
 
     // Inference
-    let response = state
+    let mut response = deployment
         .batcher
         .infer(input_length, validated_request)
         .await
         .map_err(|err| {
             tracing::error!("{err}");
-            err
+            err.into_response()
         })?;
 
     // Token details
-    // let details = match details {
-    //     true => {
-    //         let tokens = response
-    //             .token_ids
-    //             .into_iter()
-    //             .zip(response.tokens.into_iter())
-    //             .zip(response.logprobs.into_iter())
-    //             .map(|((id, text), logprob)| (id, text, logprob))
-    //             .collect();
-    //         Some(Details {
-    //             finish_reason: response.finish_reason,
-    //             generated_tokens: response.generated_tokens,
-    //             tokens,
-    //         })
-    //     }
-    //     false => None,
-    // };
+    let details = include_gen_tokens.then(|| Details {
+        finish_reason: response.reason.as_str_name().to_string(),
+        generated_tokens: response.gen_token_count,
+        tokens: take(&mut response.tokens).into_final_vec().into_iter().map(|t| TokenDetails {
+            text: t.text,
+            logprob: t.logprob,
+            rank: t.rank,
+            top_tokens: t.top_tokens.into_iter().map(|tt| (tt.text, tt.logprob)).collect(),
+        }).collect(),
+    });
 
     // Timings
     let total_time = start_time.elapsed();
@@ -133,7 +220,9 @@ async fn generate(
     let validation_time = times.queued - start_time;
     let queue_time = times.start - times.queued;
     let inference_time = times.end - times.start;
-    let time_per_token = inference_time / response.gen_token_count;
+    // No tokens are generated for echo requests
+    let time_per_token = inference_time.checked_div(response.gen_token_count)
+        .unwrap_or_else(|| Duration::new(0, 0));
 
     // Headers
     let mut headers = HeaderMap::new();
@@ -157,6 +246,21 @@ async fn generate(
         "x-time-per-token",
         time_per_token.as_millis().to_string().parse().unwrap(),
     );
+    if let Some(prefill_time) = times.prefill {
+        headers.insert("x-prefill-time", prefill_time.as_millis().to_string().parse().unwrap());
+    }
+    headers.insert("x-batches-waited", times.batches_waited.to_string().parse().unwrap());
+    // Model which produced the output, if known
+    for (name, value) in [("x-model-id", &deployment.model.id), ("x-model-version", &deployment.model.version)] {
+        if let Some(header_value) = value.parse().ok().filter(|_| !value.is_empty()) {
+            headers.insert(name, header_value);
+        }
+    }
+    if response.seed != 0 {
+        // Random seed used, so that sampled output can be reproduced
+        headers.insert("x-seed", response.seed.to_string().parse().unwrap());
+        tracing::Span::current().record("seed", response.seed);
+    }
 
     // Tracing metadata
     tracing::Span::current().record("total_time", format!("{total_time:?}"));
@@ -164,60 +268,60 @@ async fn generate(
     tracing::Span::current().record("queue_time", format!("{queue_time:?}"));
     tracing::Span::current().record("inference_time", format!("{inference_time:?}"));
     tracing::Span::current().record("time_per_token", format!("{time_per_token:?}"));
-    tracing::info!("Output: {}", response.output_text);
+    tracing::info!("Output: {}", redact(&response.output_text));
 
     // Send response
     let response = vec![GeneratedText {
         generated_text: response.output_text,
-        // details,
+        normalized_params: response.normalized_params.map(Into::into),
+        details,
     }];
-    Ok((headers, Json(response)))
+    // Token details can make the response too large to buffer
+    Ok((headers, ChunkedJson(response)))
 }
 
 
-struct BatchConfigValidator<B: BatchType> {
-    batch_type: PhantomData<B>
+struct BatchConfigValidator {
+    batch_type: Arc<dyn BatchType>,
 }
 
-impl<B: BatchType> BatchConfigValidator<B> {
+impl BatchConfigValidator {
     fn validate_batch_config(
         &self,
         max_sequence_length: usize,
         max_batch_size: usize,
         max_batch_weight: Option<usize>,
         max_prefill_weight: Option<usize>,
-    ) -> (usize, usize) {
-        let single_request_stats = <B>::update_stats(
-            &B::Stats::default(), max_sequence_length, 0
-        );
-        let single_request_weight = <B>::batch_weight(
+    ) -> Result<(usize, usize), String> {
+        let single_request_stats = BatchStats::default().update(max_sequence_length, 0);
+        let single_request_weight = self.batch_type.batch_weight(
             &single_request_stats, 1
         );
         let weight_upper_bound = single_request_weight * max_batch_size;
 
         let max_prefill_weight = max_prefill_weight.unwrap_or(
-            <B>::default_max_prefill_weight()
+            self.batch_type.default_max_prefill_weight()
         );
 
         // 0 means no max
         if max_prefill_weight > 0 {
-            let single_request_prefill_weight = <B>::prefill_weight(
+            let single_request_prefill_weight = self.batch_type.prefill_weight(
                 &single_request_stats, 1
             );
             if max_prefill_weight < single_request_prefill_weight {
-                panic!(
-                    "max_prefill_weight ({}) not large enough for max_sequence_length ({}",
+                return Err(format!(
+                    "max_prefill_weight ({}) not large enough for max_sequence_length ({})",
                     max_prefill_weight, max_sequence_length
-                )
+                ))
             }
         }
 
         let max_batch_weight = if let Some(mut max_batch_weight) = max_batch_weight {
             if max_batch_weight < single_request_weight {
-                panic!(
+                return Err(format!(
                     "max_batch_weight ({}) not large enough for max_sequence_length ({})",
                     max_batch_weight, max_sequence_length
-                )
+                ))
             }
             if max_batch_weight > weight_upper_bound {
                 warn!(
@@ -232,7 +336,7 @@ impl<B: BatchType> BatchConfigValidator<B> {
             weight_upper_bound
         };
 
-        (max_batch_weight, max_prefill_weight)
+        Ok((max_batch_weight, max_prefill_weight))
     }
 }
 
@@ -240,20 +344,198 @@ pub struct ServerRunArgs {
     pub max_concurrent_requests: usize,
     pub max_sequence_length: usize,
     pub max_new_tokens: usize,
+    pub max_top_n_tokens: u32,
+    pub default_top_n_tokens: u32,
+    /// Whether requests whose input plus max_new_tokens exceeds max_sequence_length
+    /// have max_new_tokens reduced to fit ("truncate") or are rejected ("reject")
+    pub token_limit_policy: String,
+    /// Name of the served model, which selects its policy from the parameter policy file
+    pub model_name: Option<String>,
+    /// Version of the served model, used in responses if the shards don't report one
+    pub model_version: Option<String>,
+    /// JSON file of per-model defaults and clamps of generation parameters
+    pub parameter_policy_path: Option<String>,
+    /// JSON file of named prompt templates, reloaded when modified
+    pub prompt_templates_path: Option<String>,
     pub max_batch_size: usize,
     pub max_batch_weight: Option<usize>,
     pub max_prefill_weight: Option<usize>,
     pub max_waiting_tokens: usize,
+    /// Whether max_waiting_tokens is used as-is ("fixed") or as the starting point and
+    /// bound of a choice based on recent prefill and step durations ("adaptive")
+    pub waiting_tokens_policy: String,
+    /// Prefill batches added to the running batch concurrently with its next generation
+    /// step, which requires shards that accept concurrent Prefill and NextToken calls
+    pub pipeline_prefill: bool,
+    /// Regenerate the requests of failed batches, isolating and failing only those which
+    /// cause the failure
+    pub retry_failed_batches: bool,
     pub client: ShardedClient,
     pub tokenizer: Tokenizer,
     pub validation_workers: usize,
+    /// Max number of unary responses decoded concurrently, on the blocking pool
+    pub detokenization_workers: usize,
     pub addr: SocketAddr,
     pub grpc_addr: SocketAddr,
     pub tls_key_pair: Option<(String, String)>,
     pub tls_client_ca_cert: Option<String>,
+    /// JSON file of the API keys accepted by the external gRPC services, with their callers
+    pub api_keys_path: Option<String>,
+    /// PEM public key or HMAC secret with which JWTs accepted by the external gRPC
+    /// services are signed
+    pub jwt_key_path: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    /// JWT claim holding the caller's tenant, the subject is used if absent
+    pub jwt_tenant_claim: String,
     pub output_special_tokens: bool,
+    /// Inserted between the input and output text of seq2seq models' responses which
+    /// include the input, with \n, \t and \\ escapes
+    pub seq2seq_input_separator: String,
+    pub determinism_audit_fraction: f32,
+    pub warmup: bool,
+    pub request_log_sink: Option<String>,
+    /// How prompts and outputs appear in logs and traces: full, hash, truncate:<chars> or omit
+    pub log_text_policy: String,
+    pub grpc_compression: bool,
+    /// Origins allowed to make gRPC-Web calls, comma-separated or * for any.
+    /// gRPC-Web isn't served if not set
+    pub grpc_web_origins: Option<String>,
+    pub fim_sentinel_tokens: Option<String>,
+    pub runtime_config_path: Option<String>,
+    pub log_level_setter: Option<LogLevelSetter>,
+    pub coalesce_requests: bool,
+    pub stream_buffer_size: usize,
+    pub slow_stream_policy: String,
+    pub shard_health_check_interval_secs: u64,
+    /// Max time to wait for the shards to complete a generation step
+    pub shard_step_timeout_secs: Option<u64>,
+    /// Time after a shutdown signal before generation of in-progress requests is stopped
+    pub shutdown_grace_period_secs: Option<u64>,
+    /// Time to first token objective, requests projected to exceed it are rejected
+    pub ttft_slo_millis: Option<u64>,
+    /// Whether the shards support streaming prefill with progress updates
+    pub shard_prefill_progress: bool,
+    pub safety_filter: Option<Arc<dyn SafetyFilter>>,
+    /// Hooks run at each stage of generation requests, e.g. for billing or analytics
+    pub request_hooks: Vec<Arc<dyn RequestHook>>,
+    /// Comma-separated names of built-in hooks to run in addition: logging, metrics
+    pub builtin_request_hooks: Option<String>,
+    pub max_concurrent_requests_per_client: Option<usize>,
+    /// Max total bytes of the prompts of queued requests, further requests are
+    /// rejected as if the queue were full
+    pub max_queued_prompt_bytes: Option<usize>,
+    pub batch_type: String,
+    pub response_cache_size: u64,
+    pub response_cache_ttl_secs: u64,
+    pub response_cache_store: Option<Arc<dyn ResponseCacheStore>>,
+    /// Max total bytes of registered prompts, 0 disables prompt registration
+    pub prompt_registry_capacity_bytes: u64,
+    pub registered_prompt_idle_timeout_secs: u64,
+    /// Clients of additional data-parallel replica groups, requests are routed
+    /// across these and the primary client according to their load
+    pub replica_clients: Vec<ShardedClient>,
+    /// Source of the primary and replica clients if they were discovered via DNS,
+    /// which then continues to add and remove replicas as the name's addresses change
+    pub shard_discovery: Option<ShardDiscovery>,
+    /// How running requests are preempted for more urgent ones: none, offload or requeue
+    pub preemption_policy: String,
+    pub preemption_min_generated_tokens: u32,
+    /// Tokenizer implementation used to decode generated tokens:
+    /// huggingface, sentencepiece or tiktoken
+    pub decoder_backend: String,
+    /// Model file of the sentencepiece and tiktoken decoder backends
+    pub decoder_model_path: Option<String>,
+    /// Max number of token id texts cached for token details, 0 disables the cache
+    pub token_text_cache_size: u64,
+    /// Max number of top candidate tokens decoded per response, 0 for no limit
+    pub max_decoded_top_tokens: usize,
+    /// Max number of generations submitted via the job API whose status is retained,
+    /// 0 disables the job API
+    pub max_generation_jobs: u64,
+    pub generation_job_ttl_secs: u64,
+    /// Write-ahead log of queued requests, those still queued are resubmitted as
+    /// generation jobs at startup
+    pub generation_job_journal_path: Option<String>,
+    /// Model's config.json, from which the KV cache memory per token is estimated
+    pub model_config_path: Option<String>,
+    /// Model's tokenizer_config.json, whose chat template renders the tools provided with requests
+    pub tokenizer_config_path: Option<String>,
+    /// Memory available for the KV cache across the shards of a replica. If set,
+    /// requests whose cache can't fit are rejected or deferred
+    pub kv_cache_capacity_bytes: Option<u64>,
+    /// Order in which waiting requests are batched: fifo, or sjf (shortest job first)
+    pub scheduling_policy: String,
+    /// Expected remaining tokens deducted per second waited, with the sjf policy
+    pub sjf_aging_rate: f64,
+    /// Requests with prompts of at most this many tokens, and at most
+    /// latency_lane_max_new_tokens, are batched separately in a low-latency lane
+    pub latency_lane_max_input_tokens: Option<usize>,
+    pub latency_lane_max_new_tokens: Option<u32>,
+    /// Max number of inputs in an embedding batch, 0 disables the embeddings API
+    pub max_embedding_batch_size: usize,
+    pub max_embedding_batch_tokens: usize,
+    /// Settings of the channels to the shards, used to connect to those of swapped-in models
+    pub shard_channel_config: ChannelConfig,
+    pub shard_grpc_compression: bool,
+    /// Serve the admin gRPC service, with which the model can be swapped at runtime
+    pub admin_api: bool,
+    /// Bearer token required by the /admin/state endpoint, which is only served if set
+    pub admin_token: Option<String>,
+    /// Number of recently completed generations recorded so that they can be replayed
+    /// via the admin API, 0 disables recording
+    pub replay_buffer_size: usize,
+}
+
+/// Load the model's tokenizer, disabling any truncation and padding it's configured with
+pub fn load_tokenizer(path: &str, max_sequence_length: usize) -> Result<Tokenizer, String> {
+    let mut tokenizer = Tokenizer::from_file(path)
+        .map_err(|e| format!("problem loading tokenizer {path}: {e}"))?;
+    if let Some(tp) = tokenizer.get_truncation() {
+        if tp.max_length < max_sequence_length {
+            warn!(
+                "Ignoring fast tokenizer truncation configuration with max_length {}, \
+                max_sequence_length is set to {}",
+                tp.max_length, max_sequence_length,
+            );
+        }
+    }
+    tokenizer.with_truncation(None).with_padding(None);
+    Ok(tokenizer)
 }
 
+/// Instantiate sharded client from a master unix socket
+pub async fn connect_shards(
+    master_shard_uds_path: String, channel_config: &ChannelConfig, compression: bool,
+) -> Result<ShardedClient, ClientError> {
+    let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path.clone(), channel_config)
+        .await?;
+    // Clear the cache; useful if the webserver rebooted
+    sharded_client.clear_cache().await?;
+    tracing::info!("Connected to {master_shard_uds_path}");
+    if compression {
+        sharded_client = sharded_client.with_compression(CompressionEncoding::Gzip);
+    }
+    Ok(sharded_client)
+}
+
+/// Connect to the shards at the addresses which the given `host:port` name resolves to,
+/// each of which serves the model on its own as a data-parallel replica. The name is
+/// re-resolved at the given interval once the server is running.
+pub async fn discover_shards(
+    target: String, refresh_interval: Duration, channel_config: &ChannelConfig, compression: bool,
+) -> Result<ShardDiscovery, String> {
+    ShardDiscovery::connect(target, refresh_interval, channel_config.clone(), compression).await
+}
+
+/// Callback used to change the log filter at runtime, e.g. to "info", "debug" or
+/// "info,text_generation_router::batcher=debug"
+pub type LogLevelSetter = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// Filter directive which the log filter should include for requests flagged for
+/// debugging to be logged at DEBUG level, whatever the level of the modules involved
+pub const DEBUG_REQUEST_DIRECTIVE: &str = "[{debug_request=true}]=debug";
+
 async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
     prom_handle.render()
 }
@@ -267,20 +549,35 @@ pub async fn run(mut args: ServerRunArgs) {
     tracing::info!("Shard model info: is_seq2seq = {seq2seq}, eos_token_id = {eos_token_id}, \
         use_padding = {use_padding}");
 
-    if use_padding {
-        do_run(args, seq2seq, eos_token_id, PaddedBatch{}).await
-    } else {
-        do_run(args, seq2seq, eos_token_id, FlashBatch{}).await
-    }
+    // Select the batching strategy, based on the shards' memory model unless specified
+    let shard_batch_type = if use_padding { "padded" } else { "flash" };
+    let batch_type_name = match args.batch_type.as_str() {
+        "auto" => shard_batch_type,
+        name => {
+            if name != shard_batch_type {
+                warn!("Using batch type {name} which differs from shards' batch type {shard_batch_type}");
+            }
+            name
+        },
+    };
+    let batch_type = batch_type_for_name(batch_type_name).unwrap_or_else(|e| panic!("{e}"));
+    tracing::info!("Using batch type {batch_type_name}");
+    let batch_type_name = batch_type_name.to_string();
+
+    do_run(args, seq2seq, eos_token_id, batch_type, batch_type_name).await
 }
 
 
 /// Serving method
 #[allow(clippy::too_many_arguments)]
-async fn do_run<B: BatchType>(
-    args: ServerRunArgs, seq2seq: bool, eos_token_id: u32, batch_type: B
+async fn do_run(
+    mut args: ServerRunArgs,
+    seq2seq: bool,
+    eos_token_id: u32,
+    batch_type: Arc<dyn BatchType>,
+    batch_type_name: String,
 ) {
-    let batch_config_validator = BatchConfigValidator::<B>{batch_type: PhantomData};
+    let batch_config_validator = BatchConfigValidator { batch_type: batch_type.clone() };
 
     // If max batch weight is not set, infer from max batch size and max seq length
     let (max_batch_weight, max_prefill_weight) = batch_config_validator
@@ -289,43 +586,210 @@ async fn do_run<B: BatchType>(
             args.max_batch_size,
             args.max_batch_weight,
             args.max_prefill_weight,
-        );
+        ).unwrap_or_else(|e| panic!("{e}"));
 
-    // Create state
-    let decoder = Decoder::new(
-        args.tokenizer.clone(), seq2seq, eos_token_id, !args.output_special_tokens,
-    );
-    let generation_health = Arc::new(AtomicBool::new(false));
-    let health_ext = Health::new(
-        args.client.clone(), generation_health.clone(), &args.tokenizer,
+    set_text_log_policy(
+        args.log_text_policy.parse::<TextLogPolicy>().unwrap_or_else(|e| panic!("{e}"))
     );
-    let batcher = Batcher::new(
-        args.client.clone(),
-        BatchingConfig {
-            size_limit: args.max_batch_size,
-            weight_limit: max_batch_weight,
-            prefill_weight_limit: max_prefill_weight,
-        },
-        args.max_waiting_tokens,
-        args.max_concurrent_requests,
-        decoder,
-        generation_health,
+
+    let mut request_hooks = take(&mut args.request_hooks);
+    if let Some(names) = &args.builtin_request_hooks {
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            request_hooks.push(builtin_hook(name).unwrap_or_else(|e| panic!("{e}")));
+        }
+    }
+
+    let request_log = args.request_log_sink.as_ref().map(|sink| RequestLogger::new(
+        sink.parse::<RequestLogSink>().unwrap_or_else(|e| panic!("{e}"))
+    ));
+
+    // Optionally probe the shards to verify the batch weight limit can be accommodated
+    let max_batch_weight = if args.warmup {
+        warmup(
+            batch_type.as_ref(),
+            &mut args.client.clone(),
+            &args.tokenizer,
+            args.max_sequence_length,
+            args.max_batch_size,
+            max_batch_weight,
+        ).await
+    } else {
+        max_batch_weight
+    };
+
+    let (config_sender, config_receiver) = watch::channel(BatchingConfig {
+        size_limit: args.max_batch_size,
+        weight_limit: max_batch_weight,
+        prefill_weight_limit: max_prefill_weight,
+        max_waiting_tokens: args.max_waiting_tokens,
+        queue_size_limit: args.max_concurrent_requests,
+        step_timeout: args.shard_step_timeout_secs.map(Duration::from_secs),
+    });
+    // Shared by the runtime config file and admin endpoint
+    let log_level_setter = args.log_level_setter.take().map(Arc::new);
+    if let Some(path) = args.runtime_config_path.take() {
+        // Settings omitted from the file revert to these
+        let startup_config = config_sender.borrow().clone();
+        let max_sequence_length = args.max_sequence_length;
+        let max_concurrent_requests = args.max_concurrent_requests;
+        let log_level_setter = log_level_setter.clone();
+        watch_runtime_config(path, move |rc: RuntimeConfig| {
+            let size_limit = rc.max_batch_size.unwrap_or(startup_config.size_limit);
+            let (weight_limit, prefill_weight_limit) = batch_config_validator
+                .validate_batch_config(
+                    max_sequence_length,
+                    size_limit,
+                    rc.max_batch_weight.or(Some(startup_config.weight_limit)),
+                    rc.max_prefill_weight.or(Some(startup_config.prefill_weight_limit)),
+                )?;
+            let queue_size_limit = rc.max_queue_size.unwrap_or(startup_config.queue_size_limit);
+            if queue_size_limit > max_concurrent_requests {
+                return Err(format!(
+                    "max_queue_size {queue_size_limit} exceeds max_concurrent_requests {max_concurrent_requests}"
+                ))
+            }
+            if let Some(level) = &rc.log_level {
+                match log_level_setter.as_deref() {
+                    Some(set_level) => set_level(level)?,
+                    None => return Err("log level can't be changed at runtime".to_string()),
+                }
+            }
+            let config = BatchingConfig {
+                size_limit,
+                weight_limit,
+                prefill_weight_limit,
+                max_waiting_tokens: rc.max_waiting_tokens.unwrap_or(startup_config.max_waiting_tokens),
+                queue_size_limit,
+                step_timeout: rc.shard_step_timeout_secs.map(Duration::from_secs)
+                    .or(startup_config.step_timeout),
+            };
+            info!("Batching config is now {config:?}");
+            config_sender.send_replace(config);
+            Ok(())
+        });
+    }
+
+    // Create state
+    let deployment_config = DeploymentConfig {
         batch_type,
-    );
-    let validation = Validation::new(
-        args.validation_workers,
-        args.tokenizer.clone(),
-        args.client,
-        args.max_sequence_length,
-        args.max_new_tokens,
-    );
+        batching_config: config_receiver.clone(),
+        max_concurrent_requests: args.max_concurrent_requests,
+        max_queued_prompt_bytes: args.max_queued_prompt_bytes,
+        max_sequence_length: args.max_sequence_length,
+        max_new_tokens: args.max_new_tokens,
+        top_n_tokens: TopNTokens { default: args.default_top_n_tokens, max: args.max_top_n_tokens },
+        token_limit_policy: args.token_limit_policy.parse::<TokenLimitPolicy>()
+            .unwrap_or_else(|e| panic!("{e}")),
+        validation_workers: args.validation_workers,
+        detokenization_workers: args.detokenization_workers,
+        output_special_tokens: args.output_special_tokens,
+        seq2seq_input_separator: unescape_separator(&args.seq2seq_input_separator)
+            .unwrap_or_else(|e| panic!("{e}")),
+        decoder_backend: args.decoder_backend,
+        token_text_cache_size: args.token_text_cache_size,
+        max_decoded_top_tokens: (args.max_decoded_top_tokens > 0).then_some(args.max_decoded_top_tokens),
+        fim_sentinels: args.fim_sentinel_tokens.as_ref().map(
+            |s| s.parse::<FimSentinels>().unwrap_or_else(|e| panic!("{e}"))
+        ),
+        kv_cache_capacity_bytes: args.kv_cache_capacity_bytes,
+        coalesce_requests: args.coalesce_requests,
+        stream_config: StreamBufferConfig {
+            capacity: args.stream_buffer_size,
+            policy: args.slow_stream_policy.parse::<SlowStreamPolicy>()
+                .unwrap_or_else(|e| panic!("{e}")),
+        },
+        // An externally provided cache store takes precedence over the in-memory one
+        response_cache_store: args.response_cache_store.take(),
+        response_cache_size: args.response_cache_size,
+        response_cache_ttl: Duration::from_secs(args.response_cache_ttl_secs),
+        preemption: Preemption::for_policy(&args.preemption_policy, args.preemption_min_generated_tokens)
+            .unwrap_or_else(|e| panic!("{e}")),
+        scheduling: SchedulingPolicy::for_policy(&args.scheduling_policy, args.sjf_aging_rate)
+            .unwrap_or_else(|e| panic!("{e}")),
+        lanes: LaneConfig::new(args.latency_lane_max_input_tokens, args.latency_lane_max_new_tokens),
+        embedding_batch: (args.max_embedding_batch_size > 0).then_some(EmbeddingBatchConfig {
+            max_batch_size: args.max_embedding_batch_size,
+            max_batch_tokens: args.max_embedding_batch_tokens,
+        }),
+        shard_health_check_interval: Duration::from_secs(args.shard_health_check_interval_secs),
+        ttft_slo: args.ttft_slo_millis.map(Duration::from_millis),
+        shard_prefill_progress: args.shard_prefill_progress,
+        waiting_tokens_policy: args.waiting_tokens_policy.parse::<WaitingTokensPolicy>()
+            .unwrap_or_else(|e| panic!("{e}")),
+        pipeline_prefill: args.pipeline_prefill,
+        retry_failed_batches: args.retry_failed_batches,
+        request_hooks: (!request_hooks.is_empty()).then(|| RequestHooks::new(request_hooks)),
+        determinism_audits: args.determinism_audit_fraction > 0.0,
+        parameter_policy: args.parameter_policy_path.as_ref().and_then(|path| {
+            let model_name = args.model_name.as_ref()
+                .unwrap_or_else(|| panic!("model_name is required with parameter_policy_path"));
+            let policy = ParameterPolicies::load(path).unwrap_or_else(|e| panic!("{e}")).for_model(model_name);
+            if policy.is_none() {
+                warn!("No parameter policy for model {model_name} in {path}");
+            }
+            policy
+        }),
+        model: ModelIdentity {
+            id: args.model_name.clone().unwrap_or_default(),
+            version: args.model_version.clone().unwrap_or_default(),
+        },
+    };
+    let model_paths = ModelPaths {
+        decoder_model_path: args.decoder_model_path,
+        model_config_path: args.model_config_path,
+        tokenizer_config_path: args.tokenizer_config_path,
+    };
+    let mut clients: Vec<ShardedClient> = std::iter::once(args.client)
+        .chain(args.replica_clients.drain(..)).collect();
+    let model = ModelIdentity::query(&mut clients[0], deployment_config.model.clone()).await
+        .unwrap_or_else(|e| panic!("{e}"));
+    tracing::info!("Serving model: id = {}, version = {}", model.id, model.version);
+    let deployment = Deployment::new(
+        &deployment_config, args.tokenizer, clients, seq2seq, eos_token_id, &model_paths, Arc::new(model),
+    ).unwrap_or_else(|e| panic!("{e}"));
+    let (deployment_sender, deployment_receiver) = watch::channel(Arc::new(deployment));
+    let model_swapper = (args.admin_api || args.shard_discovery.is_some()).then(|| Arc::new(ModelSwapper::new(
+        deployment_config,
+        deployment_sender,
+        args.batch_type,
+        batch_type_name,
+        model_paths,
+        args.shard_channel_config,
+        args.shard_grpc_compression,
+        args.warmup,
+    )));
+    if let Some(discovery) = args.shard_discovery {
+        discovery.spawn(model_swapper.clone().unwrap());
+    }
+    if args.generation_job_journal_path.is_some() && args.max_generation_jobs == 0 {
+        panic!("generation_job_journal_path requires max_generation_jobs > 0");
+    }
     let shared_state = ServerState {
-        validation,
-        batcher,
+        deployment: deployment_receiver.clone(),
         limit_concurrent_requests: Arc::new(Semaphore::new(args.max_concurrent_requests)),
+        max_concurrent_requests: args.max_concurrent_requests,
         max_sequence_length: args.max_sequence_length,
         max_new_tokens: args.max_new_tokens,
-        seq2seq,
+        max_top_n_tokens: args.max_top_n_tokens,
+        determinism_audit_fraction: args.determinism_audit_fraction,
+        request_log,
+        safety_filter: args.safety_filter,
+        client_limiter: args.max_concurrent_requests_per_client.map(ClientLimiter::new),
+        generation_jobs: (args.max_generation_jobs > 0).then(|| GenerationJobs::new(
+            args.max_generation_jobs, Duration::from_secs(args.generation_job_ttl_secs),
+            args.generation_job_journal_path.as_deref()
+                .map(|path| JobJournal::open(path).unwrap_or_else(|e| panic!("{e}"))),
+        )),
+        replay_buffer: (args.replay_buffer_size > 0)
+            .then(|| Arc::new(ReplayBuffer::new(args.replay_buffer_size))),
+        prompt_templates: args.prompt_templates_path.map(
+            |path| PromptTemplates::watch(path).unwrap_or_else(|e| panic!("{e}"))
+        ),
+        prompt_registry: (args.prompt_registry_capacity_bytes > 0).then(|| Arc::new(PromptRegistry::new(
+            args.prompt_registry_capacity_bytes,
+            Duration::from_secs(args.registered_prompt_idle_timeout_secs),
+        ))),
+        admin_token_digest: args.admin_token.as_deref().map(token_digest),
     };
 
 
@@ -380,22 +844,49 @@ async fn do_run<B: BatchType>(
         .expect("failed to install metrics recorder");
 
     // Create router
-    let app = Router::new()
+    let mut app = Router::new()
         // Disabling HTTP generate endpoint for now
         //.route("/generate", post(generate))
         //.layer(Extension(shared_state.clone()))
         .route("/health", get(health))
-        .layer(Extension(health_ext))
+        .layer(Extension(deployment_receiver))
+        .route("/v1/models", get(list_models))
+        .layer(Extension(shared_state.clone()))
         .route("/metrics", get(metrics))
         .layer(Extension(prom_handle));
+    if let Some(token) = &args.admin_token {
+        app = app
+            .route("/admin/state", get(admin_state))
+            .route("/admin/replay", get(list_replay_records))
+            .route("/admin/replay/:request_id", post(replay))
+            .route("/admin/log_filter", put(set_log_filter))
+            .layer(Extension(AdminState::new(
+                shared_state.clone(), config_receiver, token, log_level_setter,
+            )));
+    }
 
     let notify = Arc::new(Notify::new());
     let notify_clone = notify.clone();
+    let deployment = shared_state.deployment.clone();
+    let shutdown_grace_period = args.shutdown_grace_period_secs.map(Duration::from_secs);
+    let journal = shared_state.generation_jobs.as_ref().and_then(|jobs| jobs.journal.clone());
+    let grace_period_journal = journal.clone();
 
     // Create gRPC server
+    let authenticator = Authenticator::new(
+        args.api_keys_path.as_deref(),
+        args.jwt_key_path.map(|key_path| JwtConfig {
+            key_path,
+            issuer: args.jwt_issuer,
+            audience: args.jwt_audience,
+            tenant_claim: args.jwt_tenant_claim,
+        }),
+    ).unwrap_or_else(|e| panic!("{e}"));
+    let grpc_web = args.grpc_web_origins.as_deref()
+        .map(|origins| GrpcWebConfig::parse(origins).unwrap_or_else(|e| panic!("{e}")));
     let grpc_task = start_grpc_server(
-        args.grpc_addr, args.tls_key_pair, args.tls_client_ca_cert,
-        shared_state, args.tokenizer, async move {
+        args.grpc_addr, args.tls_key_pair, args.tls_client_ca_cert, args.grpc_compression, grpc_web,
+        shared_state, model_swapper.filter(|_| args.admin_api), authenticator, async move {
             notify_clone.notified().await
         },
     ).await;
@@ -411,9 +902,23 @@ async fn do_run<B: BatchType>(
 
     // Run server
     let server = axum::Server::bind(&args.addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         // Wait until all requests are finished to shut down
-        .with_graceful_shutdown(shutdown_signal());
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            if let Some(grace_period) = shutdown_grace_period {
+                // Stop generation of the requests still in progress once the grace period ends
+                tokio::spawn(async move {
+                    sleep(grace_period).await;
+                    // Requests still queued are resubmitted when the router restarts, and
+                    // jobs still in progress are reported as interrupted
+                    if let Some(journal) = grace_period_journal {
+                        journal.close();
+                    }
+                    deployment.borrow().batcher.stop_generation();
+                });
+            }
+        });
 
     tracing::info!("HTTP server started on port {}", args.addr.port());
 
@@ -422,6 +927,9 @@ async fn do_run<B: BatchType>(
     // Trigger gRPC server shutdown
     notify.notify_one();
     grpc_task.await.unwrap();
+    if let Some(journal) = journal {
+        journal.close();
+    }
 }
 
 /// Shutdown signal handler
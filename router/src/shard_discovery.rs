@@ -0,0 +1,148 @@
+/// Discovery of data-parallel replicas via DNS, e.g. the shards behind a headless
+/// Kubernetes service, which are added and removed as the name's addresses change
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use text_generation_client::{ChannelConfig, ClientError, CompressionEncoding, ShardedClient};
+use tokio::net::lookup_host;
+use tokio::time::{interval, MissedTickBehavior};
+use tonic::transport::Uri;
+use tracing::{error, info, warn};
+use crate::deployment::ModelSwapper;
+
+/// Replicas found by resolving a DNS name, each a single shard serving the model on its own
+pub struct ShardDiscovery {
+    /// Name and port to resolve, as `host:port`
+    target: String,
+    refresh_interval: Duration,
+    channel_config: ChannelConfig,
+    compression: bool,
+    /// Model info of the shards found at startup, which those added later must match
+    model_info: (bool, u32, bool),
+    /// Address and client of each replica in use, in the current deployment's order
+    replicas: Vec<(SocketAddr, ShardedClient)>,
+}
+
+impl ShardDiscovery {
+    /// Resolve the name and connect to the shard at each address, all of which must be reachable
+    pub(crate) async fn connect(
+        target: String, refresh_interval: Duration, channel_config: ChannelConfig, compression: bool,
+    ) -> Result<Self, String> {
+        let addrs = resolve(&target).await?;
+        if addrs.is_empty() {
+            return Err(format!("no shard addresses found for {target}"))
+        }
+        let mut replicas = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let client = connect_shard(addr, &channel_config, compression).await
+                .map_err(|e| format!("couldn't connect to shard at {addr}: {e}"))?;
+            replicas.push((addr, client));
+        }
+        let model_info = replicas[0].1.model_info().await
+            .map_err(|e| format!("couldn't get model info from shards: {e}"))?;
+        let found: Vec<_> = replicas.iter().map(|(addr, _)| addr).collect();
+        info!("Discovered {} replica(s) via {target}: {found:?}", replicas.len());
+        metrics::gauge!("tgi_discovered_replica_count", replicas.len() as f64);
+        Ok(Self { target, refresh_interval, channel_config, compression, model_info, replicas })
+    }
+
+    /// Clients of the replicas found, ordered by address
+    pub fn clients(&self) -> Vec<ShardedClient> {
+        self.replicas.iter().map(|(_, client)| client.clone()).collect()
+    }
+
+    /// Periodically re-resolve the name, switching to the new set of replicas when it changes
+    pub(crate) fn spawn(mut self, swapper: Arc<ModelSwapper>) {
+        tokio::spawn(async move {
+            let mut ticker = interval(self.refresh_interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // First tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                self.refresh(&swapper).await;
+            }
+        });
+    }
+
+    async fn refresh(&mut self, swapper: &ModelSwapper) {
+        let addrs = match resolve(&self.target).await {
+            Ok(addrs) if addrs.is_empty() => {
+                // Likely transient, keep serving with the current replicas
+                warn!("No shard addresses found for {}, keeping the current replicas", self.target);
+                return
+            },
+            Ok(addrs) => addrs,
+            Err(err) => {
+                warn!("{err}, keeping the current replicas");
+                return
+            },
+        };
+        // Replicas which remain keep their batching tasks, by their position in the deployment
+        let kept: Vec<usize> = (0..self.replicas.len())
+            .filter(|&i| addrs.contains(&self.replicas[i].0))
+            .collect();
+        let mut added = vec![];
+        for addr in addrs {
+            if self.replicas.iter().any(|(a, _)| *a == addr) {
+                continue
+            }
+            // Replicas which can't be added yet are retried at the next refresh
+            match self.connect_new_shard(addr).await {
+                Ok(client) => added.push((addr, client)),
+                Err(err) => error!("Couldn't add replica at {addr}: {err}"),
+            }
+        }
+        if (kept.is_empty() && added.is_empty()) || (kept.len() == self.replicas.len() && added.is_empty()) {
+            return
+        }
+
+        let added_addrs: Vec<_> = added.iter().map(|(addr, _)| addr).collect();
+        let removed_addrs: Vec<_> = self.replicas.iter().enumerate()
+            .filter(|(i, _)| !kept.contains(i))
+            .map(|(_, (addr, _))| addr)
+            .collect();
+        info!("Replicas via {} changed, added: {added_addrs:?}, removed: {removed_addrs:?}", self.target);
+        match swapper.swap_replicas(&kept, added.iter().map(|(_, client)| client.clone()).collect()).await {
+            Ok(()) => {
+                let replicas: Vec<_> = kept.iter().map(|&i| self.replicas[i].clone()).chain(added).collect();
+                metrics::gauge!("tgi_discovered_replica_count", replicas.len() as f64);
+                self.replicas = replicas;
+            },
+            Err(err) => error!("Couldn't switch to the new replicas, will retry: {err}"),
+        }
+    }
+
+    /// Connect to a shard which wasn't found at startup, which must serve the same model
+    async fn connect_new_shard(&self, addr: SocketAddr) -> Result<ShardedClient, String> {
+        let mut client = connect_shard(addr, &self.channel_config, self.compression).await
+            .map_err(|e| e.to_string())?;
+        let model_info = client.model_info().await.map_err(|e| e.to_string())?;
+        if model_info != self.model_info {
+            return Err("shard serves a different model".to_string())
+        }
+        Ok(client)
+    }
+}
+
+async fn resolve(target: &str) -> Result<BTreeSet<SocketAddr>, String> {
+    lookup_host(target).await
+        .map(Iterator::collect)
+        .map_err(|e| format!("couldn't resolve shard addresses of {target}: {e}"))
+}
+
+async fn connect_shard(
+    addr: SocketAddr, channel_config: &ChannelConfig, compression: bool,
+) -> Result<ShardedClient, ClientError> {
+    // IPv6 addresses are bracketed when formatted
+    let uri: Uri = format!("http://{addr}").parse()
+        .map_err(|e| ClientError::Connection(format!("invalid shard uri: {e}")))?;
+    let mut client = ShardedClient::connect_single(uri, channel_config).await?;
+    // Clear the cache; useful if the webserver rebooted
+    client.clear_cache().await?;
+    if compression {
+        client = client.with_compression(CompressionEncoding::Gzip);
+    }
+    Ok(client)
+}
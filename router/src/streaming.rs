@@ -0,0 +1,197 @@
+/// Bounded delivery of streaming responses
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use text_generation_client::ClientError;
+use crate::batcher::InferResponse;
+
+type StreamResult = Result<InferResponse, ClientError>;
+
+/// What to do with a streaming request whose consumer isn't keeping up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SlowStreamPolicy {
+    /// Merge intermediate responses until there is room in the buffer
+    Coalesce,
+    /// Coalesce, logging a warning when the buffer first fills
+    Warn,
+    /// Stop generating for the request until its consumer catches up
+    Pause,
+    /// Cancel the request
+    Cancel,
+}
+
+impl std::str::FromStr for SlowStreamPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "coalesce" => Ok(Self::Coalesce),
+            "warn" => Ok(Self::Warn),
+            "pause" => Ok(Self::Pause),
+            "cancel" => Ok(Self::Cancel),
+            _ => Err(format!("invalid slow stream policy '{s}', must be coalesce, warn, pause or cancel")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StreamBufferConfig {
+    /// Max number of undelivered responses buffered per stream
+    pub(crate) capacity: usize,
+    pub(crate) policy: SlowStreamPolicy,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum StreamSendError {
+    /// The consumer has gone away
+    Closed,
+    /// The buffer is full and the policy is to pause or cancel
+    SlowConsumer,
+}
+
+/// Sending side of a streaming response channel. Responses which don't fit in
+/// the buffer are merged into a single pending one so that memory stays bounded.
+#[derive(Debug)]
+pub(crate) struct StreamSender {
+    sender: Sender<StreamResult>,
+    policy: SlowStreamPolicy,
+    pending: Option<InferResponse>,
+    /// Number of responses merged into the pending one
+    pending_count: usize,
+    /// Most responses produced but not yet delivered to the consumer at once
+    max_lag: usize,
+    /// Whether a lagging consumer was reported, with the warn policy
+    lag_reported: bool,
+    /// Number of generated tokens to include in each response, if more than one
+    chunk_tokens: u32,
+    /// Responses with generated tokens held back until there are chunk_tokens of them
+    chunk: Option<InferResponse>,
+    /// Generated token count as of the last chunk sent
+    chunked_tokens: u32,
+}
+
+pub(crate) fn stream_channel(
+    config: StreamBufferConfig, chunk_tokens: u32,
+) -> (StreamSender, Receiver<StreamResult>) {
+    let (sender, receiver) = channel(config.capacity);
+    (StreamSender {
+        sender, policy: config.policy, pending: None, pending_count: 0, max_lag: 0, lag_reported: false,
+        chunk_tokens, chunk: None, chunked_tokens: 0,
+    }, receiver)
+}
+
+impl StreamSender {
+    /// Send an intermediate response. When the buffer is full it's held back and merged
+    /// with subsequent ones. With the pause and cancel policies, only a single response is
+    /// held back, beyond which the output is kept for the final response and an error returned.
+    /// Responses with generated tokens are first merged into chunks if configured.
+    pub(crate) fn send(&mut self, response: InferResponse) -> Result<(), StreamSendError> {
+        let response = if self.chunk_tokens > 1 && response.gen_token_count > 0 {
+            let chunk = match self.chunk.take() {
+                Some(mut chunk) => {
+                    chunk.merge(response);
+                    chunk
+                },
+                None => response,
+            };
+            if chunk.gen_token_count - self.chunked_tokens < self.chunk_tokens {
+                self.chunk = Some(chunk);
+                return Ok(())
+            }
+            self.chunked_tokens = chunk.gen_token_count;
+            chunk
+        } else {
+            response
+        };
+        let had_pending = self.pending.is_some();
+        let response = match self.pending.take() {
+            Some(mut pending) => {
+                pending.merge(response);
+                pending
+            },
+            None => response,
+        };
+        self.pending_count += 1;
+        self.max_lag = self.max_lag.max(self.lag());
+        match self.sender.try_send(Ok(response)) {
+            Ok(()) => {
+                self.pending_count = 0;
+                Ok(())
+            },
+            Err(TrySendError::Closed(_)) => Err(StreamSendError::Closed),
+            Err(TrySendError::Full(result)) => {
+                if !had_pending {
+                    metrics::increment_counter!("tgi_stream_backpressure");
+                }
+                self.pending = result.ok();
+                match self.policy {
+                    SlowStreamPolicy::Pause | SlowStreamPolicy::Cancel if had_pending => {
+                        Err(StreamSendError::SlowConsumer)
+                    },
+                    _ => Ok(()),
+                }
+            },
+        }
+    }
+
+    /// Number of responses produced which the consumer has yet to receive,
+    /// counting each of those merged while the buffer was full
+    fn lag(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity() + self.pending_count
+    }
+
+    /// The current lag the first time the buffer is found full, with the warn policy
+    pub(crate) fn lag_to_report(&mut self) -> Option<usize> {
+        let report = self.policy == SlowStreamPolicy::Warn && !self.lag_reported && self.pending.is_some();
+        report.then(|| {
+            self.lag_reported = true;
+            self.lag()
+        })
+    }
+
+    /// Deliver any held back output if there's now room for it. Returns whether the
+    /// consumer has yet to catch up, which it has once at most half of the buffer
+    /// holds undelivered responses.
+    pub(crate) fn is_lagging(&mut self) -> bool {
+        if let Some(pending) = self.pending.take() {
+            match self.sender.try_send(Ok(pending)) {
+                Ok(()) => self.pending_count = 0,
+                Err(TrySendError::Full(result) | TrySendError::Closed(result)) => self.pending = result.ok(),
+            }
+        }
+        self.pending.is_some() || self.sender.capacity() < self.sender.max_capacity() / 2
+    }
+
+    /// Send the terminating response, including any held back output.
+    /// If the buffer is full it's delivered asynchronously once there is room.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn send_final(&mut self, result: StreamResult) -> Result<(), StreamResult> {
+        metrics::histogram!("tgi_stream_max_consumer_lag", self.max_lag as f64);
+        let result = match (self.chunk.take(), result) {
+            (Some(mut chunk), Ok(response)) => {
+                chunk.merge(response);
+                Ok(chunk)
+            },
+            (_, result) => result,
+        };
+        let result = match (self.pending.take(), result) {
+            (Some(mut pending), Ok(response)) => {
+                pending.merge(response);
+                Ok(pending)
+            },
+            (_, result) => result,
+        };
+        match self.sender.try_send(result) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Closed(result)) => Err(result),
+            Err(TrySendError::Full(result)) => {
+                let sender = self.sender.clone();
+                tokio::spawn(async move { sender.send(result).await.unwrap_or_default() });
+                Ok(())
+            },
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
@@ -0,0 +1,46 @@
+/// Token healing for prompts which end part-way through a token
+use tokenizers::Encoding;
+
+/// Back off the last token of an encoded prompt. Returns the byte offset at which
+/// to truncate the prompt and the removed text, which the first generated token
+/// is then constrained to start with. None if there is no suitable token to remove.
+pub(crate) fn heal_prompt(input: &str, encoding: &Encoding) -> Option<(usize, String)> {
+    // Ignore any special tokens appended by the tokenizer
+    let last = encoding.get_special_tokens_mask().iter().rposition(|&special| special == 0)?;
+    let (start, end) = encoding.get_offsets()[last];
+    // Don't remove the entire prompt, or a token which doesn't end the text
+    if start == 0 || start >= end || end != input.len() || !input.is_char_boundary(start) {
+        return None
+    }
+    Some((start, input[start..].to_string()))
+}
+
+/// Removes healed prompt text from the start of generated output,
+/// which may be spread across multiple streamed chunks
+#[derive(Clone, Debug)]
+pub(crate) struct HealedPrefix {
+    remaining: String,
+}
+
+impl HealedPrefix {
+    pub(crate) fn new(prefix: String) -> Self {
+        Self { remaining: prefix }
+    }
+
+    pub(crate) fn strip(&mut self, text: &mut String) {
+        if self.remaining.is_empty() {
+            return
+        }
+        let matched = text.char_indices().zip(self.remaining.chars())
+            .find(|((_, tc), pc)| tc != pc)
+            .map_or_else(|| text.len().min(self.remaining.len()), |((i, _), _)| i);
+        if matched == text.len() || matched == self.remaining.len() {
+            text.drain(..matched);
+            self.remaining.drain(..matched);
+        } else {
+            // The output diverged from the healed text, leave it as-is
+            tracing::warn!("Generated output doesn't start with healed prompt text");
+            self.remaining.clear();
+        }
+    }
+}
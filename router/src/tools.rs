@@ -0,0 +1,192 @@
+/// Structured tool-calling output mode
+use std::collections::HashSet;
+use std::fs;
+use minijinja::{Environment, ErrorKind};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::pb::fmaas::ToolCall;
+use crate::validation::ValidationError;
+
+/// A tool which the model may call, described by a JSON schema for its arguments
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ToolDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub parameters: Value,
+}
+
+/// Tool definitions must have distinct non-empty names and object-typed argument schemas
+pub(crate) fn validate_tools(tools: &[ToolDefinition]) -> Result<(), ValidationError> {
+    let mut names = HashSet::new();
+    for tool in tools {
+        if tool.name.is_empty() || !names.insert(&tool.name) {
+            return Err(ValidationError::ToolName(tool.name.clone()))
+        }
+        if !tool.parameters.is_object() {
+            return Err(ValidationError::ToolSchema(tool.name.clone()))
+        }
+    }
+    Ok(())
+}
+
+/// JSON schema of the tool-call envelope which constrained decoding should
+/// enforce: {"name": <tool name>, "arguments": <tool arguments>}
+pub(crate) fn tool_call_schema(tools: &[ToolDefinition]) -> String {
+    let variants: Vec<Value> = tools.iter().map(|tool| json!({
+        "type": "object",
+        "description": tool.description,
+        "properties": {
+            "name": { "const": tool.name },
+            "arguments": tool.parameters,
+        },
+        "required": ["name", "arguments"],
+        "additionalProperties": false,
+    })).collect();
+    json!({ "oneOf": variants }).to_string()
+}
+
+/// Extract the tool-call envelope from generated text. Any text preceding the
+/// JSON object or following it is ignored. Returns None if the text doesn't
+/// contain a well-formed call to one of the provided tools.
+pub(crate) fn parse_tool_call(tools: &[ToolDefinition], text: &str) -> Option<ToolCall> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        name: String,
+        arguments: serde_json::Map<String, Value>,
+    }
+
+    let call = text.find('{')
+        .and_then(|start| serde_json::Deserializer::from_str(&text[start..])
+            .into_iter::<Envelope>().next())
+        .and_then(Result::ok)
+        .filter(|envelope| tools.iter().any(|t| t.name == envelope.name))
+        .map(|Envelope { name, arguments }| ToolCall {
+            name, arguments: Value::Object(arguments).to_string(),
+        });
+    if call.is_none() {
+        metrics::increment_counter!("tgi_tool_call_parse_failure");
+    }
+    call
+}
+
+/// Chat template section of a Hugging Face tokenizer_config.json, either a single
+/// template or named ones
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    #[serde(default)]
+    chat_template: Option<ChatTemplates>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChatTemplates {
+    Single(String),
+    Named(Vec<NamedChatTemplate>),
+}
+
+#[derive(Deserialize)]
+struct NamedChatTemplate {
+    name: String,
+    template: String,
+}
+
+/// The model's chat template, used to render the definitions of the tools
+/// provided with a request into its prompt
+#[derive(Debug)]
+pub(crate) struct ChatTemplate {
+    env: Environment<'static>,
+}
+
+impl ChatTemplate {
+    /// Load the chat template from the model's tokenizer_config.json, preferring one named
+    /// "tool_use" to the default. None if the model doesn't have one
+    pub(crate) fn load(path: &str) -> Result<Option<Self>, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read tokenizer config {path}: {e}"))?;
+        let config: TokenizerConfig = serde_json::from_str(&contents)
+            .map_err(|e| format!("invalid tokenizer config {path}: {e}"))?;
+        let template = match config.chat_template {
+            None => return Ok(None),
+            Some(ChatTemplates::Single(template)) => template,
+            Some(ChatTemplates::Named(templates)) => {
+                let find = |name: &str| templates.iter().position(|t| t.name == name);
+                match find("tool_use").or_else(|| find("default")) {
+                    Some(i) => templates.into_iter().nth(i).unwrap().template,
+                    None => return Ok(None),
+                }
+            },
+        };
+        let mut env = Environment::new();
+        env.add_function("raise_exception", |message: String| -> Result<String, minijinja::Error> {
+            Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+        });
+        env.add_template_owned("chat", template)
+            .map_err(|e| format!("invalid chat template in {path}: {e}"))?;
+        Ok(Some(Self { env }))
+    }
+
+    /// The prompt as a user message along with the tools, followed by the start of the
+    /// assistant's reply. Fails if the template doesn't include the tools in its output
+    pub(crate) fn render(&self, prompt: &str, tools: &[ToolDefinition]) -> Result<String, ValidationError> {
+        let tools: Vec<Value> = tools.iter().map(|tool| json!({
+            "type": "function",
+            "function": {
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": tool.parameters,
+            },
+        })).collect();
+        let rendered = self.env.get_template("chat")
+            .and_then(|template| template.render(minijinja::context! {
+                messages => [json!({ "role": "user", "content": prompt })],
+                tools => tools,
+                add_generation_prompt => true,
+                // The tokenizer adds any special tokens when the prompt is encoded
+                bos_token => "",
+                eos_token => "",
+            }))
+            .map_err(|e| ValidationError::ToolTemplate(e.to_string()))?;
+        if !tools.iter().all(|tool| rendered.contains(tool["function"]["name"].as_str().unwrap_or_default())) {
+            return Err(ValidationError::ToolTemplate("it doesn't include the tool definitions".to_string()))
+        }
+        Ok(rendered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::{ChatTemplate, ToolDefinition};
+
+    fn template(contents: serde_json::Value) -> ChatTemplate {
+        let path = std::env::temp_dir().join(format!("tokenizer_config_{}.json", rand::random::<u32>()));
+        std::fs::write(&path, contents.to_string()).unwrap();
+        let template = ChatTemplate::load(path.to_str().unwrap()).unwrap().unwrap();
+        std::fs::remove_file(path).unwrap();
+        template
+    }
+
+    #[test]
+    fn renders_tools_through_chat_template() {
+        let template = template(json!({"chat_template": [
+            {"name": "default", "template": "{{ messages[0].content }}"},
+            {"name": "tool_use", "template": "{{ bos_token }}Tools:{% for tool in tools %} \
+                {{ tool.function.name }}({{ tool.function.parameters | tojson }}){% endfor %}\n\
+                {% for m in messages %}<{{ m.role }}>{{ m.content }}{% endfor %}\
+                {% if add_generation_prompt %}<assistant>{% endif %}"},
+        ]}));
+        let tools = vec![ToolDefinition {
+            name: "get_weather".to_string(),
+            description: String::new(),
+            parameters: json!({"type": "object"}),
+        }];
+        assert_eq!(
+            template.render("Weather in Paris?", &tools).unwrap(),
+            "Tools: get_weather({\"type\":\"object\"})\n<user>Weather in Paris?<assistant>",
+        );
+
+        let ignores_tools = self::template(json!({"chat_template": "<user>{{ messages[0].content }}"}));
+        assert!(ignores_tools.render("Weather in Paris?", &tools).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+/// Step-by-step trace of generation, returned for debugging when requested
+use std::mem::take;
+use text_generation_client::Token;
+use tokio::time::Instant;
+use crate::GenerateParameters;
+use crate::batcher::{TokenInfos, TopTokenBudget};
+use crate::decoder::Decoder;
+use crate::pb::fmaas;
+use crate::pb::fmaas::StopReason;
+use crate::pb::fmaas::generation_trace::{Penalty, Step, StopCriterion};
+
+/// Minimum number of top candidate tokens requested from the shards for traced requests
+pub(crate) const TRACE_TOP_N: u32 = 5;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GenerationTrace {
+    steps: Vec<Step>,
+    /// Chosen token of each step, moved into its step once decoded
+    tokens: Vec<Token>,
+    /// Time of the most recent step
+    last_step_time: Option<Instant>,
+}
+
+impl GenerationTrace {
+    pub(crate) fn record(
+        &mut self,
+        token: Token,
+        text: Option<&String>,
+        penalties: Vec<Penalty>,
+        stop_criteria: Vec<StopCriterion>,
+        stop_reason: StopReason,
+        batch_time: Option<Instant>,
+    ) {
+        let now = Instant::now();
+        let since = self.last_step_time.or(batch_time).unwrap_or(now);
+        self.last_step_time = Some(now);
+        self.tokens.push(token);
+        self.steps.push(Step {
+            token: None,
+            text: text.cloned().unwrap_or_default(),
+            penalties,
+            stop_criteria,
+            stop_reason: stop_reason as i32,
+            duration_micros: now.duration_since(since).as_micros() as u64,
+        });
+    }
+
+    pub(crate) fn decode(&mut self, decoder: &Decoder, budget: &mut TopTokenBudget) {
+        for (step, token) in self.steps.iter_mut().zip(take(&mut self.tokens)) {
+            step.token = Some(TokenInfos::decode_token_info(&token, decoder, budget));
+        }
+    }
+}
+
+impl From<GenerationTrace> for fmaas::GenerationTrace {
+    fn from(trace: GenerationTrace) -> Self {
+        Self { steps: trace.steps }
+    }
+}
+
+/// Clear token details which were only requested from the shards for the trace
+pub(crate) fn strip_trace_details(token: &mut Token, params: &GenerateParameters) {
+    if !params.include_logprobs && !params.tracks_logprob_sum() {
+        token.logprob = 0.0;
+    }
+    if !params.include_ranks {
+        token.rank = 0;
+    }
+    let include_top_n = params.include_top_n.unwrap_or_default();
+    if include_top_n < TRACE_TOP_N {
+        token.top_tokens.truncate(include_top_n as usize);
+    }
+}
+
+/// Penalties in effect when the shards chose the token following
+/// `prior_tokens` generated tokens
+pub(crate) fn applied_penalties(params: &GenerateParameters, prior_tokens: u32) -> Vec<Penalty> {
+    let penalty = |name: &str, value: f32| Penalty { name: name.to_string(), value };
+    let mut penalties = vec![];
+    if params.repetition_penalty != 1.0 {
+        penalties.push(penalty("repetition_penalty", params.repetition_penalty));
+        if params.repetition_penalty_range != 0 {
+            penalties.push(penalty("repetition_penalty_range", params.repetition_penalty_range as f32));
+        }
+    }
+    if let Some((start_index, decay_factor)) = params.length_penalty {
+        if prior_tokens > start_index {
+            // Factor applied to the score of the EOS token
+            penalties.push(penalty(
+                "length_penalty", decay_factor.powi((prior_tokens - start_index) as i32),
+            ));
+        }
+    }
+    if params.no_repeat_ngram_size != 0 {
+        penalties.push(penalty("no_repeat_ngram_size", params.no_repeat_ngram_size as f32));
+    }
+    if let Some((_, delta)) = params.watermark {
+        // Bias added to the logits of green list tokens
+        penalties.push(penalty("watermark", delta));
+    }
+    if !params.bad_words_ids.is_empty() {
+        // Number of banned token sequences
+        penalties.push(penalty("bad_words", params.bad_words_ids.len() as f32));
+    }
+    penalties
+}
+
+pub(crate) fn stop_criterion(name: &str, met: bool, detail: String) -> StopCriterion {
+    StopCriterion { name: name.to_string(), met, detail }
+}
@@ -1,20 +1,100 @@
 /// Payload validation logic
 use std::collections::hash_map::RandomState;
+use std::sync::Arc;
 use std::time::Duration;
-use crate::{ErrorResponse, GenerateParameters, GenerateRequest};
+use crate::{ErrorResponse, GenerateParameters, GenerateRequest, TruncationSide};
+use crate::tools::{ChatTemplate, validate_tools};
+use crate::token_healing::heal_prompt;
+use crate::kv_cache::KvCacheModel;
+use crate::parameter_policy::ParameterPolicy;
 use axum::http::StatusCode;
 use axum::Json;
 use moka::sync::Cache;
 use rand::Rng;
 use rand::rngs::ThreadRng;
+use regex::RegexBuilder;
 use thiserror::Error;
+use tokenizers::Encoding;
 use tokenizers::tokenizer::Tokenizer;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Instant;
-use text_generation_client::{ClientError, ShardedClient};
+use text_generation_client::{ClientError, ShardCapabilities, ShardedClient};
 
 const MAX_STOP_SEQS: usize = 6;
 const MAX_STOP_SEQ_TOKENS: usize = 40;
+const MAX_STOP_REGEX_LENGTH: usize = 256;
+/// Bound on the compiled size of each stop regex, in bytes
+const STOP_REGEX_SIZE_LIMIT: usize = 1 << 20;
+const MAX_NO_REPEAT_NGRAM_SIZE: u32 = 10;
+const MAX_BAD_WORDS: usize = 64;
+const MAX_BAD_WORD_TOKENS: usize = 20;
+const MAX_BANNED_TOKEN_IDS: usize = 256;
+const MAX_TEMPERATURE_BREAKPOINTS: usize = 16;
+
+/// Number of top candidate tokens included for each returned token
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TopNTokens {
+    /// Applied if token details are requested without specifying top_n_tokens
+    pub(crate) default: u32,
+    pub(crate) max: u32,
+}
+
+/// What to do when a request's input plus max_new_tokens exceeds the max sequence length
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TokenLimitPolicy {
+    /// Reduce max_new_tokens to fit, with a warning in the response
+    Truncate,
+    /// Fail validation
+    Reject,
+}
+
+impl std::str::FromStr for TokenLimitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Self::Truncate),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("invalid token limit policy '{s}', must be truncate or reject")),
+        }
+    }
+}
+
+/// Generation parameters which the shards must implement. Requests using those which
+/// not all of the shards support are rejected rather than having them silently ignored
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ShardSupport {
+    pub(crate) repetition_penalty_range: bool,
+    pub(crate) no_repeat_ngram_size: bool,
+    pub(crate) token_healing: bool,
+    pub(crate) watermark: bool,
+    pub(crate) bad_words: bool,
+    pub(crate) input_ids: bool,
+    pub(crate) json_schema: bool,
+}
+
+impl From<&ShardCapabilities> for ShardSupport {
+    fn from(capabilities: &ShardCapabilities) -> Self {
+        Self {
+            repetition_penalty_range: capabilities.repetition_penalty_range,
+            no_repeat_ngram_size: capabilities.no_repeat_ngram_size,
+            token_healing: capabilities.token_healing,
+            watermark: capabilities.watermark,
+            bad_words: capabilities.bad_words,
+            input_ids: capabilities.input_ids,
+            json_schema: capabilities.json_schema,
+        }
+    }
+}
+
+/// Input of a request to validate
+#[derive(Debug)]
+pub(crate) enum Input {
+    /// Text, with an optional fill-in-the-middle suffix
+    Text(String, Option<String>),
+    /// Already tokenized input, along with its decoded text
+    TokenIds(Vec<u32>, String),
+}
 
 /// Validation
 #[derive(Debug, Clone)]
@@ -24,12 +104,20 @@ pub struct Validation {
 }
 
 impl Validation {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         workers: usize,
         tokenizer: Tokenizer,
         client: ShardedClient,
         max_sequence_length: usize,
         max_new_tokens: usize,
+        top_n_tokens: TopNTokens,
+        token_limit_policy: TokenLimitPolicy,
+        fim_sentinels: Option<FimSentinels>,
+        chat_template: Option<Arc<ChatTemplate>>,
+        kv_cache: Option<KvCacheModel>,
+        parameter_policy: Option<ParameterPolicy>,
+        shard_support: ShardSupport,
     ) -> Self {
         // Create channel
         let (
@@ -43,6 +131,13 @@ impl Validation {
             client,
             max_sequence_length,
             max_new_tokens,
+            top_n_tokens,
+            token_limit_policy,
+            fim_sentinels,
+            chat_template,
+            kv_cache,
+            parameter_policy,
+            shard_support,
             validation_receiver,
         ));
 
@@ -56,7 +151,7 @@ impl Validation {
         &self,
         prefix_id: Option<String>,
         parameters: GenerateParameters,
-        inputs: Vec<String>,
+        inputs: Vec<Input>,
     ) -> Result<Vec<(usize, GenerateRequest)>, ValidationError> {
         // Create response channel
         let (sender, receiver) = oneshot::channel();
@@ -71,12 +166,20 @@ impl Validation {
 
 /// Validation task
 /// Load balance the validation requests between multiple validation workers
+#[allow(clippy::too_many_arguments)]
 async fn validation_task(
     workers: usize,
     tokenizer: Tokenizer,
     client: ShardedClient,
     max_sequence_length: usize,
     max_new_tokens: usize,
+    top_n_tokens: TopNTokens,
+    token_limit_policy: TokenLimitPolicy,
+    fim_sentinels: Option<FimSentinels>,
+    chat_template: Option<Arc<ChatTemplate>>,
+    kv_cache: Option<KvCacheModel>,
+    parameter_policy: Option<ParameterPolicy>,
+    shard_support: ShardSupport,
     mut receiver: mpsc::UnboundedReceiver<ValidationRequest>,
 ) {
     let mut workers_senders = Vec::with_capacity(workers);
@@ -89,13 +192,16 @@ async fn validation_task(
 
     // Create workers
     for _ in 0..workers {
-        let tokenizer_clone: Tokenizer = tokenizer.clone().into();
+        let tokenizer_clone: Tokenizer = tokenizer.clone();
         // Create channel to communicate with worker
         let (worker_sender, worker_receiver) = mpsc::channel(workers);
         workers_senders.push(worker_sender);
 
         let client = client.clone();
         let prefix_cache = prefix_cache.clone();
+        let fim_sentinels = fim_sentinels.clone();
+        let chat_template = chat_template.clone();
+        let parameter_policy = parameter_policy.clone();
         // Spawn worker
         tokio::task::spawn_blocking(move || validation_worker(
             tokenizer_clone,
@@ -103,6 +209,13 @@ async fn validation_task(
             client,
             max_sequence_length,
             max_new_tokens,
+            top_n_tokens,
+            token_limit_policy,
+            fim_sentinels,
+            chat_template,
+            kv_cache,
+            parameter_policy,
+            shard_support,
             worker_receiver,
         ));
     }
@@ -121,12 +234,20 @@ async fn validation_task(
 
 /// Check the parameters inside the payload and get the number of tokens inside the input using
 /// the tokenizer
+#[allow(clippy::too_many_arguments)]
 fn validation_worker(
     tokenizer: Tokenizer,
     mut prefix_cache: Cache<String, usize, RandomState>,
     mut client: ShardedClient,
     max_sequence_length: usize,
     max_max_new_tokens: usize,
+    top_n_tokens: TopNTokens,
+    token_limit_policy: TokenLimitPolicy,
+    fim_sentinels: Option<FimSentinels>,
+    chat_template: Option<Arc<ChatTemplate>>,
+    kv_cache: Option<KvCacheModel>,
+    parameter_policy: Option<ParameterPolicy>,
+    shard_support: ShardSupport,
     mut receiver: mpsc::Receiver<ValidationRequest>,
 ) {
     // Seed rng
@@ -145,14 +266,51 @@ fn validation_worker(
             &mut client,
             max_sequence_length,
             max_max_new_tokens,
+            top_n_tokens,
+            token_limit_policy,
+            fim_sentinels.as_ref(),
+            chat_template.as_deref(),
+            kv_cache.as_ref(),
+            parameter_policy.as_ref(),
+            shard_support,
             &mut rng,
         );
         response_tx.send(result).unwrap_or_default()
     }
 }
 
+/// Remove text from the end or middle of an input so that it encodes to at most
+/// max_tokens tokens, including any special tokens added by the tokenizer
+fn truncate_input(input: &str, encoding: &Encoding, max_tokens: usize, side: TruncationSide) -> String {
+    // Start offsets of the tokens of the input text itself
+    let starts = encoding.get_offsets().iter().zip(encoding.get_special_tokens_mask())
+        .filter(|(_, &special)| special == 0).map(|((start, _), _)| *start).collect::<Vec<_>>();
+    let keep = max_tokens.saturating_sub(encoding.len() - starts.len());
+    if keep >= starts.len() {
+        return input.to_string()
+    }
+    let cut = |i: usize| floor_char_boundary(input, starts[i]);
+    match side {
+        TruncationSide::Left => unreachable!("left truncation is done by the shards"),
+        TruncationSide::Right => input[..cut(keep)].to_string(),
+        TruncationSide::Middle => {
+            let head = keep / 2;
+            let tail = keep - head;
+            let tail_start = if tail == 0 { input.len() } else { cut(starts.len() - tail) };
+            [&input[..cut(head)], &input[tail_start..]].concat()
+        },
+    }
+}
+
+fn floor_char_boundary(input: &str, mut index: usize) -> usize {
+    while !input.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
 fn prompt_prefix_lookup(
-    client: &mut ShardedClient, prefix_id: &String,
+    client: &mut ShardedClient, prefix_id: &str,
 ) -> Result<usize, ClientError> {
     let start_time = Instant::now();
     let result = client.prefix_lookup(prefix_id);
@@ -164,93 +322,234 @@ fn prompt_prefix_lookup(
     result
 }
 
+/// Check the generation parameters, independently of the inputs. All invalid
+/// parameters are reported together rather than just the first
+/// All of the errors in the request's parameters which can be checked without the tokenizer
+fn validate_parameters(
+    params: &GenerateParameters, max_max_new_tokens: usize, max_top_n_tokens: u32, support: ShardSupport,
+) -> Vec<ValidationError> {
+    let mut errors = vec![];
+    let mut check = |invalid: bool, err: ValidationError| if invalid { errors.push(err) };
+
+    check(params.temperature != 0.0 && params.temperature < 0.05, ValidationError::Temperature);
+    check(
+        params.temperature_schedule.len() > MAX_TEMPERATURE_BREAKPOINTS
+            || params.temperature_schedule.iter().any(|&(_, t)| t < 0.05)
+            || params.temperature_schedule.windows(2).any(|w| w[0].0 >= w[1].0),
+        ValidationError::TemperatureSchedule(MAX_TEMPERATURE_BREAKPOINTS),
+    );
+    check(params.top_p <= 0.0 || params.top_p > 1.0, ValidationError::TopP);
+    check(params.typical_p < 0.0 || params.typical_p >= 1.0, ValidationError::TypicalP);
+    check(params.top_k < 0, ValidationError::TopK);
+    // Greedy decoding ignores these, so they were likely meant for sampling
+    check(
+        params.temperature == 0.0 && (params.top_k != 0 || params.top_p != 1.0 || params.typical_p != 0.0
+            || !params.temperature_schedule.is_empty()),
+        ValidationError::SampleParametersGreedy,
+    );
+    check(params.max_new_tokens as usize > max_max_new_tokens, ValidationError::MaxNewTokens(max_max_new_tokens));
+    check(params.min_new_tokens > params.max_new_tokens, ValidationError::MinNewTokens);
+    check(params.repetition_penalty <= 0.0, ValidationError::RepetitionPenalty);
+    check(
+        params.repetition_penalty_range != 0 && params.repetition_penalty == 1.0,
+        ValidationError::RepetitionPenaltyRange,
+    );
+    check(
+        params.no_repeat_ngram_size > MAX_NO_REPEAT_NGRAM_SIZE,
+        ValidationError::NoRepeatNgramSize(MAX_NO_REPEAT_NGRAM_SIZE),
+    );
+    check(
+        params.repetition_penalty_range != 0 && !support.repetition_penalty_range,
+        ValidationError::Unsupported("repetition_penalty_range"),
+    );
+    check(
+        params.no_repeat_ngram_size != 0 && !support.no_repeat_ngram_size,
+        ValidationError::Unsupported("no_repeat_ngram_size"),
+    );
+    check(
+        matches!(params.length_penalty, Some((_, decay)) if !(1.0..=10.0).contains(&decay)),
+        ValidationError::LengthPenalty,
+    );
+    if let Some((start_index, _)) = params.length_penalty {
+        check(
+            start_index >= params.max_new_tokens,
+            ValidationError::LengthPenaltyStart(start_index, params.max_new_tokens),
+        );
+    }
+    check(
+        matches!(params.watermark, Some((gamma, delta)) if gamma <= 0.0 || gamma >= 1.0 || delta <= 0.0),
+        ValidationError::Watermark,
+    );
+    check(params.watermark.is_some() && !support.watermark, ValidationError::Unsupported("watermark"));
+    check(
+        params.stop_seqs.len() > MAX_STOP_SEQS || params.stop_token_ids.len() > MAX_STOP_SEQS
+            || params.stop_token_ids.iter().any(|ids| ids.is_empty() || ids.len() > MAX_STOP_SEQ_TOKENS),
+        ValidationError::StopSequences,
+    );
+    check(
+        params.stop_regex.len() > MAX_STOP_SEQS
+            || params.stop_regex.iter().any(|r| r.is_empty() || r.len() > MAX_STOP_REGEX_LENGTH),
+        ValidationError::StopRegexCount(MAX_STOP_SEQS, MAX_STOP_REGEX_LENGTH),
+    );
+    check(
+        params.bad_words.len() > MAX_BAD_WORDS || params.bad_words.iter().any(|w| w.trim().is_empty()),
+        ValidationError::BadWords(MAX_BAD_WORDS, MAX_BAD_WORD_TOKENS),
+    );
+    check(
+        params.banned_token_ids.len() > MAX_BANNED_TOKEN_IDS,
+        ValidationError::BannedTokenIds(MAX_BANNED_TOKEN_IDS),
+    );
+    check(
+        (!params.bad_words.is_empty() || !params.banned_token_ids.is_empty()) && !support.bad_words,
+        ValidationError::Unsupported("bad_words and banned_token_ids"),
+    );
+    check(
+        [params.min_token_logprob, params.min_mean_logprob].iter().flatten().any(|&lp| lp >= 0.0),
+        ValidationError::LogprobThreshold,
+    );
+    if !params.tools.is_empty() {
+        if let Err(err) = validate_tools(&params.tools) {
+            check(true, err);
+        }
+        check(params.include_input_text, ValidationError::ToolInputText);
+        check(!support.json_schema, ValidationError::Unsupported("tools"));
+    }
+    check(
+        params.token_healing && (!params.tools.is_empty() || (params.truncate_input_tokens > 0
+            && params.truncation_side != TruncationSide::Left)),
+        ValidationError::TokenHealing,
+    );
+    check(params.token_healing && !support.token_healing, ValidationError::Unsupported("token_healing"));
+    check(
+        (params.include_logprobs || params.include_ranks || params.include_top_n.unwrap_or_default() != 0)
+            && !(params.include_input_tokens || params.include_gen_tokens),
+        ValidationError::TokenDetail,
+    );
+    check(params.include_top_n.unwrap_or_default() > max_top_n_tokens, ValidationError::TopNTokens(max_top_n_tokens));
+    check(params.include_input_offsets && !params.include_input_tokens, ValidationError::InputOffsets);
+    check(params.echo && !params.tools.is_empty(), ValidationError::Echo);
+
+    errors
+}
+
+/// Combine the errors found in a request, if any
+fn collected(mut errors: Vec<ValidationError>) -> Result<(), ValidationError> {
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.pop().unwrap()),
+        _ => Err(ValidationError::Multiple(errors)),
+    }
+}
+
+/// Check the stop sequences and bad words, which need the tokenizer, and compile the
+/// stop regexes and bad words' token ids, returning any errors
+fn prepare_stop_criteria(params: &mut GenerateParameters, tokenizer: &Tokenizer) -> Vec<ValidationError> {
+    let stop_seq_error = params.stop_seqs.iter()
+        .find_map(|s| if s.is_empty() {
+            Some(ValidationError::StopSequences) // Stop sequence can't be empty string
+        } else {
+            match tokenizer.encode(&s[..], false) {
+                Ok(enc) if enc.len() <= MAX_STOP_SEQ_TOKENS => None,
+                Ok(_) => Some(ValidationError::StopSequences),
+                Err(err) => Some(ValidationError::Tokenizer(err.to_string())),
+            }
+        });
+    let mut errors: Vec<_> = stop_seq_error.into_iter().collect();
+
+    for pattern in &params.stop_regex {
+        match RegexBuilder::new(pattern).size_limit(STOP_REGEX_SIZE_LIMIT).build() {
+            // A pattern matching empty text would stop generation immediately
+            Ok(regex) if regex.is_match("") => errors.push(ValidationError::StopRegex(
+                pattern.clone(), "matches empty text".to_string(),
+            )),
+            Ok(regex) => params.compiled_stop_regex.push(regex),
+            Err(err) => errors.push(ValidationError::StopRegex(pattern.clone(), err.to_string())),
+        }
+    }
+
+    match bad_words_ids(params, tokenizer) {
+        Ok(ids) => params.bad_words_ids = ids,
+        Err(err) => errors.push(err),
+    }
+
+    errors
+}
+
+#[allow(clippy::too_many_arguments)]
 fn validate(
     prefix_id: Option<String>,
-    params: GenerateParameters,
-    inputs: Vec<String>,
+    mut params: GenerateParameters,
+    inputs: Vec<Input>,
     tokenizer: &Tokenizer,
     prefix_cache: &mut Cache<String, usize, RandomState>,
     client: &mut ShardedClient,
     max_sequence_length: usize,
     max_max_new_tokens: usize,
+    top_n_tokens: TopNTokens,
+    token_limit_policy: TokenLimitPolicy,
+    fim_sentinels: Option<&FimSentinels>,
+    chat_template: Option<&ChatTemplate>,
+    kv_cache: Option<&KvCacheModel>,
+    parameter_policy: Option<&ParameterPolicy>,
+    shard_support: ShardSupport,
     rng: &mut ThreadRng,
 ) -> Result<Vec<(usize, GenerateRequest)>, ValidationError> {
-    let min_new_tokens = params.min_new_tokens as usize;
-    let max_new_tokens = params.max_new_tokens as usize;
-
-    if params.temperature != 0.0 && params.temperature < 0.05 {
-        return Err(ValidationError::Temperature);
-    }
-    if params.top_p <= 0.0 || params.top_p > 1.0 {
-        return Err(ValidationError::TopP);
+    // Scoring requests don't generate, so the policy doesn't apply to them
+    if let Some(policy) = parameter_policy.filter(|_| !params.echo) {
+        params.policy_warnings = policy.apply(&mut params);
     }
-    if params.typical_p >= 1.0 {
-        return Err(ValidationError::TypicalP);
+    if params.echo {
+        // The shards generate a token when processing the input, which is discarded
+        params.include_input_tokens = true;
+        params.max_new_tokens = 1;
+        params.min_new_tokens = 0;
     }
-    if params.top_k < 0 {
-        return Err(ValidationError::TopK);
-    }
-    if max_new_tokens > max_max_new_tokens {
-        return Err(ValidationError::MaxNewTokens(max_max_new_tokens));
-    }
-    if min_new_tokens > max_new_tokens {
-        return Err(ValidationError::MinNewTokens);
-    }
-    if params.repetition_penalty <= 0.0 {
-        return Err(ValidationError::RepetitionPenalty);
-    }
-    if let Some((_, decay_factor)) = params.length_penalty {
-        if decay_factor < 1.0 || decay_factor > 10.0 {
-            return Err(ValidationError::LengthPenalty);
-        }
-    }
-    if params.stop_seqs.len() > MAX_STOP_SEQS {
-        return Err(ValidationError::StopSequences);
-    }
-    if (params.include_logprobs || params.include_ranks || params.include_top_n != 0) &&
-        !(params.include_input_tokens || params.include_gen_tokens) {
-        return Err(ValidationError::TokenDetail);
+    if params.include_top_n.is_none() && (params.include_input_tokens || params.include_gen_tokens) {
+        params.include_top_n = Some(top_n_tokens.default);
     }
+    let min_new_tokens = params.min_new_tokens as usize;
+    let max_new_tokens = params.max_new_tokens as usize;
 
-    params.stop_seqs.iter()
-        .map(|s| if s.is_empty() {
-            Err(ValidationError::StopSequences) // Stop sequence can't be empty string
-        } else {
-            match tokenizer.encode(&s[..], false) {
-                Ok(enc) if enc.len() <= MAX_STOP_SEQ_TOKENS => Ok(()),
-                Ok(_) => Err(ValidationError::StopSequences),
-                Err(err) => Err(ValidationError::Tokenizer(err.to_string())),
-            }
-        }).find(|r| r.is_err()).unwrap_or(Ok(()))?;
+    // Errors are collected so that all of a request's problems are reported at once
+    let mut errors = validate_parameters(&params, max_max_new_tokens, top_n_tokens.max, shard_support);
+
+    errors.extend(prepare_stop_criteria(&mut params, tokenizer));
 
-    let prefix_length = if let Some(prefix_id) = &prefix_id {
-        prefix_cache.try_get_with_by_ref(
+    let prefix_length = match &prefix_id {
+        Some(prefix_id) => prefix_cache.try_get_with_by_ref(
             prefix_id, || prompt_prefix_lookup(client, prefix_id),
-        ).map_err(|e| ValidationError::PromptPrefix(
-            prefix_id.clone(),
-            e.to_string(),
-        ))?
-    } else {
-        0
+        ).unwrap_or_else(|e| {
+            errors.push(ValidationError::PromptPrefix(prefix_id.clone(), e.to_string()));
+            0
+        }),
+        None => 0,
     };
 
-    // Get the number of tokens in the inputs
-    match inputs.iter().map(
-        |input| tokenizer.encode(input.clone(), true).map(|enc| {
-            let input_length = enc.len();
-            metrics::histogram!("tgi_request_raw_input_length", input_length as f64);
-            input_length
-        })
-    ).collect::<Result<Vec<usize>, tokenizers::Error>>() {
-        Ok(input_lengths) => {
-            input_lengths.into_iter().zip(inputs).map(|(mut input_length, input)| {
-                let mut parameters = params.clone();
-                if parameters.truncate_input_tokens > 0 && parameters.truncate_input_tokens < input_length {
-                    input_length = params.truncate_input_tokens;
-                } else {
-                    // Indicates no truncation is necessary
-                    parameters.truncate_input_tokens = 0;
-                }
+    collected(errors)?;
+
+    // Format any fill-in-the-middle inputs or render provided tools, then tokenize
+    // each input applying any token healing and truncation
+    match inputs.into_iter().map(|input| match input {
+        Input::Text(input, None) if !params.tools.is_empty() => match chat_template {
+            None => Err(ValidationError::ToolTemplate("the model doesn't have one".to_string())),
+            Some(template) => template.render(&input, &params.tools)
+                .and_then(|input| prepare_text(input, &params, tokenizer)),
+        },
+        Input::Text(input, None) => prepare_text(input, &params, tokenizer),
+        Input::Text(input, Some(suffix)) => match fim_sentinels {
+            _ if params.token_healing => Err(ValidationError::TokenHealing),
+            _ if !params.tools.is_empty() => Err(ValidationError::ToolInput),
+            None => Err(ValidationError::FimUnsupported),
+            Some(_) if params.truncate_input_tokens > 0 => Err(ValidationError::FimTruncation),
+            Some(fim) => prepare_text(fim.format(&input, &suffix), &params, tokenizer),
+        },
+        Input::TokenIds(..) if !params.tools.is_empty() => Err(ValidationError::ToolInput),
+        Input::TokenIds(..) if !shard_support.input_ids => Err(ValidationError::Unsupported("input_token_ids")),
+        Input::TokenIds(ids, text) => prepare_token_ids(ids, text, &params, tokenizer),
+    }).collect::<Result<Vec<PreparedInput>, ValidationError>>() {
+        Ok(prepared_inputs) => {
+            prepared_inputs.into_iter().map(|prepared| {
+                let PreparedInput { text: input, token_ids, length: input_length, healed_prefix, mut parameters } = prepared;
                 // Add prefix length to obtain effective token length
                 let effective_input_length = input_length + prefix_length;
                 if effective_input_length >= max_sequence_length {
@@ -268,12 +567,22 @@ fn validate(
                         min_new_tokens,
                         max_sequence_length,
                     ))
+                } else if effective_input_length + max_new_tokens > max_sequence_length
+                    && token_limit_policy == TokenLimitPolicy::Reject {
+                    Err(ValidationError::TotalTokens(
+                        input_length,
+                        prefix_length,
+                        max_new_tokens,
+                        max_sequence_length,
+                    ))
                 } else {
-                    // If sampling mode and seed is None, assign a random one
-                    if parameters.temperature != 0.0 && parameters.seed.is_none() {
+                    // If sampling mode or watermarking and seed is None, assign a random one
+                    if (parameters.temperature != 0.0 || parameters.watermark.is_some())
+                        && parameters.seed.is_none() {
                         // We generate a 32bit seed so the values aren't too many digits,
                         // since this will be returned in the API response
                         parameters.seed = Some(rng.gen::<u32>() as u64);
+                        parameters.normalized.push("seed".to_string());
                     }
 
                     if effective_input_length + max_new_tokens > max_sequence_length {
@@ -281,14 +590,43 @@ fn validate(
                         // appropriate stop reason is returned
                         parameters.max_new_tokens = (max_sequence_length - effective_input_length) as u32;
                         parameters.max_is_token_limit = true;
+                        if !parameters.normalized.iter().any(|name| name == "max_new_tokens") {
+                            parameters.normalized.push("max_new_tokens".to_string());
+                        }
                     }
 
+                    // Reject requests whose cache couldn't fit even in an otherwise empty batch
+                    if let Some(kv_cache) = kv_cache {
+                        let tokens = effective_input_length + parameters.max_new_tokens as usize;
+                        if !kv_cache.fits(tokens) {
+                            return Err(ValidationError::KvCacheMemory(
+                                kv_cache.footprint(tokens), kv_cache.capacity_bytes(),
+                            ))
+                        }
+                    }
+
+                    // Character offsets of the tokens of the final input text
+                    let input_offsets = if parameters.include_input_offsets {
+                        tokenizer.encode_char_offsets(&input[..], true)
+                            .map_err(|err| ValidationError::Tokenizer(err.to_string()))?
+                            .get_offsets().to_vec()
+                    } else {
+                        vec![]
+                    };
+
                     Ok((
                         input_length,
                         GenerateRequest {
                             prefix_id: prefix_id.clone(),
                             inputs: input,
+                            input_token_ids: token_ids,
                             parameters,
+                            tenant: None,
+                            priority: 0,
+                            healed_prefix,
+                            input_offsets,
+                            debug: false,
+                            journal: Default::default(),
                         }
                     ))
                 }
@@ -301,14 +639,164 @@ fn validate(
                 results
             })
         },
-        Err(err) => Err(ValidationError::Tokenizer(err.to_string())),
+        Err(err) => Err(err),
+    }
+}
+
+/// An input after tokenization, token healing and any truncation done by the router
+struct PreparedInput {
+    text: String,
+    /// Empty unless the input was pre-tokenized
+    token_ids: Vec<u32>,
+    /// Number of tokens passed to the model, after any truncation by the shards
+    length: usize,
+    healed_prefix: Option<String>,
+    parameters: GenerateParameters,
+}
+
+fn prepare_text(
+    mut input: String, params: &GenerateParameters, tokenizer: &Tokenizer,
+) -> Result<PreparedInput, ValidationError> {
+    let mut enc = tokenizer.encode(input.clone(), true)
+        .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+    metrics::histogram!("tgi_request_raw_input_length", enc.len() as f64);
+    let mut parameters = params.clone();
+    // Remove the last token, to be regenerated. The remaining text is encoded again
+    // since the tokens before it may not be the same without it
+    let mut healed_prefix = None;
+    if let Some((offset, prefix)) = params.token_healing.then(|| heal_prompt(&input, &enc)).flatten() {
+        input.truncate(offset);
+        enc = tokenizer.encode(input.clone(), true)
+            .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+        healed_prefix = Some(prefix);
+    }
+    let mut input_length = enc.len();
+    if parameters.truncate_input_tokens > 0 && parameters.truncate_input_tokens < input_length {
+        parameters.normalized.push("truncate_input_tokens".to_string());
+        if params.truncation_side != TruncationSide::Left {
+            input = truncate_input(&input, &enc, params.truncate_input_tokens, params.truncation_side);
+            input_length = tokenizer.encode(&input[..], true)
+                .map_err(|err| ValidationError::Tokenizer(err.to_string()))?.len();
+        }
+        // The shards remove any tokens still in excess from the left
+        if input_length > params.truncate_input_tokens {
+            input_length = params.truncate_input_tokens;
+        } else {
+            parameters.truncate_input_tokens = 0;
+        }
+    } else {
+        // Indicates no truncation is necessary
+        parameters.truncate_input_tokens = 0;
+    }
+    Ok(PreparedInput { text: input, token_ids: vec![], length: input_length, healed_prefix, parameters })
+}
+
+/// Pre-tokenized inputs are used as is, so are only checked against the vocabulary.
+/// text is their decoded text
+fn prepare_token_ids(
+    mut ids: Vec<u32>, mut text: String, params: &GenerateParameters, tokenizer: &Tokenizer,
+) -> Result<PreparedInput, ValidationError> {
+    // Both rely on the offsets of the tokens within the input text
+    if params.token_healing || params.include_input_offsets {
+        return Err(ValidationError::InputTokenIdsUnsupported)
+    }
+    let vocab_size = tokenizer.get_vocab_size(true) as u32;
+    if let Some(&id) = ids.iter().find(|&&id| id >= vocab_size) {
+        return Err(ValidationError::InputTokenId(id, vocab_size))
+    }
+    metrics::histogram!("tgi_request_raw_input_length", ids.len() as f64);
+    let mut parameters = params.clone();
+    let mut input_length = ids.len();
+    let keep = params.truncate_input_tokens;
+    if keep > 0 && keep < input_length {
+        parameters.normalized.push("truncate_input_tokens".to_string());
+        input_length = keep;
+        match params.truncation_side {
+            // The shards remove the tokens in excess from the left
+            TruncationSide::Left => {},
+            side => {
+                if side == TruncationSide::Right {
+                    ids.truncate(keep);
+                } else {
+                    let tail_start = ids.len() - (keep - keep / 2);
+                    ids.drain(keep / 2..tail_start);
+                }
+                text = tokenizer.decode(ids.clone(), false)
+                    .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+                parameters.truncate_input_tokens = 0;
+            },
+        }
+    } else {
+        // Indicates no truncation is necessary
+        parameters.truncate_input_tokens = 0;
+    }
+    Ok(PreparedInput { text, token_ids: ids, length: input_length, healed_prefix: None, parameters })
+}
+
+/// Token id sequences which must never be generated. Most tokenizers encode a word
+/// differently at the start of the text than following a space, so both are banned
+fn bad_words_ids(
+    params: &GenerateParameters, tokenizer: &Tokenizer,
+) -> Result<Vec<Vec<u32>>, ValidationError> {
+    let mut sequences: Vec<Vec<u32>> = vec![];
+    for word in &params.bad_words {
+        for text in [word.clone(), format!(" {word}")] {
+            let ids = tokenizer.encode(text, false)
+                .map_err(|err| ValidationError::Tokenizer(err.to_string()))?
+                .get_ids().to_vec();
+            if ids.len() > MAX_BAD_WORD_TOKENS {
+                return Err(ValidationError::BadWords(MAX_BAD_WORDS, MAX_BAD_WORD_TOKENS))
+            }
+            if !ids.is_empty() && !sequences.contains(&ids) {
+                sequences.push(ids);
+            }
+        }
+    }
+    let vocab_size = tokenizer.get_vocab_size(true) as u32;
+    for &id in &params.banned_token_ids {
+        if id >= vocab_size {
+            return Err(ValidationError::BannedTokenId(id, vocab_size))
+        }
+        if !sequences.iter().any(|ids| ids[..] == [id]) {
+            sequences.push(vec![id]);
+        }
+    }
+    Ok(sequences)
+}
+
+/// Model-specific sentinel tokens used to format fill-in-the-middle prompts
+#[derive(Clone, Debug)]
+pub(crate) struct FimSentinels {
+    prefix: String,
+    suffix: String,
+    middle: String,
+}
+
+impl FimSentinels {
+    /// Prefix-suffix-middle ordering, the model generates the middle
+    fn format(&self, input: &str, suffix: &str) -> String {
+        format!("{}{input}{}{suffix}{}", self.prefix, self.suffix, self.middle)
+    }
+}
+
+impl std::str::FromStr for FimSentinels {
+    type Err = String;
+
+    /// Parse from comma-separated prefix,suffix,middle sentinel tokens
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(',').collect::<Vec<&str>>()[..] {
+            [prefix, suffix, middle] if ![prefix, suffix, middle].contains(&"") => Ok(Self {
+                prefix: prefix.to_string(), suffix: suffix.to_string(), middle: middle.to_string(),
+            }),
+            _ => Err(format!("invalid fim sentinel tokens '{s}', must be <prefix>,<suffix>,<middle>")),
+        }
     }
 }
 
 type ValidationRequest = (
     Option<String>,
     GenerateParameters,
-    Vec<String>,
+    Vec<Input>,
     oneshot::Sender<Result<Vec<(usize, GenerateRequest)>, ValidationError>>,
 );
 
@@ -316,16 +804,34 @@ type ValidationRequest = (
 pub enum ValidationError {
     #[error("temperature must be >= 0.05")]
     Temperature,
+    #[error("temperature_schedule must have at most {0} breakpoints, in increasing order of \
+        token_index, with temperatures >= 0.05")]
+    TemperatureSchedule(usize),
     #[error("top_p must be > 0.0 and <= 1.0")]
     TopP,
     #[error("top_k must be strictly positive")]
     TopK,
-    #[error("typical_p must be < 1.0")]
+    #[error("typical_p must be >= 0.0 and < 1.0")]
     TypicalP,
     #[error("repetition_penalty must be > 0.0")]
     RepetitionPenalty,
-    #[error("length_penalty must be >= 1.0 and <= 10.0")]
+    #[error("repetition_penalty_range requires repetition_penalty to be set")]
+    RepetitionPenaltyRange,
+    #[error("no_repeat_ngram_size must be <= {0}")]
+    NoRepeatNgramSize(u32),
+    #[error("length_penalty decay_factor must be >= 1.0 and <= 10.0")]
     LengthPenalty,
+    #[error("length_penalty start_index ({0}) must be < max_new_tokens ({1}), \
+        otherwise the penalty is never applied")]
+    LengthPenaltyStart(u32, u32),
+    #[error("watermark gamma must be > 0.0 and < 1.0, and delta must be > 0.0")]
+    Watermark,
+    #[error("can specify at most {0} non-blank bad words, each not more than {1} tokens")]
+    BadWords(usize, usize),
+    #[error("can specify at most {0} banned token ids")]
+    BannedTokenIds(usize),
+    #[error("banned token id {0} must be < the vocabulary size ({1})")]
+    BannedTokenId(u32, u32),
     #[error("max_new_tokens must be <= {0}")]
     MaxNewTokens(usize),
     #[error("min_new_tokens must be <= max_new_tokens")]
@@ -334,16 +840,78 @@ pub enum ValidationError {
     InputLength(usize, usize, usize, usize),
     #[error("input tokens ({0}) plus prefix length ({1}) must be < {2}")]
     InputLength2(usize, usize, usize),
+    #[error("input tokens ({0}) plus prefix length ({1}) plus max_new_tokens ({2}) must be <= {3}")]
+    TotalTokens(usize, usize, usize, usize),
     #[error("tokenizer error {0}")]
     Tokenizer(String),
     #[error("can specify at most 6 non-empty stop sequences, each not more than 40 tokens")]
     StopSequences,
+    #[error("can specify at most {0} non-empty stop regex patterns, each not more than {1} bytes")]
+    StopRegexCount(usize, usize),
+    #[error("invalid stop regex '{0}': {1}")]
+    StopRegex(String, String),
     #[error("must request input and/or generated tokens to request extra token detail")]
     TokenDetail,
+    #[error("top_n_tokens must be <= {0}")]
+    TopNTokens(u32),
+    #[error("must request input tokens to request input token offsets")]
+    InputOffsets,
+    #[error("echo isn't supported with tools")]
+    Echo,
+    #[error("echo isn't supported for streaming requests")]
+    EchoStreaming,
     #[error("can't retrieve prompt prefix with id '{0}': {1}")]
     PromptPrefix(String, String),
     #[error("sampling parameters aren't applicable in greedy decoding mode")]
-    SampleParametersGreedy
+    SampleParametersGreedy,
+    #[error("logprob thresholds must be < 0.0")]
+    LogprobThreshold,
+    #[error("fill-in-the-middle suffix provided but not configured for this model")]
+    FimUnsupported,
+    #[error("input truncation isn't supported with fill-in-the-middle")]
+    FimTruncation,
+    #[error("tool names must be non-empty and unique, got '{0}'")]
+    ToolName(String),
+    #[error("parameters of tool '{0}' must be a JSON schema object")]
+    ToolSchema(String),
+    #[error("tools aren't supported for streaming requests")]
+    ToolStreaming,
+    #[error("input text can't be included in the response when tools are provided")]
+    ToolInputText,
+    #[error("couldn't render tools with the model's chat template: {0}")]
+    ToolTemplate(String),
+    #[error("tools aren't supported with a fill-in-the-middle suffix or input_token_ids")]
+    ToolInput,
+    #[error("token_healing isn't supported with tools, a fill-in-the-middle suffix, \
+        or right or middle input truncation")]
+    TokenHealing,
+    #[error("estimated KV cache memory of input plus max_new_tokens ({0} bytes) exceeds the \
+        available capacity ({1} bytes)")]
+    KvCacheMemory(u64, u64),
+    #[error("input tokens ({0}) must be <= {1}")]
+    EmbedInputLength(usize, usize),
+    #[error("input token id {0} must be < the vocabulary size ({1})")]
+    InputTokenId(u32, u32),
+    #[error("token_healing and input token offsets aren't supported with input_token_ids")]
+    InputTokenIdsUnsupported,
+    #[error("{0} isn't supported by this model's shards")]
+    Unsupported(&'static str),
+    #[error("{}", join_errors(.0))]
+    Multiple(Vec<ValidationError>),
+}
+
+fn join_errors(errors: &[ValidationError]) -> String {
+    errors.iter().map(ValidationError::to_string).collect::<Vec<_>>().join("; ")
+}
+
+impl ValidationError {
+    /// Messages of the individual errors
+    pub(crate) fn messages(&self) -> Vec<String> {
+        match self {
+            ValidationError::Multiple(errors) => errors.iter().map(ValidationError::to_string).collect(),
+            err => vec![err.to_string()],
+        }
+    }
 }
 
 impl From<ValidationError> for (StatusCode, Json<ErrorResponse>) {
@@ -352,7 +920,91 @@ impl From<ValidationError> for (StatusCode, Json<ErrorResponse>) {
             StatusCode::UNPROCESSABLE_ENTITY,
             Json(ErrorResponse {
                 error: err.to_string(),
+                error_code: "validation_error",
+                retry: None,
+                errors: err.messages(),
             }),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::default_parameters;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::tokenizer::Tokenizer;
+    use text_generation_client::ShardCapabilities;
+    use super::{collected, prepare_stop_criteria, ShardSupport, validate_parameters, ValidationError};
+
+    fn word_tokenizer() -> Tokenizer {
+        let vocab: HashMap<String, u32> = ["[UNK]", "the", "quick", "fox"].iter().enumerate()
+            .map(|(id, word)| (word.to_string(), id as u32)).collect();
+        let model = WordLevel::builder().vocab(vocab).unk_token("[UNK]".to_string()).build().unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Whitespace {});
+        tokenizer
+    }
+
+    #[test]
+    fn collects_stop_criteria_errors() {
+        let mut params = default_parameters();
+        params.stop_seqs = vec!["".to_string()];
+        params.stop_regex = vec!["fox".to_string(), "(".to_string(), "a*".to_string()];
+        params.banned_token_ids = vec![1, 100];
+
+        let errors = prepare_stop_criteria(&mut params, &word_tokenizer());
+        let messages: Vec<_> = errors.iter().map(ValidationError::to_string).collect();
+        assert_eq!(messages.len(), 4, "{messages:?}");
+        assert!(matches!(errors[0], ValidationError::StopSequences));
+        assert!(matches!(&errors[1], ValidationError::StopRegex(p, _) if p == "("));
+        assert!(matches!(&errors[2], ValidationError::StopRegex(p, _) if p == "a*"));
+        assert!(matches!(errors[3], ValidationError::BannedTokenId(100, 4)));
+        // Valid patterns are still compiled
+        assert_eq!(params.compiled_stop_regex.len(), 1);
+    }
+
+    #[test]
+    fn checks_parameters_against_shard_capabilities() {
+        // Those reported by the Python shards
+        let capabilities = ShardCapabilities {
+            repetition_penalty_range: true,
+            no_repeat_ngram_size: true,
+            token_healing: true,
+            watermark: true,
+            bad_words: true,
+            ..ShardCapabilities::legacy()
+        };
+        let mut params = default_parameters();
+        params.repetition_penalty = 1.2;
+        params.repetition_penalty_range = 64;
+        params.no_repeat_ngram_size = 3;
+        params.watermark = Some((0.25, 2.0));
+        params.banned_token_ids = vec![5];
+        params.token_healing = true;
+
+        let supported = ShardSupport::from(&capabilities);
+        assert!(collected(validate_parameters(&params, 100, 10, supported)).is_ok());
+
+        // Alongside a shard which predates the parameters, all are rejected
+        let mixed = ShardCapabilities::combine([Some(capabilities), None]).unwrap();
+        let err = collected(validate_parameters(&params, 100, 10, ShardSupport::from(&mixed))).unwrap_err();
+        assert_eq!(err.messages(), vec![
+            "repetition_penalty_range isn't supported by this model's shards",
+            "no_repeat_ngram_size isn't supported by this model's shards",
+            "watermark isn't supported by this model's shards",
+            "bad_words and banned_token_ids isn't supported by this model's shards",
+            "token_healing isn't supported by this model's shards",
+        ]);
+
+        // Parameters with invalid values are rejected even when supported
+        params.no_repeat_ngram_size = 100;
+        params.watermark = Some((1.5, 2.0));
+        let err = collected(validate_parameters(&params, 100, 10, supported)).unwrap_err();
+        assert_eq!(err.messages(), vec![
+            "no_repeat_ngram_size must be <= 10",
+            "watermark gamma must be > 0.0 and < 1.0, and delta must be > 0.0",
+        ]);
+    }
+}
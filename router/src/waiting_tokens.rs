@@ -0,0 +1,85 @@
+/// Choice of how many generation steps the running batch waits before being extended with
+/// waiting requests, when fewer are waiting than would be needed to extend it sooner
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Weight of the latest measurement in the moving averages of prefill and step durations
+const DURATION_WEIGHT: f64 = 0.1;
+/// Share of the running batch's time that adding requests to it should take up
+const TARGET_PREFILL_SHARE: f64 = 0.2;
+/// Max multiple of the configured max_waiting_tokens that the adaptive choice can reach
+const MAX_WAITING_TOKENS_SCALE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum WaitingTokensPolicy {
+    /// Always use the configured max_waiting_tokens
+    Fixed,
+    /// Wait long enough that prefills take up a bounded share of the running batch's
+    /// time, based on recent prefill and step durations, but less while more requests
+    /// are queued. The configured max_waiting_tokens is used until there are enough
+    /// measurements
+    Adaptive,
+}
+
+impl FromStr for WaitingTokensPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Self::Fixed),
+            "adaptive" => Ok(Self::Adaptive),
+            _ => Err(format!("invalid waiting tokens policy '{s}', must be fixed or adaptive")),
+        }
+    }
+}
+
+/// Chooses max_waiting_tokens for a replica's batching loop
+#[derive(Debug)]
+pub(crate) struct WaitingTokensController {
+    policy: WaitingTokensPolicy,
+    /// Moving average durations of prefills and of generation steps
+    prefill_time: Option<Duration>,
+    step_time: Option<Duration>,
+}
+
+impl WaitingTokensController {
+    pub(crate) fn new(policy: WaitingTokensPolicy) -> Self {
+        Self { policy, prefill_time: None, step_time: None }
+    }
+
+    pub(crate) fn record_prefill(&mut self, duration: Duration) {
+        self.prefill_time = Some(moving_average(self.prefill_time, duration));
+    }
+
+    pub(crate) fn record_step(&mut self, duration: Duration) {
+        self.step_time = Some(moving_average(self.step_time, duration));
+    }
+
+    /// Steps to wait, given the configured value, the number of waiting
+    /// requests and the size of the running batch
+    pub(crate) fn max_waiting_tokens(&self, configured: usize, queued: usize, batch_size: usize) -> usize {
+        let (WaitingTokensPolicy::Adaptive, Some(prefill), Some(step)) =
+            (self.policy, self.prefill_time, self.step_time) else {
+            return configured
+        };
+        if step.is_zero() {
+            return configured
+        }
+        // Steps the running batch could generate in the time taken by a prefill
+        let prefill_steps = prefill.as_secs_f64() / step.as_secs_f64();
+        let waiting = prefill_steps * (1.0 - TARGET_PREFILL_SHARE) / TARGET_PREFILL_SHARE;
+        // The longer the queue relative to the batch, the more its latency matters
+        let pressure = 1.0 + queued as f64 / batch_size.max(1) as f64;
+        let max_waiting_tokens = ((waiting / pressure).round() as usize)
+            .clamp(1, configured.max(1) * MAX_WAITING_TOKENS_SCALE);
+        metrics::gauge!("tgi_batch_max_waiting_tokens", max_waiting_tokens as f64);
+        max_waiting_tokens
+    }
+}
+
+fn moving_average(average: Option<Duration>, latest: Duration) -> Duration {
+    match average {
+        Some(avg) => avg.mul_f64(1.0 - DURATION_WEIGHT) + latest.mul_f64(DURATION_WEIGHT),
+        None => latest,
+    }
+}
@@ -0,0 +1,126 @@
+/// Startup warm-up and batch capacity probing
+use std::cmp::min;
+use tokenizers::Tokenizer;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use text_generation_client::{Batch, ClientError, NextTokenChooserParameters, Request, ShardedClient};
+use crate::batch_types::{BatchStats, BatchType};
+
+const WARMUP_WORD: &str = "warmup";
+
+/// Send synthetic prefill batches of increasing size and sequence length through the shards.
+/// This triggers any lazy kernel compilation/allocation prior to serving and verifies that
+/// the configured max batch weight can actually be accommodated.
+///
+/// Returns the max batch weight to use - the configured one if all probes succeeded,
+/// otherwise the weight of the largest successful probe.
+pub(crate) async fn warmup(
+    batch_type: &dyn BatchType,
+    client: &mut ShardedClient,
+    tokenizer: &Tokenizer,
+    max_sequence_length: usize,
+    max_batch_size: usize,
+    max_batch_weight: usize,
+) -> usize {
+    let start_time = Instant::now();
+    let text = warmup_text(tokenizer, max_sequence_length);
+
+    let mut seq_lengths = vec![max_sequence_length / 8, max_sequence_length / 2, max_sequence_length];
+    seq_lengths.retain(|l| *l > 0);
+    seq_lengths.dedup();
+
+    let mut largest_ok = 0;
+    for seq_len in seq_lengths {
+        let mut batch_size = 1;
+        loop {
+            let weight = probe_weight(batch_type, seq_len, batch_size);
+            if weight > max_batch_weight {
+                break
+            }
+            let probe_start = Instant::now();
+            let result = probe(client, &text, seq_len, batch_size).await;
+            // Make sure nothing is left behind in the shards' cache
+            client.clear_cache().await.expect("Unable to clear cache after warm-up probe");
+            match result {
+                Ok(()) => {
+                    info!("Warm-up probe with batch size {batch_size} and sequence length \
+                        {seq_len} (weight {weight}) succeeded in {:?}", probe_start.elapsed());
+                    largest_ok = largest_ok.max(weight);
+                },
+                Err(err) => {
+                    warn!("Warm-up probe with batch size {batch_size} and sequence length \
+                        {seq_len} (weight {weight}) failed: {err}");
+                    return reduced_weight(batch_type, largest_ok, max_sequence_length)
+                },
+            }
+            if batch_size >= max_batch_size {
+                break
+            }
+            batch_size = min(batch_size * 2, max_batch_size);
+        }
+    }
+
+    info!("Warm-up completed in {:?}", start_time.elapsed());
+    max_batch_weight
+}
+
+fn probe_weight(batch_type: &dyn BatchType, seq_len: usize, batch_size: usize) -> usize {
+    let stats = (0..batch_size).fold(
+        BatchStats::default(), |stats, _| stats.update(seq_len, 0)
+    );
+    batch_type.batch_weight(&stats, batch_size)
+}
+
+fn reduced_weight(batch_type: &dyn BatchType, largest_ok: usize, max_sequence_length: usize) -> usize {
+    let min_weight = probe_weight(batch_type, max_sequence_length, 1);
+    if largest_ok < min_weight {
+        panic!(
+            "Warm-up failed: unable to process a single request of max_sequence_length ({})",
+            max_sequence_length
+        )
+    }
+    warn!("Reducing max_batch_weight to ({largest_ok}) based on warm-up probes");
+    largest_ok
+}
+
+/// Input text that encodes to at least the given number of tokens,
+/// which is then truncated to the exact length by the shards
+fn warmup_text(tokenizer: &Tokenizer, token_count: usize) -> String {
+    let word_tokens = tokenizer.encode(WARMUP_WORD, false)
+        .expect("Tokenization error").len().max(1);
+    let words = token_count / word_tokens + 1;
+    vec![WARMUP_WORD; words].join(" ")
+}
+
+async fn probe(
+    client: &mut ShardedClient, text: &str, seq_len: usize, batch_size: usize,
+) -> Result<(), ClientError> {
+    let requests = (0..batch_size as u64).map(|i| Request {
+        // Use ids that won't collide with real requests
+        id: u64::MAX - i,
+        prefix_id: String::new(),
+        inputs: text.to_string(),
+        input_length: seq_len as u32,
+        truncate: true,
+        max_output_length: 1,
+        parameters: Some(NextTokenChooserParameters {
+            ..Default::default()
+        }),
+        stream_response: false,
+        details: None,
+        healing_prefix: String::new(),
+        resumed_id: None,
+        input_ids: vec![],
+    }).collect();
+    let batch = Batch {
+        id: u64::MAX,
+        requests,
+        total_tokens: (seq_len * batch_size) as u32,
+    };
+    match client.prefill(batch, None, None).await? {
+        Some((_, _, errors, _)) if !errors.is_empty() => Err(
+            ClientError::Generation(errors[0].message.clone())
+        ),
+        _ => Ok(()),
+    }
+}
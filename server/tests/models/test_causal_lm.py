@@ -80,6 +80,58 @@ def test_causal_lm_batch_type(default_causal_lm):
     assert default_causal_lm.batch_type == CausalLMBatch
 
 
+def test_causal_lm_embed(default_causal_lm):
+    texts = ["Test", "A longer test input", "Truncated test input"]
+    lengths = [len(default_causal_lm.tokenizer(text).input_ids) for text in texts]
+    lengths[2] = 2
+    assert default_causal_lm.supports_embeddings
+
+    embeddings = default_causal_lm.embed(texts, lengths, [False, False, True])
+    assert embeddings.shape == (3, default_causal_lm.model.config.hidden_size)
+
+    # Padding doesn't affect the embedding of shorter inputs
+    alone = default_causal_lm.embed(texts[:1], lengths[:1], [False])
+    assert torch.allclose(alone[0], embeddings[0], atol=1e-4)
+    truncated = default_causal_lm.embed([" test input"], [2], [False])
+    assert torch.allclose(truncated[0], embeddings[2], atol=1e-4)
+
+
+
+def test_causal_lm_batch_split(default_causal_lm):
+    def prefilled_batch():
+        tokenizer = default_causal_lm.tokenizer
+        requests = [
+            generate_pb2.Request(id=i, inputs=text, input_length=len(tokenizer(text).input_ids), max_output_length=5)
+            for i, text in enumerate(["Test", "A longer test input", "Another test"])
+        ]
+        batch, _ = CausalLMBatch.from_pb(
+            generate_pb2.Batch(id=0, requests=requests), tokenizer, default_causal_lm.device, None, None,
+        )
+        default_causal_lm.generate_token(batch, first=True)
+        return batch
+
+    expected, _, _ = default_causal_lm.generate_token(prefilled_batch())
+
+    remaining, split = CausalLMBatch.split(prefilled_batch(), [1])
+    assert [r.id for r in remaining.requests] == [0, 2]
+    assert [r.id for r in split.requests] == [1]
+
+    # Offloaded to host memory and restored, the split request generates the same next token
+    split = split.to(torch.device("cpu")).to(default_causal_lm.device)
+    remaining_tokens, _, _ = default_causal_lm.generate_token(remaining)
+    split_tokens, _, _ = default_causal_lm.generate_token(split)
+    assert [t.token_id for t in remaining_tokens] == [expected[0].token_id, expected[2].token_id]
+    assert [t.token_id for t in split_tokens] == [expected[1].token_id]
+
+    # Concatenated out of id order, requests are still pruned by id
+    batch = CausalLMBatch.concatenate([remaining, split])
+    batch = CausalLMBatch.prune(batch, [1, 0])
+    assert [r.id for r in batch.requests] == [2]
+
+    remaining, split = CausalLMBatch.split(batch, [2])
+    assert remaining is None and split is batch
+
+
 def test_causal_lm_generate_token(default_causal_lm, default_causal_lm_batch):
     sequence_length = len(default_causal_lm_batch.all_input_ids[0])
     generated_texts, next_batch = default_causal_lm.generate_token(
@@ -88,6 +88,46 @@ def test_seq2seq_lm_batch_type(default_seq2seq_lm):
     assert default_seq2seq_lm.batch_type == Seq2SeqLMBatch
 
 
+def test_seq2seq_lm_embed(default_seq2seq_lm):
+    texts = ["Test", "A longer test input"]
+    lengths = [len(default_seq2seq_lm.tokenizer(text).input_ids) for text in texts]
+    assert default_seq2seq_lm.supports_embeddings
+
+    embeddings = default_seq2seq_lm.embed(texts, lengths, [False, False])
+    assert embeddings.shape == (2, default_seq2seq_lm.model.config.d_model)
+
+    # Padding doesn't affect the embedding of shorter inputs
+    alone = default_seq2seq_lm.embed(texts[:1], lengths[:1], [False])
+    assert torch.allclose(alone[0], embeddings[0], atol=1e-4)
+
+
+
+def test_seq2seq_lm_batch_split(default_seq2seq_lm):
+    def prefilled_batch():
+        tokenizer = default_seq2seq_lm.tokenizer
+        requests = [
+            generate_pb2.Request(id=i, inputs=text, input_length=len(tokenizer(text).input_ids), max_output_length=5)
+            for i, text in enumerate(["Test", "A longer test input"])
+        ]
+        batch, _ = Seq2SeqLMBatch.from_pb(
+            generate_pb2.Batch(id=0, requests=requests), tokenizer, default_seq2seq_lm.device, None, None,
+        )
+        default_seq2seq_lm.generate_token(batch, first=True)
+        return batch
+
+    expected, _, _ = default_seq2seq_lm.generate_token(prefilled_batch())
+
+    remaining, split = Seq2SeqLMBatch.split(prefilled_batch(), [0])
+    assert [r.id for r in remaining.requests] == [1]
+    assert [r.id for r in split.requests] == [0]
+
+    # Offloaded to host memory and restored, the split request generates the same next token
+    split = split.to(torch.device("cpu")).to(default_seq2seq_lm.device)
+    remaining_tokens, _, _ = default_seq2seq_lm.generate_token(remaining)
+    split_tokens, _, _ = default_seq2seq_lm.generate_token(split)
+    assert [t.token_id for t in split_tokens + remaining_tokens] == [t.token_id for t in expected]
+
+
 def test_seq2seq_lm_generate_token(default_seq2seq_lm, default_seq2seq_lm_batch):
     sequence_length = len(default_seq2seq_lm_batch.input_ids[0])
     generated_texts, next_batch = default_seq2seq_lm.generate_token(
@@ -0,0 +1,34 @@
+import torch
+
+from text_generation_server.utils.logits_process import WatermarkLogitsProcessor
+from text_generation_server.utils.tokens import banned_bad_word_tokens, banned_ngram_tokens
+
+
+def test_banned_ngram_tokens():
+    assert banned_ngram_tokens([1, 2, 3, 1, 2], 3) == [3]
+    assert banned_ngram_tokens([1, 2, 3, 1], 2) == [2]
+    assert banned_ngram_tokens([1, 2, 3], 3) == []
+    assert banned_ngram_tokens([1], 3) == []
+    # Every generated token is banned when n is 1
+    assert banned_ngram_tokens([5, 6], 1) == [5, 6]
+
+
+def test_banned_bad_word_tokens():
+    bad_words_ids = [[3, 4], [2, 3, 5], [9], [1, 2], [7, 3, 6]]
+    assert banned_bad_word_tokens([1, 2, 3], bad_words_ids) == [4, 5]
+    assert banned_bad_word_tokens([], bad_words_ids) == []
+    assert banned_bad_word_tokens([3], [[1, 3, 4]]) == []
+
+
+def test_watermark_green_list():
+    processor = WatermarkLogitsProcessor(gamma=0.25, delta=2.0, seed=42, device=torch.device("cpu"))
+    input_ids = torch.tensor([[5, 7]])
+    scores = processor(input_ids, torch.zeros((1, 100)))
+
+    green_ids = (scores[0] == 2.0).nonzero().squeeze(-1)
+    assert len(green_ids) == 25
+    assert (scores[0] == 0.0).sum() == 75
+    # The same seed and previous token give the same green list
+    assert torch.equal(green_ids.sort().values, processor.green_list(7, 100, torch.device("cpu")).sort().values)
+    other = WatermarkLogitsProcessor(gamma=0.25, delta=2.0, seed=43, device=torch.device("cpu"))
+    assert not torch.equal(other.green_list(7, 100, torch.device("cpu")).sort().values, green_ids.sort().values)
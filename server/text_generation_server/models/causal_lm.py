@@ -1,10 +1,11 @@
 import logging
+from copy import copy
 from operator import itemgetter
 
 import torch
 
 from dataclasses import dataclass
-from transformers import AutoModelForCausalLM, PreTrainedTokenizerBase
+from transformers import AutoModelForCausalLM, PreTrainedModel, PreTrainedTokenizerBase
 from typing import Optional, Tuple, List, Type, Union, Any
 
 from text_generation_server.models.model import Model, CUDA_PAD_TO_MULT_OF_8
@@ -101,7 +102,7 @@ class CausalLMBatch(Batch):
             max_remaining_tokens.append(max_output_length)
             padding_right_offset = max(padding_right_offset, max_output_length)
             next_token_choosers.append(NextTokenChooser.from_pb(
-                r.parameters, r.details.logprobs, tokenizer, device,
+                r.parameters, r.details.logprobs, tokenizer, device, r.healing_prefix,
             ))
             i += 1
 
@@ -422,6 +423,18 @@ class CausalLMBatch(Batch):
 
         return batch
 
+    @classmethod
+    def split(cls, batch: "CausalLMBatch", request_ids: List[int]) -> Tuple[Optional["CausalLMBatch"], "CausalLMBatch"]:
+        split_ids = set(request_ids)
+        remaining_ids = [r.id for r in batch.requests if r.id not in split_ids]
+        if not remaining_ids:
+            return None, batch
+        split_batch = copy(batch)
+        # Pruning replaces the layers of the past key values in-place
+        split_batch.past_key_values = list(batch.past_key_values)
+        split_batch = cls.prune(split_batch, remaining_ids)
+        return cls.prune(batch, request_ids), split_batch
+
 def update_layer(layer, batch_size, keep_indices, past_kv_length, hdl, three_dim_pkv) -> List[torch.Tensor]:
     """Slice past kv layer with specific batch indices to keep"""
 
@@ -511,6 +524,21 @@ class CausalLM(Model):
             outputs.past_key_values,
         )
 
+    @property
+    def supports_embeddings(self) -> bool:
+        # ONNX runtime models only output logits
+        return isinstance(self.model, PreTrainedModel)
+
+    def final_hidden_states(self, input_ids: torch.Tensor, attention_mask: torch.Tensor) -> torch.Tensor:
+        model_inputs = {"input_ids": input_ids, "attention_mask": attention_mask}
+        if self.use_position_ids:
+            # Inputs are left-padded
+            position_ids = attention_mask.long().cumsum(-1) - 1
+            position_ids.masked_fill_(attention_mask == 0, 1)
+            model_inputs["position_ids"] = position_ids
+        outputs = self.model.forward(**model_inputs, output_hidden_states=True, return_dict=True)
+        return outputs.hidden_states[-1]
+
     def generate_token(
         self, batch: CausalLMBatch, first: bool = False, for_concat: bool = False,
     ) -> Tuple[List[TokenInfo], Optional[List[InputTokens]], List[GenerateError]]:
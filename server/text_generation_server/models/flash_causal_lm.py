@@ -117,7 +117,7 @@ class FlashCausalLMBatch(Batch):
             cu_seqlens.append(cumulative_length + input_length)
 
             next_token_choosers.append(
-                NextTokenChooser.from_pb(r.parameters, r.details.logprobs, tokenizer, device)
+                NextTokenChooser.from_pb(r.parameters, r.details.logprobs, tokenizer, device, r.healing_prefix)
             )
             all_input_ids_tensor.append(F.pad(tokenized_input, (0, r.max_output_length)))
 
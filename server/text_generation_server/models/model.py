@@ -125,20 +125,45 @@ class Model(ABC):
     ) -> Tuple[List[TokenInfo], Optional[List[InputTokens]], List[GenerateError]]:
         raise NotImplementedError
 
+    @property
+    def supports_embeddings(self) -> bool:
+        return False
+
+    def final_hidden_states(self, input_ids: torch.Tensor, attention_mask: torch.Tensor) -> torch.Tensor:
+        """Final hidden state of each input token, for models which support embeddings"""
+        raise NotImplementedError
+
+    def embed(self, input_texts: List[str], input_lengths: List[int], truncated: List[bool]) -> torch.Tensor:
+        """Embedding of each input, the mean of the final hidden states of its tokens.
+        Truncated inputs are truncated from the left to their input length"""
+        tokenized_inputs = self.tokenizer(
+            input_texts,
+            return_tensors="pt",
+            padding="max_length",
+            truncation=True,
+            max_length=max(input_lengths),
+            return_token_type_ids=False,
+        ).to(self.device)
+        input_ids = tokenized_inputs["input_ids"]
+        attention_mask = tokenized_inputs["attention_mask"]
+
+        # Mask out truncated tokens, as when batched for generation
+        for i, input_length in enumerate(input_lengths):
+            if truncated[i]:
+                attention_mask[i, :-input_length] = 0
+                input_ids[i, :-input_length] = self.tokenizer.pad_token_id
+
+        hidden_states = self.final_hidden_states(input_ids, attention_mask)
+        mask = attention_mask.unsqueeze(-1).to(hidden_states.dtype)
+        return (hidden_states * mask).sum(dim=1) / mask.sum(dim=1).clamp(min=1)
+
     @staticmethod
     def get_indices_to_keep(
         requests: List[generate_pb2.Request], completed_ids: List[int],
     ) -> List[int]:
-        # Compile list of indices to retain
-        next_batch_keep_indices = []
-        completed = iter(completed_ids)
-        next_id = next(completed)
-        for i, r in enumerate(requests):
-            while next_id is not None and r.id > next_id:
-                next_id = next(completed, None)
-            if r.id != next_id:
-                next_batch_keep_indices.append(i)
-        return next_batch_keep_indices
+        # Requests may be out of id order once offloaded requests are resumed
+        completed = set(completed_ids)
+        return [i for i, r in enumerate(requests) if r.id not in completed]
 
     def _setup_prompt_encoder(self) -> bool:
         if hasattr(self.model, "named_children"):
@@ -1,10 +1,11 @@
 import logging
+from copy import copy
 from operator import itemgetter
 
 import torch
 
 from dataclasses import dataclass
-from transformers import AutoModelForSeq2SeqLM, PreTrainedTokenizerBase
+from transformers import AutoModelForSeq2SeqLM, PreTrainedModel, PreTrainedTokenizerBase
 from typing import Optional, Tuple, List, Type, Union, Any
 
 from transformers.modeling_outputs import BaseModelOutput
@@ -121,7 +122,7 @@ class Seq2SeqLMBatch(Batch):
             max_remaining_tokens.append(max_output_length)
             padding_right_offset = max(padding_right_offset, max_output_length)
             next_token_choosers.append(NextTokenChooser.from_pb(
-                r.parameters, r.details.logprobs, tokenizer, device,
+                r.parameters, r.details.logprobs, tokenizer, device, r.healing_prefix,
             ))
             i += 1
 
@@ -471,6 +472,18 @@ class Seq2SeqLMBatch(Batch):
 
         return batch
 
+    @classmethod
+    def split(cls, batch: "Seq2SeqLMBatch", request_ids: List[int]) -> Tuple[Optional["Seq2SeqLMBatch"], "Seq2SeqLMBatch"]:
+        split_ids = set(request_ids)
+        remaining_ids = [r.id for r in batch.requests if r.id not in split_ids]
+        if not remaining_ids:
+            return None, batch
+        split_batch = copy(batch)
+        # Pruning replaces the tensors of each layer of the past key values in-place
+        split_batch.past_key_values = [list(layer) for layer in batch.past_key_values]
+        split_batch = cls.prune(split_batch, remaining_ids)
+        return cls.prune(batch, request_ids), split_batch
+
 
 class Seq2SeqLM(Model):
     def __init__(
@@ -546,6 +559,18 @@ class Seq2SeqLM(Model):
             outputs.past_key_values,
         )
 
+    @property
+    def supports_embeddings(self) -> bool:
+        # ONNX runtime models only output logits
+        return isinstance(self.model, PreTrainedModel)
+
+    def final_hidden_states(self, input_ids: torch.Tensor, attention_mask: torch.Tensor) -> torch.Tensor:
+        # Only the encoder is run
+        outputs = self.model.get_encoder()(
+            input_ids=input_ids, attention_mask=attention_mask, return_dict=True,
+        )
+        return outputs.last_hidden_state
+
     def generate_token(
         self, batch: Seq2SeqLMBatch, first: bool = False, for_concat: bool = False,
     ) -> Tuple[List[TokenInfo], Optional[List[InputTokens]], List[GenerateError]]:
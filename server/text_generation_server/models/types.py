@@ -3,7 +3,7 @@ from functools import total_ordering
 import torch
 
 from abc import ABC, abstractmethod
-from dataclasses import dataclass
+from dataclasses import dataclass, fields
 from typing import List, Optional, Tuple
 
 from transformers import PreTrainedTokenizerBase
@@ -56,11 +56,32 @@ class Batch(ABC):
     def prune(cls, batch: "Batch", completed_ids: List[int]) -> Optional["Batch"]:
         raise NotImplementedError
 
+    @classmethod
+    def split(cls, batch: "Batch", request_ids: List[int]) -> Tuple[Optional["Batch"], "Batch"]:
+        """Split the given requests from a batch into their own batch. Returns
+        the remaining batch, None if no requests remain, and the split one"""
+        # Optional method
+        raise NotImplementedError
+
+    def to(self, device: torch.device) -> "Batch":
+        """Move the batch's tensors to the given device"""
+        for field in fields(self):
+            setattr(self, field.name, _to_device(getattr(self, field.name), device))
+        return self
+
     def compact(self):
         # Optional method
         pass
 
 
+def _to_device(value, device: torch.device):
+    if torch.is_tensor(value):
+        return value.to(device)
+    if isinstance(value, (list, tuple)):
+        return type(value)(_to_device(v, device) for v in value)
+    return value
+
+
 @dataclass(eq=True)
 @total_ordering
 class TopToken:
@@ -1,4 +1,5 @@
 import asyncio
+import inspect
 import logging
 import os
 import threading
@@ -11,12 +12,14 @@ from grpc import aio, StatusCode
 from grpc._cython.cygrpc import AbortError
 
 from grpc_reflection.v1alpha import reflection
+from importlib.metadata import version, PackageNotFoundError
 from pathlib import Path
 from typing import List, Optional
 
 from text_generation_server.cache import Cache
 from text_generation_server.models import Model, get_model, Seq2SeqLM
 from text_generation_server.models.flash_causal_lm import FlashCausalLM
+from text_generation_server.models.types import GenerateError
 from text_generation_server.pb import generate_pb2_grpc, generate_pb2
 from text_generation_server.pb.generate_pb2 import ModelInfoResponse
 from text_generation_server.prompt_cache import PrefixNotFound
@@ -26,8 +29,25 @@ COMPACT_BEFORE_PREFILL = os.getenv("COMPACT_BEFORE_PREFILL", "true") != "false"
 
 HEALTHCHECK_BATCH_ID = (1 << 64) - 1
 
+try:
+    SERVER_VERSION = version("text-generation-server")
+except PackageNotFoundError:
+    SERVER_VERSION = ""
+
 
 def log_errs(func):
+    if inspect.isasyncgenfunction(func):
+        async def gen_with_log(*args, **kwargs):
+            try:
+                async for item in func(*args, **kwargs):
+                    yield item
+            except AbortError:
+                raise
+            except Exception:
+                logging.exception(f"{func.__name__} failed")
+                raise
+        return gen_with_log
+
     async def func_with_log(*args, **kwargs):
         try:
             return await func(*args, **kwargs)
@@ -40,10 +60,20 @@ def log_errs(func):
 
 
 class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
-    def __init__(self, model: Model, cache: Cache, server_urls: List[str]):
+    def __init__(
+        self, model: Model, cache: Cache, server_urls: List[str], model_id: str = "", model_version: str = "",
+    ):
         self.cache = cache
         self.model = model
+        self.model_id = model_id
+        self.model_version = model_version
         self.server_urls = server_urls
+        # Batches prefilled to be concatenated with the running batch, which may be
+        # cached when its next token is generated if the router pipelines prefills
+        self.pending_batch_ids = set()
+        # Requests removed from their batch by OffloadRequests, each held as its
+        # own batch in host memory until it's resumed
+        self.offloaded = {}
 
     async def ServiceDiscovery(
         self, request: generate_pb2.ServiceDiscoveryRequest, context
@@ -55,6 +85,8 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         self, request: generate_pb2.ClearCacheRequest, context
     ) -> generate_pb2.ClearCacheResponse:
         self.cache.clear()
+        self.pending_batch_ids.clear()
+        self.offloaded.clear()
         return generate_pb2.ClearCacheResponse()
 
     @log_errs
@@ -64,6 +96,27 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
                 if isinstance(self.model, Seq2SeqLM) else ModelInfoResponse.ModelType.CAUSAL_LM,
             eos_token=self.model.config.eos_token_id,
             batch_padding=not isinstance(self.model, FlashCausalLM),
+            model_id=self.model_id,
+            model_version=self.model_version,
+        )
+
+    @log_errs
+    async def Capabilities(
+        self, request: generate_pb2.CapabilitiesRequest, context
+    ) -> generate_pb2.CapabilitiesResponse:
+        # Streamed prefill only returns the result
+        return generate_pb2.CapabilitiesResponse(
+            version=SERVER_VERSION,
+            top_n_tokens=True,
+            embeddings=self.model.supports_embeddings,
+            # Requests are split from padded batches
+            offload=not isinstance(self.model, FlashCausalLM),
+            repetition_penalty_range=True,
+            no_repeat_ngram_size=True,
+            watermark=True,
+            bad_words=True,
+            token_healing=True,
+            deterministic=True,
         )
 
     @log_errs
@@ -73,6 +126,26 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         return generate_pb2.HealthResponse()
 
 
+    @log_errs
+    async def Embed(self, request: generate_pb2.EmbedRequest, context) -> generate_pb2.EmbedResponse:
+        if not self.model.supports_embeddings:
+            await context.abort(StatusCode.UNIMPLEMENTED, "embeddings aren't supported for this model")
+        inputs = request.inputs
+        if not inputs:
+            return generate_pb2.EmbedResponse()
+        with self.model.context_manager():
+            embeddings = self.model.embed(
+                [i.inputs for i in inputs],
+                [i.input_length for i in inputs],
+                [i.truncate > 0 for i in inputs],
+            )
+        return generate_pb2.EmbedResponse(
+            embeddings=[
+                generate_pb2.Embedding(id=i.id, values=embedding.float().tolist())
+                for i, embedding in zip(inputs, embeddings)
+            ],
+        )
+
     @log_errs
     async def PrefixLookup(self, request: generate_pb2.PrefixLookupRequest, context) -> generate_pb2.PrefixLookupResponse:
         try:
@@ -86,52 +159,63 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
 
     @log_errs
     async def Prefill(self, request: generate_pb2.PrefillRequest, context) -> generate_pb2.PrefillResponse:
-        with self.model.context_manager():
-            # Prune any existing batches first
-            for cbatch in request.to_prune:
-                batch_to_prune = self.cache.pop(cbatch.batch_id)
-                if batch_to_prune is None:
-                    raise ValueError(f"Batch ID {cbatch.batch_id} not found in cache.")
-
-                if cbatch.HasField("status"):
-                    pruned_batch = self.model.batch_type.prune(batch_to_prune, cbatch.status.completed_ids)
-                    self.cache.set(pruned_batch)
+        return await self._prefill(request)
 
-                # Ensure completed batches are garbage collected before calling prefill
-                del batch_to_prune
+    @log_errs
+    async def PrefillStream(self, request: generate_pb2.PrefillRequest, context):
+        # Inputs are prefilled in a single pass, so there is no progress to report
+        # before the result. Models which prefill in chunks send a progress update
+        # after each chunk but the last
+        yield generate_pb2.PrefillStreamResponse(result=await self._prefill(request))
 
+    async def _prefill(self, request: generate_pb2.PrefillRequest) -> generate_pb2.PrefillResponse:
+        with self.model.context_manager():
             is_healthcheck = request.batch.id == HEALTHCHECK_BATCH_ID
 
             if COMPACT_BEFORE_PREFILL and not is_healthcheck:
                 self.cache.compact()
 
+            # Requests resuming offloaded ones are restored rather than prefilled
+            pb_batch = request.batch
+            resumed = [r for r in pb_batch.requests if r.HasField("resumed_id")]
+            if resumed:
+                pb_batch = generate_pb2.Batch(
+                    id=pb_batch.id,
+                    requests=[r for r in pb_batch.requests if not r.HasField("resumed_id")],
+                )
+
             # Construct new batch
             input_token_info = None
             batch, errors = self.model.batch_type.from_pb(
-                request.batch,
+                pb_batch,
                 tokenizer=self.model.tokenizer,
                 device=self.model.device,
                 embeddings_lookup=self.model.word_embeddings,
                 prefix_cache=self.model.prefix_cache,
                 use_position_ids=self.model.use_position_ids,
-            )
+            ) if pb_batch.requests else (None, [])
 
             batch_id = 0
+            for_concat = len(self.cache) > 0
             if batch is not None:
-                for_concat = len(self.cache) > 0
                 # Prefill and generate first token
                 output_tokens, input_token_info, decode_errors = self.model.generate_token(
                     batch, first=True, for_concat=for_concat,
                 )
+                errors.extend(decode_errors)
+            else:
+                output_tokens = []
+
+            if resumed:
+                batch = self._resume(resumed, batch, output_tokens, errors)
+
+            if batch is not None:
+                batch.batch_id = request.batch.id
                 if not is_healthcheck:
                     self.cache.set(batch)
+                    if for_concat:
+                        self.pending_batch_ids.add(batch.get_id())
                 batch_id = batch.get_id()
-                if errors:
-                    errors.extend(decode_errors)
-                else:
-                    errors = decode_errors
-            else:
-                output_tokens = []
 
             return generate_pb2.PrefillResponse(
                 result=generate_pb2.GenerateResult(
@@ -146,6 +230,78 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
                 ] if input_token_info is not None else None,
             )
 
+    def _resume(self, requests, batch, output_tokens, errors):
+        """Restore the offloaded requests which the given ones resume and generate their next
+        tokens, returning them concatenated with the newly prefilled batch, if any"""
+        batches = []
+        for r in requests:
+            offloaded = self.offloaded.pop(r.resumed_id, None)
+            if offloaded is None:
+                errors.append(GenerateError(
+                    request_id=r.id, message=f"Offloaded request #{r.resumed_id} not found",
+                ))
+                continue
+            # Responses are returned for the new request id and options
+            offloaded.requests = [r]
+            batches.append(offloaded.to(self.model.device))
+        if not batches:
+            return batch
+
+        batch_type = self.model.batch_type
+        resumed = batches[0] if len(batches) == 1 else batch_type.concatenate(batches)
+        del batches
+        resumed_tokens, _, decode_errors = self.model.generate_token(resumed)
+        output_tokens.extend(resumed_tokens)
+        errors.extend(decode_errors)
+        return resumed if batch is None else batch_type.concatenate([batch, resumed])
+
+    @log_errs
+    async def UpdateBatch(self, request: generate_pb2.UpdateBatchRequest, context) -> generate_pb2.UpdateBatchResponse:
+        cbatch = request.batch
+        with self.model.context_manager():
+            batch = self.cache.pop(cbatch.batch_id)
+            self.pending_batch_ids.discard(cbatch.batch_id)
+            if batch is None:
+                raise ValueError(f"Batch ID {cbatch.batch_id} not found in cache.")
+
+            if not cbatch.HasField("status"):
+                # Batch is finished, ensure it's garbage collected
+                del batch
+                return generate_pb2.UpdateBatchResponse()
+
+            batch = self.model.batch_type.prune(batch, cbatch.status.completed_ids)
+            if batch is None:
+                return generate_pb2.UpdateBatchResponse()
+            self.cache.set(batch)
+            return generate_pb2.UpdateBatchResponse(batch_id=batch.get_id())
+
+    @log_errs
+    async def OffloadRequests(
+        self, request: generate_pb2.OffloadRequestsRequest, context
+    ) -> generate_pb2.OffloadRequestsResponse:
+        cbatch = request.batch
+        with self.model.context_manager():
+            batch = self.cache.pop(cbatch.batch_id)
+            self.pending_batch_ids.discard(cbatch.batch_id)
+            if batch is None:
+                raise ValueError(f"Batch ID {cbatch.batch_id} not found in cache.")
+            if cbatch.HasField("status"):
+                batch = self.model.batch_type.prune(batch, cbatch.status.completed_ids)
+
+            for request_id in request.request_ids:
+                if batch is None:
+                    break
+                if not any(r.id == request_id for r in batch.requests):
+                    continue
+                # Each is split into its own batch so that it can be resumed independently
+                batch, offloaded = self.model.batch_type.split(batch, [request_id])
+                self.offloaded[request_id] = offloaded.to(torch.device("cpu"))
+
+            if batch is None:
+                return generate_pb2.OffloadRequestsResponse()
+            self.cache.set(batch)
+            return generate_pb2.OffloadRequestsResponse(batch_id=batch.get_id())
+
     @log_errs
     async def NextToken(self, request: generate_pb2.NextTokenRequest, context) -> generate_pb2.NextTokenResponse:
         if len(request.batches) == 0:
@@ -155,6 +311,7 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
             batches = []
             for cbatch in request.batches:
                 batch = self.cache.pop(cbatch.batch_id)
+                self.pending_batch_ids.discard(cbatch.batch_id)
                 if cbatch.HasField("status"):
                     if batch is None:
                         raise ValueError(f"Batch ID {cbatch.batch_id} not found in cache.")
@@ -162,9 +319,12 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
                     if batch is not None:
                         batches.append(batch)
 
-            if len(self.cache) > 0:
-                print(f"WARN: Clearing additional batches found in cache: {self.cache.keys()}")
-                self.cache.clear()
+            # Batches prefilled concurrently with this step are kept for the next one
+            stale_batch_ids = [bid for bid in self.cache.keys() if bid not in self.pending_batch_ids]
+            if stale_batch_ids:
+                print(f"WARN: Clearing additional batches found in cache: {stale_batch_ids}")
+                for bid in stale_batch_ids:
+                    self.cache.delete(bid)
 
             if len(batches) == 0:
                 # All batches finished, nothing to do
@@ -272,7 +432,12 @@ def serve(
 
         server = aio.server()
         generate_pb2_grpc.add_TextGenerationServiceServicer_to_server(
-            TextGenerationService(model, Cache(), server_urls), server
+            TextGenerationService(
+                model, Cache(), server_urls,
+                model_id=model_name,
+                # The resolved commit of the checkpoint if a revision wasn't requested
+                model_version=revision or getattr(model.config, "_commit_hash", None) or "",
+            ), server
         )
         # SERVICE_NAMES = (
         #     generate_pb2.DESCRIPTOR.services_by_name["TextGenerationService"].full_name,
@@ -503,4 +503,38 @@ class TypicalLogitsWarper(LogitsWarper):
         indices_to_remove = sorted_indices_to_remove.scatter(1, sorted_indices, sorted_indices_to_remove)
 
         scores = scores.masked_fill(indices_to_remove, self.filter_value)
-        return scores
\ No newline at end of file
+        return scores
+
+class WatermarkLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] that adds a bias to the logits of a pseudo-random "green list" of tokens. See [A Watermark
+    for Large Language Models](https://arxiv.org/abs/2301.10226). The green list at each step is determined by the
+    seed and the previous token, so the watermark can be detected given the seed.
+
+    Args:
+        gamma (`float`):
+            Fraction of the vocabulary in the green list, > 0 and < 1.
+        delta (`float`):
+            Bias added to the logits of green list tokens.
+        seed (`int`):
+            Key of the green lists.
+    """
+
+    # Mixes the previous token into the seed
+    HASH_KEY = 15485863
+
+    def __init__(self, gamma: float, delta: float, seed: int, device: torch.device):
+        self.gamma = gamma
+        self.delta = delta
+        self.seed = seed
+        self.generator = torch.Generator(device)
+
+    def green_list(self, prev_token: int, vocab_size: int, device: torch.device) -> torch.LongTensor:
+        self.generator.manual_seed((self.seed * self.HASH_KEY + prev_token) % (1 << 63))
+        vocab_permutation = torch.randperm(vocab_size, generator=self.generator, device=device)
+        return vocab_permutation[:int(vocab_size * self.gamma)]
+
+    def __call__(self, input_ids: torch.LongTensor, scores: torch.FloatTensor) -> torch.FloatTensor:
+        green_ids = self.green_list(int(input_ids[0, -1]), scores.shape[-1], scores.device)
+        scores[:, green_ids] += self.delta
+        return scores
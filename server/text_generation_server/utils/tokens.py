@@ -9,7 +9,7 @@ from transformers.generation.logits_process import RepetitionPenaltyLogitsProces
 from text_generation_server.models.types import TokenInfo, TopToken, InputTokens
 from text_generation_server.pb import generate_pb2
 from text_generation_server.utils.dist import RANK
-from text_generation_server.utils.logits_process import static_warper
+from text_generation_server.utils.logits_process import static_warper, WatermarkLogitsProcessor
 
 FP32_LOGITS = os.getenv("FP32_LOGITS_PROCESS") == "true"
 
@@ -43,9 +43,15 @@ class NextTokenChooser:
     def __init__(
         self, temperature=1.0, top_k=None, top_p=None, typical_p=None, seed=None,
         repetition_penalty: Optional[float] = None,
+        repetition_penalty_range: Optional[int] = None,
+        no_repeat_ngram_size: Optional[int] = None,
+        watermark: Optional[Tuple[float, float]] = None,
+        bad_words_ids: Optional[List[List[int]]] = None,
+        healing_ids: Optional[List[int]] = None,
         length_penalty: Optional[Tuple[int, float]] = None,
         min_new_tokens=0, eos_token_id=None, device=None,
         return_logprobs=False,
+        temperature_schedule: Optional[List[Tuple[int, float]]] = None,
     ):
         if min_new_tokens > 0 and eos_token_id is None:
             raise ValueError("Must provide eos_token_id for min_new_tokens > 0")
@@ -57,8 +63,34 @@ class NextTokenChooser:
             RepetitionPenaltyLogitsProcessor(penalty=float(repetition_penalty))
             if repetition_penalty is not None else None
         )
+        # Number of most recent tokens penalized, None for the entire sequence
+        self.repetition_penalty_range = repetition_penalty_range
+        self.no_repeat_ngram_size = no_repeat_ngram_size
+        # Single tokens are always banned, the last token of longer sequences
+        # only following the rest of the sequence
+        self.bad_words_ids = bad_words_ids
+        self.banned_ids = [ids[0] for ids in bad_words_ids if len(ids) == 1] if bad_words_ids else None
+        # Tokens which the first generated token is constrained to, for token healing
+        self.healing_ids = healing_ids
+        # (gamma, delta) green list fraction and bias, keyed by the seed
+        self.watermark_processor = (
+            WatermarkLogitsProcessor(*watermark, seed=seed, device=device)
+            if watermark is not None else None
+        )
+        # Number of tokens generated before the current step, which end input_ids
+        self.steps = 0
         self.length_penalty = length_penalty if length_penalty is not None and length_penalty[1] > 1.0 else None
 
+        # (token_index, temperature) breakpoints, starting from the request's temperature
+        self.temperature_schedule = None
+        self.generated_tokens = 0
+        if temperature != 0.0 and temperature_schedule:
+            if temperature_schedule[0][0] != 0:
+                temperature_schedule = [(0, temperature)] + temperature_schedule
+            self.temperature_schedule = temperature_schedule
+            # Temperature is applied per token instead, so the cached warper can be shared
+            temperature = 1.0
+
         if temperature == 0.0:
             self.static_warper = None
             self.choice = Greedy()
@@ -88,8 +120,34 @@ class NextTokenChooser:
 
         # Apply repetition penalty if applicable
         if self.repetition_processor is not None:
-            scores = self.repetition_processor(input_ids, scores)
-
+            penalized_ids = input_ids if self.repetition_penalty_range is None \
+                else input_ids[:, -self.repetition_penalty_range:]
+            scores = self.repetition_processor(penalized_ids, scores)
+
+        # Ban tokens which would repeat an n-gram of the generated sequence
+        # or complete a bad word
+        if self.steps > 0 and (self.no_repeat_ngram_size is not None or self.bad_words_ids):
+            generated_ids = input_ids[0, -self.steps:].tolist()
+            banned_ids = []
+            if self.no_repeat_ngram_size is not None:
+                banned_ids += banned_ngram_tokens(generated_ids, self.no_repeat_ngram_size)
+            if self.bad_words_ids:
+                banned_ids += banned_bad_word_tokens(generated_ids, self.bad_words_ids)
+            if banned_ids:
+                scores[:, banned_ids] = -float("inf")
+        if self.banned_ids:
+            scores[:, self.banned_ids] = -float("inf")
+
+        # The first token must continue the text removed from the end of the prompt
+        if self.steps == 0 and self.healing_ids:
+            allowed = scores[:, self.healing_ids]
+            scores[:] = -float("inf")
+            scores[:, self.healing_ids] = allowed
+
+        if self.watermark_processor is not None:
+            scores = self.watermark_processor(input_ids, scores)
+
+        self.steps += 1
         return scores
 
     def __call__(
@@ -101,8 +159,12 @@ class NextTokenChooser:
         # Processs and warp logits
         final_scores = self._process_logits(input_ids, scores)
 
+        if self.temperature_schedule is not None:
+            final_scores.div_(self._scheduled_temperature())
+            self.generated_tokens += 1
+
         if self.static_warper is not None:
-            final_scores, logprobs = self.static_warper(scores)
+            final_scores, logprobs = self.static_warper(final_scores)
         else:
             # Compute logprobs if requested
             logprobs = torch.log_softmax(final_scores, -1) if self.return_logprobs else None
@@ -114,6 +176,16 @@ class NextTokenChooser:
         next_ids = self.choice(final_scores)
         return next_ids.view(-1), final_scores, logprobs
 
+    def _scheduled_temperature(self) -> float:
+        # Interpolate linearly between the breakpoints either side of the current token
+        index = self.generated_tokens
+        prev_index, prev_temp = self.temperature_schedule[0]
+        for next_index, next_temp in self.temperature_schedule[1:]:
+            if index < next_index:
+                return prev_temp + (next_temp - prev_temp) * (index - prev_index) / (next_index - prev_index)
+            prev_index, prev_temp = next_index, next_temp
+        return prev_temp
+
     @classmethod
     def from_pb(
         cls,
@@ -121,6 +193,7 @@ class NextTokenChooser:
         return_logprobs: bool,
         tokenizer: PreTrainedTokenizerBase,
         device: torch.device,
+        healing_prefix: str = "",
     ) -> "NextTokenChooser":
         return NextTokenChooser(
             temperature=pb.temperature,
@@ -129,12 +202,18 @@ class NextTokenChooser:
             typical_p=pb.typical_p,
             seed=pb.seed if pb.HasField('seed') else None,
             repetition_penalty=pb.repetition_penalty if pb.HasField('repetition_penalty') else None,
+            repetition_penalty_range=pb.repetition_penalty_range if pb.HasField('repetition_penalty_range') else None,
+            no_repeat_ngram_size=pb.no_repeat_ngram_size if pb.HasField('no_repeat_ngram_size') else None,
+            watermark=(pb.watermark.gamma, pb.watermark.delta) if pb.HasField('watermark') else None,
+            bad_words_ids=[list(seq.token_ids) for seq in pb.bad_words_ids],
+            healing_ids=healing_token_ids(tokenizer, healing_prefix) if healing_prefix else None,
             length_penalty=(pb.length_penalty.start_index, pb.length_penalty.decay_factor)
             if pb.HasField('length_penalty') else None,
             min_new_tokens=pb.min_new_tokens,
             eos_token_id=getattr(tokenizer, 'model_eos_token_id', tokenizer.eos_token_id),
             device=device,
             return_logprobs=return_logprobs,
+            temperature_schedule=[(b.token_index, b.temperature) for b in pb.temperature_schedule],
         )
 
     @staticmethod
@@ -149,6 +228,32 @@ class NextTokenChooser:
                 print("WARNING: Found nan in logits, before warp =", bool(nan_before))
 
 
+def banned_ngram_tokens(ids: List[int], n: int) -> List[int]:
+    """Tokens which would complete an n-gram already present in ids"""
+    prefix = ids[len(ids) - n + 1:] if n > 1 else []
+    if len(prefix) < n - 1:
+        return []
+    return [ids[i + n - 1] for i in range(len(ids) - n + 1) if ids[i:i + n - 1] == prefix]
+
+
+def banned_bad_word_tokens(ids: List[int], bad_words_ids: List[List[int]]) -> List[int]:
+    """Last tokens of the multi-token bad words whose other tokens end ids"""
+    return [
+        bad_ids[-1] for bad_ids in bad_words_ids
+        if 1 < len(bad_ids) <= len(ids) + 1 and ids[len(ids) - len(bad_ids) + 1:] == bad_ids[:-1]
+    ]
+
+
+def healing_token_ids(tokenizer: PreTrainedTokenizerBase, prefix: str) -> Optional[List[int]]:
+    """Tokens whose text starts with the prefix, None if there are none"""
+    # Decoded once per tokenizer
+    vocab = getattr(tokenizer, "healing_vocab", None)
+    if vocab is None:
+        vocab = tokenizer.batch_decode([[i] for i in range(len(tokenizer))])
+        tokenizer.healing_vocab = vocab
+    return [i for i, text in enumerate(vocab) if text.startswith(prefix)] or None
+
+
 # Extract requested token information from model output
 def get_token_info(
     request: generate_pb2.Request,
@@ -197,7 +302,7 @@ def get_input_tokens_info(request, input_token_ids, all_input_logits) -> InputTo
     #TODO optimize this ... can do single gather for chosen and topn logprobs
 
     # Collect logprobs if requested
-    return_logprobs = request.details.logprobs
+    return_logprobs = request.details.logprobs or request.details.input_logprobs
     if return_logprobs:
         all_input_logprobs = torch.log_softmax(all_input_logits, -1)
         # logprobs of input tokens (except the first one)
@@ -209,7 +314,7 @@ def get_input_tokens_info(request, input_token_ids, all_input_logits) -> InputTo
         logprobs_gen = FLOAT_ZEROS
 
     # Collect ranks if requested
-    if request.details.ranks:
+    if request.details.ranks or request.details.input_ranks:
         if return_logprobs:
             # Use logprobs that are already gathered
             ranks_gen = chain(SINGLE_ZERO, ((all_input_logprobs > input_logprobs).sum(dim=1) + 1))